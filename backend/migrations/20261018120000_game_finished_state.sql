@@ -0,0 +1,26 @@
+-- Until now, the only way to learn if a game is over was to replay the whole
+-- action history. The timeout of a running game was only known in memory.
+-- We now persist both on every update of the game.
+
+-- The serialized pacosako::VictoryState, e.g. "Running" or {"PacoVictory":"White"}.
+-- NULL for games that were not updated since this migration.
+ALTER TABLE game ADD COLUMN victory_state TEXT;
+-- When the game was decided. NULL while the game is still running.
+ALTER TABLE game ADD COLUMN finished_at TIMESTAMP;
+-- Unix timestamp in milliseconds when the controlling player runs out of time.
+-- NULL if there is no running clock.
+ALTER TABLE game ADD COLUMN timeout_at INTEGER;
+
+CREATE INDEX idx_game_timeout_at ON game (timeout_at) WHERE timeout_at IS NOT NULL;
+
+-- Games that already ran out of time can be classified without a replay.
+UPDATE game
+SET victory_state = json_object(
+        'TimeoutVictory',
+        CASE json_extract(timer, '$.timer_state.Timeout')
+            WHEN 'White' THEN 'Black'
+            ELSE 'White'
+        END
+    ),
+    finished_at = datetime(json_extract(timer, '$.last_timestamp'))
+WHERE json_extract(timer, '$.timer_state.Timeout') IS NOT NULL;
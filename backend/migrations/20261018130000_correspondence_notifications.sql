@@ -0,0 +1,25 @@
+-- Correspondence games need to know whose turn it is without replaying the
+-- game. This allows listing all games where it is the user's turn.
+-- Either 'White' or 'Black'. NULL for finished games and for games that were
+-- not updated since this migration.
+ALTER TABLE game ADD COLUMN controlling_player TEXT;
+
+CREATE INDEX idx_game_white_player_to_move ON game (white_player, controlling_player);
+CREATE INDEX idx_game_black_player_to_move ON game (black_player, controlling_player);
+
+-- Every notification we send is logged here. This makes sure the same event
+-- is not delivered twice, e.g. when the server restarts.
+CREATE TABLE notification_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    game_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    -- 'your_turn' or 'deadline_approaching'
+    kind TEXT NOT NULL,
+    -- Number of actions in the game when the event happened. Together with the
+    -- kind this identifies the turn the notification is about.
+    action_count INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (game_id) REFERENCES game(id),
+    FOREIGN KEY (user_id) REFERENCES user(id),
+    UNIQUE (game_id, user_id, kind, action_count)
+);
@@ -0,0 +1,16 @@
+-- Takebacks remove actions from the action history of a game. To still show
+-- them in the replay, every accepted takeback is recorded here.
+CREATE TABLE game_takeback (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    game_id INTEGER NOT NULL,
+    -- 'White' or 'Black'
+    requested_by TEXT NOT NULL,
+    -- The action history was truncated to this length.
+    action_index INTEGER NOT NULL,
+    -- JSON list of the removed actions, with their timestamps.
+    removed_actions TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (game_id) REFERENCES game(id)
+);
+
+CREATE INDEX idx_game_takeback_game_id ON game_takeback (game_id);
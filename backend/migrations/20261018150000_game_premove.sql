@@ -0,0 +1,16 @@
+-- Premoves and conditional moves prepared by a player during the turn of their
+-- opponent. They are executed by the server once the opponent's turn is done.
+CREATE TABLE game_premove (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    game_id INTEGER NOT NULL,
+    -- 'White' or 'Black', the player who prepared the premove.
+    player TEXT NOT NULL,
+    -- Start of the opponent's turn this premove answers.
+    action_index INTEGER NOT NULL,
+    -- JSON of a sync_match::Premove, possibly with follow up premoves.
+    premove TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (game_id) REFERENCES game(id)
+);
+
+CREATE INDEX idx_game_premove_game_id ON game_premove (game_id, player);
@@ -0,0 +1,39 @@
+-- Bot accounts play through the REST API and authenticate with API tokens.
+ALTER TABLE user ADD COLUMN is_bot INTEGER NOT NULL DEFAULT 0;
+
+-- Only a hash of the token is stored, the token itself is shown once.
+CREATE TABLE api_token (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    -- Chosen by the user to tell their tokens apart.
+    name TEXT NOT NULL,
+    -- Hex encoded blake3 hash of the token.
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP NULL,
+    FOREIGN KEY (user_id) REFERENCES user(id)
+);
+
+CREATE INDEX idx_api_token_user_id ON api_token (user_id);
+
+-- A challenge is an invitation to play a game with given parameters.
+CREATE TABLE challenge (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    challenger_id INTEGER NOT NULL,
+    -- Null for an open challenge that anyone can accept.
+    challenged_id INTEGER NULL,
+    -- 'White' or 'Black' for the color of the challenger, null for random.
+    color TEXT NULL,
+    -- JSON of the sync_match::MatchParameters for the game.
+    parameters TEXT NOT NULL,
+    -- 'open' or 'accepted'
+    status TEXT NOT NULL DEFAULT 'open',
+    -- The game that was created when the challenge was accepted.
+    game_id INTEGER NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (challenger_id) REFERENCES user(id),
+    FOREIGN KEY (challenged_id) REFERENCES user(id),
+    FOREIGN KEY (game_id) REFERENCES game(id)
+);
+
+CREATE INDEX idx_challenge_challenged_id ON challenge (challenged_id, status);
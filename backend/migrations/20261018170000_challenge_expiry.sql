@@ -0,0 +1,9 @@
+-- Challenges expire if nobody answers them. They can also be declined by the
+-- challenged user or canceled by the challenger.
+-- status is now one of 'open', 'accepted', 'declined', 'canceled', 'expired'.
+ALTER TABLE challenge ADD COLUMN expires_at TIMESTAMP NULL;
+
+UPDATE challenge SET expires_at = datetime(created_at, '+1 days');
+
+CREATE INDEX idx_challenge_challenger_id ON challenge (challenger_id, status);
+CREATE INDEX idx_challenge_expires_at ON challenge (expires_at) WHERE status = 'open';
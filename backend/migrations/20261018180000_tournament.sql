@@ -0,0 +1,54 @@
+-- Tournaments pair registered players into games with the same parameters.
+CREATE TABLE tournament (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    -- 'Swiss' or 'Arena'
+    kind TEXT NOT NULL,
+    -- JSON of the sync_match::MatchParameters for all games.
+    parameters TEXT NOT NULL,
+    -- Swiss only, the number of rounds.
+    rounds INTEGER NULL,
+    -- Arena only, how long new games are paired.
+    duration_minutes INTEGER NULL,
+    -- 'registration', 'running' or 'finished'
+    status TEXT NOT NULL DEFAULT 'registration',
+    current_round INTEGER NOT NULL DEFAULT 0,
+    created_by INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    started_at TIMESTAMP NULL,
+    -- Arena only, unix milliseconds after which no new games are paired.
+    ends_at INTEGER NULL,
+    finished_at TIMESTAMP NULL,
+    FOREIGN KEY (created_by) REFERENCES user(id)
+);
+
+CREATE INDEX idx_tournament_status ON tournament (status);
+
+CREATE TABLE tournament_player (
+    tournament_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    -- Withdrawn players are not paired anymore, but keep their results.
+    withdrawn INTEGER NOT NULL DEFAULT 0,
+    joined_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (tournament_id, user_id),
+    FOREIGN KEY (tournament_id) REFERENCES tournament(id),
+    FOREIGN KEY (user_id) REFERENCES user(id)
+);
+
+CREATE TABLE tournament_game (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    tournament_id INTEGER NOT NULL,
+    round INTEGER NOT NULL,
+    -- Null for a bye.
+    game_id INTEGER NULL,
+    white_player INTEGER NOT NULL,
+    -- Null for a bye.
+    black_player INTEGER NULL,
+    -- 'WhiteWins', 'BlackWins' or 'Draw', null while the game is running.
+    outcome TEXT NULL,
+    FOREIGN KEY (tournament_id) REFERENCES tournament(id),
+    FOREIGN KEY (game_id) REFERENCES game(id)
+);
+
+CREATE INDEX idx_tournament_game_tournament_id ON tournament_game (tournament_id);
+CREATE INDEX idx_tournament_game_game_id ON tournament_game (game_id);
@@ -0,0 +1,17 @@
+-- Columns and indexes for the game search.
+
+-- Openings from pacosako::analysis::classify_opening, comma separated.
+-- Empty if no known opening was played, NULL if the game was not classified
+-- yet. Old games are classified in the background after the server starts.
+ALTER TABLE game ADD COLUMN opening TEXT;
+
+-- Virtual columns can be added to an existing table, stored ones can't.
+-- The index makes sure the value is only computed when the game is written.
+ALTER TABLE game ADD COLUMN action_count INTEGER
+    GENERATED ALWAYS AS (json_array_length(action_history)) VIRTUAL;
+
+CREATE INDEX idx_game_created ON game (created);
+CREATE INDEX idx_game_victory_state ON game (victory_state);
+CREATE INDEX idx_game_expected_time_limit ON game (expected_time_limit);
+CREATE INDEX idx_game_action_count ON game (action_count);
+CREATE INDEX idx_game_unclassified ON game (id) WHERE opening IS NULL;
@@ -0,0 +1,9 @@
+-- Replay analysis computed by the server. Rows posted by AIs have no version.
+alter table game_replay_metadata add column analysis_version integer;
+create index idx_game_replay_metadata_game on game_replay_metadata(game_id);
+
+-- The version of the replay analysis stored for the game, null if the game was
+-- never analyzed.
+alter table game add column replay_analysis_version integer;
+create index idx_game_replay_analysis on game(replay_analysis_version)
+    where finished_at is not null;
@@ -0,0 +1,16 @@
+-- Email addresses users give when they register. Only verified addresses are
+-- used for anything.
+CREATE TABLE user_email (
+    user_id INTEGER PRIMARY KEY,
+    email TEXT NOT NULL,
+    verified_at TIMESTAMP NULL,
+    -- Hex encoded blake3 hash of the token in the verification link. Null once
+    -- the address is verified.
+    verification_token_hash TEXT NULL UNIQUE,
+    verification_expires_at TIMESTAMP NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES user(id)
+);
+
+-- Usernames are unique regardless of case.
+CREATE INDEX idx_login_identifier_lower ON login(lower(identifier));
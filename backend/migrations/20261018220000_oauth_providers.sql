@@ -0,0 +1,24 @@
+-- Logins with any OAuth2 provider, not only Discord. The type is 'password' or
+-- the name of the provider. Identifiers are unique per type, as different
+-- providers may use the same user ids.
+CREATE TABLE login_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    identifier TEXT NOT NULL,
+    hashed_password TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_login TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES user(id),
+    UNIQUE (type, identifier)
+);
+
+INSERT INTO login_new (id, user_id, type, identifier, hashed_password, created_at, last_login)
+SELECT id, user_id, type, identifier, hashed_password, created_at, last_login FROM login;
+
+DROP TABLE login;
+ALTER TABLE login_new RENAME TO login;
+
+CREATE INDEX idx_login_user_id ON login(user_id);
+-- Usernames are unique regardless of case.
+CREATE INDEX idx_login_username_lower ON login(lower(identifier)) WHERE type = 'password';
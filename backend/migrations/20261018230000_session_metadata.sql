@@ -0,0 +1,9 @@
+-- Metadata so users can recognize their sessions, and an idle expiry.
+-- Sessions expire at `expires_at`, or when they were not used for a while.
+ALTER TABLE session ADD COLUMN last_seen_at TIMESTAMP NULL;
+ALTER TABLE session ADD COLUMN user_agent TEXT NULL;
+-- Hex encoded keyed blake3 hash of the client address. The address itself is
+-- never stored.
+ALTER TABLE session ADD COLUMN ip_hash TEXT NULL;
+
+CREATE INDEX idx_session_expires_at ON session(expires_at);
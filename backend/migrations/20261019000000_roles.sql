@@ -0,0 +1,26 @@
+-- Roles bundle permissions, so we don't have to grant every permission to every
+-- moderator one by one. Which permissions a role has is defined in the code
+-- (login::permission), just like the permissions themselves. Permissions that
+-- are granted directly in `user_permission` still work.
+CREATE TABLE user_role (
+    user_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    granted_by INTEGER NULL,
+    granted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, role),
+    FOREIGN KEY (user_id) REFERENCES user(id)
+);
+
+-- Who granted or revoked which role, when and why. There are no foreign keys,
+-- the audit trail stays when users delete their account.
+CREATE TABLE user_role_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    action TEXT NOT NULL CHECK (action IN ('grant', 'revoke')),
+    changed_by INTEGER NOT NULL,
+    changed_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    reason TEXT NULL
+);
+
+CREATE INDEX idx_user_role_audit_user_id ON user_role_audit(user_id);
@@ -0,0 +1,56 @@
+-- Moderation: reports by users, sanctions against users and an audit trail of
+-- everything moderators do. None of these tables have foreign keys to users,
+-- so the history stays when accounts are deleted.
+
+-- Bans and mutes. A sanction is active until it expires or is lifted.
+-- `expires_at` is null for permanent sanctions.
+CREATE TABLE user_sanction (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('ban', 'mute')),
+    reason TEXT NOT NULL,
+    created_by INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NULL,
+    lifted_at TIMESTAMP NULL,
+    lifted_by INTEGER NULL
+);
+
+CREATE INDEX idx_user_sanction_user_id ON user_sanction(user_id);
+
+-- Users report a player, a game or both.
+CREATE TABLE report (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    reported_by INTEGER NOT NULL,
+    user_id INTEGER NULL,
+    game_id INTEGER NULL,
+    category TEXT NOT NULL,
+    description TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    status TEXT NOT NULL DEFAULT 'open' CHECK (status IN ('open', 'resolved', 'dismissed')),
+    handled_by INTEGER NULL,
+    handled_at TIMESTAMP NULL,
+    resolution TEXT NULL,
+    CHECK (user_id IS NOT NULL OR game_id IS NOT NULL)
+);
+
+CREATE INDEX idx_report_status ON report(status);
+
+-- Annulled games don't count for anything, like the player statistics.
+ALTER TABLE game ADD COLUMN annulled_at TIMESTAMP NULL;
+
+CREATE TABLE moderation_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    moderator_id INTEGER NOT NULL,
+    action TEXT NOT NULL,
+    user_id INTEGER NULL,
+    game_id INTEGER NULL,
+    report_id INTEGER NULL,
+    -- JSON with details that depend on the action.
+    details TEXT NULL,
+    reason TEXT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_moderation_audit_user_id ON moderation_audit(user_id);
+CREATE INDEX idx_moderation_audit_game_id ON moderation_audit(game_id);
@@ -0,0 +1,22 @@
+-- Fair-play analysis: how closely the decisions of a user follow the model.
+-- There is one row per user, a new analysis replaces the old one.
+CREATE TABLE fair_play_analysis (
+    user_id INTEGER PRIMARY KEY,
+    status TEXT NOT NULL DEFAULT 'queued' CHECK (status IN ('queued', 'running', 'done', 'failed')),
+    requested_by INTEGER NOT NULL,
+    requested_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    analyzed_at TIMESTAMP NULL,
+    games INTEGER NOT NULL DEFAULT 0,
+    decisions INTEGER NOT NULL DEFAULT 0,
+    top1_agreement REAL NULL,
+    average_rank REAL NULL,
+    -- In hundredths of a model value, per decision.
+    average_value_loss REAL NULL,
+    -- Average seconds per decision, split by whether the favorite of the
+    -- model was played.
+    think_time_top1 REAL NULL,
+    think_time_other REAL NULL,
+    error TEXT NULL
+);
+
+CREATE INDEX idx_fair_play_analysis_status ON fair_play_analysis(status);
@@ -0,0 +1,19 @@
+-- Exports of all data of a user, generated in the background. There is one
+-- per user, a new request replaces the previous export.
+CREATE TABLE account_export (
+    user_id INTEGER PRIMARY KEY,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'done', 'failed')),
+    requested_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    finished_at TIMESTAMP NULL,
+    -- The JSON archive, once it is done.
+    archive TEXT NULL,
+    error TEXT NULL,
+    FOREIGN KEY (user_id) REFERENCES user(id)
+);
+
+-- Who posted the replay metadata, so it can be exported. Null for the server
+-- side replay analysis and for metadata posted before this column existed.
+ALTER TABLE game_replay_metadata ADD COLUMN created_by INTEGER NULL;
+
+CREATE INDEX idx_game_replay_metadata_created_by ON game_replay_metadata(created_by)
+    WHERE created_by IS NOT NULL;
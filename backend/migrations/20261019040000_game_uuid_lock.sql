@@ -0,0 +1,14 @@
+-- Client UUIDs that locked a side of a game for an anonymous player. Stored so
+-- the locks survive a restart and the player can later claim the side for
+-- their account. The row is removed once a user owns the side.
+CREATE TABLE game_uuid_lock (
+    game_id INTEGER NOT NULL,
+    -- 'White' or 'Black'
+    player TEXT NOT NULL,
+    uuid TEXT NOT NULL,
+    locked_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (game_id, player),
+    FOREIGN KEY (game_id) REFERENCES game(id)
+);
+
+CREATE INDEX idx_game_uuid_lock_uuid ON game_uuid_lock(uuid);
@@ -0,0 +1,17 @@
+-- Profile fields a user can edit on /api/me/profile. All of them are optional.
+
+-- Shown instead of the name. Unique regardless of case, see the index below.
+ALTER TABLE user ADD COLUMN display_name TEXT NULL;
+ALTER TABLE user ADD COLUMN bio TEXT NULL;
+-- ISO 3166-1 alpha-2 code like 'DE', the frontend shows it as a flag.
+ALTER TABLE user ADD COLUMN country TEXT NULL;
+-- Preferred language of the user interface, like 'en'. Takes precedence over
+-- the language cookie when the user is logged in.
+ALTER TABLE user ADD COLUMN language TEXT NULL;
+-- Board colors as a JSON object, in the format of the frontend color config.
+ALTER TABLE user ADD COLUMN board_theme TEXT NULL;
+-- Time control preselected when the user creates a game, as a JSON timer
+-- config.
+ALTER TABLE user ADD COLUMN default_time_control TEXT NULL;
+
+CREATE UNIQUE INDEX idx_user_display_name ON user(lower(display_name));
@@ -0,0 +1,14 @@
+-- Users follow each other. Two users who follow each other are friends.
+CREATE TABLE follow (
+    follower_id INTEGER NOT NULL,
+    followed_id INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (follower_id, followed_id),
+    FOREIGN KEY (follower_id) REFERENCES user(id),
+    FOREIGN KEY (followed_id) REFERENCES user(id)
+);
+
+CREATE INDEX idx_follow_followed_id ON follow(followed_id);
+
+-- The feed lists finished games of followed players, newest first.
+CREATE INDEX idx_game_finished_at ON game(finished_at) WHERE finished_at IS NOT NULL;
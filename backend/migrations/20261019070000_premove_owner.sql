@@ -0,0 +1,5 @@
+-- Who prepared a premove. The premove is only executed if this player still
+-- controls the side when the opponent's turn is done. NULL for premoves stored
+-- before this migration, which are never executed.
+ALTER TABLE game_premove ADD COLUMN uuid TEXT;
+ALTER TABLE game_premove ADD COLUMN user_id INTEGER;
@@ -0,0 +1,12 @@
+-- Usernames are unique regardless of case. The index of the registration only
+-- sped up the lookup, so two accounts could still get the same name when they
+-- registered at the same time.
+--
+-- Creating the unique index fails while such accounts exist. They have to be
+-- resolved by hand together with the affected users, a login can't be renamed
+-- behind their back. This lists them:
+--
+--   SELECT lower(identifier), group_concat(user_id) FROM login
+--   WHERE type = 'password' GROUP BY lower(identifier) HAVING count(*) > 1;
+DROP INDEX idx_login_username_lower;
+CREATE UNIQUE INDEX idx_login_username_lower ON login(lower(identifier)) WHERE type = 'password';
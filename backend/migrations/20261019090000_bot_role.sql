@@ -0,0 +1,4 @@
+-- Bot accounts are marked with `user.is_bot`, the bot role only duplicated it.
+-- Users who had the role become bot accounts.
+UPDATE user SET is_bot = 1 WHERE id IN (SELECT user_id FROM user_role WHERE role = 'bot');
+DELETE FROM user_role WHERE role = 'bot';
@@ -0,0 +1,3 @@
+-- Followers only learn which game a player is in if the player allows it,
+-- see doc/following.md.
+ALTER TABLE user ADD COLUMN share_games_with_followers INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,21 @@
+-- Assignments stay in the audit log when the account that made them is
+-- deleted. SQLite can't drop a NOT NULL constraint, so the table is copied.
+CREATE TABLE game_assignment_audit_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    game_id INTEGER NOT NULL,
+    -- Null once the account that made the assignment is deleted.
+    assigned_by INTEGER NULL,
+    assigned_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    white_assignee INTEGER,
+    black_assignee INTEGER,
+    FOREIGN KEY (game_id) REFERENCES game(id),
+    FOREIGN KEY (assigned_by) REFERENCES user(id),
+    FOREIGN KEY (white_assignee) REFERENCES user(id),
+    FOREIGN KEY (black_assignee) REFERENCES user(id)
+);
+
+INSERT INTO game_assignment_audit_new (id, game_id, assigned_by, assigned_at, white_assignee, black_assignee)
+SELECT id, game_id, assigned_by, assigned_at, white_assignee, black_assignee FROM game_assignment_audit;
+
+DROP TABLE game_assignment_audit;
+ALTER TABLE game_assignment_audit_new RENAME TO game_assignment_audit;
@@ -0,0 +1,11 @@
+-- SQLite's lower() only folds ASCII, but display names may use any script. The
+-- server stores the display name in lower case next to it, folded with the
+-- Unicode rules, and the uniqueness is checked on that column.
+ALTER TABLE user ADD COLUMN display_name_lower TEXT NULL;
+
+-- Existing names can only be folded by SQLite here. They are folded completely
+-- the next time they are changed.
+UPDATE user SET display_name_lower = lower(display_name) WHERE display_name IS NOT NULL;
+
+DROP INDEX idx_user_display_name;
+CREATE UNIQUE INDEX idx_user_display_name_lower ON user(display_name_lower);
@@ -0,0 +1,642 @@
+//! Export of all data we store about a user, as a single JSON archive.
+//!
+//! Accounts with many games take a while to export, so the archive is always
+//! generated in the background. The user requests an export, polls its status
+//! and downloads the archive once it is done. Archives are removed after
+//! `ARCHIVE_LIFETIME_DAYS`.
+//!
+//! Secrets are never exported: no password hashes, OAuth tokens, API tokens or
+//! session ids. The format is described in doc/account-export.md.
+
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::{
+    db::{self, replay_metadata::CreatedReplayMetadata, Connection, Pool},
+    export::ExportedGame,
+    login::{
+        api_token::{self, ApiTokenInfo},
+        oauth::{self, LoginInfo},
+        permission,
+        profile::{self, BoardTheme},
+        session::{self, SessionData, SessionInfo},
+        UserId,
+    },
+    rate_limit::RateLimiter,
+    timer::TimerConfig,
+    AppState, ServerError,
+};
+
+/// Version of the archive format.
+const FORMAT_VERSION: u32 = 1;
+
+/// Archives are removed this long after they are done.
+const ARCHIVE_LIFETIME_DAYS: i64 = 7;
+
+/// How many games are loaded from the database at once.
+const GAME_BATCH_SIZE: i64 = 100;
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+lazy_static! {
+    /// Exports requested by the same user.
+    static ref EXPORTS_PER_USER: RateLimiter = RateLimiter::new(3, Duration::from_secs(24 * 60 * 60));
+}
+
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router
+        .route("/me/export", get(export_status).post(request_export))
+        .route("/me/export/download", get(download_export))
+}
+
+// Archive /////////////////////////////////////////////////////////////////////
+
+#[derive(Serialize)]
+struct AccountArchive {
+    format_version: u32,
+    generated_at: String,
+    profile: Profile,
+    logins: Vec<LoginInfo>,
+    api_tokens: Vec<ApiTokenInfo>,
+    sessions: Vec<SessionInfo>,
+    /// All games the user played, oldest first, in the format of the game
+    /// export. AI players come with their configuration.
+    games: Vec<ExportedGame>,
+    /// Replay metadata the user posted for games.
+    replay_metadata: Vec<CreatedReplayMetadata>,
+    challenges: Vec<Challenge>,
+    tournaments: Vec<TournamentEntry>,
+    reports: Vec<Report>,
+    sanctions: Vec<Sanction>,
+    /// Users the user follows.
+    following: Vec<Follow>,
+    /// Users that follow the user.
+    followers: Vec<Follow>,
+}
+
+#[derive(Serialize)]
+struct Profile {
+    user_id: i64,
+    name: Option<String>,
+    display_name: Option<String>,
+    avatar: String,
+    bio: Option<String>,
+    country: Option<String>,
+    language: Option<String>,
+    board_theme: Option<BoardTheme>,
+    default_time_control: Option<TimerConfig>,
+    share_games_with_followers: bool,
+    is_bot: bool,
+    created_at: Option<String>,
+    updated_at: Option<String>,
+    email: Option<Email>,
+    roles: Vec<permission::Role>,
+    /// Permissions granted directly, on top of the roles.
+    permissions: Vec<String>,
+    /// Names of AI models this account plays as.
+    model_names: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Email {
+    email: String,
+    verified_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Challenge {
+    id: i64,
+    challenger_id: i64,
+    challenged_id: Option<i64>,
+    color: Option<String>,
+    /// JSON of the match parameters.
+    parameters: String,
+    status: String,
+    game_id: Option<i64>,
+    created_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TournamentEntry {
+    tournament_id: i64,
+    name: String,
+    withdrawn: bool,
+    joined_at: Option<String>,
+}
+
+/// A report the user sent.
+#[derive(Serialize)]
+struct Report {
+    id: i64,
+    user_id: Option<i64>,
+    game_id: Option<i64>,
+    category: String,
+    description: String,
+    created_at: Option<String>,
+    status: String,
+    resolution: Option<String>,
+}
+
+/// A sanction against the user. Which moderator gave it is not exported.
+#[derive(Serialize)]
+struct Sanction {
+    kind: String,
+    reason: String,
+    created_at: Option<String>,
+    expires_at: Option<String>,
+    lifted_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Follow {
+    user_id: i64,
+    created_at: Option<String>,
+}
+
+impl AccountArchive {
+    /// Collects the archive. Games are loaded in batches with a fresh
+    /// connection each, so a large export doesn't keep one busy.
+    async fn generate(user_id: UserId, pool: &Pool) -> Result<Self, ServerError> {
+        let mut conn = pool.conn().await?;
+        let profile = load_profile(user_id, &mut conn).await?;
+        let logins = oauth::logins_of(user_id, &mut conn).await?;
+        let api_tokens = api_token::tokens_of(user_id, &mut conn).await?;
+        let sessions = session::active_sessions(user_id, None, &mut conn).await?;
+        let replay_metadata = db::replay_metadata::created_by(user_id, &mut conn).await?;
+        let challenges = load_challenges(user_id, &mut conn).await?;
+        let tournaments = load_tournaments(user_id, &mut conn).await?;
+        let reports = load_reports(user_id, &mut conn).await?;
+        let sanctions = load_sanctions(user_id, &mut conn).await?;
+        let (following, followers) = load_follows(user_id, &mut conn).await?;
+        drop(conn);
+
+        let mut games = vec![];
+        let mut after_id = 0;
+        loop {
+            let mut conn = pool.conn().await?;
+            let records =
+                db::game::for_player_export(user_id.0, after_id, GAME_BATCH_SIZE, &mut conn).await?;
+            let done = records.len() < GAME_BATCH_SIZE as usize;
+            for record in records {
+                after_id = record.game.key.parse()?;
+                games.push(ExportedGame::load(record, &mut conn).await?);
+            }
+            if done {
+                break;
+            }
+        }
+
+        Ok(AccountArchive {
+            format_version: FORMAT_VERSION,
+            generated_at: chrono::Utc::now().to_rfc3339(),
+            profile,
+            logins,
+            api_tokens,
+            sessions,
+            games,
+            replay_metadata,
+            challenges,
+            tournaments,
+            reports,
+            sanctions,
+            following,
+            followers,
+        })
+    }
+}
+
+async fn load_profile(user_id: UserId, conn: &mut Connection) -> Result<Profile, ServerError> {
+    let user = sqlx::query!(
+        r#"select name, avatar, is_bot, created_at as "created_at: String", updated_at as "updated_at: String"
+        from user where id = ?"#,
+        user_id.0
+    )
+        .fetch_optional(&mut **conn)
+        .await?
+        .ok_or(ServerError::NotFound)?;
+    let email = sqlx::query_as!(
+        Email,
+        r#"select email, verified_at as "verified_at: String" from user_email where user_id = ?"#,
+        user_id.0
+    )
+        .fetch_optional(&mut **conn)
+        .await?;
+    let profile = profile::load_profile(user_id, &mut *conn).await?;
+    let roles = permission::roles_of(user_id, &mut *conn).await?;
+    let permissions = sqlx::query!(
+        "select permission from user_permission where user_id = ? order by permission",
+        user_id.0
+    )
+        .fetch_all(&mut **conn)
+        .await?;
+    let model_names = sqlx::query!(
+        "select model_name from user_modelName where user_id = ? order by model_name",
+        user_id.0
+    )
+        .fetch_all(&mut **conn)
+        .await?;
+
+    Ok(Profile {
+        user_id: user_id.0,
+        name: user.name,
+        display_name: profile.display_name,
+        avatar: user.avatar,
+        bio: profile.bio,
+        country: profile.country,
+        language: profile.language,
+        board_theme: profile.board_theme,
+        default_time_control: profile.default_time_control,
+        share_games_with_followers: profile.share_games_with_followers,
+        is_bot: user.is_bot != 0,
+        created_at: user.created_at,
+        updated_at: user.updated_at,
+        email,
+        roles,
+        permissions: permissions.into_iter().map(|r| r.permission).collect(),
+        model_names: model_names.into_iter().map(|r| r.model_name).collect(),
+    })
+}
+
+/// Challenges the user sent or received.
+async fn load_challenges(user_id: UserId, conn: &mut Connection) -> Result<Vec<Challenge>, ServerError> {
+    Ok(sqlx::query_as!(
+        Challenge,
+        r#"select id as "id!", challenger_id, challenged_id, color, parameters, status, game_id,
+        created_at as "created_at: String"
+        from challenge where challenger_id = ? or challenged_id = ?
+        order by id"#,
+        user_id.0,
+        user_id.0
+    )
+        .fetch_all(&mut **conn)
+        .await?)
+}
+
+async fn load_tournaments(user_id: UserId, conn: &mut Connection) -> Result<Vec<TournamentEntry>, ServerError> {
+    let rows = sqlx::query!(
+        r#"select tournament_id, name, withdrawn, joined_at as "joined_at: String"
+        from tournament_player join tournament on tournament.id = tournament_player.tournament_id
+        where user_id = ?
+        order by tournament_id"#,
+        user_id.0
+    )
+        .fetch_all(&mut **conn)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| TournamentEntry {
+            tournament_id: r.tournament_id,
+            name: r.name,
+            withdrawn: r.withdrawn != 0,
+            joined_at: r.joined_at,
+        })
+        .collect())
+}
+
+async fn load_reports(user_id: UserId, conn: &mut Connection) -> Result<Vec<Report>, ServerError> {
+    Ok(sqlx::query_as!(
+        Report,
+        r#"select id as "id!", user_id, game_id, category, description,
+        created_at as "created_at: String", status, resolution
+        from report where reported_by = ?
+        order by id"#,
+        user_id.0
+    )
+        .fetch_all(&mut **conn)
+        .await?)
+}
+
+async fn load_sanctions(user_id: UserId, conn: &mut Connection) -> Result<Vec<Sanction>, ServerError> {
+    Ok(sqlx::query_as!(
+        Sanction,
+        r#"select kind, reason, created_at as "created_at: String",
+        expires_at as "expires_at: String", lifted_at as "lifted_at: String"
+        from user_sanction where user_id = ?
+        order by id"#,
+        user_id.0
+    )
+        .fetch_all(&mut **conn)
+        .await?)
+}
+
+async fn load_follows(
+    user_id: UserId,
+    conn: &mut Connection,
+) -> Result<(Vec<Follow>, Vec<Follow>), ServerError> {
+    let following = sqlx::query_as!(
+        Follow,
+        r#"select followed_id as user_id, created_at as "created_at?: String"
+        from follow where follower_id = ?
+        order by created_at"#,
+        user_id.0
+    )
+        .fetch_all(&mut **conn)
+        .await?;
+    let followers = sqlx::query_as!(
+        Follow,
+        r#"select follower_id as user_id, created_at as "created_at?: String"
+        from follow where followed_id = ?
+        order by created_at"#,
+        user_id.0
+    )
+        .fetch_all(&mut **conn)
+        .await?;
+    Ok((following, followers))
+}
+
+// Background work /////////////////////////////////////////////////////////////
+
+/// Generates the archive and stores it. Failures are stored with the export,
+/// so the user sees them.
+async fn generate_export(pool: Pool, user_id: UserId) {
+    let result = match AccountArchive::generate(user_id, &pool).await {
+        Ok(archive) => serde_json::to_string(&archive).map_err(ServerError::from),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = store_export(user_id, result, &pool).await {
+        warn!("Could not store the account export of user {}: {:?}", user_id.0, e);
+    }
+}
+
+async fn store_export(
+    user_id: UserId,
+    result: Result<String, ServerError>,
+    pool: &Pool,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    match result {
+        Ok(archive) => {
+            sqlx::query!(
+                r"update account_export set status = 'done', finished_at = CURRENT_TIMESTAMP, archive = ?
+                where user_id = ?",
+                archive,
+                user_id.0
+            )
+                .execute(&mut *conn)
+                .await?;
+        }
+        Err(e) => {
+            warn!("Account export of user {} failed: {:?}", user_id.0, e);
+            sqlx::query!(
+                r"update account_export
+                set status = 'failed', finished_at = CURRENT_TIMESTAMP, error = 'The export failed, please try again.'
+                where user_id = ?",
+                user_id.0
+            )
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes expired archives once an hour. Exports that were interrupted by a
+/// restart are marked as failed, so they can be requested again.
+pub async fn run_cleanup(pool: Pool) {
+    if let Err(e) = fail_interrupted_exports(&pool).await {
+        warn!("Marking interrupted account exports failed: {:?}", e);
+    }
+    let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match delete_expired_exports(&pool).await {
+            Ok(0) => {}
+            Ok(count) => info!("Removed {} expired account exports.", count),
+            Err(e) => warn!("Removing expired account exports failed: {:?}", e),
+        }
+    }
+}
+
+async fn fail_interrupted_exports(pool: &Pool) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    sqlx::query!(
+        r"update account_export
+        set status = 'failed', finished_at = CURRENT_TIMESTAMP, error = 'The server restarted, please try again.'
+        where status = 'pending'"
+    )
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+async fn delete_expired_exports(pool: &Pool) -> Result<u64, ServerError> {
+    let mut conn = pool.conn().await?;
+    let lifetime = format!("-{} days", ARCHIVE_LIFETIME_DAYS);
+    let result = sqlx::query!(
+        "delete from account_export where finished_at <= datetime(CURRENT_TIMESTAMP, ?)",
+        lifetime
+    )
+        .execute(&mut *conn)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+// Endpoints ///////////////////////////////////////////////////////////////////
+
+#[derive(Serialize)]
+struct ExportStatus {
+    /// "pending", "done" or "failed".
+    status: String,
+    requested_at: Option<String>,
+    finished_at: Option<String>,
+    /// When the archive is removed.
+    expires_at: Option<String>,
+    /// Size of the archive in bytes.
+    size: Option<i64>,
+    error: Option<String>,
+}
+
+async fn load_status(user_id: UserId, conn: &mut Connection) -> Result<Option<ExportStatus>, ServerError> {
+    let lifetime = format!("+{} days", ARCHIVE_LIFETIME_DAYS);
+    Ok(sqlx::query_as!(
+        ExportStatus,
+        r#"select status, requested_at as "requested_at: String", finished_at as "finished_at: String",
+        datetime(finished_at, ?) as "expires_at: String", length(archive) as "size: i64", error
+        from account_export where user_id = ?"#,
+        lifetime,
+        user_id.0
+    )
+        .fetch_optional(&mut **conn)
+        .await?)
+}
+
+/// GET /api/me/export shows the state of the latest export.
+async fn export_status(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Json<ExportStatus>, ServerError> {
+    let mut conn = pool.conn().await?;
+    load_status(session.user_id, &mut conn)
+        .await?
+        .map(Json)
+        .ok_or(ServerError::NotFound)
+}
+
+/// Marks a new export of the user as pending, unless one already is. Only the
+/// request that made the change may generate the archive, so concurrent
+/// requests don't both start a generation.
+async fn start_export(user_id: UserId, conn: &mut Connection) -> Result<bool, ServerError> {
+    let result = sqlx::query!(
+        r"insert into account_export (user_id) values (?)
+        on conflict (user_id) do update set
+        status = 'pending', requested_at = CURRENT_TIMESTAMP, finished_at = null, archive = null, error = null
+        where account_export.status != 'pending'",
+        user_id.0
+    )
+        .execute(&mut **conn)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// POST /api/me/export starts a new export. If one is already being
+/// generated, its state is returned instead.
+async fn request_export(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Result<Json<ExportStatus>, (StatusCode, &'static str)>, ServerError> {
+    let mut conn = pool.conn().await?;
+    if let Some(status) = load_status(session.user_id, &mut conn).await? {
+        if status.status == "pending" {
+            return Ok(Ok(Json(status)));
+        }
+    }
+    if !EXPORTS_PER_USER.try_acquire(&session.user_id.0.to_string()) {
+        return Ok(Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "You requested too many exports, please try again tomorrow.",
+        )));
+    }
+
+    let started = start_export(session.user_id, &mut conn).await?;
+    let status = load_status(session.user_id, &mut conn)
+        .await?
+        .ok_or(ServerError::NotFound)?;
+    drop(conn);
+
+    if started {
+        tokio::spawn(generate_export(pool, session.user_id));
+    }
+
+    Ok(Ok(Json(status)))
+}
+
+/// GET /api/me/export/download returns the archive once it is done.
+async fn download_export(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Response, ServerError> {
+    let mut conn = pool.conn().await?;
+    let row = sqlx::query!(
+        "select status, archive from account_export where user_id = ?",
+        session.user_id.0
+    )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ServerError::NotFound)?;
+    let Some(archive) = row.archive.filter(|_| row.status == "done") else {
+        return Ok((StatusCode::CONFLICT, "The export is not done.").into_response());
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"pacosako-account-{}.json\"", session.user_id.0),
+            ),
+        ],
+        archive,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::login::{session::ClientInfo, user};
+
+    #[tokio::test]
+    async fn test_archive_has_no_secrets() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let user_id = user::create_user("alice", "identicon:1", &mut conn).await.unwrap();
+        let password_hash = "$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$secret-password-hash";
+        user::create_password_login(user_id, "alice", password_hash, &mut conn)
+            .await
+            .unwrap();
+        let login = sqlx::query!("select id from login where user_id = ?", user_id.0)
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "insert into oauth_token (login_id, access_token, refresh_token) values (?, 'secret-access', 'secret-refresh')",
+            login.id
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "insert into api_token (user_id, name, token_hash) values (?, 'engine', 'secret-token-hash')",
+            user_id.0
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let client = ClientInfo {
+            user_agent: Some("test".to_string()),
+            ip_hash: None,
+        };
+        session::create_session(user_id, false, &client, &mut conn)
+            .await
+            .unwrap();
+        let session = sqlx::query!(r#"select id as "id!" from session where user_id = ?"#, user_id.0)
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        let archive = AccountArchive::generate(user_id, &pool).await.unwrap();
+        assert_eq!(archive.logins.len(), 1);
+        assert_eq!(archive.api_tokens.len(), 1);
+        assert_eq!(archive.sessions.len(), 1);
+
+        let json = serde_json::to_string(&archive).unwrap();
+        for secret in [
+            password_hash,
+            "secret-access",
+            "secret-refresh",
+            "secret-token-hash",
+            &session.id,
+        ] {
+            assert!(!json.contains(secret), "The archive contains {}", secret);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_one_export_is_started() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let user_id = user::create_user("alice", "identicon:1", &mut conn).await.unwrap();
+
+        assert!(start_export(user_id, &mut conn).await.unwrap());
+        // A second request while the first is pending doesn't start another.
+        assert!(!start_export(user_id, &mut conn).await.unwrap());
+
+        drop(conn);
+        store_export(user_id, Ok("{}".to_string()), &pool).await.unwrap();
+        let mut conn = pool.conn().await.unwrap();
+        assert!(start_export(user_id, &mut conn).await.unwrap());
+        let status = load_status(user_id, &mut conn).await.unwrap().unwrap();
+        assert_eq!(status.status, "pending");
+        assert!(status.size.is_none());
+    }
+}
@@ -3,9 +3,10 @@ use std::sync::atomic;
 use axum::{
     extract::{
         Query,
-        WebSocketUpgrade, ws::{Message, WebSocket},
+        WebSocketUpgrade, ws::{close_code, CloseFrame, Message, WebSocket},
     },
-    response::Response,
+    http::StatusCode,
+    response::{IntoResponse, Response},
 };
 use dashmap::DashMap;
 use futures_util::{
@@ -20,8 +21,8 @@ use tokio::{
 };
 
 use crate::{
-    login::{session::SessionData, SessionId},
-    ws::{LogicMsg, to_logic},
+    login::{session::SessionData, SessionId, UserId},
+    ws::{is_shutting_down, LogicMsg, presence, to_logic},
 };
 use crate::ws::socket_auth::SocketAuth;
 
@@ -41,9 +42,15 @@ pub async fn websocket_handler(
     Query(params): Query<UuidQuery>,
     ws: WebSocketUpgrade,
 ) -> Response {
+    if is_shutting_down() {
+        // The client retries with back-off and will reach the restarted server.
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server is restarting").into_response();
+    }
+
+    let user_id = session.as_ref().map(|s| s.user_id);
     let session_id = session.map(|s| s.session_id);
 
-    ws.on_upgrade(move |websocket| handle_socket(websocket, params.uuid, session_id))
+    ws.on_upgrade(move |websocket| handle_socket(websocket, params.uuid, session_id, user_id))
 }
 
 /// Errors that can occur when managing socket ids.
@@ -88,6 +95,7 @@ impl SocketId {
     }
     /// Remove a socket from the map. This aborts the socket's tasks.
     pub fn remove(self) {
+        presence::disconnect(self);
         if let Some((_, data)) = ALL_SOCKETS.remove(&self) {
             let remaining_sockets = ALL_SOCKETS.len();
             info!(
@@ -99,6 +107,20 @@ impl SocketId {
         }
     }
 
+    /// Asks every connected client to close the websocket because the server
+    /// restarts. Clients reconnect when they see this close code.
+    pub async fn close_all_for_restart() {
+        let ids: Vec<SocketId> = ALL_SOCKETS.iter().map(|entry| *entry.key()).collect();
+        info!("Closing {} websocket(s) for restart.", ids.len());
+        for id in ids {
+            id.send(Message::Close(Some(CloseFrame {
+                code: close_code::RESTART,
+                reason: "Server is restarting".into(),
+            })))
+                .await;
+        }
+    }
+
     /// Returns the number of currently connected websockets.
     pub fn count_connections() -> usize {
         ALL_SOCKETS.len()
@@ -127,13 +149,24 @@ struct SocketData {
 }
 
 /// Handles a new websocket connection, setting up the tasks that read and
-/// write to the socket. This also registers the socket on its id.
-async fn handle_socket(socket: WebSocket, uuid: String, session_id: Option<SessionId>) {
+/// write to the socket. This also registers the socket on its id and, for
+/// logged-in users, in the presence.
+async fn handle_socket(
+    socket: WebSocket,
+    uuid: String,
+    session_id: Option<SessionId>,
+    user_id: Option<UserId>,
+) {
     let (sender, receiver) = socket.split();
 
     let (tx, rx) = tokio::sync::mpsc::channel(32);
 
     let id = SocketId::new();
+    // Before the tasks start, so a socket that closes right away is removed
+    // from the presence as well.
+    if let Some(user_id) = user_id {
+        presence::connect(id, user_id);
+    }
 
     let writer_task_abort_handle = tokio::spawn(write(sender, rx, id)).abort_handle();
     let reader_task_abort_handle = tokio::spawn(read(receiver, id)).abort_handle();
@@ -0,0 +1,185 @@
+//! Bot API. Bots authenticate with an API token and play over REST, while an
+//! event stream tells them about challenges and game updates.
+//!
+//! See doc/bot-api.md for a description of the protocol.
+
+use axum::{
+    extract::{Path, State},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use pacosako::PacoAction;
+
+use crate::{
+    challenge::{self, ChallengeInfo, ChallengeList, ChallengeRequest},
+    db::{self, Pool},
+    event_stream::{self, EventStream, StreamEvent},
+    login::{
+        api_token::{self, ApiAuth},
+        user::{self, load_public_user_data, PublicUserData},
+    },
+    sync_match::CurrentMatchStateClient,
+    ws, AppState, ServerError,
+};
+
+/// Adds the bot API and the token management to the given router.
+/// This is expected to be nested at "/api".
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router
+        .route(
+            "/me/api_tokens",
+            get(api_token::list_tokens).post(api_token::create_token),
+        )
+        .route("/me/api_tokens/:id", delete(api_token::revoke_token))
+        .route("/bot/account", get(get_account))
+        .route("/bot/account/upgrade", post(upgrade_account))
+        .route("/bot/stream/event", get(stream_events))
+        .route("/bot/game/stream/:key", get(stream_game))
+        .route("/bot/game/:key/move", post(post_move))
+        .route("/bot/challenge", get(list_challenges).post(create_challenge))
+        .route("/bot/challenge/:id/accept", post(accept_challenge))
+        .route("/bot/challenge/:id/decline", post(decline_challenge))
+        .route("/bot/challenge/:id/cancel", post(cancel_challenge))
+}
+
+fn require_bot(auth: &ApiAuth) -> Result<(), ServerError> {
+    if auth.is_bot {
+        Ok(())
+    } else {
+        Err(ServerError::NotAllowed(
+            "This requires a bot account.".to_string(),
+        ))
+    }
+}
+
+async fn get_account(
+    auth: ApiAuth,
+    State(pool): State<Pool>,
+) -> Result<Json<PublicUserData>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(load_public_user_data(auth.user_id, &mut conn).await?))
+}
+
+/// Turns the account into a bot account. Only accounts which did not play any
+/// games yet can be upgraded, so human games don't end up on a bot account.
+async fn upgrade_account(auth: ApiAuth, State(pool): State<Pool>) -> Result<(), ServerError> {
+    if auth.is_bot {
+        return Ok(());
+    }
+    let mut conn = pool.conn().await?;
+    if db::game::count_for_player(auth.user_id.0, &mut conn).await? > 0 {
+        return Err(ServerError::NotAllowed(
+            "Only accounts without games can become bots.".to_string(),
+        ));
+    }
+    user::set_bot(auth.user_id, &mut conn).await?;
+    info!("User {} is now a bot account.", auth.user_id.0);
+    Ok(())
+}
+
+async fn stream_events(auth: ApiAuth) -> Result<EventStream, ServerError> {
+    require_bot(&auth)?;
+    Ok(event_stream::account_stream(auth.user_id))
+}
+
+/// Streams the game, starting with the current state.
+async fn stream_game(
+    auth: ApiAuth,
+    Path(key): Path<String>,
+    State(pool): State<Pool>,
+) -> Result<EventStream, ServerError> {
+    require_bot(&auth)?;
+    let mut conn = pool.conn().await?;
+    let Some(game) = db::game::select(key.parse()?, &mut conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    let state =
+        CurrentMatchStateClient::try_new_without_sender(game.current_state()?, &mut conn).await?;
+    Ok(event_stream::game_stream(
+        key,
+        StreamEvent::new("gameState", &state)?,
+    ))
+}
+
+#[derive(Serialize)]
+struct MoveRejected {
+    error: String,
+}
+
+/// Submits a chain of actions. The chain is applied completely or not at all.
+async fn post_move(
+    auth: ApiAuth,
+    Path(key): Path<String>,
+    Json(actions): Json<Vec<PacoAction>>,
+) -> Result<Result<(), (hyper::StatusCode, Json<MoveRejected>)>, ServerError> {
+    require_bot(&auth)?;
+    if actions.is_empty() {
+        return Err(ServerError::BadRequest);
+    }
+
+    let (done, result) = oneshot::channel();
+    ws::to_logic(ws::LogicMsg::BotAction {
+        key,
+        actions,
+        user_id: auth.user_id,
+        done,
+    })
+        .await;
+
+    match result.await {
+        Ok(Ok(())) => Ok(Ok(())),
+        Ok(Err(error)) => Ok(Err((
+            hyper::StatusCode::BAD_REQUEST,
+            Json(MoveRejected { error }),
+        ))),
+        Err(_) => Err(ServerError::NotFound),
+    }
+}
+
+async fn list_challenges(
+    auth: ApiAuth,
+    State(pool): State<Pool>,
+) -> Result<Json<ChallengeList>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(challenge::list(auth.user_id, &mut conn).await?))
+}
+
+async fn create_challenge(
+    auth: ApiAuth,
+    State(pool): State<Pool>,
+    Json(request): Json<ChallengeRequest>,
+) -> Result<Json<ChallengeInfo>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(challenge::create(auth.user_id, request, &mut conn).await?))
+}
+
+/// Accepts the challenge and returns the key of the new game.
+async fn accept_challenge(
+    auth: ApiAuth,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<String, ServerError> {
+    let mut conn = pool.conn().await?;
+    challenge::accept(id, auth.user_id, &mut conn).await
+}
+
+async fn decline_challenge(
+    auth: ApiAuth,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    challenge::decline(id, auth.user_id, &mut conn).await
+}
+
+async fn cancel_challenge(
+    auth: ApiAuth,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    challenge::cancel(id, auth.user_id, &mut conn).await
+}
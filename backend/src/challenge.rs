@@ -0,0 +1,460 @@
+//! Challenges are invitations to play a game with given parameters.
+//!
+//! A challenge is either sent to a specific user or open for anyone. When it
+//! is accepted, the game is created with both players already assigned. The
+//! room of the game turns them into a `SideProtection::UserLock` for each
+//! side, so the sides are locked to the users from the first move on.
+//!
+//! Until then, the challenged user can decline and the challenger can cancel.
+//! Challenges nobody answers expire.
+//!
+//! Both users learn about changes over their account event stream.
+
+use axum::{
+    extract::{Path, State},
+    routing::{get, post},
+    Json, Router,
+};
+use pacosako::PlayerColor;
+use serde::{Deserialize, Serialize};
+use sqlx::Acquire;
+
+use crate::db::{self, challenge::ChallengeRecord, Connection, Pool};
+use crate::event_stream::{self, EventStream, StreamEvent};
+use crate::follow;
+use crate::login::session::SessionData;
+use crate::login::user::{load_public_user_data, PublicUserData};
+use crate::login::UserId;
+use crate::sync_match::{MatchParameters, SynchronizedMatch};
+use crate::{AppState, ServerError};
+
+/// Challenges that are not answered within this time expire.
+const DEFAULT_LIFETIME_HOURS: i64 = 24;
+
+/// Upper limit for the lifetime of a challenge.
+const MAX_LIFETIME_HOURS: i64 = 7 * 24;
+
+/// Adds the challenge API for logged-in users to the given router.
+/// This is expected to be nested at "/api".
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router
+        .route("/me/events", get(stream_events))
+        .route("/challenge", get(list_challenges).post(create_challenge))
+        .route("/challenge/:id/accept", post(accept_challenge))
+        .route("/challenge/:id/decline", post(decline_challenge))
+        .route("/challenge/:id/cancel", post(cancel_challenge))
+}
+
+#[derive(Deserialize)]
+pub struct ChallengeRequest {
+    /// The user to challenge. Without an opponent, anyone can accept.
+    pub opponent: Option<UserId>,
+    /// The color of the challenger. Without a color, it is chosen randomly.
+    pub color: Option<PlayerColor>,
+    pub parameters: MatchParameters,
+    /// How long the challenge stays open. Defaults to a day.
+    #[serde(default)]
+    pub lifetime_hours: Option<i64>,
+}
+
+/// A challenge as the users see it.
+#[derive(Serialize, Clone)]
+pub struct ChallengeInfo {
+    pub id: i64,
+    pub challenger: PublicUserData,
+    pub challenged: Option<UserId>,
+    pub color: Option<PlayerColor>,
+    pub parameters: MatchParameters,
+    pub status: String,
+    pub game_key: Option<String>,
+    pub created_at: Option<String>,
+    pub expires_at: Option<String>,
+}
+
+/// Challenges of a user, as shown on the play page.
+#[derive(Serialize)]
+pub struct ChallengeList {
+    pub incoming: Vec<ChallengeInfo>,
+    pub outgoing: Vec<ChallengeInfo>,
+}
+
+impl ChallengeInfo {
+    async fn load(record: ChallengeRecord, conn: &mut Connection) -> Result<Self, ServerError> {
+        Ok(Self {
+            id: record.id,
+            challenger: load_public_user_data(record.challenger, conn).await?,
+            challenged: record.challenged,
+            color: record.color,
+            parameters: record.parameters,
+            status: record.status,
+            game_key: record.game_key,
+            created_at: record.created_at,
+            expires_at: record.expires_at,
+        })
+    }
+}
+
+/// Tells the other user that the challenge was closed without a game.
+#[derive(Serialize)]
+struct ChallengeClosed {
+    challenge_id: i64,
+}
+
+/// Sent to both players when a challenge is accepted.
+#[derive(Serialize)]
+struct GameStart<'a> {
+    challenge_id: i64,
+    game_key: &'a str,
+    color: PlayerColor,
+}
+
+/// Stores a new challenge and tells the challenged user about it.
+pub async fn create(
+    challenger: UserId,
+    request: ChallengeRequest,
+    conn: &mut Connection,
+) -> Result<ChallengeInfo, ServerError> {
+    // Games with AI are created directly, there is nobody to ask.
+    if !request.parameters.is_legal() || request.parameters.ai_side_request.is_some() {
+        return Err(ServerError::BadRequest);
+    }
+    let lifetime_hours = request.lifetime_hours.unwrap_or(DEFAULT_LIFETIME_HOURS);
+    if !(1..=MAX_LIFETIME_HOURS).contains(&lifetime_hours) {
+        return Err(ServerError::BadRequest);
+    }
+    if let Some(opponent) = request.opponent {
+        if opponent == challenger {
+            return Err(ServerError::BadRequest);
+        }
+        load_public_user_data(opponent, conn)
+            .await
+            .map_err(|_| ServerError::NotFound)?;
+    }
+
+    let parameters = request.parameters.sanitize();
+    let id = db::challenge::insert(
+        challenger,
+        request.opponent,
+        request.color,
+        &parameters,
+        lifetime_hours,
+        conn,
+    )
+        .await?;
+    let Some(record) = db::challenge::select(id, conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    let info = ChallengeInfo::load(record, conn).await?;
+
+    if let Some(opponent) = request.opponent {
+        event_stream::publish_to_user(opponent, StreamEvent::new("challenge", &info)?);
+    }
+    info!("User {} created challenge {}.", challenger.0, id);
+    Ok(info)
+}
+
+/// Challenges the user may accept.
+pub async fn incoming(user_id: UserId, conn: &mut Connection) -> Result<Vec<ChallengeInfo>, ServerError> {
+    let records = db::challenge::incoming(user_id, conn).await?;
+    load_all(records, conn).await
+}
+
+/// Open challenges of the user and those they can accept.
+pub async fn list(user_id: UserId, conn: &mut Connection) -> Result<ChallengeList, ServerError> {
+    let incoming = incoming(user_id, conn).await?;
+    let outgoing = db::challenge::outgoing(user_id, conn).await?;
+    Ok(ChallengeList {
+        incoming,
+        outgoing: load_all(outgoing, conn).await?,
+    })
+}
+
+async fn load_all(
+    records: Vec<ChallengeRecord>,
+    conn: &mut Connection,
+) -> Result<Vec<ChallengeInfo>, ServerError> {
+    let mut result = Vec::with_capacity(records.len());
+    for record in records {
+        result.push(ChallengeInfo::load(record, conn).await?);
+    }
+    Ok(result)
+}
+
+/// Accepts the challenge and creates the game. Returns the key of the game.
+pub async fn accept(id: i64, user_id: UserId, conn: &mut Connection) -> Result<String, ServerError> {
+    let Some(challenge) = db::challenge::select(id, conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    if challenge.challenger == user_id || challenge.challenged.is_some_and(|c| c != user_id) {
+        return Err(ServerError::NotAllowed(
+            "This challenge is not for you.".to_string(),
+        ));
+    }
+    let challenger_color = challenge.color.unwrap_or_else(|| {
+        if rand::random() {
+            PlayerColor::White
+        } else {
+            PlayerColor::Black
+        }
+    });
+    let mut game = SynchronizedMatch::new_with_key("0", challenge.parameters);
+    match challenger_color {
+        PlayerColor::White => {
+            game.white_player = Some(challenge.challenger);
+            game.black_player = Some(user_id);
+        }
+        PlayerColor::Black => {
+            game.white_player = Some(user_id);
+            game.black_player = Some(challenge.challenger);
+        }
+    }
+
+    // Either the challenge is closed and has its game, or neither happens.
+    let mut tx = conn.begin().await?;
+    if !db::challenge::close(id, "accepted", &mut tx).await? {
+        return Err(ServerError::NotAllowed(
+            "This challenge is not open anymore.".to_string(),
+        ));
+    }
+    db::game::insert(&mut game, &mut tx).await?;
+    db::challenge::set_game(id, &game.key, &mut tx).await?;
+    tx.commit().await?;
+
+    for (player, color) in [
+        (challenge.challenger, challenger_color),
+        (user_id, challenger_color.other()),
+    ] {
+        let event = GameStart {
+            challenge_id: id,
+            game_key: &game.key,
+            color,
+        };
+        event_stream::publish_to_user(player, StreamEvent::new("gameStart", &event)?);
+    }
+    follow::announce_game_start(&game.key, &[challenge.challenger, user_id], conn).await;
+    info!("Challenge {} was accepted, created game {}.", id, game.key);
+    Ok(game.key)
+}
+
+/// Declines a challenge that was sent to the user. Open challenges are not
+/// sent to anyone in particular, so they can't be declined.
+pub async fn decline(id: i64, user_id: UserId, conn: &mut Connection) -> Result<(), ServerError> {
+    let Some(challenge) = db::challenge::select(id, conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    if challenge.challenged != Some(user_id) {
+        return Err(ServerError::NotAllowed(
+            "This challenge is not for you.".to_string(),
+        ));
+    }
+    if !db::challenge::close(id, "declined", conn).await? {
+        return Err(ServerError::NotAllowed(
+            "This challenge is not open anymore.".to_string(),
+        ));
+    }
+    notify_closed(challenge.challenger, "challengeDeclined", id)
+}
+
+/// Withdraws a challenge the user created.
+pub async fn cancel(id: i64, user_id: UserId, conn: &mut Connection) -> Result<(), ServerError> {
+    let Some(challenge) = db::challenge::select(id, conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    if challenge.challenger != user_id {
+        return Err(ServerError::NotAllowed(
+            "Only the challenger can cancel a challenge.".to_string(),
+        ));
+    }
+    if !db::challenge::close(id, "canceled", conn).await? {
+        return Err(ServerError::NotAllowed(
+            "This challenge is not open anymore.".to_string(),
+        ));
+    }
+    match challenge.challenged {
+        Some(challenged) => notify_closed(challenged, "challengeCanceled", id),
+        None => Ok(()),
+    }
+}
+
+/// Expires all challenges which are past their lifetime and tells both users.
+/// Returns how many challenges expired.
+pub async fn expire(conn: &mut Connection) -> Result<usize, ServerError> {
+    let expired = db::challenge::expire_due(conn).await?;
+    for challenge in &expired {
+        notify_closed(challenge.challenger, "challengeExpired", challenge.id)?;
+        if let Some(challenged) = challenge.challenged {
+            notify_closed(challenged, "challengeExpired", challenge.id)?;
+        }
+    }
+    Ok(expired.len())
+}
+
+fn notify_closed(user_id: UserId, kind: &'static str, challenge_id: i64) -> Result<(), ServerError> {
+    let event = StreamEvent::new(kind, &ChallengeClosed { challenge_id })?;
+    event_stream::publish_to_user(user_id, event);
+    Ok(())
+}
+
+async fn stream_events(session: SessionData) -> EventStream {
+    event_stream::account_stream(session.user_id)
+}
+
+async fn list_challenges(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Json<ChallengeList>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(list(session.user_id, &mut conn).await?))
+}
+
+async fn create_challenge(
+    session: SessionData,
+    State(pool): State<Pool>,
+    Json(request): Json<ChallengeRequest>,
+) -> Result<Json<ChallengeInfo>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(create(session.user_id, request, &mut conn).await?))
+}
+
+/// Accepts the challenge and returns the key of the new game.
+async fn accept_challenge(
+    session: SessionData,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<String, ServerError> {
+    let mut conn = pool.conn().await?;
+    accept(id, session.user_id, &mut conn).await
+}
+
+async fn decline_challenge(
+    session: SessionData,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    decline(id, session.user_id, &mut conn).await
+}
+
+async fn cancel_challenge(
+    session: SessionData,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    cancel(id, session.user_id, &mut conn).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::login::user::create_user;
+
+    struct Setup {
+        pool: Pool,
+        alice: UserId,
+        bob: UserId,
+        carol: UserId,
+    }
+
+    async fn setup() -> Setup {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let alice = create_user("alice", "identicon:1", &mut conn).await.unwrap();
+        let bob = create_user("bob", "identicon:2", &mut conn).await.unwrap();
+        let carol = create_user("carol", "identicon:3", &mut conn).await.unwrap();
+        drop(conn);
+        Setup {
+            pool,
+            alice,
+            bob,
+            carol,
+        }
+    }
+
+    /// Alice challenges Bob and plays white.
+    async fn challenge(s: &Setup, conn: &mut Connection) -> i64 {
+        let parameters: MatchParameters = serde_json::from_str("{}").unwrap();
+        db::challenge::insert(s.alice, Some(s.bob), Some(PlayerColor::White), &parameters, 1, conn)
+            .await
+            .unwrap()
+    }
+
+    async fn status(id: i64, conn: &mut Connection) -> ChallengeRecord {
+        db::challenge::select(id, conn).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        let s = setup().await;
+        let mut conn = s.pool.conn().await.unwrap();
+        let id = challenge(&s, &mut conn).await;
+
+        assert!(matches!(
+            accept(id, s.carol, &mut conn).await,
+            Err(ServerError::NotAllowed(_))
+        ));
+        assert!(matches!(
+            accept(id, s.alice, &mut conn).await,
+            Err(ServerError::NotAllowed(_))
+        ));
+
+        let key = accept(id, s.bob, &mut conn).await.unwrap();
+        let game = db::game::select(key.parse().unwrap(), &mut conn).await.unwrap().unwrap();
+        assert_eq!(game.white_player, Some(s.alice));
+        assert_eq!(game.black_player, Some(s.bob));
+        let record = status(id, &mut conn).await;
+        assert_eq!(record.status, "accepted");
+        assert_eq!(record.game_key, Some(key));
+
+        // A challenge creates a single game.
+        assert!(matches!(
+            accept(id, s.bob, &mut conn).await,
+            Err(ServerError::NotAllowed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_decline() {
+        let s = setup().await;
+        let mut conn = s.pool.conn().await.unwrap();
+        let id = challenge(&s, &mut conn).await;
+
+        assert!(matches!(
+            decline(id, s.alice, &mut conn).await,
+            Err(ServerError::NotAllowed(_))
+        ));
+        decline(id, s.bob, &mut conn).await.unwrap();
+        assert_eq!(status(id, &mut conn).await.status, "declined");
+
+        assert!(matches!(
+            accept(id, s.bob, &mut conn).await,
+            Err(ServerError::NotAllowed(_))
+        ));
+        assert_eq!(status(id, &mut conn).await.game_key, None);
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let s = setup().await;
+        let mut conn = s.pool.conn().await.unwrap();
+        let id = challenge(&s, &mut conn).await;
+        let open = challenge(&s, &mut conn).await;
+        sqlx::query!(
+            "update challenge set expires_at = datetime(CURRENT_TIMESTAMP, '-1 minutes') where id = ?",
+            id
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+
+        // Expired challenges can't be accepted, even before they are swept.
+        assert!(matches!(
+            accept(id, s.bob, &mut conn).await,
+            Err(ServerError::NotAllowed(_))
+        ));
+        assert_eq!(expire(&mut conn).await.unwrap(), 1);
+        assert_eq!(status(id, &mut conn).await.status, "expired");
+        assert_eq!(status(open, &mut conn).await.status, "open");
+        assert_eq!(expire(&mut conn).await.unwrap(), 0);
+    }
+}
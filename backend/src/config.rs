@@ -2,7 +2,7 @@
 //! and reading the configuration.
 
 use serde::Deserialize;
-use std::{env, fs};
+use std::{collections::HashMap, env, fs};
 
 #[derive(Clone, Deserialize)]
 pub struct EnvironmentConfig {
@@ -15,13 +15,98 @@ pub struct EnvironmentConfig {
     pub discord_client_id: String,
     /// The path to the file containing secrets like the Discord client secret
     pub secrets_file: String,
+    /// Notifications for correspondence games are appended to this file as
+    /// JSON lines. This is a stand-in for development and tests.
+    #[serde(default)]
+    pub notification_file: Option<String>,
+    /// Notifications for correspondence games are posted as JSON to this url.
+    #[serde(default)]
+    pub notification_webhook_url: Option<String>,
+    /// Mails like the email verification are appended to this file as JSON
+    /// lines. This is a stand-in for development and tests.
+    #[serde(default)]
+    pub mail_file: Option<String>,
+    /// Mails are posted as JSON to this url, which is expected to send them.
+    #[serde(default)]
+    pub mail_webhook_url: Option<String>,
+    /// Set this when the server runs behind a reverse proxy. The address of
+    /// the client is then taken from the `X-Forwarded-For` header.
+    #[serde(default)]
+    pub behind_proxy: bool,
+    /// Login providers besides Discord, which is configured with
+    /// `discord_client_id`.
+    #[serde(default)]
+    pub oauth_providers: Vec<OAuthProviderConfig>,
+    /// The fair-play analysis posts board tensors to this url and expects the
+    /// raw model output back. Without it, the analysis is off.
+    #[serde(default)]
+    pub fair_play_model_url: Option<String>,
     /// Secrets loaded from the secrets file
     pub discord_client_secret: String,
+    /// Client secrets of the `oauth_providers` by provider name, loaded from
+    /// the secrets file.
+    #[serde(default)]
+    pub oauth_client_secrets: HashMap<String, String>,
+    /// Bot token for sending direct messages on Discord. Without it, players
+    /// who logged in with Discord don't get notifications there.
+    #[serde(default)]
+    pub discord_bot_token: Option<String>,
+}
+
+/// Where the endpoints of a login provider come from.
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OAuthProviderKind {
+    Discord,
+    Github,
+    Google,
+    /// Any OpenID Connect provider. The endpoints are discovered from the
+    /// `issuer`.
+    Oidc,
+    /// All endpoints are given in the config.
+    #[default]
+    Custom,
+}
+
+/// A login provider with OAuth2. Endpoints and fields given here override the
+/// defaults of the `kind`.
+#[derive(Clone, Deserialize, Debug)]
+pub struct OAuthProviderConfig {
+    /// Used in urls and stored with the logins, like "github". Don't change it
+    /// after users logged in with the provider.
+    pub name: String,
+    /// Shown to users. Defaults to the name.
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub kind: OAuthProviderKind,
+    pub client_id: String,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub authorization_url: Option<String>,
+    #[serde(default)]
+    pub token_url: Option<String>,
+    #[serde(default)]
+    pub user_info_url: Option<String>,
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// Field of the user info that identifies the user, like "id" or "sub".
+    #[serde(default)]
+    pub subject_field: Option<String>,
+    /// Fields of the user info to take the display name from. The first one
+    /// that is set wins.
+    #[serde(default)]
+    pub name_fields: Option<Vec<String>>,
 }
 
 #[derive(Deserialize)]
 pub struct SecretsConfig {
     discord_client_secret: String,
+    #[serde(default)]
+    discord_bot_token: Option<String>,
+    #[serde(default)]
+    oauth_client_secrets: HashMap<String, String>,
 }
 
 /// Determines from the first command line argument which config file to load.
@@ -90,6 +175,8 @@ fn load_config_inner() -> Result<EnvironmentConfig, String> {
     // Merge the secrets into the config
     let config = EnvironmentConfig {
         discord_client_secret: secrets.discord_client_secret,
+        discord_bot_token: secrets.discord_bot_token,
+        oauth_client_secrets: secrets.oauth_client_secrets,
         ..config
     };
 
@@ -0,0 +1,184 @@
+use pacosako::PlayerColor;
+
+use crate::db::Connection;
+use crate::login::UserId;
+use crate::sync_match::MatchParameters;
+use crate::ServerError;
+
+use super::game::{color_to_str, str_to_color};
+
+/// Database representation of a challenge.
+pub struct ChallengeRecord {
+    pub id: i64,
+    pub challenger: UserId,
+    /// None for an open challenge.
+    pub challenged: Option<UserId>,
+    /// Color of the challenger, None for random.
+    pub color: Option<PlayerColor>,
+    pub parameters: MatchParameters,
+    /// One of 'open', 'accepted', 'declined', 'canceled' or 'expired'.
+    pub status: String,
+    pub game_key: Option<String>,
+    pub created_at: Option<String>,
+    pub expires_at: Option<String>,
+}
+
+struct RawChallenge {
+    id: i64,
+    challenger_id: i64,
+    challenged_id: Option<i64>,
+    color: Option<String>,
+    parameters: String,
+    status: String,
+    game_id: Option<i64>,
+    created_at: Option<String>,
+    expires_at: Option<String>,
+}
+
+impl TryFrom<RawChallenge> for ChallengeRecord {
+    type Error = ServerError;
+
+    fn try_from(raw: RawChallenge) -> Result<Self, Self::Error> {
+        Ok(ChallengeRecord {
+            id: raw.id,
+            challenger: UserId(raw.challenger_id),
+            challenged: raw.challenged_id.map(UserId),
+            color: raw.color.as_deref().map(str_to_color),
+            parameters: serde_json::from_str(&raw.parameters)?,
+            status: raw.status,
+            game_key: raw.game_id.map(|id| id.to_string()),
+            created_at: raw.created_at,
+            expires_at: raw.expires_at,
+        })
+    }
+}
+
+/// Stores a new challenge that stays open for the given number of hours.
+pub async fn insert(
+    challenger: UserId,
+    challenged: Option<UserId>,
+    color: Option<PlayerColor>,
+    parameters: &MatchParameters,
+    lifetime_hours: i64,
+    conn: &mut Connection,
+) -> Result<i64, ServerError> {
+    let challenged = challenged.map(|u| u.0);
+    let color = color.map(color_to_str);
+    let parameters = serde_json::to_string(parameters)?;
+    let lifetime = format!("+{lifetime_hours} hours");
+
+    let id = sqlx::query!(
+        r"insert into challenge (challenger_id, challenged_id, color, parameters, expires_at)
+        values (?, ?, ?, ?, datetime(CURRENT_TIMESTAMP, ?))",
+        challenger.0,
+        challenged,
+        color,
+        parameters,
+        lifetime
+    )
+        .execute(conn)
+        .await?
+        .last_insert_rowid();
+
+    Ok(id)
+}
+
+pub async fn select(id: i64, conn: &mut Connection) -> Result<Option<ChallengeRecord>, ServerError> {
+    let raw = sqlx::query_as!(
+        RawChallenge,
+        r#"select id as "id!", challenger_id, challenged_id, color, parameters, status, game_id,
+        created_at as "created_at: String", expires_at as "expires_at: String"
+        from challenge where id = ?"#,
+        id
+    )
+        .fetch_optional(conn)
+        .await?;
+
+    raw.map(ChallengeRecord::try_from).transpose()
+}
+
+/// Open challenges the user can accept. This includes open challenges of
+/// other users.
+pub async fn incoming(user_id: UserId, conn: &mut Connection) -> Result<Vec<ChallengeRecord>, ServerError> {
+    let raw = sqlx::query_as!(
+        RawChallenge,
+        r#"select id as "id!", challenger_id, challenged_id, color, parameters, status, game_id,
+        created_at as "created_at: String", expires_at as "expires_at: String"
+        from challenge
+        where status = 'open' and expires_at > CURRENT_TIMESTAMP
+        and challenger_id != ? and (challenged_id = ? or challenged_id is null)
+        order by id"#,
+        user_id.0,
+        user_id.0
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw.into_iter().map(ChallengeRecord::try_from).collect()
+}
+
+/// Open challenges the user created.
+pub async fn outgoing(user_id: UserId, conn: &mut Connection) -> Result<Vec<ChallengeRecord>, ServerError> {
+    let raw = sqlx::query_as!(
+        RawChallenge,
+        r#"select id as "id!", challenger_id, challenged_id, color, parameters, status, game_id,
+        created_at as "created_at: String", expires_at as "expires_at: String"
+        from challenge
+        where status = 'open' and expires_at > CURRENT_TIMESTAMP and challenger_id = ?
+        order by id"#,
+        user_id.0
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw.into_iter().map(ChallengeRecord::try_from).collect()
+}
+
+/// Moves an open challenge to the new status, e.g. 'accepted' or 'declined'.
+/// Returns false if the challenge was not open anymore, e.g. because someone
+/// else accepted it first or it expired.
+pub async fn close(
+    id: i64,
+    status: &str,
+    conn: &mut sqlx::SqliteConnection,
+) -> Result<bool, ServerError> {
+    let result = sqlx::query!(
+        r"update challenge set status = ?
+        where id = ? and status = 'open' and expires_at > CURRENT_TIMESTAMP",
+        status,
+        id
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Marks all open challenges that are past their expiry date as expired and
+/// returns them.
+pub async fn expire_due(conn: &mut Connection) -> Result<Vec<ChallengeRecord>, ServerError> {
+    let raw = sqlx::query_as!(
+        RawChallenge,
+        r#"update challenge set status = 'expired'
+        where status = 'open' and expires_at <= CURRENT_TIMESTAMP
+        returning id as "id!", challenger_id, challenged_id, color, parameters, status, game_id,
+        created_at as "created_at: String", expires_at as "expires_at: String""#
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw.into_iter().map(ChallengeRecord::try_from).collect()
+}
+
+pub async fn set_game(
+    id: i64,
+    game_key: &str,
+    conn: &mut sqlx::SqliteConnection,
+) -> Result<(), ServerError> {
+    let game_id: i64 = game_key.parse()?;
+    sqlx::query!("update challenge set game_id = ? where id = ?", game_id, id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
@@ -1,15 +1,18 @@
-use pacosako::PlayerColor;
+use pacosako::{DenseBoard, PacoAction, PlayerColor, VictoryState};
 use pacosako::setup_options::SetupOptionsAllOptional;
 
-use crate::{ServerError, sync_match::SynchronizedMatch};
+use serde::Serialize;
+
+use crate::{ServerError, sync_match::{CurrentMatchState, Premove, StampedAction, SynchronizedMatch}};
 use crate::db::Connection;
 use crate::login::UserId;
-use crate::timer::Timer;
+use crate::timer::{Timer, TimerState};
+use crate::ws::socket_auth::SocketIdentity;
 
 /// Stores the game in the database as a new entry and updates the id
 pub async fn insert(
     game: &mut SynchronizedMatch,
-    conn: &mut Connection,
+    conn: &mut sqlx::SqliteConnection,
 ) -> Result<(), ServerError> {
     let action_history = serde_json::to_string(&game.actions)?;
 
@@ -22,14 +25,18 @@ pub async fn insert(
 
     let white_player = game.white_player.map(|u| u.0);
     let black_player = game.black_player.map(|u| u.0);
+    let opening = classify_opening(game).unwrap_or_default();
+    let victory_state = serde_json::to_string(&game.current_state()?.victory_state)?;
 
     let id = sqlx::query!(
-        "insert into game (action_history, timer, setup, white_player, black_player) values (?, ?, ?, ?, ?)",
+        "insert into game (action_history, timer, setup, white_player, black_player, opening, victory_state) values (?, ?, ?, ?, ?, ?, ?)",
         action_history,
         timer,
         setup,
         white_player,
-        black_player
+        black_player,
+        opening,
+        victory_state
     )
         .execute(conn)
         .await?
@@ -40,8 +47,17 @@ pub async fn insert(
     Ok(())
 }
 
-/// Updates the game in the database.
-pub async fn update(game: &SynchronizedMatch, conn: &mut Connection) -> Result<(), ServerError> {
+/// Updates the game in the database. The state must be the current state of
+/// the game, it is used to persist the outcome and when the clock runs out.
+///
+/// Players that are already stored are kept. They only change with
+/// `set_player` and `set_players`, so a room that loaded the game before a
+/// player was assigned, e.g. by claiming, can't undo the assignment.
+pub async fn update(
+    game: &SynchronizedMatch,
+    state: &CurrentMatchState,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
     let id: i64 = game.key.parse()?;
 
     let action_history = serde_json::to_string(&game.actions)?;
@@ -55,14 +71,35 @@ pub async fn update(game: &SynchronizedMatch, conn: &mut Connection) -> Result<(
     let white_player = game.white_player.map(|u| u.0);
     let black_player = game.black_player.map(|u| u.0);
 
+    let victory_state = serde_json::to_string(&state.victory_state)?;
+    let is_over = state.victory_state.is_over();
+    let timeout_at = timeout_at(state);
+    let controlling_player = if is_over {
+        None
+    } else {
+        Some(color_to_str(state.controlling_player))
+    };
+    let opening = classify_opening(game).unwrap_or_default();
+
     sqlx::query!(
         r"update game
-        set action_history = ?, timer = ?, white_player = ?, black_player = ?
+        set action_history = ?, timer = ?,
+            white_player = coalesce(white_player, ?), black_player = coalesce(black_player, ?),
+            victory_state = ?,
+            finished_at = case when ? then coalesce(finished_at, CURRENT_TIMESTAMP) else null end,
+            timeout_at = ?,
+            controlling_player = ?,
+            opening = ?
         where id = ?",
         action_history,
         timer,
         white_player,
         black_player,
+        victory_state,
+        is_over,
+        timeout_at,
+        controlling_player,
+        opening,
         id
     )
         .execute(conn)
@@ -71,6 +108,324 @@ pub async fn update(game: &SynchronizedMatch, conn: &mut Connection) -> Result<(
     Ok(())
 }
 
+/// Openings of the game as stored in the `opening` column.
+fn classify_opening(game: &SynchronizedMatch) -> Result<String, ServerError> {
+    let actions: Vec<PacoAction> = game.actions.iter().map(PacoAction::from).collect();
+    let board = DenseBoard::with_options(&game.setup_options)?;
+    Ok(pacosako::analysis::classify_opening(&board, &actions)?)
+}
+
+/// Games stored before openings were classified, oldest first.
+pub async fn unclassified(limit: i64, conn: &mut Connection) -> Result<Vec<SynchronizedMatch>, ServerError> {
+    let raw_games = sqlx::query_as!(
+        RawGame,
+        r"select id, action_history, timer, setup, white_player, black_player from game
+        where opening is null
+        order by id
+        limit ?",
+        limit
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw_games.into_iter().map(|raw| raw.into_match()).collect()
+}
+
+/// Classifies the opening of a game which was not classified yet. Games which
+/// can't be replayed get an empty opening, so they are not tried again.
+pub async fn store_opening(game: &SynchronizedMatch, conn: &mut Connection) -> Result<(), ServerError> {
+    let id: i64 = game.key.parse()?;
+    let opening = classify_opening(game).unwrap_or_default();
+
+    sqlx::query!(
+        "update game set opening = ? where id = ? and opening is null",
+        opening,
+        id
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Games that were not updated since the outcome is persisted, by id. Only
+/// games after `after_id` are returned, so games that can't be replayed are
+/// skipped instead of being returned again.
+pub async fn without_outcome(
+    after_id: i64,
+    limit: i64,
+    conn: &mut Connection,
+) -> Result<Vec<SynchronizedMatch>, ServerError> {
+    let raw_games = sqlx::query_as!(
+        RawGame,
+        r"select id, action_history, timer, setup, white_player, black_player from game
+        where victory_state is null and id > ?
+        order by id
+        limit ?",
+        after_id,
+        limit
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw_games.into_iter().map(|raw| raw.into_match()).collect()
+}
+
+/// Stores the outcome of a game which was not updated since the outcome is
+/// persisted. A finished game counts as finished when its last action was
+/// played. Games changed in the meantime are left alone.
+pub async fn store_outcome(
+    game: &SynchronizedMatch,
+    state: &CurrentMatchState,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let id: i64 = game.key.parse()?;
+    let victory_state = serde_json::to_string(&state.victory_state)?;
+    let finished_at = if state.victory_state.is_over() {
+        let last_action = game.actions.last().map(|a| a.timestamp());
+        Some(last_action.unwrap_or_else(chrono::Utc::now).format("%Y-%m-%d %H:%M:%S").to_string())
+    } else {
+        None
+    };
+
+    sqlx::query!(
+        "update game set victory_state = ?, finished_at = ? where id = ? and victory_state is null",
+        victory_state,
+        finished_at,
+        id
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Finished games without a replay analysis of the given version, newest
+/// first. Recent games are more likely to be looked at.
+pub async fn without_replay_analysis(
+    version: i64,
+    limit: i64,
+    conn: &mut Connection,
+) -> Result<Vec<SynchronizedMatch>, ServerError> {
+    let raw_games = sqlx::query_as!(
+        RawGame,
+        r"select id, action_history, timer, setup, white_player, black_player from game
+        where finished_at is not null
+        and (replay_analysis_version is null or replay_analysis_version < ?)
+        order by id desc
+        limit ?",
+        version,
+        limit
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw_games.into_iter().map(|raw| raw.into_match()).collect()
+}
+
+/// Filters of the game search. All filters are optional and must all match.
+#[derive(Default)]
+pub struct GameSearch {
+    pub player: Option<UserId>,
+    /// The color `player` played. Requires `player`.
+    pub player_color: Option<PlayerColor>,
+    /// Only games between `player` and this user. Requires `player`.
+    pub opponent: Option<UserId>,
+    /// One of these victory states. Use `finished` for running games.
+    pub victory_states: Vec<VictoryState>,
+    pub finished: Option<bool>,
+    /// Bounds for `expected_time_limit` in seconds.
+    pub min_time_limit: Option<f64>,
+    pub max_time_limit: Option<f64>,
+    /// False for games without a clock.
+    pub timed: Option<bool>,
+    /// True for games with a custom starting position, like Fischer random.
+    pub custom_setup: Option<bool>,
+    /// A single opening name, as returned by `classify_opening`.
+    pub opening: Option<String>,
+    /// Timestamps formatted like the `created` column, "YYYY-MM-DD HH:MM:SS".
+    /// The lower bound is inclusive, the upper bound exclusive.
+    pub created_from: Option<String>,
+    pub created_until: Option<String>,
+    pub min_actions: Option<i64>,
+    /// Cursor, only games with a lower id are returned.
+    pub before_id: Option<i64>,
+    pub limit: i64,
+}
+
+enum SearchValue {
+    Int(i64),
+    Real(f64),
+    Text(String),
+}
+
+/// Searches games, newest first. The query is built from the filters which
+/// are set, so SQLite can pick the best index for each search.
+pub async fn search(filter: &GameSearch, conn: &mut Connection) -> Result<Vec<SynchronizedMatch>, ServerError> {
+    let mut conditions: Vec<String> = vec![];
+    let mut values: Vec<SearchValue> = vec![];
+
+    let mut side_condition = |user: UserId, color: Option<PlayerColor>| match color {
+        Some(PlayerColor::White) => {
+            conditions.push("white_player = ?".to_string());
+            values.push(SearchValue::Int(user.0));
+        }
+        Some(PlayerColor::Black) => {
+            conditions.push("black_player = ?".to_string());
+            values.push(SearchValue::Int(user.0));
+        }
+        None => {
+            conditions.push("(white_player = ? or black_player = ?)".to_string());
+            values.push(SearchValue::Int(user.0));
+            values.push(SearchValue::Int(user.0));
+        }
+    };
+    if let Some(player) = filter.player {
+        side_condition(player, filter.player_color);
+    }
+    if let Some(opponent) = filter.opponent {
+        side_condition(opponent, filter.player_color.map(|c| c.other()));
+    }
+
+    if !filter.victory_states.is_empty() {
+        let placeholders = vec!["?"; filter.victory_states.len()].join(", ");
+        conditions.push(format!("victory_state in ({placeholders})"));
+        for victory_state in &filter.victory_states {
+            values.push(SearchValue::Text(serde_json::to_string(victory_state)?));
+        }
+    }
+    match filter.finished {
+        Some(true) => conditions.push("finished_at is not null".to_string()),
+        Some(false) => conditions.push("finished_at is null".to_string()),
+        None => {}
+    }
+
+    if let Some(min) = filter.min_time_limit {
+        conditions.push("expected_time_limit >= ?".to_string());
+        values.push(SearchValue::Real(min));
+    }
+    if let Some(max) = filter.max_time_limit {
+        conditions.push("expected_time_limit <= ?".to_string());
+        values.push(SearchValue::Real(max));
+    }
+    match filter.timed {
+        Some(true) => conditions.push("timer is not null".to_string()),
+        Some(false) => conditions.push("timer is null".to_string()),
+        None => {}
+    }
+    match filter.custom_setup {
+        Some(true) => conditions.push("json_extract(setup, '$.starting_fen') is not null".to_string()),
+        Some(false) => conditions.push("json_extract(setup, '$.starting_fen') is null".to_string()),
+        None => {}
+    }
+    if let Some(opening) = &filter.opening {
+        // The column may list several openings, like "Swedish Knights, Rai".
+        conditions.push(r"(', ' || opening || ', ') like ? escape '\'".to_string());
+        let escaped = opening.replace('\\', r"\\").replace('%', r"\%").replace('_', r"\_");
+        values.push(SearchValue::Text(format!("%, {escaped}, %")));
+    }
+
+    if let Some(from) = &filter.created_from {
+        conditions.push("created >= ?".to_string());
+        values.push(SearchValue::Text(from.clone()));
+    }
+    if let Some(until) = &filter.created_until {
+        conditions.push("created < ?".to_string());
+        values.push(SearchValue::Text(until.clone()));
+    }
+    if let Some(min_actions) = filter.min_actions {
+        conditions.push("action_count >= ?".to_string());
+        values.push(SearchValue::Int(min_actions));
+    }
+    if let Some(before_id) = filter.before_id {
+        conditions.push("id < ?".to_string());
+        values.push(SearchValue::Int(before_id));
+    }
+
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("where {}", conditions.join(" and "))
+    };
+    let sql = format!(
+        "select id, action_history, timer, setup, white_player, black_player from game
+        {where_clause}
+        order by id desc
+        limit ?"
+    );
+
+    let mut query = sqlx::query_as::<_, RawGame>(&sql);
+    for value in values {
+        query = match value {
+            SearchValue::Int(value) => query.bind(value),
+            SearchValue::Real(value) => query.bind(value),
+            SearchValue::Text(value) => query.bind(value),
+        };
+    }
+    let raw_games = query.bind(filter.limit).fetch_all(conn).await?;
+
+    raw_games.into_iter().map(|raw| raw.into_match()).collect()
+}
+
+/// Stores a game that ran out of time, but only if nobody changed the game
+/// since it was loaded. Returns false if the game was changed in the meantime.
+/// In that case, whoever changed it also took care of the timer.
+pub async fn finish_on_timeout(
+    game: &SynchronizedMatch,
+    state: &CurrentMatchState,
+    loaded_action_history: &str,
+    conn: &mut Connection,
+) -> Result<bool, ServerError> {
+    let id: i64 = game.key.parse()?;
+
+    let timer = if let Some(ref timer) = game.timer {
+        Some(serde_json::to_string(timer)?)
+    } else {
+        None
+    };
+    let victory_state = serde_json::to_string(&state.victory_state)?;
+
+    let rows_affected = sqlx::query!(
+        r"update game
+        set timer = ?, victory_state = ?, finished_at = CURRENT_TIMESTAMP, timeout_at = null,
+            controlling_player = null
+        where id = ? and action_history = ? and finished_at is null",
+        timer,
+        victory_state,
+        id,
+        loaded_action_history
+    )
+        .execute(conn)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+pub(super) fn color_to_str(color: PlayerColor) -> &'static str {
+    match color {
+        PlayerColor::White => "White",
+        PlayerColor::Black => "Black",
+    }
+}
+
+pub(super) fn str_to_color(color: &str) -> PlayerColor {
+    if color == "White" {
+        PlayerColor::White
+    } else {
+        PlayerColor::Black
+    }
+}
+
+/// When the controlling player runs out of time, in unix milliseconds.
+fn timeout_at(state: &CurrentMatchState) -> Option<i64> {
+    let timer = state.timer.as_ref()?;
+    if state.victory_state.is_over() || timer.get_state() != TimerState::Running {
+        return None;
+    }
+    Some(timer.timeout(state.controlling_player).timestamp_millis())
+}
+
 pub async fn select(
     id: i64,
     conn: &mut Connection,
@@ -103,6 +458,63 @@ pub async fn latest(conn: &mut Connection) -> Result<Vec<SynchronizedMatch>, Ser
     raw_games.into_iter().map(|raw| raw.into_match()).collect()
 }
 
+/// Keys and timeouts (unix milliseconds) of all unfinished games with a
+/// running clock.
+pub async fn pending_timeouts(conn: &mut Connection) -> Result<Vec<(String, i64)>, ServerError> {
+    let rows = sqlx::query!(
+        r#"select id, timeout_at as "timeout_at!" from game
+        where timeout_at is not null and finished_at is null"#
+    )
+        .fetch_all(conn)
+        .await?;
+
+    Ok(rows.into_iter().map(|r| (format!("{}", r.id), r.timeout_at)).collect())
+}
+
+/// Games with a running clock that were not updated since the timeout was
+/// persisted. Their timeout needs to be computed from the full game.
+pub async fn running_without_timeout(conn: &mut Connection) -> Result<Vec<SynchronizedMatch>, ServerError> {
+    let raw_games = sqlx::query_as!(
+        RawGame,
+        r"select id, action_history, timer, setup, white_player, black_player from game
+        where json_extract(timer, '$.timer_state') = 'Running'
+        and timeout_at is null and finished_at is null"
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw_games.into_iter().map(|raw| raw.into_match()).collect()
+}
+
+/// Unfinished games whose clock ran out before the given unix milliseconds.
+/// Also returns the action history as stored, so the game can be finished
+/// with an optimistic concurrency check.
+pub async fn expired(
+    now: i64,
+    limit: i64,
+    conn: &mut Connection,
+) -> Result<Vec<(SynchronizedMatch, String)>, ServerError> {
+    let raw_games = sqlx::query_as!(
+        RawGame,
+        r"select id, action_history, timer, setup, white_player, black_player from game
+        where timeout_at <= ? and finished_at is null
+        order by timeout_at
+        limit ?",
+        now,
+        limit
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw_games
+        .into_iter()
+        .map(|raw| {
+            let action_history = raw.action_history.clone();
+            Ok((raw.into_match()?, action_history))
+        })
+        .collect()
+}
+
 pub async fn for_player(
     user_id: i64,
     offset: i64,
@@ -126,6 +538,210 @@ pub async fn for_player(
     raw_games.into_iter().map(|raw| raw.into_match()).collect()
 }
 
+/// A game together with the timestamps only exports need.
+pub struct ExportRecord {
+    pub game: SynchronizedMatch,
+    pub created: Option<String>,
+    pub finished_at: Option<String>,
+}
+
+struct RawExportRecord {
+    id: i64,
+    action_history: String,
+    timer: Option<String>,
+    setup: String,
+    white_player: Option<i64>,
+    black_player: Option<i64>,
+    created: Option<String>,
+    finished_at: Option<String>,
+}
+
+impl TryFrom<RawExportRecord> for ExportRecord {
+    type Error = ServerError;
+
+    fn try_from(raw: RawExportRecord) -> Result<Self, Self::Error> {
+        let game = RawGame {
+            id: raw.id,
+            action_history: raw.action_history,
+            timer: raw.timer,
+            setup: raw.setup,
+            white_player: raw.white_player,
+            black_player: raw.black_player,
+        }
+            .into_match()?;
+        Ok(ExportRecord {
+            game,
+            created: raw.created,
+            finished_at: raw.finished_at,
+        })
+    }
+}
+
+pub async fn select_for_export(id: i64, conn: &mut Connection) -> Result<Option<ExportRecord>, ServerError> {
+    let raw = sqlx::query_as!(
+        RawExportRecord,
+        r#"select id, action_history, timer, setup, white_player, black_player,
+        created as "created: String", finished_at as "finished_at: String"
+        from game where id = ?"#,
+        id
+    )
+        .fetch_optional(conn)
+        .await?;
+
+    raw.map(ExportRecord::try_from).transpose()
+}
+
+/// Games of the player with an id above `after_id`, oldest first. Paging by id
+/// keeps long exports stable while new games are created.
+pub async fn for_player_export(
+    user_id: i64,
+    after_id: i64,
+    limit: i64,
+    conn: &mut Connection,
+) -> Result<Vec<ExportRecord>, ServerError> {
+    let raw = sqlx::query_as!(
+        RawExportRecord,
+        r#"select id, action_history, timer, setup, white_player, black_player,
+        created as "created: String", finished_at as "finished_at: String"
+        from game
+        where (white_player = ? or black_player = ?) and id > ?
+        order by id
+        limit ?"#,
+        user_id,
+        user_id,
+        after_id,
+        limit
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw.into_iter().map(ExportRecord::try_from).collect()
+}
+
+/// Finished games of the players the user follows, newest first. Annulled
+/// games are left out.
+pub async fn finished_by_followed(
+    user_id: i64,
+    offset: i64,
+    limit: i64,
+    conn: &mut Connection,
+) -> Result<Vec<ExportRecord>, ServerError> {
+    let raw = sqlx::query_as!(
+        RawExportRecord,
+        r#"select id, action_history, timer, setup, white_player, black_player,
+        created as "created: String", finished_at as "finished_at: String"
+        from game
+        where finished_at is not null and annulled_at is null
+            and exists(
+                select 1 from follow
+                where follower_id = ? and followed_id in (game.white_player, game.black_player)
+            )
+        order by finished_at desc, id desc
+        limit ? offset ?"#,
+        user_id,
+        limit,
+        offset
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw.into_iter().map(ExportRecord::try_from).collect()
+}
+
+/// Whether the user plays in the game and it is still running.
+pub async fn is_running_for_player(key: i64, user_id: i64, conn: &mut Connection) -> Result<bool, ServerError> {
+    let res = sqlx::query!(
+        r"select exists(
+            select 1 from game
+            where id = ? and (white_player = ? or black_player = ?)
+                and finished_at is null and annulled_at is null
+        ) as running",
+        key,
+        user_id,
+        user_id
+    )
+        .fetch_one(conn)
+        .await?;
+    Ok(res.running == 1)
+}
+
+/// A game together with what the player statistics need on top.
+pub struct StatisticsRecord {
+    pub game: SynchronizedMatch,
+    /// None if the opening was not classified yet.
+    pub opening: Option<String>,
+    /// Model names of AIs playing the sides.
+    pub white_ai: Option<String>,
+    pub black_ai: Option<String>,
+}
+
+/// All games of the player which are over, or might be over because they were
+/// stored before the outcome was persisted.
+pub async fn for_player_statistics(
+    user_id: i64,
+    conn: &mut Connection,
+) -> Result<Vec<StatisticsRecord>, ServerError> {
+    let rows = sqlx::query!(
+        r#"select id, action_history, timer, setup, white_player, black_player, opening,
+        (select model_name from game_aiConfig where game_id = game.id and player_color = 'w') as "white_ai?: String",
+        (select model_name from game_aiConfig where game_id = game.id and player_color = 'b') as "black_ai?: String"
+        from game
+        where (white_player = ? or black_player = ?)
+        and (finished_at is not null or victory_state is null)
+        and annulled_at is null"#,
+        user_id,
+        user_id
+    )
+        .fetch_all(conn)
+        .await?;
+
+    rows.into_iter()
+        .map(|r| {
+            let game = RawGame {
+                id: r.id,
+                action_history: r.action_history,
+                timer: r.timer,
+                setup: r.setup,
+                white_player: r.white_player,
+                black_player: r.black_player,
+            }
+                .into_match()?;
+            Ok(StatisticsRecord {
+                game,
+                opening: r.opening,
+                white_ai: r.white_ai,
+                black_ai: r.black_ai,
+            })
+        })
+        .collect()
+}
+
+/// The latest finished games of the player for the fair-play analysis, newest
+/// first. Annulled games and games against the AI are left out.
+pub async fn for_fair_play(
+    user_id: i64,
+    limit: i64,
+    conn: &mut Connection,
+) -> Result<Vec<SynchronizedMatch>, ServerError> {
+    let raw_games = sqlx::query_as!(
+        RawGame,
+        r"select id, action_history, timer, setup, white_player, black_player from game
+        where (white_player = ? or black_player = ?)
+        and finished_at is not null
+        and annulled_at is null
+        and not exists (select 1 from game_aiConfig where game_id = game.id)
+        order by id desc
+        limit ?",
+        user_id,
+        user_id,
+        limit
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw_games.into_iter().map(|raw| raw.into_match()).collect()
+}
+
 pub async fn count_for_player(user_id: i64, conn: &mut Connection) -> Result<i32, ServerError> {
     Ok(sqlx::query!(
         r"select count(*) as count from game
@@ -139,8 +755,293 @@ pub async fn count_for_player(user_id: i64, conn: &mut Connection) -> Result<i32
         .unwrap_or(0))
 }
 
+/// Unfinished games of the player where it is their turn, newest first.
+pub async fn for_player_to_move(
+    user_id: i64,
+    offset: i64,
+    limit: i64,
+    conn: &mut Connection,
+) -> Result<Vec<SynchronizedMatch>, ServerError> {
+    let raw_games = sqlx::query_as!(
+        RawGame,
+        r"select id, action_history, timer, setup, white_player, black_player from game
+        where finished_at is null
+        and ((white_player = ? and controlling_player = 'White')
+            or (black_player = ? and controlling_player = 'Black'))
+        order by id desc
+        limit ? offset ?",
+        user_id,
+        user_id,
+        limit,
+        offset,
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw_games.into_iter().map(|raw| raw.into_match()).collect()
+}
+
+pub async fn count_for_player_to_move(user_id: i64, conn: &mut Connection) -> Result<i32, ServerError> {
+    Ok(sqlx::query!(
+        r"select count(*) as count from game
+            where finished_at is null
+            and ((white_player = ? and controlling_player = 'White')
+                or (black_player = ? and controlling_player = 'Black'))",
+        user_id,
+        user_id
+    )
+        .fetch_one(conn)
+        .await?
+        .count
+        .unwrap_or(0))
+}
+
+/// A player in a correspondence game whose time for the current turn runs out
+/// soon.
+pub struct ApproachingDeadline {
+    pub key: String,
+    pub user_id: UserId,
+    /// Unix milliseconds
+    pub timeout_at: i64,
+    pub action_count: i64,
+}
+
+/// Correspondence games where the clock runs out between `now` and `until`,
+/// both in unix milliseconds.
+pub async fn approaching_deadline(
+    now: i64,
+    until: i64,
+    conn: &mut Connection,
+) -> Result<Vec<ApproachingDeadline>, ServerError> {
+    let rows = sqlx::query!(
+        r#"select id,
+            case controlling_player when 'White' then white_player else black_player end as "user_id: i64",
+            timeout_at as "timeout_at!",
+            json_array_length(action_history) as "action_count!: i64"
+        from game
+        where finished_at is null
+        and timeout_at > ? and timeout_at <= ?
+        and json_extract(timer, '$.config.mode') = 'FixedPerMove'"#,
+        now,
+        until
+    )
+        .fetch_all(conn)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|r| {
+            Some(ApproachingDeadline {
+                key: format!("{}", r.id),
+                user_id: UserId(r.user_id?),
+                timeout_at: r.timeout_at,
+                action_count: r.action_count,
+            })
+        })
+        .collect())
+}
+
+/// A takeback that was accepted in a game. Used to show removed actions in
+/// the replay.
+#[derive(Serialize)]
+pub struct TakebackRecord {
+    pub requested_by: PlayerColor,
+    /// The action history was truncated to this length.
+    pub action_index: i64,
+    pub removed_actions: Vec<StampedAction>,
+    pub created_at: Option<String>,
+}
+
+pub async fn insert_takeback(
+    key: &str,
+    requested_by: PlayerColor,
+    action_index: usize,
+    removed_actions: &[StampedAction],
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let id: i64 = key.parse()?;
+    let requested_by = color_to_str(requested_by);
+    let action_index = action_index as i64;
+    let removed_actions = serde_json::to_string(removed_actions)?;
+
+    sqlx::query!(
+        r"insert into game_takeback (game_id, requested_by, action_index, removed_actions)
+        values (?, ?, ?, ?)",
+        id,
+        requested_by,
+        action_index,
+        removed_actions
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn takebacks(id: i64, conn: &mut Connection) -> Result<Vec<TakebackRecord>, ServerError> {
+    let rows = sqlx::query!(
+        r#"select requested_by, action_index, removed_actions, created_at as "created_at: String" from game_takeback
+        where game_id = ?
+        order by id"#,
+        id
+    )
+        .fetch_all(conn)
+        .await?;
+
+    rows.into_iter()
+        .map(|r| {
+            Ok(TakebackRecord {
+                requested_by: str_to_color(&r.requested_by),
+                action_index: r.action_index,
+                removed_actions: serde_json::from_str(&r.removed_actions)?,
+                created_at: r.created_at,
+            })
+        })
+        .collect()
+}
+
+/// Stores premoves of the player that answer the opponent's turn starting at
+/// `action_index`. The owner is whoever controlled the side when the premoves
+/// were prepared.
+pub async fn insert_premoves(
+    key: &str,
+    player: PlayerColor,
+    owner: &SocketIdentity,
+    action_index: usize,
+    premoves: &[Premove],
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let id: i64 = key.parse()?;
+    let player = color_to_str(player);
+    let action_index = action_index as i64;
+    let user_id = owner.user_id.map(|u| u.0);
+
+    for premove in premoves {
+        let premove = serde_json::to_string(premove)?;
+        sqlx::query!(
+            r"insert into game_premove (game_id, player, action_index, premove, uuid, user_id)
+            values (?, ?, ?, ?, ?, ?)",
+            id,
+            player,
+            action_index,
+            premove,
+            owner.uuid,
+            user_id
+        )
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Lists the premoves the player prepared for the opponent's turn starting at
+/// `action_index`, in the order they were set.
+pub async fn premoves(
+    key: &str,
+    player: PlayerColor,
+    action_index: usize,
+    conn: &mut Connection,
+) -> Result<Vec<Premove>, ServerError> {
+    let id: i64 = key.parse()?;
+    let player = color_to_str(player);
+    let action_index = action_index as i64;
+
+    let rows = sqlx::query!(
+        r"select premove from game_premove
+        where game_id = ? and player = ? and action_index = ?
+        order by id",
+        id,
+        player,
+        action_index
+    )
+        .fetch_all(conn)
+        .await?;
+
+    rows.into_iter()
+        .map(|r| Ok(serde_json::from_str(&r.premove)?))
+        .collect()
+}
+
+/// A premove together with the player who prepared it.
+pub struct OwnedPremove {
+    pub premove: Premove,
+    /// None for premoves stored before owners were recorded.
+    pub owner: Option<SocketIdentity>,
+}
+
+/// Removes all premoves of the player up to the opponent's turn starting at
+/// `action_index`. Returns those that answer exactly this turn, older ones are
+/// outdated and just dropped.
+pub async fn take_premoves(
+    key: &str,
+    player: PlayerColor,
+    action_index: usize,
+    conn: &mut Connection,
+) -> Result<Vec<OwnedPremove>, ServerError> {
+    let id: i64 = key.parse()?;
+    let player = color_to_str(player);
+    let action_index = action_index as i64;
+
+    let rows = sqlx::query!(
+        r"select premove, uuid, user_id from game_premove
+        where game_id = ? and player = ? and action_index = ?
+        order by id",
+        id,
+        player,
+        action_index
+    )
+        .fetch_all(&mut **conn)
+        .await?;
+    let premoves = rows
+        .into_iter()
+        .map(|r| {
+            Ok(OwnedPremove {
+                premove: serde_json::from_str(&r.premove)?,
+                owner: r.uuid.map(|uuid| SocketIdentity {
+                    uuid,
+                    user_id: r.user_id.map(UserId),
+                }),
+            })
+        })
+        .collect::<Result<Vec<_>, ServerError>>()?;
+
+    sqlx::query!(
+        r"delete from game_premove where game_id = ? and player = ? and action_index <= ?",
+        id,
+        player,
+        action_index
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(premoves)
+}
+
+/// Removes premoves of the game. Without a player, this affects both players.
+pub async fn clear_premoves(
+    key: &str,
+    player: Option<PlayerColor>,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let id: i64 = key.parse()?;
+    let player = player.map(color_to_str);
+
+    sqlx::query!(
+        r"delete from game_premove where game_id = ? and (? is null or player = ?)",
+        id,
+        player,
+        player
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
 // Database representation of a sync_match::SynchronizedMatch
 // We don't fully normalize the data, instead we just dump JSON into the db.
+#[derive(sqlx::FromRow)]
 struct RawGame {
     id: i64,
     action_history: String,
@@ -205,3 +1106,85 @@ pub async fn set_player(
 
     Ok(())
 }
+
+/// Sets both players of a game. Moderators use this to correct the players,
+/// a side without a player is unassigned.
+pub async fn set_players(
+    key: i64,
+    white_player: Option<UserId>,
+    black_player: Option<UserId>,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let white_player = white_player.map(|u| u.0);
+    let black_player = black_player.map(|u| u.0);
+    sqlx::query!(
+        "update game set white_player = ?, black_player = ? where id = ?",
+        white_player,
+        black_player,
+        key
+    )
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// The UUIDs that locked the white and the black side of the game.
+pub async fn uuid_locks(key: i64, conn: &mut Connection) -> Result<(Option<String>, Option<String>), ServerError> {
+    let rows = sqlx::query!("select player, uuid from game_uuid_lock where game_id = ?", key)
+        .fetch_all(conn)
+        .await?;
+
+    let mut locks = (None, None);
+    for row in rows {
+        match str_to_color(&row.player) {
+            PlayerColor::White => locks.0 = Some(row.uuid),
+            PlayerColor::Black => locks.1 = Some(row.uuid),
+        }
+    }
+    Ok(locks)
+}
+
+pub async fn insert_uuid_lock(
+    key: i64,
+    player: PlayerColor,
+    uuid: &str,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let player = color_to_str(player);
+    sqlx::query!(
+        "insert or replace into game_uuid_lock (game_id, player, uuid) values (?, ?, ?)",
+        key,
+        player,
+        uuid
+    )
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_uuid_lock(key: i64, player: PlayerColor, conn: &mut Connection) -> Result<(), ServerError> {
+    let player = color_to_str(player);
+    sqlx::query!(
+        "delete from game_uuid_lock where game_id = ? and player = ?",
+        key,
+        player
+    )
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Annulled games don't count for the statistics. Returns false if the game
+/// doesn't exist.
+pub async fn set_annulled(key: i64, annulled: bool, conn: &mut Connection) -> Result<bool, ServerError> {
+    let result = sqlx::query!(
+        r"update game
+        set annulled_at = case when ? then coalesce(annulled_at, CURRENT_TIMESTAMP) else null end
+        where id = ?",
+        annulled,
+        key
+    )
+        .execute(conn)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
@@ -1,5 +1,11 @@
 /// Everything related to the play page.
 pub mod game;
+/// Invitations to play a game.
+pub mod challenge;
+/// Tournaments, their players and games.
+pub mod tournament;
+/// Replay analysis and the metadata AIs post for replays.
+pub mod replay_metadata;
 // pub(crate) mod puzzle;
 
 use sqlx::pool::PoolConnection;
@@ -24,3 +30,30 @@ impl Pool {
         self.0.acquire().await
     }
 }
+
+/// SQLITE_CONSTRAINT_UNIQUE, the extended result code of a violated unique
+/// constraint or index.
+const UNIQUE_VIOLATION: &str = "2067";
+
+/// True if the error is a violated unique constraint or index, like a name
+/// someone else registered at the same time.
+pub fn is_unique_violation(error: &sqlx::Error) -> bool {
+    matches!(error, sqlx::Error::Database(e) if e.code().as_deref() == Some(UNIQUE_VIOLATION))
+}
+
+/// A fresh in-memory database with all migrations applied. There is a single
+/// connection, every connection to `sqlite::memory:` would see its own
+/// database otherwise.
+#[cfg(test)]
+pub async fn test_pool() -> Pool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Could not open the in-memory database.");
+    sqlx::migrate!()
+        .run(&pool)
+        .await
+        .expect("Could not migrate the in-memory database.");
+    Pool(pool)
+}
@@ -0,0 +1,120 @@
+use serde::Serialize;
+use sqlx::Acquire;
+
+use crate::db::Connection;
+use crate::login::UserId;
+use crate::ServerError;
+
+/// A row of `game_replay_metadata`.
+pub struct ReplayMetadataRecord {
+    pub action_index: i64,
+    pub category: String,
+    /// Json encoded payload.
+    pub metadata: String,
+    /// None for metadata posted by AIs.
+    pub analysis_version: Option<i64>,
+}
+
+pub async fn for_game(game_id: i64, conn: &mut Connection) -> Result<Vec<ReplayMetadataRecord>, ServerError> {
+    Ok(sqlx::query_as!(
+        ReplayMetadataRecord,
+        r"select action_index, category, metadata, analysis_version
+        from game_replay_metadata
+        where game_id = ?
+        order by action_index",
+        game_id
+    )
+        .fetch_all(conn)
+        .await?)
+}
+
+/// Stores metadata posted by `created_by`.
+pub async fn insert(
+    game_id: i64,
+    action_index: i64,
+    category: &str,
+    metadata: &str,
+    created_by: UserId,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    sqlx::query!(
+        r"insert into game_replay_metadata (game_id, action_index, category, metadata, created_by)
+        values (?, ?, ?, ?, ?)",
+        game_id,
+        action_index,
+        category,
+        metadata,
+        created_by.0
+    )
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// A row of `game_replay_metadata` posted by a user.
+#[derive(Serialize)]
+pub struct CreatedReplayMetadata {
+    pub game_id: i64,
+    pub action_index: i64,
+    pub category: String,
+    /// Json encoded payload.
+    pub metadata: String,
+}
+
+/// All metadata the user posted, ordered by game.
+pub async fn created_by(user_id: UserId, conn: &mut Connection) -> Result<Vec<CreatedReplayMetadata>, ServerError> {
+    Ok(sqlx::query_as!(
+        CreatedReplayMetadata,
+        r"select game_id, action_index, category, metadata
+        from game_replay_metadata
+        where created_by = ?
+        order by game_id, action_index",
+        user_id.0
+    )
+        .fetch_all(conn)
+        .await?)
+}
+
+/// Replaces the stored replay analysis of the game and remembers its version.
+/// Metadata posted by AIs is kept.
+pub async fn store_analysis(
+    game_id: i64,
+    version: i64,
+    rows: &[ReplayMetadataRecord],
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let mut tx = conn.begin().await?;
+
+    sqlx::query!(
+        "delete from game_replay_metadata where game_id = ? and analysis_version is not null",
+        game_id
+    )
+        .execute(&mut tx)
+        .await?;
+
+    for row in rows {
+        sqlx::query!(
+            r"insert into game_replay_metadata
+            (game_id, action_index, category, metadata, analysis_version)
+            values (?, ?, ?, ?, ?)",
+            game_id,
+            row.action_index,
+            row.category,
+            row.metadata,
+            version
+        )
+            .execute(&mut tx)
+            .await?;
+    }
+
+    sqlx::query!(
+        "update game set replay_analysis_version = ? where id = ?",
+        version,
+        game_id
+    )
+        .execute(&mut tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
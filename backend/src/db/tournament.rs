@@ -0,0 +1,348 @@
+use crate::db::Connection;
+use crate::login::UserId;
+use crate::sync_match::MatchParameters;
+use crate::tournament::pairing::{Outcome, Pairing, TournamentKind};
+use crate::ServerError;
+
+/// Database representation of a tournament.
+pub struct TournamentRecord {
+    pub id: i64,
+    pub name: String,
+    pub kind: TournamentKind,
+    pub parameters: MatchParameters,
+    pub rounds: Option<i64>,
+    pub duration_minutes: Option<i64>,
+    /// One of 'registration', 'running' or 'finished'.
+    pub status: String,
+    pub current_round: i64,
+    pub created_by: UserId,
+    pub created_at: Option<String>,
+    pub started_at: Option<String>,
+    /// Unix milliseconds, arena only.
+    pub ends_at: Option<i64>,
+    pub finished_at: Option<String>,
+}
+
+struct RawTournament {
+    id: i64,
+    name: String,
+    kind: String,
+    parameters: String,
+    rounds: Option<i64>,
+    duration_minutes: Option<i64>,
+    status: String,
+    current_round: i64,
+    created_by: i64,
+    created_at: Option<String>,
+    started_at: Option<String>,
+    ends_at: Option<i64>,
+    finished_at: Option<String>,
+}
+
+impl TryFrom<RawTournament> for TournamentRecord {
+    type Error = ServerError;
+
+    fn try_from(raw: RawTournament) -> Result<Self, Self::Error> {
+        Ok(TournamentRecord {
+            id: raw.id,
+            name: raw.name,
+            kind: if raw.kind == "Arena" {
+                TournamentKind::Arena
+            } else {
+                TournamentKind::Swiss
+            },
+            parameters: serde_json::from_str(&raw.parameters)?,
+            rounds: raw.rounds,
+            duration_minutes: raw.duration_minutes,
+            status: raw.status,
+            current_round: raw.current_round,
+            created_by: UserId(raw.created_by),
+            created_at: raw.created_at,
+            started_at: raw.started_at,
+            ends_at: raw.ends_at,
+            finished_at: raw.finished_at,
+        })
+    }
+}
+
+/// A game of a tournament as stored in the database.
+pub struct TournamentGame {
+    pub round: i64,
+    /// None for a bye.
+    pub game_key: Option<String>,
+    pub pairing: Pairing,
+}
+
+fn kind_to_str(kind: TournamentKind) -> &'static str {
+    match kind {
+        TournamentKind::Swiss => "Swiss",
+        TournamentKind::Arena => "Arena",
+    }
+}
+
+fn outcome_to_str(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::WhiteWins => "WhiteWins",
+        Outcome::BlackWins => "BlackWins",
+        Outcome::Draw => "Draw",
+    }
+}
+
+fn str_to_outcome(outcome: &str) -> Option<Outcome> {
+    match outcome {
+        "WhiteWins" => Some(Outcome::WhiteWins),
+        "BlackWins" => Some(Outcome::BlackWins),
+        "Draw" => Some(Outcome::Draw),
+        _ => None,
+    }
+}
+
+pub async fn insert(
+    name: &str,
+    kind: TournamentKind,
+    parameters: &MatchParameters,
+    rounds: Option<i64>,
+    duration_minutes: Option<i64>,
+    created_by: UserId,
+    conn: &mut Connection,
+) -> Result<i64, ServerError> {
+    let kind = kind_to_str(kind);
+    let parameters = serde_json::to_string(parameters)?;
+
+    let id = sqlx::query!(
+        r"insert into tournament (name, kind, parameters, rounds, duration_minutes, created_by)
+        values (?, ?, ?, ?, ?, ?)",
+        name,
+        kind,
+        parameters,
+        rounds,
+        duration_minutes,
+        created_by.0
+    )
+        .execute(conn)
+        .await?
+        .last_insert_rowid();
+
+    Ok(id)
+}
+
+pub async fn select(id: i64, conn: &mut Connection) -> Result<Option<TournamentRecord>, ServerError> {
+    let raw = sqlx::query_as!(
+        RawTournament,
+        r#"select id as "id!", name, kind, parameters, rounds, duration_minutes, status, current_round,
+        created_by, created_at as "created_at: String", started_at as "started_at: String", ends_at,
+        finished_at as "finished_at: String"
+        from tournament where id = ?"#,
+        id
+    )
+        .fetch_optional(conn)
+        .await?;
+
+    raw.map(TournamentRecord::try_from).transpose()
+}
+
+/// Tournaments that did not finish yet come first, then the latest ones.
+pub async fn latest(limit: i64, conn: &mut Connection) -> Result<Vec<TournamentRecord>, ServerError> {
+    let raw = sqlx::query_as!(
+        RawTournament,
+        r#"select id as "id!", name, kind, parameters, rounds, duration_minutes, status, current_round,
+        created_by, created_at as "created_at: String", started_at as "started_at: String", ends_at,
+        finished_at as "finished_at: String"
+        from tournament
+        order by status = 'finished', id desc
+        limit ?"#,
+        limit
+    )
+        .fetch_all(conn)
+        .await?;
+
+    raw.into_iter().map(TournamentRecord::try_from).collect()
+}
+
+/// Ids of all running arena tournaments.
+pub async fn running_arenas(conn: &mut Connection) -> Result<Vec<i64>, ServerError> {
+    let rows = sqlx::query!(
+        r#"select id as "id!" from tournament where status = 'running' and kind = 'Arena'"#
+    )
+        .fetch_all(conn)
+        .await?;
+
+    Ok(rows.into_iter().map(|r| r.id).collect())
+}
+
+/// Registers the player. Players who withdrew before may join again.
+pub async fn add_player(id: i64, user_id: UserId, conn: &mut Connection) -> Result<(), ServerError> {
+    sqlx::query!(
+        r"insert into tournament_player (tournament_id, user_id) values (?, ?)
+        on conflict (tournament_id, user_id) do update set withdrawn = 0",
+        id,
+        user_id.0
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Before the tournament starts, players are removed completely. Later, they
+/// keep their results, but are not paired anymore.
+pub async fn withdraw_player(
+    id: i64,
+    user_id: UserId,
+    before_start: bool,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    if before_start {
+        sqlx::query!(
+            "delete from tournament_player where tournament_id = ? and user_id = ?",
+            id,
+            user_id.0
+        )
+            .execute(conn)
+            .await?;
+    } else {
+        sqlx::query!(
+            "update tournament_player set withdrawn = 1 where tournament_id = ? and user_id = ?",
+            id,
+            user_id.0
+        )
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// All players in the order they joined, with a flag if they withdrew.
+pub async fn players(id: i64, conn: &mut Connection) -> Result<Vec<(UserId, bool)>, ServerError> {
+    let rows = sqlx::query!(
+        r"select user_id, withdrawn from tournament_player
+        where tournament_id = ?
+        order by joined_at, rowid",
+        id
+    )
+        .fetch_all(conn)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| (UserId(r.user_id), r.withdrawn != 0))
+        .collect())
+}
+
+/// Moves the tournament from registration to running. Returns false if it was
+/// started already.
+pub async fn start(id: i64, ends_at: Option<i64>, conn: &mut Connection) -> Result<bool, ServerError> {
+    let result = sqlx::query!(
+        r"update tournament set status = 'running', started_at = CURRENT_TIMESTAMP, ends_at = ?
+        where id = ? and status = 'registration'",
+        ends_at,
+        id
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Moves a Swiss tournament to the next round. Returns false if another task
+/// did that already.
+pub async fn next_round(id: i64, current_round: i64, conn: &mut Connection) -> Result<bool, ServerError> {
+    let result = sqlx::query!(
+        r"update tournament set current_round = current_round + 1
+        where id = ? and status = 'running' and current_round = ?",
+        id,
+        current_round
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn finish(id: i64, conn: &mut Connection) -> Result<bool, ServerError> {
+    let result = sqlx::query!(
+        r"update tournament set status = 'finished', finished_at = CURRENT_TIMESTAMP
+        where id = ? and status = 'running'",
+        id
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn insert_game(
+    id: i64,
+    round: i64,
+    game_key: Option<&str>,
+    pairing: &Pairing,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let game_id: Option<i64> = game_key.map(str::parse).transpose()?;
+    let black = pairing.black.map(|u| u.0);
+    let outcome = pairing.outcome.map(outcome_to_str);
+
+    sqlx::query!(
+        r"insert into tournament_game (tournament_id, round, game_id, white_player, black_player, outcome)
+        values (?, ?, ?, ?, ?, ?)",
+        id,
+        round,
+        game_id,
+        pairing.white.0,
+        black,
+        outcome
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// All games of the tournament in the order they were paired.
+pub async fn games(id: i64, conn: &mut Connection) -> Result<Vec<TournamentGame>, ServerError> {
+    let rows = sqlx::query!(
+        r"select round, game_id, white_player, black_player, outcome from tournament_game
+        where tournament_id = ?
+        order by id",
+        id
+    )
+        .fetch_all(conn)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| TournamentGame {
+            round: r.round,
+            game_key: r.game_id.map(|id| id.to_string()),
+            pairing: Pairing {
+                white: UserId(r.white_player),
+                black: r.black_player.map(UserId),
+                outcome: r.outcome.as_deref().and_then(str_to_outcome),
+            },
+        })
+        .collect())
+}
+
+/// Stores the outcome of a tournament game. Returns the id of the tournament
+/// if this game belongs to one and did not have an outcome before.
+pub async fn set_outcome(
+    game_key: &str,
+    outcome: Outcome,
+    conn: &mut Connection,
+) -> Result<Option<i64>, ServerError> {
+    let game_id: i64 = game_key.parse()?;
+    let outcome = outcome_to_str(outcome);
+
+    let row = sqlx::query!(
+        r"update tournament_game set outcome = ?
+        where game_id = ? and outcome is null
+        returning tournament_id",
+        outcome,
+        game_id
+    )
+        .fetch_optional(conn)
+        .await?;
+
+    Ok(row.map(|r| r.tournament_id))
+}
@@ -0,0 +1,112 @@
+//! Server sent event streams for API clients like bots.
+//!
+//! There are two kinds of streams. The account stream of a user tells them
+//! about incoming challenges and games that start for them. A game stream
+//! tells every subscriber about each change of a single game.
+//!
+//! Events are only delivered to streams that are connected at the time. A
+//! client that connects later has to load the current state over REST.
+
+use std::convert::Infallible;
+use std::hash::Hash;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use dashmap::DashMap;
+use futures_util::stream::{self, Stream};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use crate::login::UserId;
+
+lazy_static! {
+    static ref ACCOUNT_STREAMS: DashMap<i64, Vec<UnboundedSender<StreamEvent>>> = DashMap::new();
+    static ref GAME_STREAMS: DashMap<String, Vec<UnboundedSender<StreamEvent>>> = DashMap::new();
+}
+
+/// An event that is already serialized, so it can be sent to many streams.
+#[derive(Clone, Debug)]
+pub struct StreamEvent {
+    kind: &'static str,
+    data: String,
+}
+
+impl StreamEvent {
+    pub fn new(kind: &'static str, data: &impl Serialize) -> Result<Self, serde_json::Error> {
+        Ok(Self {
+            kind,
+            data: serde_json::to_string(data)?,
+        })
+    }
+}
+
+pub type EventStream = Sse<Box<dyn Stream<Item = Result<Event, Infallible>> + Send + Unpin>>;
+
+/// Opens the account stream of the user.
+pub fn account_stream(user_id: UserId) -> EventStream {
+    subscribe(&ACCOUNT_STREAMS, user_id.0, None)
+}
+
+/// Opens a stream for the game. The initial event is sent first, this should
+/// be the current state of the game.
+pub fn game_stream(key: String, initial: StreamEvent) -> EventStream {
+    subscribe(&GAME_STREAMS, key, Some(initial))
+}
+
+pub fn publish_to_user(user_id: UserId, event: StreamEvent) {
+    publish(&ACCOUNT_STREAMS, &user_id.0, event);
+}
+
+pub fn publish_to_game(key: &str, event: StreamEvent) {
+    publish(&GAME_STREAMS, key, event);
+}
+
+/// Preparing a game event is not free, so this allows skipping it when no one
+/// is listening.
+pub fn has_game_subscribers(key: &str) -> bool {
+    GAME_STREAMS.contains_key(key)
+}
+
+/// Ends all streams. Otherwise, the graceful shutdown would wait for the
+/// clients to disconnect on their own.
+pub fn close_all() {
+    ACCOUNT_STREAMS.clear();
+    GAME_STREAMS.clear();
+}
+
+fn subscribe<K: Eq + Hash>(
+    streams: &DashMap<K, Vec<UnboundedSender<StreamEvent>>>,
+    key: K,
+    initial: Option<StreamEvent>,
+) -> EventStream {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    if let Some(initial) = initial {
+        let _ = sender.send(initial);
+    }
+    let mut senders = streams.entry(key).or_default();
+    senders.retain(|s| !s.is_closed());
+    senders.push(sender);
+
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        let event = receiver.recv().await?;
+        let event = Event::default().event(event.kind).data(event.data);
+        Some((Ok(event), receiver))
+    });
+    Sse::new(Box::new(Box::pin(stream)) as Box<_>).keep_alive(KeepAlive::default())
+}
+
+fn publish<K, Q>(streams: &DashMap<K, Vec<UnboundedSender<StreamEvent>>>, key: &Q, event: StreamEvent)
+where
+    K: Eq + Hash + std::borrow::Borrow<Q>,
+    Q: Eq + Hash + ?Sized,
+{
+    let Some(mut senders) = streams.get_mut(key) else {
+        return;
+    };
+    senders.retain(|s| s.send(event.clone()).is_ok());
+    let is_empty = senders.is_empty();
+    drop(senders);
+    if is_empty {
+        streams.remove_if(key, |_, senders| senders.is_empty());
+    }
+}
@@ -0,0 +1,441 @@
+//! Game export for analysis outside of the site.
+//!
+//! Games are exported either as JSON or as a PGN-style text. Both formats are
+//! described in doc/game-export.md. Changes to them must keep old fields and
+//! bump `FORMAT_VERSION`, as scripts of our analysts depend on them.
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+
+use pacosako::analysis::incremental_replay::history_to_replay_notation_without_paco_in_2;
+use pacosako::setup_options::SetupOptions;
+use pacosako::{DenseBoard, PacoAction, PlayerColor, VictoryState};
+
+use crate::db::{self, game::ExportRecord, Connection, Pool};
+use crate::login::user::{load_public_user_data, load_user_data_for_game, AiMetaData, PublicUserData};
+use crate::login::UserId;
+use crate::sync_match::StampedAction;
+use crate::timer::{ClockMode, TimerConfig};
+use crate::{config::EnvironmentConfig, AppState, ServerError};
+
+/// Version of the exported format.
+const FORMAT_VERSION: u32 = 1;
+
+/// How many games are loaded from the database for each chunk of a bulk export.
+const EXPORT_BATCH_SIZE: i64 = 100;
+
+/// Lines of moves in the text format are wrapped after this many characters.
+const TEXT_LINE_WIDTH: usize = 80;
+
+/// Adds the export API to the given router.
+/// This is expected to be nested at "/api".
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router
+        .route("/game/:key/export", get(export_game))
+        .route("/user/:user_id/games/export", get(export_user_games))
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Json,
+    Text,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[derive(Serialize)]
+struct ExportedPlayer {
+    user_id: UserId,
+    name: String,
+    is_bot: bool,
+    /// Set if the player is an AI, with the configuration used in this game.
+    ai: Option<AiMetaData>,
+}
+
+impl From<PublicUserData> for ExportedPlayer {
+    fn from(user: PublicUserData) -> Self {
+        Self {
+            user_id: user.user_id,
+            name: user.name,
+            is_bot: user.is_bot,
+            ai: user.ai,
+        }
+    }
+}
+
+/// A single game in the JSON format.
+#[derive(Serialize)]
+pub struct ExportedGame {
+    format_version: u32,
+    key: String,
+    created: Option<String>,
+    finished_at: Option<String>,
+    white: Option<ExportedPlayer>,
+    black: Option<ExportedPlayer>,
+    setup: SetupOptions,
+    timer: Option<TimerConfig>,
+    victory_state: VictoryState,
+    /// "1-0", "0-1", "1/2-1/2" or "*" while the game is running.
+    result: &'static str,
+    /// Name of the opening, empty if no known opening was played.
+    opening: String,
+    /// Half-moves in the notation of the replay page.
+    moves: Vec<HalfMoveExport>,
+    /// All actions with the time they were done.
+    actions: Vec<StampedAction>,
+}
+
+#[derive(Serialize)]
+struct HalfMoveExport {
+    move_number: u32,
+    player: PlayerColor,
+    notation: String,
+}
+
+impl ExportedGame {
+    pub async fn load(record: ExportRecord, conn: &mut Connection) -> Result<Self, ServerError> {
+        let game = record.game;
+        let victory_state = game.current_state()?.victory_state;
+        let actions: Vec<PacoAction> = game.actions.iter().map(PacoAction::from).collect();
+        let initial_board = DenseBoard::with_options(&game.setup_options)?;
+        let replay = history_to_replay_notation_without_paco_in_2(&initial_board, &actions)?;
+        let (white, black) = load_user_data_for_game(&game.key, conn).await?;
+
+        Ok(Self {
+            format_version: FORMAT_VERSION,
+            created: record.created,
+            finished_at: record.finished_at,
+            white: white.map(ExportedPlayer::from),
+            black: black.map(ExportedPlayer::from),
+            timer: game.timer.map(|timer| timer.config),
+            victory_state,
+            result: result(victory_state),
+            opening: replay.opening().to_string(),
+            moves: replay
+                .notation()
+                .iter()
+                .map(|half_move| HalfMoveExport {
+                    move_number: half_move.move_number(),
+                    player: half_move.current_player(),
+                    notation: half_move.label(),
+                })
+                .collect(),
+            key: game.key,
+            setup: game.setup_options,
+            actions: game.actions,
+        })
+    }
+
+    /// The server url is used to link to the replay in the text format.
+    fn render(&self, format: ExportFormat, server_url: &str) -> Result<String, ServerError> {
+        match format {
+            ExportFormat::Json => Ok(serde_json::to_string(self)?),
+            ExportFormat::Text => Ok(self.to_text(server_url)),
+        }
+    }
+
+    /// Renders the game in the PGN-style text format.
+    fn to_text(&self, server_url: &str) -> String {
+        let mut text = String::new();
+        let mut tag = |name: &str, value: &str| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            text.push_str(&format!("[{name} \"{value}\"]\n"));
+        };
+        let player_name = |player: &Option<ExportedPlayer>| match player {
+            Some(player) => player.name.clone(),
+            None => "?".to_string(),
+        };
+
+        tag("Event", "Paco Ŝako game");
+        tag("Site", &format!("{}/replay/{}", server_url, self.key));
+        tag("Date", &pgn_date(self.created.as_deref()));
+        tag("White", &player_name(&self.white));
+        tag("Black", &player_name(&self.black));
+        tag("Result", self.result);
+        tag("Termination", termination(self.victory_state));
+        tag("SafeMode", &self.setup.safe_mode.to_string());
+        tag(
+            "DrawAfterRepetitions",
+            &self.setup.draw_after_n_repetitions.to_string(),
+        );
+        if let Some(fen) = &self.setup.starting_fen {
+            tag("SetUp", "1");
+            tag("FEN", fen);
+        }
+        if let Some(timer) = &self.timer {
+            tag("TimeControl", &time_control(timer));
+            if timer.mode != ClockMode::Fischer {
+                tag("ClockMode", &format!("{:?}", timer.mode));
+            }
+        }
+        if !self.opening.is_empty() {
+            tag("Opening", &self.opening);
+        }
+        text.push('\n');
+
+        let mut tokens = Vec::with_capacity(self.moves.len() * 2 + 1);
+        for (i, half_move) in self.moves.iter().enumerate() {
+            match half_move.player {
+                PlayerColor::White => tokens.push(format!("{}.", half_move.move_number)),
+                // Black needs a move number if white's half-move is not right before.
+                PlayerColor::Black if i == 0 => tokens.push(format!("{}...", half_move.move_number)),
+                PlayerColor::Black => {}
+            }
+            tokens.push(half_move.notation.clone());
+        }
+        tokens.push(self.result.to_string());
+
+        let mut line_length = 0;
+        for token in tokens {
+            if line_length > 0 && line_length + 1 + token.chars().count() > TEXT_LINE_WIDTH {
+                text.push('\n');
+                line_length = 0;
+            } else if line_length > 0 {
+                text.push(' ');
+                line_length += 1;
+            }
+            line_length += token.chars().count();
+            text.push_str(&token);
+        }
+        text.push('\n');
+        text
+    }
+}
+
+fn result(victory_state: VictoryState) -> &'static str {
+    match victory_state {
+        VictoryState::Running => "*",
+        VictoryState::PacoVictory(PlayerColor::White)
+        | VictoryState::TimeoutVictory(PlayerColor::White) => "1-0",
+        VictoryState::PacoVictory(PlayerColor::Black)
+        | VictoryState::TimeoutVictory(PlayerColor::Black) => "0-1",
+        VictoryState::NoProgressDraw | VictoryState::RepetitionDraw => "1/2-1/2",
+    }
+}
+
+fn termination(victory_state: VictoryState) -> &'static str {
+    match victory_state {
+        VictoryState::Running => "unterminated",
+        VictoryState::PacoVictory(_) => "paco",
+        VictoryState::TimeoutVictory(_) => "time forfeit",
+        VictoryState::NoProgressDraw => "no progress",
+        VictoryState::RepetitionDraw => "repetition",
+    }
+}
+
+/// Turns "2024-01-31 12:00:00" into "2024.01.31".
+fn pgn_date(created: Option<&str>) -> String {
+    match created.and_then(|created| created.get(..10)) {
+        Some(date) => date.replace('-', "."),
+        None => "????.??.??".to_string(),
+    }
+}
+
+/// Seconds of white's budget and the increment, like "300+5". Different
+/// budgets are written as "white/black+increment".
+fn time_control(timer: &TimerConfig) -> String {
+    let white = timer.time_budget_white.num_seconds();
+    let black = timer.time_budget_black.num_seconds();
+    let increment = timer.increment.map_or(0, |i| i.num_seconds());
+    if white == black {
+        format!("{white}+{increment}")
+    } else {
+        format!("{white}/{black}+{increment}")
+    }
+}
+
+fn content_type(format: ExportFormat, bulk: bool) -> &'static str {
+    match format {
+        ExportFormat::Json if bulk => "application/x-ndjson",
+        ExportFormat::Json => "application/json",
+        ExportFormat::Text => "text/plain; charset=utf-8",
+    }
+}
+
+async fn export_game(
+    Path(key): Path<String>,
+    Query(query): Query<ExportQuery>,
+    State(pool): State<Pool>,
+    State(config): State<EnvironmentConfig>,
+) -> Result<Response, ServerError> {
+    let mut conn = pool.conn().await?;
+    let Some(record) = db::game::select_for_export(key.parse()?, &mut conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    let game = ExportedGame::load(record, &mut conn).await?;
+    Ok((
+        [(header::CONTENT_TYPE, content_type(query.format, false))],
+        game.render(query.format, &config.server_url)?,
+    )
+        .into_response())
+}
+
+/// Where a bulk export continues with the next chunk.
+struct ExportCursor {
+    pool: Pool,
+    server_url: String,
+    user_id: UserId,
+    format: ExportFormat,
+    after_id: i64,
+    done: bool,
+}
+
+impl ExportCursor {
+    /// Renders the next batch of games. Each chunk uses its own connection,
+    /// so slow clients don't keep one busy.
+    async fn next_chunk(mut self) -> Result<Option<(String, Self)>, ServerError> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut conn = self.pool.conn().await?;
+        let records =
+            db::game::for_player_export(self.user_id.0, self.after_id, EXPORT_BATCH_SIZE, &mut conn)
+                .await?;
+        self.done = records.len() < EXPORT_BATCH_SIZE as usize;
+        if records.is_empty() {
+            return Ok(None);
+        }
+
+        let mut chunk = String::new();
+        for record in records {
+            self.after_id = record.game.key.parse()?;
+            let game = ExportedGame::load(record, &mut conn).await?;
+            chunk.push_str(&game.render(self.format, &self.server_url)?);
+            // Text games are separated by an empty line, JSON games are one per line.
+            chunk.push('\n');
+        }
+        Ok(Some((chunk, self)))
+    }
+}
+
+/// Streams all games of the user, oldest first.
+async fn export_user_games(
+    Path(user_id): Path<i64>,
+    Query(query): Query<ExportQuery>,
+    State(pool): State<Pool>,
+    State(config): State<EnvironmentConfig>,
+) -> Result<Response, ServerError> {
+    let user_id = UserId(user_id);
+    {
+        let mut conn = pool.conn().await?;
+        load_public_user_data(user_id, &mut conn)
+            .await
+            .map_err(|_| ServerError::NotFound)?;
+    }
+
+    let cursor = ExportCursor {
+        pool,
+        server_url: config.server_url,
+        user_id,
+        format: query.format,
+        after_id: 0,
+        done: false,
+    };
+    let body = Body::from_stream(stream::try_unfold(cursor, ExportCursor::next_chunk));
+    let extension = match query.format {
+        ExportFormat::Json => "ndjson",
+        ExportFormat::Text => "txt",
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type(query.format, true).to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"pacosako-user-{}.{extension}\"", user_id.0),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn game(moves: Vec<(u32, PlayerColor, &str)>, victory_state: VictoryState) -> ExportedGame {
+        ExportedGame {
+            format_version: FORMAT_VERSION,
+            key: "42".to_string(),
+            created: Some("2024-01-31 12:00:00".to_string()),
+            finished_at: None,
+            white: Some(ExportedPlayer {
+                user_id: UserId(1),
+                name: "Al \"Fox\"".to_string(),
+                is_bot: false,
+                ai: None,
+            }),
+            black: None,
+            setup: SetupOptions {
+                safe_mode: true,
+                draw_after_n_repetitions: 3,
+                starting_fen: None,
+            },
+            timer: None,
+            victory_state,
+            result: result(victory_state),
+            opening: String::new(),
+            moves: moves
+                .into_iter()
+                .map(|(move_number, player, notation)| HalfMoveExport {
+                    move_number,
+                    player,
+                    notation: notation.to_string(),
+                })
+                .collect(),
+            actions: vec![],
+        }
+    }
+
+    #[test]
+    fn test_text_export() {
+        let game = game(
+            vec![
+                (1, PlayerColor::White, "d2>d4"),
+                (1, PlayerColor::Black, "e7>e5"),
+                (2, PlayerColor::White, "d4xe5"),
+            ],
+            VictoryState::PacoVictory(PlayerColor::White),
+        );
+        assert_eq!(
+            game.to_text("https://pacoplay.com"),
+            "[Event \"Paco Ŝako game\"]\n\
+            [Site \"https://pacoplay.com/replay/42\"]\n\
+            [Date \"2024.01.31\"]\n\
+            [White \"Al \\\"Fox\\\"\"]\n\
+            [Black \"?\"]\n\
+            [Result \"1-0\"]\n\
+            [Termination \"paco\"]\n\
+            [SafeMode \"true\"]\n\
+            [DrawAfterRepetitions \"3\"]\n\
+            \n\
+            1. d2>d4 e7>e5 2. d4xe5 1-0\n"
+        );
+    }
+
+    #[test]
+    fn test_text_export_wraps_lines() {
+        let moves = (1..=20)
+            .flat_map(|n| [(n, PlayerColor::White, "a1>a2"), (n, PlayerColor::Black, "h8>h7")])
+            .collect();
+        let text = game(moves, VictoryState::Running).to_text("https://pacoplay.com");
+        let move_lines: Vec<&str> = text.split("\n\n").nth(1).unwrap().lines().collect();
+        assert!(move_lines.len() > 1);
+        assert!(move_lines.iter().all(|line| line.chars().count() <= TEXT_LINE_WIDTH));
+        assert!(move_lines.last().unwrap().ends_with(" *"));
+    }
+}
@@ -0,0 +1,510 @@
+//! Fair-play analysis: compares the decisions of a user with the model.
+//!
+//! Moderators queue a user for analysis. A worker replays their latest games,
+//! evaluates every decision with the model and stores how often the user
+//! played the model's favorite and how much value they gave away. Users who
+//! follow the model much more closely than everyone else are flagged for
+//! review. A flag is a hint for the moderators, not a verdict.
+//!
+//! The model runs in a separate service at `fair_play_model_url`, the server
+//! only sends it board tensors.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use pacosako::ai::fair_play::{analyze_game, Decision, FairPlayStatistics};
+use pacosako::ai::model_backend::ModelBackend;
+use pacosako::ai::model_evaluation::ModelEvaluation;
+use pacosako::{DenseBoard, PacoAction, PacoBoard, PacoError, PlayerColor};
+
+use crate::{
+    config::EnvironmentConfig,
+    db::{self, Connection, Pool},
+    login::{
+        permission::{ModerateUsers, RequirePermission},
+        UserId,
+    },
+    moderation::{self, AuditEntry},
+    sync_match::SynchronizedMatch,
+    AppState, ServerError,
+};
+
+/// How many of the latest games of a user are analyzed.
+const GAME_LIMIT: i64 = 50;
+
+/// Users with fewer decisions are neither compared nor part of the population
+/// others are compared with.
+const MIN_DECISIONS: i64 = 200;
+
+/// Scores are only computed when at least this many other users are analyzed.
+const MIN_POPULATION: usize = 10;
+
+/// Standard deviations from the population that flag a user.
+const FLAG_THRESHOLD: f64 = 3.0;
+
+/// How long the worker sleeps when nobody queues an analysis.
+const IDLE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+static WAKE_UP: Notify = Notify::const_new();
+
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router
+        .route("/moderation/fair_play", get(list_analyses))
+        .route("/moderation/fair_play/:user_id", post(request_analysis))
+}
+
+// Model ///////////////////////////////////////////////////////////////////////
+
+/// Evaluates the model in a separate service over HTTP.
+struct RemoteModel {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct ModelRequest<'a> {
+    input: &'a [f32],
+}
+
+#[derive(Deserialize)]
+struct ModelResponse {
+    output: Vec<f32>,
+}
+
+impl ModelBackend for RemoteModel {
+    async fn evaluate_model(&mut self, board: &DenseBoard) -> Result<ModelEvaluation, PacoError> {
+        let mut input = [0f32; 30 * 8 * 8];
+        pacosako::ai::repr::tensor_representation(board, &mut input);
+
+        let model_error = |e: &dyn std::fmt::Display| PacoError::MlModelError(e.to_string());
+        let body = serde_json::to_string(&ModelRequest { input: &input }).map_err(|e| model_error(&e))?;
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| model_error(&e))?
+            .text()
+            .await
+            .map_err(|e| model_error(&e))?;
+        let response: ModelResponse = serde_json::from_str(&response).map_err(|e| model_error(&e))?;
+        // The value and the policy for 132 action indices.
+        if response.output.len() != 133 {
+            return Err(PacoError::MlModelError(format!(
+                "Expected 133 model outputs, got {}.",
+                response.output.len()
+            )));
+        }
+
+        ModelEvaluation::new(board.actions()?, board.controlling_player, &response.output)
+    }
+}
+
+// Worker //////////////////////////////////////////////////////////////////////
+
+/// Runs the worker forever. Errors are logged and the worker tries again
+/// later.
+pub async fn run(pool: Pool, model_url: String) {
+    // Analyses interrupted by a restart start over.
+    if let Err(e) = requeue_running(&pool).await {
+        warn!("Could not requeue fair-play analyses: {:?}", e);
+    }
+
+    let mut model = RemoteModel {
+        client: reqwest::Client::new(),
+        url: model_url,
+    };
+    loop {
+        match analyze_next(&pool, &mut model).await {
+            // There may be more users waiting.
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => warn!("Fair-play analysis failed: {:?}", e),
+        }
+        tokio::select! {
+            _ = WAKE_UP.notified() => {}
+            _ = tokio::time::sleep(IDLE_INTERVAL) => {}
+        }
+    }
+}
+
+async fn requeue_running(pool: &Pool) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    sqlx::query!("update fair_play_analysis set status = 'queued' where status = 'running'")
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Analyzes the next queued user. Returns false if nobody is queued.
+async fn analyze_next(pool: &Pool, model: &mut RemoteModel) -> Result<bool, ServerError> {
+    let (user_id, games) = {
+        let mut conn = pool.conn().await?;
+        let Some(user_id) = start_next(&mut conn).await? else {
+            return Ok(false);
+        };
+        let games = db::game::for_fair_play(user_id, GAME_LIMIT, &mut conn).await?;
+        (user_id, games)
+    };
+
+    // The model can take a while, so we don't hold a database connection.
+    let result = analyze_user(UserId(user_id), &games, model).await;
+
+    let mut conn = pool.conn().await?;
+    match result {
+        Ok(statistics) => store_result(user_id, games.len() as i64, &statistics, &mut conn).await?,
+        Err(e) => {
+            warn!("Fair-play analysis of user {} failed: {:?}", user_id, e);
+            store_failure(user_id, &format!("{e:?}"), &mut conn).await?;
+        }
+    }
+    Ok(true)
+}
+
+/// Marks the oldest queued analysis as running and returns its user.
+async fn start_next(conn: &mut Connection) -> Result<Option<i64>, ServerError> {
+    let next = sqlx::query!(
+        r#"select user_id as "user_id!" from fair_play_analysis
+        where status = 'queued'
+        order by requested_at
+        limit 1"#
+    )
+        .fetch_optional(&mut **conn)
+        .await?;
+    let Some(next) = next else {
+        return Ok(None);
+    };
+    sqlx::query!(
+        "update fair_play_analysis set status = 'running' where user_id = ?",
+        next.user_id
+    )
+        .execute(&mut **conn)
+        .await?;
+    Ok(Some(next.user_id))
+}
+
+async fn analyze_user(
+    user_id: UserId,
+    games: &[SynchronizedMatch],
+    model: &mut RemoteModel,
+) -> Result<FairPlayStatistics, ServerError> {
+    let mut decisions: Vec<Decision> = vec![];
+    for game in games {
+        let actions: Vec<PacoAction> = game.actions.iter().map(PacoAction::from).collect();
+        let think_times = think_times(game);
+        for (color, player) in [
+            (PlayerColor::White, game.white_player),
+            (PlayerColor::Black, game.black_player),
+        ] {
+            if player != Some(user_id) {
+                continue;
+            }
+            let board = DenseBoard::with_options(&game.setup_options)?;
+            decisions.extend(analyze_game(model, board, &actions, &think_times, color).await?);
+        }
+    }
+    Ok(FairPlayStatistics::from_decisions(&decisions))
+}
+
+/// Seconds since the previous action, for each action. The first action has
+/// no previous one.
+fn think_times(game: &SynchronizedMatch) -> Vec<Option<f32>> {
+    let mut times = Vec::with_capacity(game.actions.len());
+    let mut previous: Option<DateTime<Utc>> = None;
+    for action in &game.actions {
+        let timestamp = action.timestamp();
+        times.push(previous.map(|p| (timestamp - p).num_milliseconds() as f32 / 1000.));
+        previous = Some(timestamp);
+    }
+    times
+}
+
+async fn store_result(
+    user_id: i64,
+    games: i64,
+    statistics: &FairPlayStatistics,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let decisions = statistics.decisions as i64;
+    // A moderator may have queued the user again in the meantime. Then the
+    // analysis runs once more and this result is dropped.
+    sqlx::query!(
+        r"update fair_play_analysis
+        set status = 'done', analyzed_at = CURRENT_TIMESTAMP, error = null,
+        games = ?, decisions = ?, top1_agreement = ?, average_rank = ?, average_value_loss = ?,
+        think_time_top1 = ?, think_time_other = ?
+        where user_id = ? and status = 'running'",
+        games,
+        decisions,
+        statistics.top1_agreement,
+        statistics.average_rank,
+        statistics.average_value_loss,
+        statistics.think_time_top1,
+        statistics.think_time_other,
+        user_id
+    )
+        .execute(&mut **conn)
+        .await?;
+    Ok(())
+}
+
+async fn store_failure(user_id: i64, error: &str, conn: &mut Connection) -> Result<(), ServerError> {
+    sqlx::query!(
+        r"update fair_play_analysis set status = 'failed', analyzed_at = CURRENT_TIMESTAMP, error = ?
+        where user_id = ? and status = 'running'",
+        error,
+        user_id
+    )
+        .execute(&mut **conn)
+        .await?;
+    Ok(())
+}
+
+// Outliers ////////////////////////////////////////////////////////////////////
+
+/// How far a user is from everyone else, in standard deviations.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+struct OutlierScore {
+    /// Positive if the user plays the model's favorite more often.
+    agreement: f64,
+    /// Positive if the user loses less value per decision.
+    precision: f64,
+}
+
+impl OutlierScore {
+    fn flagged(&self) -> bool {
+        self.agreement >= FLAG_THRESHOLD || self.precision >= FLAG_THRESHOLD
+    }
+}
+
+/// Compares each user with all other users, given as (top-1 agreement,
+/// average value loss). Users without enough data are `None`, they get no
+/// score and are not compared against.
+fn outlier_scores(population: &[Option<(f64, f64)>]) -> Vec<Option<OutlierScore>> {
+    population
+        .iter()
+        .enumerate()
+        .map(|(index, own)| {
+            let (agreement, value_loss) = (*own)?;
+            let others: Vec<(f64, f64)> = population
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != index)
+                .filter_map(|(_, other)| *other)
+                .collect();
+            if others.len() < MIN_POPULATION {
+                return None;
+            }
+            let (agreement_mean, agreement_deviation) = mean_and_deviation(others.iter().map(|o| o.0))?;
+            let (loss_mean, loss_deviation) = mean_and_deviation(others.iter().map(|o| o.1))?;
+            Some(OutlierScore {
+                agreement: (agreement - agreement_mean) / agreement_deviation,
+                precision: (loss_mean - value_loss) / loss_deviation,
+            })
+        })
+        .collect()
+}
+
+/// Returns `None` if all values are (almost) the same.
+fn mean_and_deviation(values: impl Iterator<Item = f64> + Clone) -> Option<(f64, f64)> {
+    let count = values.clone().count() as f64;
+    let mean = values.clone().sum::<f64>() / count;
+    let variance = values.map(|v| (v - mean).powi(2)).sum::<f64>() / count;
+    let deviation = variance.sqrt();
+    if deviation < 1e-6 {
+        None
+    } else {
+        Some((mean, deviation))
+    }
+}
+
+// Endpoints ///////////////////////////////////////////////////////////////////
+
+#[derive(Serialize)]
+struct AnalysisEntry {
+    user_id: i64,
+    status: String,
+    requested_by: i64,
+    requested_at: String,
+    analyzed_at: Option<String>,
+    games: i64,
+    decisions: i64,
+    top1_agreement: Option<f32>,
+    average_rank: Option<f32>,
+    average_value_loss: Option<f32>,
+    think_time_top1: Option<f32>,
+    think_time_other: Option<f32>,
+    error: Option<String>,
+    score: Option<OutlierScore>,
+    flagged: bool,
+}
+
+/// GET /api/moderation/fair_play lists all analyses, flagged users first.
+async fn list_analyses(
+    _moderator: RequirePermission<ModerateUsers>,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<AnalysisEntry>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let rows = sqlx::query!(
+        r#"select user_id, status, requested_by, requested_at as "requested_at!: String",
+        analyzed_at as "analyzed_at: String", games, decisions, top1_agreement, average_rank,
+        average_value_loss, think_time_top1, think_time_other, error
+        from fair_play_analysis
+        order by requested_at desc"#
+    )
+        .fetch_all(&mut *conn)
+        .await?;
+    let mut entries: Vec<AnalysisEntry> = rows
+        .into_iter()
+        .map(|r| AnalysisEntry {
+            user_id: r.user_id,
+            status: r.status,
+            requested_by: r.requested_by,
+            requested_at: r.requested_at,
+            analyzed_at: r.analyzed_at,
+            games: r.games,
+            decisions: r.decisions,
+            top1_agreement: r.top1_agreement,
+            average_rank: r.average_rank,
+            average_value_loss: r.average_value_loss,
+            think_time_top1: r.think_time_top1,
+            think_time_other: r.think_time_other,
+            error: r.error,
+            score: None,
+            flagged: false,
+        })
+        .collect();
+
+    // Only finished analyses with enough decisions are compared, even if a
+    // newer analysis is already queued.
+    let population: Vec<Option<(f64, f64)>> = entries
+        .iter()
+        .map(|e| match (e.top1_agreement, e.average_value_loss) {
+            (Some(agreement), Some(value_loss)) if e.decisions >= MIN_DECISIONS => {
+                Some((agreement as f64, value_loss as f64))
+            }
+            _ => None,
+        })
+        .collect();
+    for (entry, score) in entries.iter_mut().zip(outlier_scores(&population)) {
+        entry.flagged = score.is_some_and(|s| s.flagged());
+        entry.score = score;
+    }
+    // The sort is stable, so flagged users stay ordered by request time.
+    entries.sort_by_key(|e| !e.flagged);
+
+    Ok(Json(entries))
+}
+
+#[derive(Serialize)]
+struct AnalysisRequested {
+    user_id: i64,
+    status: &'static str,
+}
+
+/// POST /api/moderation/fair_play/:user_id queues the user for analysis. An
+/// existing result is replaced when the new analysis is done.
+async fn request_analysis(
+    moderator: RequirePermission<ModerateUsers>,
+    State(config): State<EnvironmentConfig>,
+    State(pool): State<Pool>,
+    Path(user_id): Path<i64>,
+) -> Result<Result<Json<AnalysisRequested>, (StatusCode, &'static str)>, ServerError> {
+    if config.fair_play_model_url.is_none() {
+        return Ok(Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The fair-play analysis is not configured.",
+        )));
+    }
+
+    let mut conn = pool.conn().await?;
+    if !moderation::user_exists(UserId(user_id), &mut conn).await? {
+        return Err(ServerError::NotFound);
+    }
+    let moderator = moderator.session.user_id;
+    sqlx::query!(
+        r"insert into fair_play_analysis (user_id, requested_by) values (?, ?)
+        on conflict (user_id) do update set
+        status = 'queued', requested_by = excluded.requested_by, requested_at = CURRENT_TIMESTAMP",
+        user_id,
+        moderator.0
+    )
+        .execute(&mut *conn)
+        .await?;
+    moderation::write_audit(
+        moderator,
+        AuditEntry {
+            action: "request_fair_play_analysis",
+            user_id: Some(user_id),
+            game_id: None,
+            report_id: None,
+            details: None,
+            reason: None,
+        },
+        &mut conn,
+    )
+        .await?;
+    WAKE_UP.notify_one();
+
+    Ok(Ok(Json(AnalysisRequested {
+        user_id,
+        status: "queued",
+    })))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A population where everyone agrees with the model 40% to 50% of the
+    /// time and loses 8 to 12 hundredths per decision.
+    fn population() -> Vec<Option<(f64, f64)>> {
+        (0..20)
+            .map(|i| Some((0.4 + (i % 5) as f64 * 0.025, 8. + (i % 3) as f64 * 2.)))
+            .collect()
+    }
+
+    #[test]
+    fn test_regular_users_are_not_flagged() {
+        let scores = outlier_scores(&population());
+        assert!(scores.iter().all(|s| s.is_some_and(|s| !s.flagged())));
+    }
+
+    #[test]
+    fn test_outliers_are_flagged() {
+        let mut population = population();
+        // Plays the favorite nearly always.
+        population.push(Some((0.95, 9.)));
+        // Loses almost no value.
+        population.push(Some((0.45, 0.5)));
+        // Not enough decisions.
+        population.push(None);
+
+        let scores = outlier_scores(&population);
+        let agreeing = scores[20].unwrap();
+        assert!(agreeing.flagged());
+        assert!(agreeing.agreement > FLAG_THRESHOLD);
+        let precise = scores[21].unwrap();
+        assert!(precise.flagged());
+        assert!(precise.precision > FLAG_THRESHOLD);
+        assert_eq!(scores[22], None);
+    }
+
+    #[test]
+    fn test_small_population_has_no_scores() {
+        let population: Vec<_> = population().into_iter().take(MIN_POPULATION).collect();
+        assert!(outlier_scores(&population).iter().all(Option::is_none));
+    }
+}
@@ -0,0 +1,397 @@
+//! Users follow other users. Two users who follow each other are friends.
+//!
+//! Following a player shows if they are online or playing right now, and puts
+//! their finished games into the feed. Which game a player is in is only
+//! shared with players who turned on `share_games_with_followers` in their
+//! profile. Their followers see the game in the presence and, when connected,
+//! get a websocket message when the player starts a game. Presence comes from
+//! the websockets, see `ws::presence`.
+
+use std::collections::{HashMap, HashSet};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{self, Connection, Pool},
+    login::{
+        session::SessionData,
+        user::{load_public_user_data, PublicUserData},
+        UserId,
+    },
+    moderation,
+    sync_match::CompressedMatchStateClient,
+    ws::{self, presence, ServerMessage},
+    AppState, ServerError,
+};
+
+/// Upper limit for the number of users someone follows.
+const MAX_FOLLOWING: i64 = 500;
+
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router
+        .route("/me/following", get(list_following))
+        .route("/me/following/:user_id", put(follow).delete(unfollow))
+        .route("/me/followers", get(list_followers))
+        .route("/me/feed", get(feed))
+}
+
+// Follow //////////////////////////////////////////////////////////////////////
+
+/// PUT /api/me/following/:user_id
+async fn follow(
+    session: SessionData,
+    State(pool): State<Pool>,
+    Path(user_id): Path<i64>,
+) -> Result<Result<(), (StatusCode, &'static str)>, ServerError> {
+    let followed = UserId(user_id);
+    if followed == session.user_id {
+        return Ok(Err((StatusCode::BAD_REQUEST, "You can't follow yourself.")));
+    }
+    let mut conn = pool.conn().await?;
+    if !moderation::user_exists(followed, &mut conn).await? {
+        return Err(ServerError::NotFound);
+    }
+    let following = sqlx::query!(
+        "select count(*) as count from follow where follower_id = ?",
+        session.user_id.0
+    )
+        .fetch_one(&mut *conn)
+        .await?;
+    if following.count >= MAX_FOLLOWING as i32 {
+        return Ok(Err((StatusCode::BAD_REQUEST, "You follow too many players.")));
+    }
+
+    sqlx::query!(
+        "insert or ignore into follow (follower_id, followed_id) values (?, ?)",
+        session.user_id.0,
+        followed.0
+    )
+        .execute(&mut *conn)
+        .await?;
+    Ok(Ok(()))
+}
+
+/// DELETE /api/me/following/:user_id
+async fn unfollow(
+    session: SessionData,
+    State(pool): State<Pool>,
+    Path(user_id): Path<i64>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    sqlx::query!(
+        "delete from follow where follower_id = ? and followed_id = ?",
+        session.user_id.0,
+        user_id
+    )
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+// Lists ///////////////////////////////////////////////////////////////////////
+
+/// Derived from the websockets of the user.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Presence {
+    Offline,
+    Online,
+    /// Connected to a running game the user plays in. The game is only given
+    /// if the user shares their games with followers.
+    Playing {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        game_key: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct FollowedUser {
+    user: PublicUserData,
+    /// The user follows back.
+    friend: bool,
+    presence: Presence,
+    since: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Follower {
+    user: PublicUserData,
+    /// The logged-in user follows back.
+    friend: bool,
+    since: Option<String>,
+}
+
+/// GET /api/me/following lists followed users, online users first.
+async fn list_following(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<FollowedUser>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let rows = sqlx::query!(
+        r#"select f.followed_id, f.created_at as "since?: String",
+            exists(select 1 from follow b where b.follower_id = f.followed_id and b.followed_id = f.follower_id) as "friend!: bool"
+        from follow f where f.follower_id = ?
+        order by f.created_at desc"#,
+        session.user_id.0
+    )
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let user_ids: Vec<UserId> = rows.iter().map(|r| UserId(r.followed_id)).collect();
+    let mut presences = presences_of(&user_ids, &mut conn).await?;
+
+    let mut result = Vec::with_capacity(rows.len());
+    for row in rows {
+        let user_id = UserId(row.followed_id);
+        result.push(FollowedUser {
+            user: load_public_user_data(user_id, &mut conn).await?,
+            friend: row.friend,
+            presence: presences.remove(&user_id).unwrap_or(Presence::Offline),
+            since: row.since,
+        });
+    }
+    // Stable, so the newest follows stay first within each group.
+    result.sort_by_key(|f| presence_order(&f.presence));
+    Ok(Json(result))
+}
+
+fn presence_order(presence: &Presence) -> u8 {
+    match presence {
+        Presence::Playing { .. } => 0,
+        Presence::Online => 1,
+        Presence::Offline => 2,
+    }
+}
+
+/// GET /api/me/followers
+async fn list_followers(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<Follower>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let rows = sqlx::query!(
+        r#"select f.follower_id, f.created_at as "since?: String",
+            exists(select 1 from follow b where b.follower_id = f.followed_id and b.followed_id = f.follower_id) as "friend!: bool"
+        from follow f where f.followed_id = ?
+        order by f.created_at desc"#,
+        session.user_id.0
+    )
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let mut result = Vec::with_capacity(rows.len());
+    for row in rows {
+        result.push(Follower {
+            user: load_public_user_data(UserId(row.follower_id), &mut conn).await?,
+            friend: row.friend,
+            since: row.since,
+        });
+    }
+    Ok(Json(result))
+}
+
+/// Users without a websocket are missing in the result.
+async fn presences_of(
+    user_ids: &[UserId],
+    conn: &mut Connection,
+) -> Result<HashMap<UserId, Presence>, ServerError> {
+    let sharing = sharing_games(user_ids, conn).await?;
+    let mut result = HashMap::new();
+    for (user_id, game_keys) in presence::games_of(user_ids) {
+        let mut presence = Presence::Online;
+        for key in game_keys {
+            let Ok(id) = key.parse() else {
+                continue;
+            };
+            if db::game::is_running_for_player(id, user_id.0, &mut *conn).await? {
+                let game_key = sharing.contains(&user_id).then_some(key);
+                presence = Presence::Playing { game_key };
+                break;
+            }
+        }
+        result.insert(user_id, presence);
+    }
+    Ok(result)
+}
+
+/// The users who share their games with followers.
+async fn sharing_games(user_ids: &[UserId], conn: &mut Connection) -> Result<HashSet<UserId>, ServerError> {
+    let ids = serde_json::to_string(&user_ids.iter().map(|u| u.0).collect::<Vec<_>>())?;
+    let rows = sqlx::query!(
+        r#"select id as "id!" from user
+        where share_games_with_followers and id in (select value from json_each(?))"#,
+        ids
+    )
+        .fetch_all(&mut **conn)
+        .await?;
+    Ok(rows.into_iter().map(|r| UserId(r.id)).collect())
+}
+
+// Feed ////////////////////////////////////////////////////////////////////////
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    #[serde(default)]
+    offset: u32,
+    /// Between 1 and 100.
+    #[serde(default = "default_feed_limit")]
+    limit: u32,
+}
+
+fn default_feed_limit() -> u32 {
+    20
+}
+
+#[derive(Serialize)]
+struct FeedEntry {
+    finished_at: Option<String>,
+    game: CompressedMatchStateClient,
+}
+
+/// GET /api/me/feed?offset=0&limit=20 lists recently finished games of
+/// followed players, newest first.
+async fn feed(
+    session: SessionData,
+    State(pool): State<Pool>,
+    Query(query): Query<FeedQuery>,
+) -> Result<Json<Vec<FeedEntry>>, ServerError> {
+    if query.limit < 1 || query.limit > 100 {
+        return Err(ServerError::BadRequest);
+    }
+    let mut conn = pool.conn().await?;
+    let records = db::game::finished_by_followed(
+        session.user_id.0,
+        query.offset as i64,
+        query.limit as i64,
+        &mut conn,
+    )
+        .await?;
+
+    let (finished_at, games): (Vec<_>, Vec<_>) =
+        records.into_iter().map(|r| (r.finished_at, r.game)).unzip();
+    let games = CompressedMatchStateClient::try_new_many(&games, &mut conn).await?;
+    let result = finished_at
+        .into_iter()
+        .zip(games)
+        .map(|(finished_at, game)| FeedEntry { finished_at, game })
+        .collect();
+    Ok(Json(result))
+}
+
+// Push ////////////////////////////////////////////////////////////////////////
+
+/// A followed player who started a game.
+#[derive(Serialize, Clone, Debug)]
+pub struct FollowedPlayer {
+    pub user_id: UserId,
+    /// The display name, if the player has one.
+    pub name: String,
+}
+
+/// Tells connected followers of the players that the game started, for players
+/// who share their games with followers. A follower of both players gets a
+/// single message. Failures are only logged, the game goes on either way.
+pub async fn announce_game_start(game_key: &str, players: &[UserId], conn: &mut Connection) {
+    if let Err(e) = try_announce_game_start(game_key, players, conn).await {
+        warn!("Could not tell followers about game {}: {:?}", game_key, e);
+    }
+}
+
+async fn try_announce_game_start(
+    game_key: &str,
+    players: &[UserId],
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let sharing = sharing_games(players, conn).await?;
+    let mut follows = vec![];
+    for &player in players.iter().filter(|p| sharing.contains(p)) {
+        let followers = sqlx::query!("select follower_id from follow where followed_id = ?", player.0)
+            .fetch_all(&mut **conn)
+            .await?;
+        follows.extend(followers.into_iter().map(|r| (UserId(r.follower_id), player)));
+    }
+
+    for (follower, followed) in group_by_follower(follows, players) {
+        let sockets = presence::sockets_of(follower);
+        if sockets.is_empty() {
+            continue;
+        }
+        let mut followed_players = Vec::with_capacity(followed.len());
+        for user_id in followed {
+            let user = load_public_user_data(user_id, &mut *conn).await?;
+            followed_players.push(FollowedPlayer {
+                user_id,
+                name: user.display_name.unwrap_or(user.name),
+            });
+        }
+        let message = ServerMessage::FollowedPlayerStartedGame {
+            game_key: game_key.to_string(),
+            players: followed_players,
+        };
+        for socket in sockets {
+            ws::send_msg(message.clone(), &socket).await;
+        }
+    }
+    Ok(())
+}
+
+/// Groups (follower, followed) pairs by follower. Players of the game don't
+/// need to hear about their own game.
+fn group_by_follower(
+    follows: Vec<(UserId, UserId)>,
+    players: &[UserId],
+) -> HashMap<UserId, Vec<UserId>> {
+    let mut result: HashMap<UserId, Vec<UserId>> = HashMap::new();
+    for (follower, followed) in follows {
+        if players.contains(&follower) {
+            continue;
+        }
+        let entry = result.entry(follower).or_default();
+        if !entry.contains(&followed) {
+            entry.push(followed);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::login::user::create_user;
+
+    #[test]
+    fn followers_of_both_players_get_one_entry() {
+        let (alice, bob, carol, dave) = (UserId(1), UserId(2), UserId(3), UserId(4));
+        let grouped = group_by_follower(
+            vec![(carol, alice), (carol, bob), (dave, bob), (bob, alice)],
+            &[alice, bob],
+        );
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[&carol], vec![alice, bob]);
+        assert_eq!(grouped[&dave], vec![bob]);
+    }
+
+    #[tokio::test]
+    async fn games_are_only_shared_after_opting_in() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let alice = create_user("alice", "identicon:1", &mut conn).await.unwrap();
+        let bob = create_user("bob", "identicon:2", &mut conn).await.unwrap();
+        sqlx::query!("update user set share_games_with_followers = 1 where id = ?", bob.0)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+
+        let sharing = sharing_games(&[alice, bob], &mut conn).await.unwrap();
+        assert_eq!(sharing, HashSet::from([bob]));
+
+        let hidden = serde_json::to_value(Presence::Playing { game_key: None }).unwrap();
+        assert_eq!(hidden, serde_json::json!({"status": "playing"}));
+    }
+}
@@ -10,6 +10,7 @@ use serde::{Deserialize, Serialize};
 use pacosako::PlayerColor;
 
 use crate::protection::backdated_user_assignment::backdate_user_assignment;
+use crate::protection::claim::{claim_all_games, claim_game};
 use crate::{
     actors::websocket::UuidQuery,
     db::{self, Pool},
@@ -25,6 +26,7 @@ use crate::{
 };
 
 mod frontend_ai;
+pub mod search;
 
 /// Adds the game management API to the given router.
 /// This is expected to be nested at "/api".
@@ -32,12 +34,16 @@ pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
     api_router
         .route("/create_game", post(create_game))
         .route("/game/:key", get(get_game))
+        .route("/game/:key/takebacks", get(get_takebacks))
         .route("/ai/game/:key", post(post_action_to_game))
         .route("/ai/game/:key/metadata/:color", post(post_ai_metadata))
         .route("/game/recent", get(recently_created_games))
+        .route("/game/search", get(search::search_games))
         .route("/branch_game", post(branch_game))
         .route("/me/games", get(my_games))
         .route("/game/backdate", post(backdate_user_assignment))
+        .route("/game/:key/claim", post(claim_game))
+        .route("/me/claim_games", post(claim_all_games))
 }
 
 /// Create a match on the database and return the id.
@@ -122,6 +128,16 @@ async fn get_game(
     }
 }
 
+/// Lists all takebacks of the game, so the replay can show the removed actions.
+async fn get_takebacks(
+    Path(key): Path<String>,
+    pool: State<Pool>,
+) -> Result<Json<Vec<db::game::TakebackRecord>>, ServerError> {
+    let key: i64 = key.parse()?;
+    let mut conn = pool.conn().await?;
+    Ok(Json(db::game::takebacks(key, &mut conn).await?))
+}
+
 async fn post_action_to_game(
     session: Option<SessionData>,
     Path(key): Path<String>,
@@ -138,12 +154,17 @@ async fn post_action_to_game(
 }
 
 async fn post_ai_metadata(
-    session: SessionData, // TODO: Verify that only AIs can call this.
+    session: SessionData,
     Path((key, player_color)): Path<(String, PlayerColor)>,
     pool: State<Pool>,
     Json(metadata): Json<AiMetaData>,
 ) -> Result<(), ServerError> {
     let mut conn = pool.conn().await?;
+    if !user::is_ai_account(session.user_id, &mut conn).await? {
+        return Err(ServerError::NotAllowed(
+            "Only AI accounts can set AI metadata.".to_string(),
+        ));
+    }
     user::write_one_ai_config_for_game(&key, player_color, &metadata, &mut conn).await?;
 
     // If this side of the game does not have player protection yet, we set it
@@ -182,6 +203,9 @@ struct PagingQuery {
     offset: u32,
     /// Limit must be between 1 and 100.
     limit: u32,
+    /// Only list unfinished games where it is the user's turn.
+    #[serde(default)]
+    your_turn: bool,
 }
 
 #[derive(Serialize)]
@@ -201,15 +225,22 @@ async fn my_games(
         return Err(ServerError::BadRequest);
     }
 
-    let games: Vec<SynchronizedMatch> = db::game::for_player(
-        session.user_id.0,
-        params.offset as i64,
-        params.limit as i64,
-        &mut conn,
-    )
-        .await?;
+    let user_id = session.user_id.0;
+    let offset = params.offset as i64;
+    let limit = params.limit as i64;
 
-    let total_games = db::game::count_for_player(session.user_id.0, &mut conn).await? as usize;
+    let (games, total_games) = if params.your_turn {
+        (
+            db::game::for_player_to_move(user_id, offset, limit, &mut conn).await?,
+            db::game::count_for_player_to_move(user_id, &mut conn).await?,
+        )
+    } else {
+        (
+            db::game::for_player(user_id, offset, limit, &mut conn).await?,
+            db::game::count_for_player(user_id, &mut conn).await?,
+        )
+    };
+    let total_games = total_games as usize;
 
     let mut result = Vec::with_capacity(games.len());
 
@@ -0,0 +1,376 @@
+//! Game search. Translates the query parameters into a `db::game::GameSearch`
+//! and pages through the result with a cursor.
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use pacosako::{PlayerColor, VictoryState};
+
+use crate::db::{self, game::GameSearch, Pool};
+use crate::login::UserId;
+use crate::sync_match::CompressedMatchStateClient;
+use crate::ServerError;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+/// How many old games are classified at once when the server starts.
+const CLASSIFY_BATCH_SIZE: i64 = 200;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ResultFilter {
+    WhiteWins,
+    BlackWins,
+    Draw,
+    Running,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TerminationFilter {
+    Paco,
+    Timeout,
+    NoProgress,
+    Repetition,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum SetupFilter {
+    Default,
+    FischerRandom,
+}
+
+#[derive(Deserialize)]
+pub(super) struct SearchQuery {
+    player: Option<i64>,
+    /// The color of `player`.
+    color: Option<PlayerColor>,
+    opponent: Option<i64>,
+    result: Option<ResultFilter>,
+    termination: Option<TerminationFilter>,
+    /// Bounds for the expected duration of the game in seconds.
+    min_time_limit: Option<f64>,
+    max_time_limit: Option<f64>,
+    /// False for games without a clock.
+    timed: Option<bool>,
+    setup: Option<SetupFilter>,
+    opening: Option<String>,
+    /// First and last day the game was created on, both inclusive.
+    from: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    min_actions: Option<i64>,
+    /// The `next_cursor` of the previous page.
+    cursor: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub(super) struct SearchResult {
+    games: Vec<CompressedMatchStateClient>,
+    /// Pass this as `cursor` to get the next page. None on the last page.
+    next_cursor: Option<i64>,
+}
+
+/// All victory states of finished games.
+const FINISHED: [VictoryState; 6] = [
+    VictoryState::PacoVictory(PlayerColor::White),
+    VictoryState::PacoVictory(PlayerColor::Black),
+    VictoryState::TimeoutVictory(PlayerColor::White),
+    VictoryState::TimeoutVictory(PlayerColor::Black),
+    VictoryState::NoProgressDraw,
+    VictoryState::RepetitionDraw,
+];
+
+fn matches_result(victory_state: VictoryState, result: ResultFilter) -> bool {
+    match (victory_state, result) {
+        (VictoryState::PacoVictory(c) | VictoryState::TimeoutVictory(c), ResultFilter::WhiteWins) => {
+            c == PlayerColor::White
+        }
+        (VictoryState::PacoVictory(c) | VictoryState::TimeoutVictory(c), ResultFilter::BlackWins) => {
+            c == PlayerColor::Black
+        }
+        (VictoryState::NoProgressDraw | VictoryState::RepetitionDraw, ResultFilter::Draw) => true,
+        _ => false,
+    }
+}
+
+fn matches_termination(victory_state: VictoryState, termination: TerminationFilter) -> bool {
+    matches!(
+        (victory_state, termination),
+        (VictoryState::PacoVictory(_), TerminationFilter::Paco)
+            | (VictoryState::TimeoutVictory(_), TerminationFilter::Timeout)
+            | (VictoryState::NoProgressDraw, TerminationFilter::NoProgress)
+            | (VictoryState::RepetitionDraw, TerminationFilter::Repetition)
+    )
+}
+
+fn start_of_day(date: NaiveDate) -> String {
+    format!("{} 00:00:00", date.format("%Y-%m-%d"))
+}
+
+impl SearchQuery {
+    fn into_filter(self) -> Result<GameSearch, ServerError> {
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(ServerError::BadRequest);
+        }
+        if self.player.is_none() && (self.color.is_some() || self.opponent.is_some()) {
+            return Err(ServerError::BadRequest);
+        }
+        if self.opponent.is_some() && self.opponent == self.player {
+            return Err(ServerError::BadRequest);
+        }
+
+        let mut filter = GameSearch {
+            player: self.player.map(UserId),
+            player_color: self.color,
+            opponent: self.opponent.map(UserId),
+            min_time_limit: self.min_time_limit,
+            max_time_limit: self.max_time_limit,
+            timed: self.timed,
+            custom_setup: self.setup.map(|setup| setup == SetupFilter::FischerRandom),
+            opening: self.opening.filter(|opening| !opening.is_empty()),
+            created_from: self.from.map(start_of_day),
+            created_until: match self.until {
+                Some(until) => Some(start_of_day(until.succ_opt().ok_or(ServerError::BadRequest)?)),
+                None => None,
+            },
+            min_actions: self.min_actions,
+            before_id: self.cursor,
+            limit,
+            ..GameSearch::default()
+        };
+
+        if self.result == Some(ResultFilter::Running) {
+            if self.termination.is_some() {
+                return Err(ServerError::BadRequest);
+            }
+            filter.finished = Some(false);
+        } else if self.result.is_some() || self.termination.is_some() {
+            filter.victory_states = FINISHED
+                .into_iter()
+                .filter(|&v| self.result.is_none_or(|r| matches_result(v, r)))
+                .filter(|&v| self.termination.is_none_or(|t| matches_termination(v, t)))
+                .collect();
+            if filter.victory_states.is_empty() {
+                // Like a draw won by white, nothing can match.
+                return Err(ServerError::BadRequest);
+            }
+        }
+        Ok(filter)
+    }
+}
+
+pub(super) async fn search_games(
+    State(pool): State<Pool>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResult>, ServerError> {
+    let filter = query.into_filter()?;
+    let mut conn = pool.conn().await?;
+    let games = db::game::search(&filter, &mut conn).await?;
+
+    let next_cursor = if games.len() as i64 == filter.limit {
+        games.last().map(|game| game.key.parse()).transpose()?
+    } else {
+        None
+    };
+    let games = CompressedMatchStateClient::try_new_many(&games, &mut conn).await?;
+
+    Ok(Json(SearchResult {
+        games,
+        next_cursor,
+    }))
+}
+
+/// Classifies the openings and outcomes of games stored before the search
+/// existed. Runs in the background once after the server starts.
+pub async fn classify_old_games(pool: Pool) {
+    let mut classified = 0;
+    loop {
+        let result = async {
+            let mut conn = pool.conn().await?;
+            let games = db::game::unclassified(CLASSIFY_BATCH_SIZE, &mut conn).await?;
+            for game in &games {
+                db::game::store_opening(game, &mut conn).await?;
+            }
+            Ok::<usize, ServerError>(games.len())
+        }
+            .await;
+        match result {
+            Ok(0) => break,
+            Ok(count) => classified += count,
+            Err(e) => {
+                warn!("Could not classify the openings of old games: {:?}", e);
+                return;
+            }
+        }
+    }
+    if classified > 0 {
+        info!("Classified the openings of {} old game(s).", classified);
+    }
+
+    match store_old_outcomes(&pool).await {
+        Ok(0) => {}
+        Ok(count) => {
+            info!("Stored the outcome of {} old game(s).", count);
+            // Finished old games can be analyzed now.
+            crate::replay_analysis::game_finished();
+        }
+        Err(e) => warn!("Could not store the outcomes of old games: {:?}", e),
+    }
+}
+
+/// Replays games which were not updated since the outcome is persisted and
+/// stores their victory state. Returns how many games were looked at.
+async fn store_old_outcomes(pool: &Pool) -> Result<usize, ServerError> {
+    let mut after_id = 0;
+    let mut count = 0;
+    loop {
+        let mut conn = pool.conn().await?;
+        let games = db::game::without_outcome(after_id, CLASSIFY_BATCH_SIZE, &mut conn).await?;
+        let Some(last) = games.last() else {
+            return Ok(count);
+        };
+        after_id = last.key.parse()?;
+        for game in &games {
+            match game.current_state() {
+                Ok(state) => db::game::store_outcome(game, &state, &mut conn).await?,
+                Err(e) => warn!("Could not replay game {}: {:?}", game.key, e),
+            }
+        }
+        count += games.len();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn query(result: Option<ResultFilter>, termination: Option<TerminationFilter>) -> SearchQuery {
+        SearchQuery {
+            player: None,
+            color: None,
+            opponent: None,
+            result,
+            termination,
+            min_time_limit: None,
+            max_time_limit: None,
+            timed: None,
+            setup: None,
+            opening: None,
+            from: None,
+            until: None,
+            min_actions: None,
+            cursor: None,
+            limit: None,
+        }
+    }
+
+    #[test]
+    fn test_result_filters() {
+        let filter = query(Some(ResultFilter::WhiteWins), None).into_filter().unwrap();
+        assert_eq!(
+            filter.victory_states,
+            vec![
+                VictoryState::PacoVictory(PlayerColor::White),
+                VictoryState::TimeoutVictory(PlayerColor::White)
+            ]
+        );
+
+        let filter = query(Some(ResultFilter::BlackWins), Some(TerminationFilter::Timeout))
+            .into_filter()
+            .unwrap();
+        assert_eq!(
+            filter.victory_states,
+            vec![VictoryState::TimeoutVictory(PlayerColor::Black)]
+        );
+
+        let filter = query(Some(ResultFilter::Running), None).into_filter().unwrap();
+        assert!(filter.victory_states.is_empty());
+        assert_eq!(filter.finished, Some(false));
+
+        assert!(query(Some(ResultFilter::Draw), Some(TerminationFilter::Paco))
+            .into_filter()
+            .is_err());
+    }
+
+    #[test]
+    fn test_date_range_is_inclusive() {
+        let mut query = query(None, None);
+        query.from = NaiveDate::from_ymd_opt(2024, 1, 31);
+        query.until = NaiveDate::from_ymd_opt(2024, 1, 31);
+        let filter = query.into_filter().unwrap();
+        assert_eq!(filter.created_from.as_deref(), Some("2024-01-31 00:00:00"));
+        assert_eq!(filter.created_until.as_deref(), Some("2024-02-01 00:00:00"));
+    }
+
+    /// A game stored before the outcome was persisted.
+    async fn insert_old_game(actions: &[pacosako::PacoAction], pool: &Pool) -> String {
+        let mut game = crate::sync_match::SynchronizedMatch {
+            key: String::new(),
+            actions: vec![],
+            timer: None,
+            setup_options: pacosako::setup_options::SetupOptions {
+                safe_mode: false,
+                draw_after_n_repetitions: 3,
+                starting_fen: Some("4k3/8/8/8/8/8/8/4R1K1 w 0 - - -".to_string()),
+            },
+            white_player: None,
+            black_player: None,
+        };
+        for &action in actions {
+            game.do_action(&[action]).unwrap();
+        }
+        let mut conn = pool.conn().await.unwrap();
+        db::game::insert(&mut game, &mut conn).await.unwrap();
+        let id: i64 = game.key.parse().unwrap();
+        sqlx::query!("update game set victory_state = null where id = ?", id)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        game.key
+    }
+
+    #[tokio::test]
+    async fn test_old_outcomes_are_stored() {
+        use pacosako::const_tile::*;
+        use pacosako::PacoAction::*;
+
+        let pool = db::test_pool().await;
+        let finished = insert_old_game(&[Lift(E1), Place(E8)], &pool).await;
+        let running = insert_old_game(&[Lift(E1), Place(E7)], &pool).await;
+
+        assert_eq!(store_old_outcomes(&pool).await.unwrap(), 2);
+        // Nothing is left for a second pass.
+        assert_eq!(store_old_outcomes(&pool).await.unwrap(), 0);
+
+        let mut conn = pool.conn().await.unwrap();
+        let games = sqlx::query!(
+            r#"select id, victory_state, finished_at as "finished_at?: String" from game order by id"#
+        )
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(format!("{}", games[0].id), finished);
+        assert_eq!(games[0].victory_state.as_deref(), Some(r#"{"PacoVictory":"White"}"#));
+        assert!(games[0].finished_at.is_some());
+        assert_eq!(format!("{}", games[1].id), running);
+        assert_eq!(games[1].victory_state.as_deref(), Some(r#""Running""#));
+        assert_eq!(games[1].finished_at, None);
+
+        let search = GameSearch {
+            victory_states: vec![VictoryState::PacoVictory(PlayerColor::White)],
+            limit: 10,
+            ..Default::default()
+        };
+        let found = db::game::search(&search, &mut conn).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].key, finished);
+    }
+}
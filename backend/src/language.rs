@@ -1,11 +1,17 @@
 use axum::{
+    extract::State,
     http::{header::ACCEPT_LANGUAGE, HeaderMap},
-    response::IntoResponse,
 };
 use lazy_static::lazy_static;
 use regex::Regex;
 use tower_cookies::{Cookie, Cookies};
 
+use crate::{
+    db::Pool,
+    login::{profile, session::SessionData},
+    ServerError,
+};
+
 /// Request guard that combines the accept-language header & the language cookie.
 pub struct UserLanguage(pub String);
 
@@ -14,9 +20,17 @@ pub struct UserLanguage(pub String);
 
 const LANGUAGE_COOKIE_NAME: &str = "language";
 
-/// This first checks if there is a language defined in the cookies. If this is
-/// not the case, it tries to guess a language from the accept-language header.
-pub fn user_language(headers: &HeaderMap, cookies: &mut Cookies) -> UserLanguage {
+/// The language a logged-in user stored in their profile comes first. Then
+/// we check if there is a language defined in the cookies. If this is not the
+/// case, it tries to guess a language from the accept-language header.
+pub fn user_language(
+    stored: Option<String>,
+    headers: &HeaderMap,
+    cookies: &mut Cookies,
+) -> UserLanguage {
+    if let Some(language) = stored.filter(|l| is_language_supported(l)) {
+        return UserLanguage(language);
+    }
     if let Some(cookie) = cookies.get(LANGUAGE_COOKIE_NAME) {
         let language = cookie.value().to_string();
         if is_language_supported(&language) {
@@ -28,15 +42,26 @@ pub fn user_language(headers: &HeaderMap, cookies: &mut Cookies) -> UserLanguage
     UserLanguage(language)
 }
 
-/// Sets the user's language cookie if the language is supported.
-pub async fn set_user_language(cookies: Cookies, language: String) -> impl IntoResponse {
+/// Sets the user's language cookie if the language is supported. For a
+/// logged-in user, the language is also stored in their profile.
+pub async fn set_user_language(
+    session: Option<SessionData>,
+    State(pool): State<Pool>,
+    cookies: Cookies,
+    language: String,
+) -> Result<(), ServerError> {
     if is_language_supported(&language) {
+        if let Some(session) = session {
+            let mut conn = pool.conn().await?;
+            profile::set_language(session.user_id, &language, &mut conn).await?;
+        }
         cookies.add(
             Cookie::build((LANGUAGE_COOKIE_NAME, language))
                 .path("/")
                 .build(),
         );
     }
+    Ok(())
 }
 
 /// Parses the "Accept-Language" header and returns the first supported language.
@@ -69,7 +94,7 @@ fn parse_languages(headers: &HeaderMap) -> Option<String> {
 
 /// Checks if a language is supported by PacoPlay by checking if there is a
 /// compiled version of the language.
-fn is_language_supported(lang: &str) -> bool {
+pub fn is_language_supported(lang: &str) -> bool {
     get_static_language_file(lang).is_some()
 }
 
@@ -0,0 +1,258 @@
+//! API tokens allow programs like bots to use the API without a session cookie.
+//!
+//! Tokens are sent as `Authorization: Bearer <token>`. We only store a hash of
+//! the token. The token itself is shown to the user once, when it is created.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, State},
+    http::{request::Parts, StatusCode},
+    Json,
+};
+use axum_auth::AuthBearer;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::db::{Connection, Pool};
+use crate::{AppState, ServerError};
+
+use super::{session::SessionData, UserId};
+
+/// Makes tokens recognizable, e.g. for secret scanners.
+const TOKEN_PREFIX: &str = "pst_";
+
+/// A user may not have more tokens than this at the same time.
+const MAX_TOKENS_PER_USER: i64 = 20;
+
+/// A request authenticated with an API token.
+pub struct ApiAuth {
+    pub user_id: UserId,
+    pub is_bot: bool,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ApiAuth {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        const UNAUTHORIZED: (StatusCode, &str) = (StatusCode::UNAUTHORIZED, "Invalid API token.");
+
+        let AuthBearer(token) = AuthBearer::from_request_parts(parts, state)
+            .await
+            .map_err(|_| UNAUTHORIZED)?;
+        let Ok(mut conn) = state.pool.conn().await else {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "No database connection."));
+        };
+        match authenticate(&token, &mut conn).await {
+            Ok(Some(auth)) => Ok(auth),
+            Ok(None) => Err(UNAUTHORIZED),
+            Err(e) => {
+                warn!("Error checking API token: {:?}", e);
+                Err((StatusCode::INTERNAL_SERVER_ERROR, "Could not check API token."))
+            }
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+/// Looks up the user for a token and remembers that the token was used.
+async fn authenticate(token: &str, conn: &mut Connection) -> Result<Option<ApiAuth>, sqlx::Error> {
+    let token_hash = hash_token(token);
+    let res = sqlx::query!(
+        r"select api_token.id, user_id, is_bot from api_token
+        join user on user.id = api_token.user_id
+        where token_hash = ?
+        and not exists (select 1 from user_sanction
+            where user_sanction.user_id = api_token.user_id and kind = 'ban' and lifted_at is null
+            and (expires_at is null or expires_at > CURRENT_TIMESTAMP))",
+        token_hash
+    )
+        .fetch_optional(&mut *conn)
+        .await?;
+    let Some(res) = res else {
+        return Ok(None);
+    };
+
+    sqlx::query!(
+        "update api_token set last_used_at = CURRENT_TIMESTAMP where id = ?",
+        res.id
+    )
+        .execute(conn)
+        .await?;
+
+    Ok(Some(ApiAuth {
+        user_id: UserId(res.user_id),
+        is_bot: res.is_bot != 0,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    id: i64,
+    /// The only time the token is visible, we don't store it.
+    token: String,
+}
+
+#[derive(Serialize)]
+pub struct ApiTokenInfo {
+    id: i64,
+    name: String,
+    created_at: Option<String>,
+    last_used_at: Option<String>,
+}
+
+/// POST /api/me/api_tokens creates a new token for the logged-in user.
+pub async fn create_token(
+    session: SessionData,
+    State(pool): State<Pool>,
+    Json(request): Json<CreateTokenRequest>,
+) -> Result<Json<CreatedToken>, ServerError> {
+    let name = request.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Err(ServerError::BadRequest);
+    }
+
+    let mut conn = pool.conn().await?;
+    let user_id = session.user_id.0;
+    let existing = sqlx::query!(
+        "select count(*) as count from api_token where user_id = ?",
+        user_id
+    )
+        .fetch_one(&mut *conn)
+        .await?;
+    if i64::from(existing.count) >= MAX_TOKENS_PER_USER {
+        return Err(ServerError::NotAllowed(
+            "You have too many API tokens.".to_string(),
+        ));
+    }
+
+    let token = format!(
+        "{TOKEN_PREFIX}{}",
+        base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+    );
+    let token_hash = hash_token(&token);
+    let id = sqlx::query!(
+        "insert into api_token (user_id, name, token_hash) values (?, ?, ?)",
+        user_id,
+        name,
+        token_hash
+    )
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+
+    info!("User {} created API token {}.", user_id, id);
+    Ok(Json(CreatedToken { id, token }))
+}
+
+/// GET /api/me/api_tokens lists the tokens of the logged-in user.
+pub async fn list_tokens(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<ApiTokenInfo>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(tokens_of(session.user_id, &mut conn).await?))
+}
+
+/// The API tokens of the user, without the tokens themselves.
+pub async fn tokens_of(user_id: UserId, conn: &mut Connection) -> Result<Vec<ApiTokenInfo>, ServerError> {
+    let rows = sqlx::query!(
+        r#"select id as "id!", name, created_at as "created_at: String", last_used_at as "last_used_at: String"
+        from api_token where user_id = ? order by id"#,
+        user_id.0
+    )
+        .fetch_all(&mut **conn)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| ApiTokenInfo {
+            id: r.id,
+            name: r.name,
+            created_at: r.created_at,
+            last_used_at: r.last_used_at,
+        })
+        .collect())
+}
+
+/// DELETE /api/me/api_tokens/:id revokes a token of the logged-in user.
+pub async fn revoke_token(
+    session: SessionData,
+    State(pool): State<Pool>,
+    Path(id): Path<i64>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let result = sqlx::query!(
+        "delete from api_token where id = ? and user_id = ?",
+        id,
+        session.user_id.0
+    )
+        .execute(&mut *conn)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotFound);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db;
+    use crate::login::user::create_user;
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let user_id = create_user("bot", "identicon:1", &mut conn).await.unwrap();
+        let token = "pst_secret";
+        let token_hash = hash_token(token);
+        sqlx::query!(
+            "insert into api_token (user_id, name, token_hash) values (?, 'engine', ?)",
+            user_id.0,
+            token_hash
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        assert!(tokens_of(user_id, &mut conn).await.unwrap()[0].last_used_at.is_none());
+
+        let auth = authenticate(token, &mut conn).await.unwrap().unwrap();
+        assert_eq!(auth.user_id, user_id);
+        assert!(!auth.is_bot);
+        assert!(tokens_of(user_id, &mut conn).await.unwrap()[0].last_used_at.is_some());
+
+        assert!(authenticate("pst_guessed", &mut conn).await.unwrap().is_none());
+        // The hash itself is no token.
+        assert!(authenticate(&token_hash, &mut conn).await.unwrap().is_none());
+
+        // Tokens of banned users stop working until the ban is lifted.
+        let ban = sqlx::query!(
+            "insert into user_sanction (user_id, kind, reason, created_by) values (?, 'ban', 'spam', ?)",
+            user_id.0,
+            user_id.0
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+        assert!(authenticate(token, &mut conn).await.unwrap().is_none());
+        sqlx::query!("update user_sanction set lifted_at = CURRENT_TIMESTAMP where id = ?", ban)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        assert!(authenticate(token, &mut conn).await.unwrap().is_some());
+    }
+}
@@ -17,18 +17,22 @@ use tower_cookies::{Cookie, cookie::SameSite, Cookies};
 use crate::{
     config::EnvironmentConfig,
     db::{Connection, Pool},
-    ServerError,
+    moderation, ServerError,
 };
 
-use self::session::SessionData;
+use self::session::{ClientInfo, SessionData};
 
 mod crypto;
 pub mod discord;
+pub mod oauth;
 pub mod session;
 pub mod user;
 pub mod permission;
+pub mod profile;
+pub mod api_token;
+pub mod registration;
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
 pub struct UserId(pub i64);
 
 #[derive(Debug, Clone)]
@@ -55,10 +59,11 @@ pub async fn username_password_route(
     Query(query): Query<DeleteUserQuery>,
     pool: State<Pool>,
     config: State<EnvironmentConfig>,
+    client: ClientInfo,
     cookies: Cookies,
     dto: Json<UsernamePasswordDTO>,
 ) -> Response {
-    match username_password(query.delete_user, pool, config, cookies, dto).await {
+    match username_password(query.delete_user, pool, config, client, cookies, dto).await {
         Ok(_) => (StatusCode::OK).into_response(),
         Err(_) => (StatusCode::UNAUTHORIZED).into_response(),
     }
@@ -68,6 +73,7 @@ async fn username_password(
     delete_user: Option<UserId>,
     pool: State<Pool>,
     config: State<EnvironmentConfig>,
+    client: ClientInfo,
     mut cookies: Cookies,
     dto: Json<UsernamePasswordDTO>,
 ) -> Result<impl IntoResponse, anyhow::Error> {
@@ -103,6 +109,7 @@ async fn username_password(
     Ok(create_session_and_attach_cookie(
         user_id,
         can_delete,
+        &client,
         &config,
         &mut cookies,
         &mut connection,
@@ -113,11 +120,15 @@ async fn username_password(
 async fn create_session_and_attach_cookie(
     user_id: UserId,
     can_delete: bool,
+    client: &ClientInfo,
     config: &EnvironmentConfig,
     cookies: &mut Cookies,
     connection: &mut Connection,
 ) -> Result<(), ServerError> {
-    let session = session::create_session(user_id, can_delete, connection).await?;
+    if moderation::is_banned(user_id, connection).await? {
+        return Err(ServerError::NotAllowed("This account is banned.".to_string()));
+    }
+    let session = session::create_session(user_id, can_delete, client, connection).await?;
     let client_session = crypto::encrypt_string(&session.0, &config.secret_key)?;
 
     let session_cookie = Cookie::build((SESSION_COOKIE, client_session))
@@ -125,7 +136,7 @@ async fn create_session_and_attach_cookie(
         .http_only(true)
         .secure(!config.dev_mode)
         .same_site(SameSite::Lax) // So links from other sites work
-        .max_age(time::Duration::days(14))
+        .max_age(time::Duration::days(session::SESSION_LIFETIME_DAYS))
         .build();
     cookies.add(session_cookie);
 
@@ -139,7 +150,7 @@ async fn get_user_for_login(
     connection: &mut Connection,
 ) -> Result<UserId, anyhow::Error> {
     let res = sqlx::query!(
-        "select user_id, hashed_password, id from login where identifier = ?",
+        "select user_id, hashed_password, id from login where type = 'password' and identifier = ?",
         dto.username
     )
         .fetch_one(&mut *connection)
@@ -183,23 +194,43 @@ fn generate_password_hash(password: &str) -> String {
         .to_string()
 }
 
+#[derive(Deserialize)]
+pub struct LogoutQuery {
+    /// Only ends the session of the request, other devices stay logged in.
+    #[serde(default)]
+    current_only: bool,
+}
+
+/// Ends all sessions of the user. With `?current_only=true` only the session of
+/// the request ends.
 pub async fn logout_route(
     session: SessionData,
+    Query(query): Query<LogoutQuery>,
     cookies: Cookies,
     pool: State<Pool>,
 ) -> impl IntoResponse {
     let mut connection = pool.conn().await.expect("No connection available");
-    sqlx::query!("delete from session where user_id = ?", session.user_id.0)
-        .execute(&mut connection)
-        .await
-        .expect("Error removing sessions for user.");
+    if query.current_only {
+        sqlx::query!("delete from session where id = ?", session.session_id.0)
+            .execute(&mut connection)
+            .await
+            .expect("Error removing session.");
+    } else {
+        session::delete_sessions(session.user_id, None, &mut connection)
+            .await
+            .expect("Error removing sessions.");
+    }
 
+    remove_session_cookie(&cookies);
+
+    format!("Logout for user {}", session.user_id.0)
+}
+
+fn remove_session_cookie(cookies: &Cookies) {
     cookies.remove(
         Cookie::build((SESSION_COOKIE, ""))
             .path("/")
             .same_site(SameSite::Strict)
             .build(),
     );
-
-    format!("Logout for user {}", session.user_id.0)
 }
@@ -0,0 +1,847 @@
+//! Login with OAuth2 and OpenID Connect providers like Discord, GitHub or
+//! Google.
+//!
+//! All providers work the same way:
+//! 1. We redirect the user to the authorization url of the provider.
+//! 2. The provider redirects back to `/api/oauth/:provider/callback` with a
+//!    code, which we exchange for an access token.
+//! 3. With the access token, we load the user info and map it to an identity:
+//!    a subject that identifies the user at the provider and a display name.
+//! 4. If a login for the identity exists, the user gets a session. Otherwise
+//!    we ask the user to confirm the creation of an account first.
+//!
+//! Logged-in users can link more providers to their account, so they can log
+//! in with any of them.
+//!
+//! We don't keep the access token. After we know who logged in, the user gets
+//! a normal session from us.
+
+use axum::{
+    extract::{Path, Query, State},
+    response::{IntoResponse, Redirect},
+    Json,
+};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tower_cookies::{cookie::SameSite, Cookie, Cookies};
+use urlencoding::encode;
+
+use crate::{
+    config::{EnvironmentConfig, OAuthProviderConfig, OAuthProviderKind},
+    db::{Connection, Pool},
+    ServerError,
+};
+
+use super::{create_session_and_attach_cookie, crypto, session::{ClientInfo, SessionData}, update_last_login, user, UserId};
+
+static OAUTH_STATE_COOKIE_NAME: &str = "oauth_state";
+
+/// Discord was the first provider. Its login works without an entry in
+/// `oauth_providers` and keeps the redirect url registered at Discord.
+pub const DISCORD: &str = "discord";
+
+/// GitHub rejects requests without a user agent.
+const USER_AGENT: &str = "pacoplay.com";
+
+lazy_static! {
+    /// OpenID Connect discovery documents by issuer. They rarely change, so we
+    /// load them once.
+    static ref DISCOVERY: DashMap<String, DiscoveryDocument> = DashMap::new();
+}
+
+/// A provider with all defaults of its kind applied.
+#[derive(Clone, Debug)]
+pub struct OAuthProvider {
+    pub name: String,
+    pub display_name: String,
+    client_id: String,
+    client_secret: String,
+    kind: OAuthProviderKind,
+    issuer: Option<String>,
+    authorization_url: Option<String>,
+    token_url: Option<String>,
+    user_info_url: Option<String>,
+    scope: String,
+    subject_field: String,
+    name_fields: Vec<String>,
+}
+
+/// The endpoints of a provider, once they are known.
+#[derive(Clone, Debug)]
+struct Endpoints {
+    authorization_url: String,
+    token_url: String,
+    user_info_url: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct DiscoveryDocument {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+/// Who logged in, according to the provider.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OAuthIdentity {
+    /// Identifies the user at the provider.
+    pub subject: String,
+    pub display_name: String,
+}
+
+impl OAuthProvider {
+    fn new(config: &OAuthProviderConfig, client_secret: String) -> Self {
+        use OAuthProviderKind::*;
+
+        let (issuer, authorization_url, token_url, user_info_url, scope, subject_field, name_fields) =
+            match config.kind {
+                Discord => (
+                    None,
+                    Some("https://discord.com/api/oauth2/authorize"),
+                    Some("https://discord.com/api/oauth2/token"),
+                    Some("https://discord.com/api/users/@me"),
+                    "identify",
+                    "id",
+                    &["global_name", "username"][..],
+                ),
+                Github => (
+                    None,
+                    Some("https://github.com/login/oauth/authorize"),
+                    Some("https://github.com/login/oauth/access_token"),
+                    Some("https://api.github.com/user"),
+                    "read:user",
+                    "id",
+                    &["name", "login"][..],
+                ),
+                Google => (
+                    Some("https://accounts.google.com"),
+                    None,
+                    None,
+                    None,
+                    "openid profile",
+                    "sub",
+                    &["name", "given_name"][..],
+                ),
+                Oidc | Custom => (
+                    None,
+                    None,
+                    None,
+                    None,
+                    "openid profile",
+                    "sub",
+                    &["name", "preferred_username", "nickname"][..],
+                ),
+            };
+
+        OAuthProvider {
+            name: config.name.clone(),
+            display_name: config.display_name.clone().unwrap_or_else(|| config.name.clone()),
+            client_id: config.client_id.clone(),
+            client_secret,
+            kind: config.kind,
+            issuer: config.issuer.clone().or(issuer.map(str::to_string)),
+            authorization_url: config.authorization_url.clone().or(authorization_url.map(str::to_string)),
+            token_url: config.token_url.clone().or(token_url.map(str::to_string)),
+            user_info_url: config.user_info_url.clone().or(user_info_url.map(str::to_string)),
+            scope: config.scope.clone().unwrap_or(scope.to_string()),
+            subject_field: config.subject_field.clone().unwrap_or(subject_field.to_string()),
+            name_fields: config
+                .name_fields
+                .clone()
+                .unwrap_or_else(|| name_fields.iter().map(|f| f.to_string()).collect()),
+        }
+    }
+
+    fn redirect_uri(&self, server_url: &str) -> String {
+        if self.name == DISCORD {
+            format!("{}/api/oauth/backFromDiscord", server_url)
+        } else {
+            format!("{}/api/oauth/{}/callback", server_url, self.name)
+        }
+    }
+
+    /// The avatar of new users is an identicon, which only allows word
+    /// characters. Discord users keep the avatars they always had.
+    fn identicon_seed(&self, identity: &OAuthIdentity) -> String {
+        if self.kind == OAuthProviderKind::Discord && identity.subject.chars().all(|c| c.is_ascii_digit()) {
+            identity.subject.clone()
+        } else {
+            let hash = blake3::hash(format!("{}:{}", self.name, identity.subject).as_bytes());
+            hash.to_hex()[..32].to_string()
+        }
+    }
+
+    async fn endpoints(&self) -> Result<Endpoints, ServerError> {
+        let discovered = match &self.issuer {
+            Some(issuer) if self.needs_discovery() => Some(discover(issuer).await?),
+            _ => None,
+        };
+        let pick = |configured: &Option<String>, discovered: Option<&String>| {
+            configured
+                .clone()
+                .or(discovered.cloned())
+                .ok_or(ServerError::OAuth2Error("The provider is missing an endpoint"))
+        };
+        Ok(Endpoints {
+            authorization_url: pick(
+                &self.authorization_url,
+                discovered.as_ref().map(|d| &d.authorization_endpoint),
+            )?,
+            token_url: pick(&self.token_url, discovered.as_ref().map(|d| &d.token_endpoint))?,
+            user_info_url: pick(
+                &self.user_info_url,
+                discovered.as_ref().map(|d| &d.userinfo_endpoint),
+            )?,
+        })
+    }
+
+    fn needs_discovery(&self) -> bool {
+        self.authorization_url.is_none() || self.token_url.is_none() || self.user_info_url.is_none()
+    }
+
+    /// Reads the identity from the user info of the provider.
+    fn identity(&self, user_info: &Value) -> Result<OAuthIdentity, ServerError> {
+        let subject = match user_info.get(&self.subject_field) {
+            Some(Value::String(subject)) if !subject.is_empty() => subject.clone(),
+            // GitHub uses numbers.
+            Some(Value::Number(subject)) => subject.to_string(),
+            _ => return Err(ServerError::OAuth2Error("The user info has no subject")),
+        };
+        let display_name = self
+            .name_fields
+            .iter()
+            .filter_map(|field| user_info.get(field)?.as_str())
+            .map(str::trim)
+            .find(|name| !name.is_empty())
+            .unwrap_or(&subject)
+            .to_string();
+        Ok(OAuthIdentity {
+            subject,
+            display_name,
+        })
+    }
+}
+
+/// All configured providers.
+pub fn providers(config: &EnvironmentConfig) -> Vec<OAuthProvider> {
+    let mut providers: Vec<OAuthProvider> = config
+        .oauth_providers
+        .iter()
+        .map(|provider| {
+            let secret = config
+                .oauth_client_secrets
+                .get(&provider.name)
+                .cloned()
+                .unwrap_or_default();
+            OAuthProvider::new(provider, secret)
+        })
+        .collect();
+
+    let has_discord = providers.iter().any(|provider| provider.name == DISCORD);
+    if !has_discord && !config.discord_client_id.is_empty() {
+        let discord = OAuthProviderConfig {
+            name: DISCORD.to_string(),
+            display_name: Some("Discord".to_string()),
+            kind: OAuthProviderKind::Discord,
+            client_id: config.discord_client_id.clone(),
+            issuer: None,
+            authorization_url: None,
+            token_url: None,
+            user_info_url: None,
+            scope: None,
+            subject_field: None,
+            name_fields: None,
+        };
+        providers.insert(0, OAuthProvider::new(&discord, config.discord_client_secret.clone()));
+    }
+    providers
+}
+
+pub(super) fn find_provider(config: &EnvironmentConfig, name: &str) -> Result<OAuthProvider, ServerError> {
+    providers(config)
+        .into_iter()
+        .find(|provider| provider.name == name)
+        .ok_or(ServerError::NotFound)
+}
+
+async fn discover(issuer: &str) -> Result<DiscoveryDocument, ServerError> {
+    if let Some(document) = DISCOVERY.get(issuer) {
+        return Ok(document.clone());
+    }
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    let response = reqwest::Client::new()
+        .get(url)
+        .header(reqwest::header::USER_AGENT, USER_AGENT)
+        .send()
+        .await?
+        .error_for_status()?;
+    let document: DiscoveryDocument = serde_json::from_str(&response.text().await?)?;
+    DISCOVERY.insert(issuer.to_string(), document.clone());
+    Ok(document)
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// Exchanges the code from the redirect for an access token.
+async fn request_token(
+    provider: &OAuthProvider,
+    endpoints: &Endpoints,
+    code: &str,
+    server_url: &str,
+) -> Result<String, ServerError> {
+    let redirect_uri = provider.redirect_uri(server_url);
+    let response = reqwest::Client::new()
+        .post(&endpoints.token_url)
+        // GitHub answers with a form unless we ask for JSON.
+        .header(reqwest::header::ACCEPT, "application/json")
+        .header(reqwest::header::USER_AGENT, USER_AGENT)
+        .form(&[
+            ("client_id", provider.client_id.as_str()),
+            ("client_secret", provider.client_secret.as_str()),
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &redirect_uri),
+        ])
+        .send()
+        .await?;
+    let body = response.text().await?;
+    match serde_json::from_str::<TokenResponse>(&body) {
+        Ok(token) => Ok(token.access_token),
+        Err(_) => {
+            warn!("Token request to {} failed: {}", provider.name, body);
+            Err(ServerError::OAuth2Error("Could not get an access token"))
+        }
+    }
+}
+
+async fn request_identity(
+    provider: &OAuthProvider,
+    endpoints: &Endpoints,
+    access_token: &str,
+) -> Result<OAuthIdentity, ServerError> {
+    let response = reqwest::Client::new()
+        .get(&endpoints.user_info_url)
+        .bearer_auth(access_token)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .header(reqwest::header::USER_AGENT, USER_AGENT)
+        .send()
+        .await?
+        .error_for_status()?;
+    let user_info: Value = serde_json::from_str(&response.text().await?)?;
+    provider.identity(&user_info)
+}
+
+/// What the user wants to do when they come back from the provider. This is
+/// part of the state, so it survives the redirects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Intent {
+    Login,
+    /// Log in with the right to delete the account.
+    LoginToDelete,
+    /// Add the provider to the account of the logged-in user.
+    Link,
+}
+
+impl Intent {
+    fn suffix(self) -> &'static str {
+        match self {
+            Intent::Login => "",
+            Intent::LoginToDelete => "-delete",
+            Intent::Link => "-link",
+        }
+    }
+
+    fn from_state(state: &str) -> Self {
+        if state.ends_with("-delete") {
+            Intent::LoginToDelete
+        } else if state.ends_with("-link") {
+            Intent::Link
+        } else {
+            Intent::Login
+        }
+    }
+}
+
+fn state_cookie(state: &str, dev_mode: bool) -> Cookie<'static> {
+    Cookie::build((OAUTH_STATE_COOKIE_NAME, state.to_string()))
+        .path("/")
+        .http_only(true)
+        .secure(!dev_mode)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::hours(1))
+        .build()
+}
+
+/// Redirects to the provider and remembers the state in a cookie.
+pub(super) async fn redirect_to_provider(
+    provider: &OAuthProvider,
+    intent: Intent,
+    config: &EnvironmentConfig,
+    cookies: &Cookies,
+) -> Result<Redirect, ServerError> {
+    let endpoints = provider.endpoints().await?;
+    let state: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    let state = format!("{}{}", state, intent.suffix());
+
+    let url = format!(
+        "{}?client_id={}&redirect_uri={}&response_type=code&scope={}&state={}",
+        endpoints.authorization_url,
+        encode(&provider.client_id),
+        encode(&provider.redirect_uri(&config.server_url)),
+        encode(&provider.scope),
+        state
+    );
+    cookies.add(state_cookie(&state, config.dev_mode));
+    Ok(Redirect::to(&url))
+}
+
+#[derive(Serialize)]
+pub struct ProviderInfo {
+    name: String,
+    display_name: String,
+    login_url: String,
+}
+
+/// GET /api/oauth/providers lists the providers users can log in with.
+pub async fn list_providers(State(config): State<EnvironmentConfig>) -> Json<Vec<ProviderInfo>> {
+    Json(
+        providers(&config)
+            .into_iter()
+            .map(|provider| ProviderInfo {
+                login_url: format!("/api/oauth/{}/login", provider.name),
+                name: provider.name,
+                display_name: provider.display_name,
+            })
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    can_delete: Option<bool>,
+    link: Option<bool>,
+}
+
+/// GET /api/oauth/:provider/login starts the login. With `link=true`, the
+/// provider is added to the account of the logged-in user instead.
+pub async fn login(
+    Path(provider): Path<String>,
+    Query(query): Query<LoginQuery>,
+    cookies: Cookies,
+    State(config): State<EnvironmentConfig>,
+) -> Result<Redirect, ServerError> {
+    let provider = find_provider(&config, &provider)?;
+    let intent = if query.link.unwrap_or(false) {
+        Intent::Link
+    } else if query.can_delete.unwrap_or(false) {
+        Intent::LoginToDelete
+    } else {
+        Intent::Login
+    };
+    redirect_to_provider(&provider, intent, &config, &cookies).await
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: String,
+    state: String,
+}
+
+/// GET /api/oauth/:provider/callback is where the provider sends the user
+/// back to.
+pub async fn callback(
+    Path(provider): Path<String>,
+    Query(query): Query<CallbackQuery>,
+    session: Option<SessionData>,
+    client: ClientInfo,
+    cookies: Cookies,
+    State(config): State<EnvironmentConfig>,
+    State(pool): State<Pool>,
+) -> Result<impl IntoResponse, ServerError> {
+    let provider = find_provider(&config, &provider)?;
+    handle_callback(&provider, query, session, client, cookies, &config, &pool).await
+}
+
+pub(super) async fn handle_callback(
+    provider: &OAuthProvider,
+    CallbackQuery { code, state }: CallbackQuery,
+    session: Option<SessionData>,
+    client: ClientInfo,
+    mut cookies: Cookies,
+    config: &EnvironmentConfig,
+    pool: &Pool,
+) -> Result<impl IntoResponse, ServerError> {
+    let Some(state_cookie) = cookies.get(OAUTH_STATE_COOKIE_NAME) else {
+        warn!("OAuth2 State cookie not found");
+        return Err(ServerError::OAuth2Error("No state cookie found"));
+    };
+    if state_cookie.value() != state {
+        warn!(
+            "OAuth2 State does not match the expected value. Expected: {}, got: {}",
+            state_cookie.value(),
+            state
+        );
+        return Err(ServerError::OAuth2Error(
+            "OAuth2 State does not match the expected value",
+        ));
+    }
+
+    let endpoints = provider.endpoints().await?;
+    let access_token = request_token(provider, &endpoints, &code, &config.server_url).await?;
+    let identity = request_identity(provider, &endpoints, &access_token).await?;
+
+    let mut conn = pool.conn().await?;
+    let user_id = user_for_login(&provider.name, &identity.subject, &mut conn).await?;
+
+    match (Intent::from_state(&state), user_id) {
+        (Intent::Link, user_id) => {
+            let Some(session) = session else {
+                return Err(ServerError::NotAllowed("You need to be logged in to link a login.".to_string()));
+            };
+            match user_id {
+                Some(user_id) if user_id != session.user_id => Err(ServerError::NotAllowed(
+                    "This login already belongs to another account.".to_string(),
+                )),
+                Some(_) => Ok(Redirect::to("/me").into_response()),
+                None => {
+                    user::create_oauth_login(session.user_id, &provider.name, &identity.subject, &mut conn).await?;
+                    info!("User {} linked a login with {}.", session.user_id.0, provider.name);
+                    Ok(Redirect::to("/me").into_response())
+                }
+            }
+        }
+        (intent, Some(user_id)) => {
+            let can_delete = intent == Intent::LoginToDelete;
+            create_session_and_attach_cookie(user_id, can_delete, &client, config, &mut cookies, &mut conn).await?;
+            if can_delete {
+                Ok(Redirect::to(&format!("/me?delete_user={}", user_id.0)).into_response())
+            } else {
+                Ok(Redirect::to("/").into_response())
+            }
+        }
+        (_, None) => Ok(account_creation_confirmation_redirect(provider, &access_token, &identity, config)?.into_response()),
+    }
+}
+
+/// Given the identity at a provider, we look up the user in the database.
+///
+/// If the user does exist, this also updates the last_login field.
+async fn user_for_login(
+    provider: &str,
+    subject: &str,
+    conn: &mut Connection,
+) -> Result<Option<UserId>, ServerError> {
+    let res = sqlx::query!(
+        "SELECT user_id FROM login WHERE type = ? AND identifier = ?",
+        provider,
+        subject
+    )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    let Some(res) = res else {
+        return Ok(None);
+    };
+    let user_id = UserId(res.user_id);
+    update_last_login(user_id, conn).await?;
+    Ok(Some(user_id))
+}
+
+/// The access token is encrypted together with the provider. This makes sure
+/// the client can hand it back to us without being able to read it. The
+/// client gets to see its display name and the seed of its initial profile
+/// picture. We can't rely on the user not tampering with those, so we don't
+/// expect them back. When the user confirms the account creation, we request
+/// the user info again.
+fn account_creation_confirmation_redirect(
+    provider: &OAuthProvider,
+    access_token: &str,
+    identity: &OAuthIdentity,
+    config: &EnvironmentConfig,
+) -> Result<Redirect, ServerError> {
+    let encrypted_access_token = crypto::encrypt_string(
+        &format!("{}\n{}", provider.name, access_token),
+        &config.secret_key,
+    )?;
+
+    // The frontend still calls the identicon seed "user_discord_id".
+    let redirect_url = format!(
+        "/me/create-account?encrypted_access_token={}&user_display_name={}&user_discord_id={}&provider={}",
+        make_base64_robust_against_elm(&encrypted_access_token),
+        encode(&identity.display_name),
+        provider.identicon_seed(identity),
+        encode(&provider.name),
+    );
+
+    Ok(Redirect::to(&redirect_url))
+}
+
+/// The encrypted access token is Base64 encoded. This means it will end with "=" in most cases.
+/// Elm's query parser does not like this and will eat the equals sign. This breaks decryption.
+/// To preserve the equals signs, we convert them to ";" which does not occur in Base64.
+fn make_base64_robust_against_elm(input: &str) -> String {
+    input.replace('=', ";")
+}
+
+/// Getting back to a regular Base64 encoded string, we replace ; by =
+/// And we also have to replace space by +, that is axum's fault.
+fn fix_back_to_base64(input: &str) -> String {
+    input.replace(';', "=").replace(' ', "+")
+}
+
+#[derive(Deserialize)]
+pub struct PleaseCreateAccountQuery {
+    encrypted_access_token: String,
+}
+
+/// GET /api/oauth/pleaseCreateAccount is called when the user confirms the
+/// account creation.
+pub async fn please_create_account(
+    Query(query): Query<PleaseCreateAccountQuery>,
+    client: ClientInfo,
+    mut cookies: Cookies,
+    State(config): State<EnvironmentConfig>,
+    State(pool): State<Pool>,
+) -> Result<impl IntoResponse, ServerError> {
+    let decrypted = crypto::decrypt_string(
+        &fix_back_to_base64(&query.encrypted_access_token),
+        &config.secret_key,
+    )?;
+    // Tokens from before there were several providers are always for Discord.
+    let (provider, access_token) = decrypted
+        .split_once('\n')
+        .unwrap_or((DISCORD, decrypted.as_str()));
+    let provider = find_provider(&config, provider)?;
+
+    // Use it another time to load user information
+    let endpoints = provider.endpoints().await?;
+    let identity = request_identity(&provider, &endpoints, access_token).await?;
+
+    let mut conn = pool.conn().await?;
+    let user_id = match user_for_login(&provider.name, &identity.subject, &mut conn).await? {
+        // The account was created in the meantime, e.g. in another tab.
+        Some(user_id) => user_id,
+        None => {
+            info!("Creating account for user {} from {}.", identity.display_name, provider.name);
+            let user_id = user::create_user(
+                &identity.display_name,
+                &format!("identicon:{}", provider.identicon_seed(&identity)),
+                &mut conn,
+            )
+                .await?;
+            user::create_oauth_login(user_id, &provider.name, &identity.subject, &mut conn).await?;
+            user_id
+        }
+    };
+
+    create_session_and_attach_cookie(user_id, false, &client, &config, &mut cookies, &mut conn).await?;
+
+    // Return to /me with a redirect. On first login, the user likely want to
+    // chose a profile picture and maybe set some other options once we put them
+    // on the /me page as well.
+    Ok(Redirect::to("/me").into_response())
+}
+
+#[derive(Serialize)]
+pub struct LoginInfo {
+    id: i64,
+    /// "password" or the name of the provider.
+    #[serde(rename = "type")]
+    login_type: String,
+    /// The username for password logins.
+    username: Option<String>,
+    created_at: Option<String>,
+    last_login: Option<String>,
+}
+
+/// GET /api/me/logins lists all the ways the logged-in user can log in.
+pub async fn list_logins(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<LoginInfo>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(logins_of(session.user_id, &mut conn).await?))
+}
+
+/// The logins of the user, without password hashes or tokens.
+pub async fn logins_of(user_id: UserId, conn: &mut Connection) -> Result<Vec<LoginInfo>, ServerError> {
+    let rows = sqlx::query!(
+        r#"select id as "id!", type as login_type, identifier,
+        created_at as "created_at: String", last_login as "last_login: String"
+        from login where user_id = ? order by id"#,
+        user_id.0
+    )
+        .fetch_all(&mut **conn)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| LoginInfo {
+            id: r.id,
+            username: (r.login_type == "password").then_some(r.identifier),
+            login_type: r.login_type,
+            created_at: r.created_at,
+            last_login: r.last_login,
+        })
+        .collect())
+}
+
+/// DELETE /api/me/logins/:id removes a login from the account of the
+/// logged-in user. The last login can't be removed.
+pub async fn remove_login(
+    session: SessionData,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let result = sqlx::query!(
+        r"delete from login where id = ? and user_id = ?
+        and (select count(*) from login where user_id = ?) > 1",
+        id,
+        session.user_id.0,
+        session.user_id.0
+    )
+        .execute(&mut *conn)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotAllowed(
+            "This login does not exist or is your last one.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use axum::{
+        extract::Form,
+        http::HeaderMap,
+        routing::{get, post},
+        Router,
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    fn provider_config(kind: OAuthProviderKind, issuer: Option<String>) -> OAuthProviderConfig {
+        OAuthProviderConfig {
+            name: "mock".to_string(),
+            display_name: None,
+            kind,
+            client_id: "client".to_string(),
+            issuer,
+            authorization_url: None,
+            token_url: None,
+            user_info_url: None,
+            scope: None,
+            subject_field: None,
+            name_fields: None,
+        }
+    }
+
+    /// A local OAuth2 server which accepts the code "good-code" and knows one
+    /// user with the access token "token".
+    async fn mock_server() -> String {
+        async fn token(Form(form): Form<Vec<(String, String)>>) -> Json<Value> {
+            let code = form.iter().find(|(key, _)| key == "code").map(|(_, v)| v.as_str());
+            if code == Some("good-code") {
+                Json(json!({"access_token": "token", "token_type": "Bearer"}))
+            } else {
+                Json(json!({"error": "invalid_grant"}))
+            }
+        }
+        async fn user_info(headers: HeaderMap) -> Json<Value> {
+            assert_eq!(headers["authorization"], "Bearer token");
+            Json(json!({"sub": "user-17", "name": null, "preferred_username": "rolf"}))
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address: SocketAddr = listener.local_addr().unwrap();
+        let base = format!("http://{}", address);
+        let discovery = json!({
+            "authorization_endpoint": format!("{base}/authorize"),
+            "token_endpoint": format!("{base}/token"),
+            "userinfo_endpoint": format!("{base}/userinfo"),
+        });
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(move || async move { Json(discovery) }),
+            )
+            .route("/token", post(token))
+            .route("/userinfo", get(user_info));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        base
+    }
+
+    #[tokio::test]
+    async fn test_login_with_oidc_discovery() {
+        let issuer = mock_server().await;
+        let provider = OAuthProvider::new(
+            &provider_config(OAuthProviderKind::Oidc, Some(issuer.clone())),
+            "secret".to_string(),
+        );
+
+        let endpoints = provider.endpoints().await.unwrap();
+        assert_eq!(endpoints.token_url, format!("{issuer}/token"));
+
+        let access_token = request_token(&provider, &endpoints, "good-code", "http://localhost")
+            .await
+            .unwrap();
+        let identity = request_identity(&provider, &endpoints, &access_token)
+            .await
+            .unwrap();
+        assert_eq!(
+            identity,
+            OAuthIdentity {
+                subject: "user-17".to_string(),
+                display_name: "rolf".to_string(),
+            }
+        );
+
+        assert!(request_token(&provider, &endpoints, "bad-code", "http://localhost")
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_identity_mapping() {
+        let github = OAuthProvider::new(&provider_config(OAuthProviderKind::Github, None), String::new());
+        let identity = github
+            .identity(&json!({"id": 583231, "login": "octocat", "name": ""}))
+            .unwrap();
+        assert_eq!(identity.subject, "583231");
+        assert_eq!(identity.display_name, "octocat");
+        assert!(github.identity(&json!({"login": "octocat"})).is_err());
+
+        let discord = OAuthProvider::new(&provider_config(OAuthProviderKind::Discord, None), String::new());
+        let identity = discord
+            .identity(&json!({"id": "80351110224678912", "username": "nelly", "global_name": "Nelly"}))
+            .unwrap();
+        assert_eq!(identity.display_name, "Nelly");
+        assert_eq!(discord.identicon_seed(&identity), "80351110224678912");
+        assert_eq!(github.identicon_seed(&identity).len(), 32);
+    }
+
+    #[test]
+    fn test_intent_survives_the_state() {
+        for intent in [Intent::Login, Intent::LoginToDelete, Intent::Link] {
+            assert_eq!(Intent::from_state(&format!("abc{}", intent.suffix())), intent);
+        }
+    }
+}
@@ -1,16 +1,85 @@
 //! Rust module for handling permissions.
 //!
-//! The related database table is `user_permission`.
-//! This table is maintained in this module.
+//! The related database tables are `user_permission` and `user_role`.
+//! These tables are maintained in this module.
+//!
+//! Permissions are plain strings. A user either has a permission directly
+//! from `user_permission` or through a role from `user_role`. Which permissions
+//! a role bundles is defined in [`Role::permissions`].
+//!
+//! Routes that need a permission take a [`RequirePermission`] extractor.
+//! Roles are granted and revoked by admins. Every change is written to the
+//! `user_role_audit` table.
+
+use std::marker::PhantomData;
 
-use sqlx::query;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{request::Parts, StatusCode},
+    routing::{delete, get},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, Acquire};
 
-use crate::db::Connection;
+use crate::db::{Connection, Pool};
+use crate::login::session::SessionData;
 use crate::login::UserId;
+use crate::{AppState, ServerError};
 
 pub const BACKDATED_USER_ASSIGNMENT: &str = "backdated_user_assignment";
+pub const CREATE_TOURNAMENT: &str = "create_tournament";
+pub const MANAGE_ROLES: &str = "manage_roles";
+/// Handle reports, ban and mute users.
+pub const MODERATE_USERS: &str = "moderate_users";
+/// Annul games and change their players.
+pub const MODERATE_GAMES: &str = "moderate_games";
+
+/// Every permission. Admins have all of them.
+const ALL_PERMISSIONS: &[&str] = &[
+    BACKDATED_USER_ASSIGNMENT,
+    CREATE_TOURNAMENT,
+    MANAGE_ROLES,
+    MODERATE_USERS,
+    MODERATE_GAMES,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Moderator,
+    TournamentDirector,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Admin, Role::Moderator, Role::TournamentDirector];
+
+    /// The name of the role in the database.
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Moderator => "moderator",
+            Role::TournamentDirector => "tournament_director",
+        }
+    }
 
-/// Checks if a user has a certain permission.
+    pub fn from_name(name: &str) -> Option<Role> {
+        Role::ALL.into_iter().find(|role| role.name() == name)
+    }
+
+    /// The permissions a user gets with this role.
+    pub fn permissions(self) -> &'static [&'static str] {
+        match self {
+            Role::Admin => ALL_PERMISSIONS,
+            Role::Moderator => &[BACKDATED_USER_ASSIGNMENT, MODERATE_USERS, MODERATE_GAMES],
+            Role::TournamentDirector => &[CREATE_TOURNAMENT],
+        }
+    }
+}
+
+/// Checks if a user has a certain permission, directly or through a role.
 pub async fn is_allowed(user_id: UserId, permission: &str, conn: &mut Connection) -> Result<bool, sqlx::Error> {
     let result = query!(
         r#"
@@ -23,8 +92,328 @@ pub async fn is_allowed(user_id: UserId, permission: &str, conn: &mut Connection
         user_id.0,
         permission
     )
-        .fetch_one(conn)
+        .fetch_one(&mut *conn)
+        .await?;
+    if result.permission_exists.is_positive() {
+        return Ok(true);
+    }
+
+    let roles = roles_of(user_id, conn).await?;
+    Ok(roles.iter().any(|role| role.permissions().contains(&permission)))
+}
+
+/// The roles of a user. Roles the code doesn't know (anymore) are ignored.
+pub async fn roles_of(user_id: UserId, conn: &mut Connection) -> Result<Vec<Role>, sqlx::Error> {
+    let rows = query!("select role from user_role where user_id = ? order by role", user_id.0)
+        .fetch_all(conn)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let role = Role::from_name(&row.role);
+            if role.is_none() {
+                warn!("User {} has unknown role {}.", user_id.0, row.role);
+            }
+            role
+        })
+        .collect())
+}
+
+/// All permissions of a user, directly granted ones and those from roles.
+async fn permissions_of(user_id: UserId, roles: &[Role], conn: &mut Connection) -> Result<Vec<String>, sqlx::Error> {
+    let rows = query!("select permission from user_permission where user_id = ?", user_id.0)
+        .fetch_all(conn)
+        .await?;
+    let mut permissions: Vec<String> = rows.into_iter().map(|row| row.permission).collect();
+    permissions.extend(
+        roles
+            .iter()
+            .flat_map(|role| role.permissions())
+            .map(|permission| permission.to_string()),
+    );
+    permissions.sort();
+    permissions.dedup();
+    Ok(permissions)
+}
+
+/// A permission as a type, so routes can declare it with [`RequirePermission`].
+pub trait Permission {
+    const NAME: &'static str;
+}
+
+macro_rules! permission_type {
+    ($type:ident, $name:ident) => {
+        pub struct $type;
+
+        impl Permission for $type {
+            const NAME: &'static str = $name;
+        }
+    };
+}
+
+permission_type!(BackdatedUserAssignment, BACKDATED_USER_ASSIGNMENT);
+permission_type!(CreateTournament, CREATE_TOURNAMENT);
+permission_type!(ManageRoles, MANAGE_ROLES);
+permission_type!(ModerateUsers, MODERATE_USERS);
+permission_type!(ModerateGames, MODERATE_GAMES);
+
+/// Extracts the session of a user that has the permission `P`. Rejects the
+/// request with 401 without a session and with 403 without the permission.
+pub struct RequirePermission<P> {
+    pub session: SessionData,
+    permission: PhantomData<fn() -> P>,
+}
+
+#[async_trait]
+impl<P: Permission> FromRequestParts<AppState> for RequirePermission<P> {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let session = SessionData::from_request_parts(parts, state).await?;
+        let Ok(mut conn) = state.pool.conn().await else {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "No database connection."));
+        };
+        match is_allowed(session.user_id, P::NAME, &mut conn).await {
+            Ok(true) => Ok(RequirePermission {
+                session,
+                permission: PhantomData,
+            }),
+            Ok(false) => Err((StatusCode::FORBIDDEN, "You don't have the permission for this.")),
+            Err(e) => {
+                warn!("Error checking permission {}: {:?}", P::NAME, e);
+                Err((StatusCode::INTERNAL_SERVER_ERROR, "Could not check permission."))
+            }
+        }
+    }
+}
+
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router
+        .route("/me/permissions", get(my_permissions))
+        .route("/admin/roles", get(list_roles).post(grant_role))
+        .route("/admin/roles/audit", get(role_audit))
+        .route("/admin/roles/:user_id/:role", delete(revoke_role))
+}
+
+#[derive(Serialize)]
+struct MyPermissions {
+    roles: Vec<Role>,
+    permissions: Vec<String>,
+}
+
+/// GET /api/me/permissions tells the frontend what the logged-in user may do.
+async fn my_permissions(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Json<MyPermissions>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let roles = roles_of(session.user_id, &mut conn).await?;
+    let permissions = permissions_of(session.user_id, &roles, &mut conn).await?;
+    Ok(Json(MyPermissions { roles, permissions }))
+}
+
+#[derive(Serialize)]
+struct RoleGrant {
+    user_id: i64,
+    name: Option<String>,
+    role: String,
+    granted_by: Option<i64>,
+    granted_at: Option<String>,
+}
+
+/// GET /api/admin/roles lists everyone who has a role.
+async fn list_roles(
+    _: RequirePermission<ManageRoles>,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<RoleGrant>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let grants = sqlx::query_as!(
+        RoleGrant,
+        r#"select user_role.user_id, user.name, user_role.role, user_role.granted_by,
+        user_role.granted_at as "granted_at: String"
+        from user_role join user on user.id = user_role.user_id
+        order by user_role.role, user_role.user_id"#
+    )
+        .fetch_all(&mut *conn)
         .await?;
+    Ok(Json(grants))
+}
+
+#[derive(Deserialize)]
+struct GrantRoleRequest {
+    user_id: UserId,
+    role: Role,
+    reason: Option<String>,
+}
+
+/// POST /api/admin/roles gives a role to a user. Granting a role the user
+/// already has changes nothing.
+async fn grant_role(
+    RequirePermission { session, .. }: RequirePermission<ManageRoles>,
+    State(pool): State<Pool>,
+    Json(request): Json<GrantRoleRequest>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let user_exists = query!("select id from user where id = ?", request.user_id.0)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some();
+    if !user_exists {
+        return Err(ServerError::NotFound);
+    }
+
+    let role = request.role.name();
+    let mut tx = conn.begin().await?;
+    let result = query!(
+        "insert or ignore into user_role (user_id, role, granted_by) values (?, ?, ?)",
+        request.user_id.0,
+        role,
+        session.user_id.0
+    )
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Ok(());
+    }
+    write_audit(request.user_id, role, "grant", session.user_id, &request.reason, &mut tx).await?;
+    tx.commit().await?;
+
+    info!("User {} granted role {} to user {}.", session.user_id.0, role, request.user_id.0);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct RevokeRoleQuery {
+    reason: Option<String>,
+}
+
+/// DELETE /api/admin/roles/:user_id/:role takes a role away from a user.
+/// The last admin can't be removed, so someone can still manage roles.
+async fn revoke_role(
+    RequirePermission { session, .. }: RequirePermission<ManageRoles>,
+    Path((user_id, role)): Path<(i64, Role)>,
+    Query(query): Query<RevokeRoleQuery>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let role_name = role.name();
+    let mut tx = conn.begin().await?;
+    let result = query!(
+        r"delete from user_role where user_id = ? and role = ?
+        and (role != 'admin' or (select count(*) from user_role where role = 'admin') > 1)",
+        user_id,
+        role_name
+    )
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        let has_role = query!(
+            "select user_id from user_role where user_id = ? and role = ?",
+            user_id,
+            role_name
+        )
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+        return Err(if has_role {
+            ServerError::NotAllowed("The last admin can't be removed.".to_string())
+        } else {
+            ServerError::NotFound
+        });
+    }
+    write_audit(UserId(user_id), role_name, "revoke", session.user_id, &query.reason, &mut tx).await?;
+    tx.commit().await?;
+
+    info!("User {} revoked role {} from user {}.", session.user_id.0, role_name, user_id);
+    Ok(())
+}
+
+async fn write_audit(
+    user_id: UserId,
+    role: &str,
+    action: &str,
+    changed_by: UserId,
+    reason: &Option<String>,
+    conn: &mut sqlx::SqliteConnection,
+) -> Result<(), sqlx::Error> {
+    query!(
+        "insert into user_role_audit (user_id, role, action, changed_by, reason) values (?, ?, ?, ?, ?)",
+        user_id.0,
+        role,
+        action,
+        changed_by.0,
+        reason
+    )
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct RoleAuditQuery {
+    user_id: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct RoleAuditEntry {
+    id: i64,
+    user_id: i64,
+    role: String,
+    action: String,
+    changed_by: i64,
+    changed_at: Option<String>,
+    reason: Option<String>,
+}
+
+/// GET /api/admin/roles/audit shows the latest role changes, optionally only
+/// those of one user.
+async fn role_audit(
+    _: RequirePermission<ManageRoles>,
+    Query(query): Query<RoleAuditQuery>,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<RoleAuditEntry>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let entries = sqlx::query_as!(
+        RoleAuditEntry,
+        r#"select id as "id!", user_id, role, action, changed_by,
+        changed_at as "changed_at: String", reason
+        from user_role_audit where ? is null or user_id = ?
+        order by id desc limit 500"#,
+        query.user_id,
+        query.user_id
+    )
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(Json(entries))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_role_names_round_trip() {
+        for role in Role::ALL {
+            assert_eq!(Role::from_name(role.name()), Some(role));
+            // The database name is also the name in the API.
+            assert_eq!(
+                serde_json::to_string(&role).unwrap(),
+                format!("\"{}\"", role.name())
+            );
+        }
+        assert_eq!(Role::from_name("superuser"), None);
+    }
 
-    Ok(result.permission_exists.is_positive())
+    #[test]
+    fn test_admin_has_all_permissions() {
+        for role in Role::ALL {
+            for permission in role.permissions() {
+                assert!(ALL_PERMISSIONS.contains(permission));
+            }
+        }
+        assert_eq!(Role::Admin.permissions(), ALL_PERMISSIONS);
+    }
 }
@@ -0,0 +1,466 @@
+//! Profile fields a user can edit on /api/me/profile.
+//!
+//! The display name, bio and country are public and part of `PublicUserData`.
+//! The preferences (language, board theme, default time control and sharing
+//! games with followers) are only shown to the user. They follow the user to
+//! every device, unlike the language cookie and the settings in local storage.
+
+use std::time::Duration;
+
+use axum::{extract::State, routing::get, Json, Router};
+use hyper::StatusCode;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    db::{self, Connection, Pool},
+    language,
+    rate_limit::RateLimiter,
+    timer::TimerConfig,
+    AppState, ServerError,
+};
+
+use super::{session::SessionData, UserId};
+
+const DISPLAY_NAME_LENGTH: std::ops::RangeInclusive<usize> = 3..=32;
+const MAX_BIO_LENGTH: usize = 500;
+
+lazy_static! {
+    /// Changing the display name all the time makes it easy to impersonate
+    /// other players.
+    static ref DISPLAY_NAME_CHANGES: RateLimiter = RateLimiter::new(5, Duration::from_secs(24 * 60 * 60));
+}
+
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router.route("/me/profile", get(get_profile).patch(update_profile))
+}
+
+/// Board colors in the format of the `ColorConfig` of the frontend.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BoardTheme {
+    pub white_tile_color: Color,
+    pub black_tile_color: Color,
+    pub border_color: Color,
+    pub highlight_color: Color,
+    pub white_piece_fill: Color,
+    pub white_piece_stroke: Color,
+    pub black_piece_fill: Color,
+    pub black_piece_stroke: Color,
+}
+
+/// All components are between 0 and 1.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+    pub alpha: f32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Profile {
+    pub user_id: UserId,
+    pub name: Option<String>,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub country: Option<String>,
+    pub language: Option<String>,
+    pub board_theme: Option<BoardTheme>,
+    pub default_time_control: Option<TimerConfig>,
+    /// Followers see which game the user is playing and get a message when a
+    /// game starts. Off unless the user turns it on.
+    pub share_games_with_followers: bool,
+}
+
+/// Fields that are missing stay unchanged, `null` removes the value.
+#[derive(Deserialize, Default)]
+pub struct ProfileUpdate {
+    #[serde(default, deserialize_with = "present")]
+    display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    bio: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    country: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    language: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    board_theme: Option<Option<BoardTheme>>,
+    #[serde(default, deserialize_with = "present")]
+    default_time_control: Option<Option<TimerConfig>>,
+    #[serde(default)]
+    share_games_with_followers: Option<bool>,
+}
+
+/// Tells a field set to `null` apart from a missing field.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// Why a profile update was rejected. The frontend shows a translated message.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileProblem {
+    DisplayNameInvalid,
+    DisplayNameTaken,
+    TooManyDisplayNameChanges,
+    BioTooLong,
+    BioInvalid,
+    CountryInvalid,
+    LanguageUnsupported,
+    BoardThemeInvalid,
+    TimeControlInvalid,
+}
+
+#[derive(Serialize)]
+pub struct ProfileRejected {
+    problem: ProfileProblem,
+}
+
+type Rejection = (StatusCode, Json<ProfileRejected>);
+
+fn reject(problem: ProfileProblem) -> Rejection {
+    let status = match problem {
+        ProfileProblem::DisplayNameTaken => StatusCode::CONFLICT,
+        ProfileProblem::TooManyDisplayNameChanges => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    (status, Json(ProfileRejected { problem }))
+}
+
+// Validation //////////////////////////////////////////////////////////////////
+
+/// Letters and digits of any script, with single spaces, `_`, `-` and `.` in
+/// between. Returns the trimmed name.
+fn validate_display_name(display_name: &str) -> Result<String, ProfileProblem> {
+    let display_name = display_name.trim();
+    let valid = DISPLAY_NAME_LENGTH.contains(&display_name.chars().count())
+        && display_name.chars().next().is_some_and(char::is_alphanumeric)
+        && display_name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == ' ' || c == '_' || c == '-' || c == '.')
+        && !display_name.contains("  ");
+    if valid {
+        Ok(display_name.to_string())
+    } else {
+        Err(ProfileProblem::DisplayNameInvalid)
+    }
+}
+
+/// Returns the trimmed bio, an empty bio is removed.
+fn validate_bio(bio: &str) -> Result<Option<String>, ProfileProblem> {
+    let bio = bio.trim();
+    if bio.chars().count() > MAX_BIO_LENGTH {
+        Err(ProfileProblem::BioTooLong)
+    } else if bio.chars().any(|c| c.is_control() && c != '\n') {
+        Err(ProfileProblem::BioInvalid)
+    } else if bio.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(bio.to_string()))
+    }
+}
+
+/// Two letter country code. We don't check that the country exists, the
+/// frontend shows unknown codes as letters instead of a flag.
+fn validate_country(country: &str) -> Result<String, ProfileProblem> {
+    if country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()) {
+        Ok(country.to_ascii_uppercase())
+    } else {
+        Err(ProfileProblem::CountryInvalid)
+    }
+}
+
+fn validate_board_theme(theme: &BoardTheme) -> Result<(), ProfileProblem> {
+    let colors = [
+        theme.white_tile_color,
+        theme.black_tile_color,
+        theme.border_color,
+        theme.highlight_color,
+        theme.white_piece_fill,
+        theme.white_piece_stroke,
+        theme.black_piece_fill,
+        theme.black_piece_stroke,
+    ];
+    let valid = colors.iter().all(|color| {
+        [color.red, color.green, color.blue, color.alpha]
+            .iter()
+            .all(|c| (0.0..=1.0).contains(c))
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(ProfileProblem::BoardThemeInvalid)
+    }
+}
+
+impl Profile {
+    /// Validates the update and applies it to the profile.
+    fn apply(&mut self, update: ProfileUpdate) -> Result<(), ProfileProblem> {
+        if let Some(display_name) = update.display_name {
+            self.display_name = display_name.as_deref().map(validate_display_name).transpose()?;
+        }
+        if let Some(bio) = update.bio {
+            self.bio = bio.as_deref().map(validate_bio).transpose()?.flatten();
+        }
+        if let Some(country) = update.country {
+            self.country = country.as_deref().map(validate_country).transpose()?;
+        }
+        if let Some(language) = update.language {
+            if language.as_deref().is_some_and(|l| !language::is_language_supported(l)) {
+                return Err(ProfileProblem::LanguageUnsupported);
+            }
+            self.language = language;
+        }
+        if let Some(board_theme) = update.board_theme {
+            board_theme.as_ref().map(validate_board_theme).transpose()?;
+            self.board_theme = board_theme;
+        }
+        if let Some(time_control) = update.default_time_control {
+            if time_control.as_ref().is_some_and(|t| !t.is_legal()) {
+                return Err(ProfileProblem::TimeControlInvalid);
+            }
+            self.default_time_control = time_control.map(|t| t.sanitize());
+        }
+        if let Some(share_games_with_followers) = update.share_games_with_followers {
+            self.share_games_with_followers = share_games_with_followers;
+        }
+        Ok(())
+    }
+}
+
+// Database ////////////////////////////////////////////////////////////////////
+
+pub async fn load_profile(user_id: UserId, conn: &mut Connection) -> Result<Profile, ServerError> {
+    let row = sqlx::query!(
+        r#"select name, display_name, bio, country, language, board_theme, default_time_control,
+            share_games_with_followers as "share_games_with_followers: bool"
+        from user where id = ?"#,
+        user_id.0
+    )
+        .fetch_optional(&mut **conn)
+        .await?
+        .ok_or(ServerError::NotFound)?;
+
+    Ok(Profile {
+        user_id,
+        name: row.name,
+        display_name: row.display_name,
+        bio: row.bio,
+        country: row.country,
+        language: row.language,
+        board_theme: parse_json(row.board_theme, user_id, "board theme"),
+        default_time_control: parse_json(row.default_time_control, user_id, "time control"),
+        share_games_with_followers: row.share_games_with_followers,
+    })
+}
+
+/// A stored value we can't read anymore is dropped. The user can set it again.
+fn parse_json<T: for<'de> Deserialize<'de>>(json: Option<String>, user_id: UserId, what: &str) -> Option<T> {
+    let json = json?;
+    serde_json::from_str(&json)
+        .map_err(|e| warn!("Stored {} of user {} is invalid: {:?}", what, user_id.0, e))
+        .ok()
+}
+
+async fn write_profile(profile: &Profile, conn: &mut Connection) -> Result<(), ServerError> {
+    let display_name_lower = profile.display_name.as_deref().map(str::to_lowercase);
+    let board_theme = profile.board_theme.as_ref().map(serde_json::to_string).transpose()?;
+    let time_control = profile
+        .default_time_control
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    sqlx::query!(
+        r"update user
+        set display_name = ?, display_name_lower = ?, bio = ?, country = ?, language = ?, board_theme = ?,
+            default_time_control = ?, share_games_with_followers = ?, updated_at = CURRENT_TIMESTAMP
+        where id = ?",
+        profile.display_name,
+        display_name_lower,
+        profile.bio,
+        profile.country,
+        profile.language,
+        board_theme,
+        time_control,
+        profile.share_games_with_followers,
+        profile.user_id.0
+    )
+        .execute(&mut **conn)
+        .await?;
+    Ok(())
+}
+
+/// Display names must not be the display name or username of someone else,
+/// regardless of case. Usernames are ASCII, so `lower` is enough for them.
+pub async fn is_display_name_taken(
+    display_name: &str,
+    user_id: UserId,
+    conn: &mut Connection,
+) -> Result<bool, ServerError> {
+    let display_name = display_name.to_lowercase();
+    let res = sqlx::query!(
+        r"select exists(
+            select 1 from user
+            where id != ? and (display_name_lower = ? or lower(name) = ?)
+        ) or exists(
+            select 1 from login
+            where user_id != ? and type = 'password' and lower(identifier) = ?
+        ) as taken",
+        user_id.0,
+        display_name,
+        display_name,
+        user_id.0,
+        display_name
+    )
+        .fetch_one(&mut **conn)
+        .await?;
+    Ok(res.taken == Some(1))
+}
+
+/// The language the user picked, if it is still supported.
+pub async fn stored_language(user_id: UserId, conn: &mut Connection) -> Result<Option<String>, ServerError> {
+    let res = sqlx::query!("select language from user where id = ?", user_id.0)
+        .fetch_optional(&mut **conn)
+        .await?;
+    Ok(res
+        .and_then(|r| r.language)
+        .filter(|l| language::is_language_supported(l)))
+}
+
+pub async fn set_language(user_id: UserId, language: &str, conn: &mut Connection) -> Result<(), ServerError> {
+    sqlx::query!(
+        "update user set language = ?, updated_at = CURRENT_TIMESTAMP where id = ?",
+        language,
+        user_id.0
+    )
+        .execute(&mut **conn)
+        .await?;
+    Ok(())
+}
+
+// Handlers ////////////////////////////////////////////////////////////////////
+
+/// GET /api/me/profile
+async fn get_profile(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Json<Profile>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(load_profile(session.user_id, &mut conn).await?))
+}
+
+/// PATCH /api/me/profile changes the given fields and returns the new profile.
+async fn update_profile(
+    session: SessionData,
+    State(pool): State<Pool>,
+    Json(update): Json<ProfileUpdate>,
+) -> Result<Result<Json<Profile>, Rejection>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let mut profile = load_profile(session.user_id, &mut conn).await?;
+    let old_display_name = profile.display_name.clone();
+
+    if let Err(problem) = profile.apply(update) {
+        return Ok(Err(reject(problem)));
+    }
+
+    let display_name_changed = profile.display_name.as_deref().map(str::to_lowercase)
+        != old_display_name.as_deref().map(str::to_lowercase);
+    if let (true, Some(display_name)) = (display_name_changed, &profile.display_name) {
+        if is_display_name_taken(display_name, session.user_id, &mut conn).await? {
+            return Ok(Err(reject(ProfileProblem::DisplayNameTaken)));
+        }
+        if !DISPLAY_NAME_CHANGES.try_acquire(&session.user_id.0.to_string()) {
+            return Ok(Err(reject(ProfileProblem::TooManyDisplayNameChanges)));
+        }
+    }
+
+    match write_profile(&profile, &mut conn).await {
+        // Someone else took the display name since we checked.
+        Err(ServerError::DatabaseError(e)) if db::is_unique_violation(&e) => {
+            Ok(Err(reject(ProfileProblem::DisplayNameTaken)))
+        }
+        Err(e) => Err(e),
+        Ok(()) => Ok(Ok(Json(profile))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_display_name() {
+        assert_eq!(validate_display_name("  Rolf  "), Ok("Rolf".to_string()));
+        assert!(validate_display_name("Paco Ŝako fan").is_ok());
+        assert!(validate_display_name("dr. rolf_2").is_ok());
+        assert!(validate_display_name("ab").is_err());
+        assert!(validate_display_name("_rolf").is_err());
+        assert!(validate_display_name("rolf  rolf").is_err());
+        assert!(validate_display_name("rolf\u{200b}").is_err());
+        assert!(validate_display_name(&"a".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn test_validate_bio_and_country() {
+        assert_eq!(validate_bio("  "), Ok(None));
+        assert_eq!(validate_bio("Hi!\nI play"), Ok(Some("Hi!\nI play".to_string())));
+        assert_eq!(validate_bio("a\u{7}"), Err(ProfileProblem::BioInvalid));
+        assert_eq!(validate_bio(&"a".repeat(501)), Err(ProfileProblem::BioTooLong));
+        assert_eq!(validate_country("de"), Ok("DE".to_string()));
+        assert_eq!(validate_country("DEU"), Err(ProfileProblem::CountryInvalid));
+        assert_eq!(validate_country("1a"), Err(ProfileProblem::CountryInvalid));
+    }
+
+    #[test]
+    fn update_keeps_missing_fields_and_clears_null() {
+        let mut profile = Profile {
+            user_id: UserId(1),
+            name: Some("rolf".to_string()),
+            display_name: Some("Rolf".to_string()),
+            bio: Some("Hi".to_string()),
+            country: None,
+            language: None,
+            board_theme: None,
+            default_time_control: None,
+            share_games_with_followers: false,
+        };
+        let update: ProfileUpdate =
+            serde_json::from_str(r#"{"bio": null, "country": "nl", "language": "en"}"#).unwrap();
+        profile.apply(update).unwrap();
+        assert_eq!(profile.display_name.as_deref(), Some("Rolf"));
+        assert_eq!(profile.bio, None);
+        assert_eq!(profile.country.as_deref(), Some("NL"));
+        assert_eq!(profile.language.as_deref(), Some("en"));
+
+        let update: ProfileUpdate = serde_json::from_str(r#"{"language": "xx"}"#).unwrap();
+        assert_eq!(profile.apply(update), Err(ProfileProblem::LanguageUnsupported));
+    }
+
+    #[tokio::test]
+    async fn display_names_are_unique_in_any_script() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let rolf = crate::login::user::create_user("rolf", "identicon:1", &mut conn).await.unwrap();
+        let other = crate::login::user::create_user("other", "identicon:2", &mut conn).await.unwrap();
+        let mut profile = load_profile(rolf, &mut conn).await.unwrap();
+        profile.display_name = Some("ŜAKO Ĉampiono".to_string());
+        write_profile(&profile, &mut conn).await.unwrap();
+
+        assert!(is_display_name_taken("ŝako ĉampiono", other, &mut conn).await.unwrap());
+        assert!(!is_display_name_taken("ŝako ĉampiono", rolf, &mut conn).await.unwrap());
+        assert!(is_display_name_taken("ROLF", other, &mut conn).await.unwrap());
+
+        // The index catches names that pass the check at the same time.
+        let mut profile = load_profile(other, &mut conn).await.unwrap();
+        profile.display_name = Some("ŝako ĉampiono".to_string());
+        match write_profile(&profile, &mut conn).await {
+            Err(ServerError::DatabaseError(e)) => assert!(db::is_unique_violation(&e)),
+            result => panic!("Expected a unique violation, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! Self-service registration with username and password.
+//!
+//! An email address is optional. If one is given, we send a link to verify it.
+//! Registrations are rate limited by address to make account spam harder.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::HeaderMap,
+    response::{IntoResponse, Redirect},
+    Json,
+};
+use base64::Engine;
+use hyper::StatusCode;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tower_cookies::Cookies;
+
+use crate::{
+    config::EnvironmentConfig,
+    db::{self, Connection, Pool},
+    mailer::{self, Mail},
+    rate_limit::{self, RateLimiter},
+    ServerError,
+};
+
+use super::{create_session_and_attach_cookie, generate_password_hash, session::{ClientInfo, SessionData}, user, UserId};
+
+const USERNAME_LENGTH: std::ops::RangeInclusive<usize> = 3..=24;
+const PASSWORD_LENGTH: std::ops::RangeInclusive<usize> = 8..=128;
+const MAX_EMAIL_LENGTH: usize = 254;
+
+/// How long the link in the verification mail works.
+const VERIFICATION_VALIDITY: chrono::Duration = chrono::Duration::hours(48);
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+lazy_static! {
+    /// Accounts created from the same address.
+    static ref REGISTRATIONS_PER_IP: RateLimiter = RateLimiter::new(3, HOUR);
+    /// All accounts, in case the spam comes from many addresses.
+    static ref REGISTRATIONS: RateLimiter = RateLimiter::new(60, HOUR);
+    /// Verification mails sent for the same user.
+    static ref VERIFICATION_MAILS: RateLimiter = RateLimiter::new(3, HOUR);
+}
+
+/// Why a registration was rejected. The frontend shows a translated message.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationProblem {
+    UsernameInvalid,
+    UsernameTaken,
+    PasswordTooShort,
+    PasswordTooLong,
+    PasswordIsUsername,
+    EmailInvalid,
+    /// The server can't send mails, so it can't verify the address.
+    EmailUnavailable,
+    TooManyRegistrations,
+}
+
+#[derive(Serialize)]
+pub struct RegistrationRejected {
+    problem: RegistrationProblem,
+}
+
+type Rejection = (StatusCode, Json<RegistrationRejected>);
+
+fn reject(problem: RegistrationProblem) -> Rejection {
+    let status = match problem {
+        RegistrationProblem::UsernameTaken => StatusCode::CONFLICT,
+        RegistrationProblem::TooManyRegistrations => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    (status, Json(RegistrationRejected { problem }))
+}
+
+fn validate_username(username: &str) -> Result<(), RegistrationProblem> {
+    let mut chars = username.chars();
+    let valid = USERNAME_LENGTH.contains(&username.len())
+        && chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(RegistrationProblem::UsernameInvalid)
+    }
+}
+
+fn validate_password(password: &str, username: &str) -> Result<(), RegistrationProblem> {
+    let length = password.chars().count();
+    if length < *PASSWORD_LENGTH.start() {
+        Err(RegistrationProblem::PasswordTooShort)
+    } else if length > *PASSWORD_LENGTH.end() {
+        Err(RegistrationProblem::PasswordTooLong)
+    } else if password.eq_ignore_ascii_case(username) {
+        Err(RegistrationProblem::PasswordIsUsername)
+    } else {
+        Ok(())
+    }
+}
+
+/// Only catches obvious mistakes. Whether the address works is shown by the
+/// verification.
+fn validate_email(email: &str) -> Result<(), RegistrationProblem> {
+    let valid = email.len() <= MAX_EMAIL_LENGTH
+        && !email.chars().any(char::is_whitespace)
+        && email.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(RegistrationProblem::EmailInvalid)
+    }
+}
+
+/// Usernames are unique regardless of case. They also can't be the display
+/// name of someone else.
+async fn is_username_taken(username: &str, conn: &mut Connection) -> Result<bool, ServerError> {
+    let res = sqlx::query!(
+        r"select exists(select 1 from login where type = 'password' and lower(identifier) = lower(?))
+            or exists(select 1 from user where display_name_lower = lower(?)) as taken",
+        username,
+        username
+    )
+        .fetch_one(conn)
+        .await?;
+    Ok(res.taken == Some(1))
+}
+
+#[derive(Deserialize)]
+pub struct UsernameQuery {
+    username: String,
+}
+
+#[derive(Serialize)]
+pub struct UsernameAvailability {
+    available: bool,
+    problem: Option<RegistrationProblem>,
+}
+
+/// GET /api/register/username_available?username=... checks a username before
+/// the user submits the registration.
+pub async fn username_available(
+    State(pool): State<Pool>,
+    Query(query): Query<UsernameQuery>,
+) -> Result<Json<UsernameAvailability>, ServerError> {
+    let problem = match validate_username(&query.username) {
+        Err(problem) => Some(problem),
+        Ok(()) => {
+            let mut conn = pool.conn().await?;
+            is_username_taken(&query.username, &mut conn)
+                .await?
+                .then_some(RegistrationProblem::UsernameTaken)
+        }
+    };
+    Ok(Json(UsernameAvailability {
+        available: problem.is_none(),
+        problem,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RegistrationRequest {
+    username: String,
+    password: String,
+    email: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Registered {
+    user_id: UserId,
+    /// False if no email was given or the mail could not be sent.
+    verification_mail_sent: bool,
+}
+
+/// POST /api/register creates an account and logs the user in.
+pub async fn register(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    State(config): State<EnvironmentConfig>,
+    State(pool): State<Pool>,
+    mut cookies: Cookies,
+    Json(request): Json<RegistrationRequest>,
+) -> Result<Result<Json<Registered>, Rejection>, ServerError> {
+    let username = request.username.trim();
+    let email = request
+        .email
+        .as_deref()
+        .map(str::trim)
+        .filter(|email| !email.is_empty());
+
+    let checks = validate_username(username)
+        .and_then(|()| validate_password(&request.password, username))
+        .and_then(|()| email.map_or(Ok(()), validate_email));
+    if let Err(problem) = checks {
+        return Ok(Err(reject(problem)));
+    }
+    if email.is_some() && !mailer::is_available() {
+        return Ok(Err(reject(RegistrationProblem::EmailUnavailable)));
+    }
+
+    let ip = rate_limit::client_ip(&headers, peer, config.behind_proxy).to_string();
+    if !REGISTRATIONS_PER_IP.try_acquire(&ip) || !REGISTRATIONS.try_acquire("all") {
+        warn!("Rejected a registration from {} because of the rate limit.", ip);
+        return Ok(Err(reject(RegistrationProblem::TooManyRegistrations)));
+    }
+
+    let mut conn = pool.conn().await?;
+    if is_username_taken(username, &mut conn).await? {
+        return Ok(Err(reject(RegistrationProblem::UsernameTaken)));
+    }
+
+    let avatar = format!("identicon:{:032x}", rand::random::<u128>());
+    let user_id = user::create_user(username, &avatar, &mut conn).await?;
+    let hashed_password = generate_password_hash(&request.password);
+    if let Err(e) = user::create_password_login(user_id, username, &hashed_password, &mut conn).await {
+        user::delete_user_without_login(user_id, &mut conn).await?;
+        if db::is_unique_violation(&e) {
+            // Someone else registered the same name at the same time.
+            return Ok(Err(reject(RegistrationProblem::UsernameTaken)));
+        }
+        return Err(e.into());
+    }
+    info!("User {} registered as {}.", user_id.0, username);
+
+    let verification_mail_sent = match email {
+        Some(email) => match start_verification(user_id, email, &config, &mut conn).await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Could not send the verification mail to user {}: {:?}", user_id.0, e);
+                false
+            }
+        },
+        None => false,
+    };
+
+    create_session_and_attach_cookie(user_id, false, &client, &config, &mut cookies, &mut conn).await?;
+
+    Ok(Ok(Json(Registered {
+        user_id,
+        verification_mail_sent,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct SetEmailRequest {
+    email: String,
+}
+
+/// POST /api/me/email replaces the email address of the logged-in user and
+/// sends a new verification mail.
+pub async fn set_email(
+    session: SessionData,
+    State(config): State<EnvironmentConfig>,
+    State(pool): State<Pool>,
+    Json(request): Json<SetEmailRequest>,
+) -> Result<Result<(), Rejection>, ServerError> {
+    let email = request.email.trim();
+    if let Err(problem) = validate_email(email) {
+        return Ok(Err(reject(problem)));
+    }
+    if !mailer::is_available() {
+        return Ok(Err(reject(RegistrationProblem::EmailUnavailable)));
+    }
+    if !VERIFICATION_MAILS.try_acquire(&session.user_id.0.to_string()) {
+        return Err(ServerError::NotAllowed(
+            "You requested too many verification mails.".to_string(),
+        ));
+    }
+
+    let mut conn = pool.conn().await?;
+    start_verification(session.user_id, email, &config, &mut conn).await?;
+    Ok(Ok(()))
+}
+
+/// Stores the unverified address and sends the verification link to it.
+async fn start_verification(
+    user_id: UserId,
+    email: &str,
+    config: &EnvironmentConfig,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let token = base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+    let token_hash = blake3::hash(token.as_bytes()).to_hex().to_string();
+    let expires_at = (chrono::Utc::now() + VERIFICATION_VALIDITY)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+
+    sqlx::query!(
+        r"insert into user_email (user_id, email, verification_token_hash, verification_expires_at)
+        values (?, ?, ?, ?)
+        on conflict (user_id) do update set
+            email = excluded.email,
+            verified_at = null,
+            verification_token_hash = excluded.verification_token_hash,
+            verification_expires_at = excluded.verification_expires_at",
+        user_id.0,
+        email,
+        token_hash,
+        expires_at
+    )
+        .execute(conn)
+        .await?;
+
+    let link = format!("{}/api/register/verify_email?token={}", config.server_url, token);
+    mailer::send(&Mail {
+        to: email.to_string(),
+        subject: "Verify your email address for Paco Ŝako".to_string(),
+        text: format!(
+            "Please open this link to verify your email address: {link}\n\n\
+            The link works for {} hours. If you didn't create an account, you can ignore this mail.",
+            VERIFICATION_VALIDITY.num_hours()
+        ),
+    })
+        .await
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailQuery {
+    token: String,
+}
+
+/// GET /api/register/verify_email?token=... is the link in the verification
+/// mail. It redirects to the profile page.
+pub async fn verify_email(
+    State(pool): State<Pool>,
+    Query(query): Query<VerifyEmailQuery>,
+) -> Result<impl IntoResponse, ServerError> {
+    let token_hash = blake3::hash(query.token.as_bytes()).to_hex().to_string();
+    let mut conn = pool.conn().await?;
+    let result = sqlx::query!(
+        r"update user_email
+        set verified_at = CURRENT_TIMESTAMP, verification_token_hash = null, verification_expires_at = null
+        where verification_token_hash = ? and verification_expires_at > CURRENT_TIMESTAMP",
+        token_hash
+    )
+        .execute(&mut conn)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotFound);
+    }
+    Ok(Redirect::to("/me"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_username() {
+        assert!(validate_username("Rolf").is_ok());
+        assert!(validate_username("paco_sako-fan2").is_ok());
+        assert!(validate_username("ab").is_err());
+        assert!(validate_username("1234567890").is_err());
+        assert!(validate_username("_rolf").is_err());
+        assert!(validate_username("rolf rolf").is_err());
+        assert!(validate_username("Ŝako").is_err());
+        assert!(validate_username(&"a".repeat(25)).is_err());
+    }
+
+    #[test]
+    fn test_validate_password() {
+        assert_eq!(validate_password("correct horse", "rolf"), Ok(()));
+        assert_eq!(
+            validate_password("short", "rolf"),
+            Err(RegistrationProblem::PasswordTooShort)
+        );
+        assert_eq!(
+            validate_password(&"x".repeat(129), "rolf"),
+            Err(RegistrationProblem::PasswordTooLong)
+        );
+        assert_eq!(
+            validate_password("RolfRolf", "rolfrolf"),
+            Err(RegistrationProblem::PasswordIsUsername)
+        );
+    }
+
+    /// The database rejects a username that only differs in case, even if the
+    /// check before creating the login missed it.
+    #[tokio::test]
+    async fn test_username_is_unique() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let rolf = user::create_user("Rolf", "identicon:1", &mut conn).await.unwrap();
+        user::create_password_login(rolf, "Rolf", "hash", &mut conn).await.unwrap();
+
+        assert!(is_username_taken("rOLF", &mut conn).await.unwrap());
+        let other = user::create_user("rolf", "identicon:2", &mut conn).await.unwrap();
+        let error = user::create_password_login(other, "rolf", "hash", &mut conn)
+            .await
+            .unwrap_err();
+        assert!(db::is_unique_violation(&error));
+    }
+
+    #[test]
+    fn test_validate_email() {
+        assert!(validate_email("rolf@example.com").is_ok());
+        assert!(validate_email("rolf@example").is_err());
+        assert!(validate_email("@example.com").is_err());
+        assert!(validate_email("rolf@@example.com").is_err());
+        assert!(validate_email("rolf @example.com").is_err());
+        assert!(validate_email("rolf@example.com.").is_err());
+    }
+}
@@ -1,14 +1,30 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
 use axum::{
     async_trait,
     Extension,
-    extract::FromRequestParts,
-    http::{request::Parts, StatusCode}, RequestPartsExt,
+    extract::{ConnectInfo, FromRequestParts, Path, Query, State},
+    http::{header::USER_AGENT, request::Parts, StatusCode}, Json, RequestPartsExt,
 };
+use serde::{Deserialize, Serialize};
 use tower_cookies::Cookies;
 
-use crate::{AppState, db::Connection, ServerError};
+use crate::{AppState, db::{Connection, Pool}, rate_limit, ServerError};
+
+use super::{crypto, remove_session_cookie, SESSION_COOKIE, SessionId, UserId};
 
-use super::{crypto, SESSION_COOKIE, SessionId, UserId};
+/// Sessions end this long after they were created, no matter what.
+pub const SESSION_LIFETIME_DAYS: i64 = 30;
+/// Sessions also end when they were not used for this long.
+const IDLE_TIMEOUT_DAYS: i64 = 14;
+/// `last_seen_at` is only updated when it is older than this, so not every
+/// request writes to the database.
+const LAST_SEEN_PRECISION_MINUTES: i64 = 5;
+/// How often expired sessions are removed from the database.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Longer user agents are cut off before they are stored.
+const MAX_USER_AGENT_LENGTH: usize = 256;
 
 /// An authenticated session. When you access this from an extractor, it has been
 /// verified that the session is valid by checking against the database.
@@ -33,17 +49,67 @@ impl FromRequestParts<AppState> for SessionData {
     }
 }
 
+/// What we remember about the client a session is used from, so users can
+/// recognize their sessions. The address is only stored as a keyed hash.
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_hash: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientInfo {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        let ip_hash = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| {
+                let ip = rate_limit::client_ip(&parts.headers, *peer, state.config.behind_proxy);
+                hash_client_address(&ip.to_string(), &state.config.secret_key)
+            });
+        Ok(ClientInfo { user_agent, ip_hash })
+    }
+}
+
+/// Keyed with the secret key, so the hash can't be reversed by hashing all
+/// possible addresses.
+fn hash_client_address(address: &str, secret_key: &str) -> String {
+    let key = blake3::derive_key("pacoplay session client address", secret_key.as_bytes());
+    blake3::keyed_hash(&key, address.as_bytes()).to_hex().to_string()
+}
+
+/// The id under which a session is shown to its user. The session id itself
+/// is a secret and never leaves the server unencrypted.
+fn public_id(session_id: &str) -> String {
+    blake3::hash(session_id.as_bytes()).to_hex()[..16].to_string()
+}
+
 pub async fn create_session(
     user_id: UserId,
     can_delete: bool,
+    client: &ClientInfo,
     connection: &mut Connection,
 ) -> Result<SessionId, ServerError> {
     let uuid = uuid::Uuid::new_v4().to_string();
+    let lifetime = format!("+{} days", SESSION_LIFETIME_DAYS);
     sqlx::query!(
-        r"insert into session (id, user_id, expires_at, can_delete) values (?, ?, datetime(CURRENT_TIMESTAMP, '+14 days'), ?)",
+        r"insert into session (id, user_id, expires_at, can_delete, last_seen_at, user_agent, ip_hash)
+        values (?, ?, datetime(CURRENT_TIMESTAMP, ?), ?, CURRENT_TIMESTAMP, ?, ?)",
         uuid,
         user_id.0,
-        can_delete
+        lifetime,
+        can_delete,
+        client.user_agent,
+        client.ip_hash
     )
         .execute(connection)
         .await?;
@@ -51,6 +117,7 @@ pub async fn create_session(
     Ok(SessionId(uuid))
 }
 
+/// Loads a session, unless it doesn't exist, has expired or the user is banned.
 pub async fn load_session(
     session_id: &SessionId,
     connection: &mut Connection,
@@ -63,9 +130,16 @@ pub async fn load_session(
         .execute(&mut *connection)
         .await?;
 
+    let idle_timeout = format!("-{} days", IDLE_TIMEOUT_DAYS);
     let res = sqlx::query!(
-        r"select user_id, can_delete as can_delete from session where id = ?",
-        session_id.0
+        r"select user_id, can_delete as can_delete from session
+        where id = ? and expires_at > CURRENT_TIMESTAMP
+        and coalesce(last_seen_at, created_at) > datetime(CURRENT_TIMESTAMP, ?)
+        and not exists (select 1 from user_sanction
+            where user_sanction.user_id = session.user_id and kind = 'ban' and lifted_at is null
+            and (expires_at is null or expires_at > CURRENT_TIMESTAMP))",
+        session_id.0,
+        idle_timeout
     )
         .fetch_one(connection)
         .await;
@@ -79,6 +153,28 @@ pub async fn load_session(
         .ok_or_else(|| anyhow::anyhow!("Session not found"))
 }
 
+/// Marks the session as used by the client. This keeps it from running into
+/// the idle timeout.
+async fn touch_session(
+    session_id: &SessionId,
+    client: &ClientInfo,
+    connection: &mut Connection,
+) -> Result<(), ServerError> {
+    let precision = format!("-{} minutes", LAST_SEEN_PRECISION_MINUTES);
+    sqlx::query!(
+        r"update session set last_seen_at = CURRENT_TIMESTAMP,
+        user_agent = coalesce(?, user_agent), ip_hash = coalesce(?, ip_hash)
+        where id = ? and (last_seen_at is null or last_seen_at < datetime(CURRENT_TIMESTAMP, ?))",
+        client.user_agent,
+        client.ip_hash,
+        session_id.0,
+        precision
+    )
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
 async fn get_session_from_request_parts(
     parts: &mut Parts,
     state: &AppState,
@@ -98,5 +194,322 @@ async fn get_session_from_request_parts(
     )?);
 
     let mut connection = state.pool.conn().await?;
-    load_session(&session_id, &mut connection).await
+    let session = load_session(&session_id, &mut connection).await?;
+
+    let Ok(client) = parts.extract_with_state::<ClientInfo, _>(state).await;
+    touch_session(&session_id, &client, &mut connection).await?;
+
+    Ok(session)
+}
+
+/// Removes expired sessions from the database every hour. They can't be used
+/// anymore anyway, this just keeps the table small.
+pub async fn run_cleanup(pool: Pool) {
+    let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match delete_expired_sessions(&pool).await {
+            Ok(0) => {}
+            Ok(count) => info!("Removed {} expired sessions.", count),
+            Err(e) => warn!("Removing expired sessions failed: {:?}", e),
+        }
+    }
+}
+
+async fn delete_expired_sessions(pool: &Pool) -> Result<u64, ServerError> {
+    let mut conn = pool.conn().await?;
+    let idle_timeout = format!("-{} days", IDLE_TIMEOUT_DAYS);
+    let result = sqlx::query!(
+        r"delete from session where expires_at is null or expires_at <= CURRENT_TIMESTAMP
+        or coalesce(last_seen_at, created_at) <= datetime(CURRENT_TIMESTAMP, ?)",
+        idle_timeout
+    )
+        .execute(&mut *conn)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    id: String,
+    created_at: Option<String>,
+    last_seen_at: Option<String>,
+    expires_at: Option<String>,
+    user_agent: Option<String>,
+    /// Only the start of the hash. Sessions from the same address share it.
+    ip_hash: Option<String>,
+    /// True for the session of the request.
+    current: bool,
+}
+
+/// GET /api/me/sessions lists the active sessions of the logged-in user.
+pub async fn list_sessions(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<SessionInfo>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(
+        active_sessions(session.user_id, Some(&session.session_id.0), &mut conn).await?,
+    ))
+}
+
+/// The active sessions of the user, most recently used first. `current` is the
+/// id of the session of the request, if there is one.
+pub async fn active_sessions(
+    user_id: UserId,
+    current: Option<&str>,
+    conn: &mut Connection,
+) -> Result<Vec<SessionInfo>, ServerError> {
+    let idle_timeout = format!("-{} days", IDLE_TIMEOUT_DAYS);
+    let rows = sqlx::query!(
+        r#"select id as "id!", created_at as "created_at: String",
+        last_seen_at as "last_seen_at: String", expires_at as "expires_at: String",
+        user_agent, ip_hash
+        from session where user_id = ? and expires_at > CURRENT_TIMESTAMP
+        and coalesce(last_seen_at, created_at) > datetime(CURRENT_TIMESTAMP, ?)
+        order by coalesce(last_seen_at, created_at) desc"#,
+        user_id.0,
+        idle_timeout
+    )
+        .fetch_all(&mut **conn)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| SessionInfo {
+            id: public_id(&r.id),
+            current: current == Some(r.id.as_str()),
+            created_at: r.created_at,
+            last_seen_at: r.last_seen_at,
+            expires_at: r.expires_at,
+            user_agent: r.user_agent,
+            ip_hash: r.ip_hash.map(|hash| hash.chars().take(8).collect()),
+        })
+        .collect())
+}
+
+/// DELETE /api/me/sessions/:id ends one session of the logged-in user. This
+/// may also be the current session.
+pub async fn revoke_session(
+    session: SessionData,
+    Path(id): Path<String>,
+    cookies: Cookies,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let session_ids = sqlx::query!(
+        r#"select id as "id!" from session where user_id = ?"#,
+        session.user_id.0
+    )
+        .fetch_all(&mut *conn)
+        .await?;
+    let Some(target) = session_ids.into_iter().find(|r| public_id(&r.id) == id) else {
+        return Err(ServerError::NotFound);
+    };
+
+    sqlx::query!("delete from session where id = ?", target.id)
+        .execute(&mut *conn)
+        .await?;
+    if target.id == session.session_id.0 {
+        remove_session_cookie(&cookies);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct RevokeAllQuery {
+    keep_current: Option<bool>,
+}
+
+/// DELETE /api/me/sessions logs the user out everywhere. With
+/// `?keep_current=true`, the session of the request stays logged in.
+pub async fn revoke_all_sessions(
+    session: SessionData,
+    Query(query): Query<RevokeAllQuery>,
+    cookies: Cookies,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    if query.keep_current.unwrap_or(false) {
+        delete_sessions(session.user_id, Some(&session.session_id), &mut conn).await?;
+    } else {
+        delete_sessions(session.user_id, None, &mut conn).await?;
+        remove_session_cookie(&cookies);
+    }
+    Ok(())
+}
+
+/// Ends all sessions of the user, except for `keep` if given.
+pub async fn delete_sessions(
+    user_id: UserId,
+    keep: Option<&SessionId>,
+    conn: &mut Connection,
+) -> Result<(), sqlx::Error> {
+    let keep = keep.map(|session_id| session_id.0.as_str());
+    sqlx::query!(
+        "delete from session where user_id = ? and (? is null or id != ?)",
+        user_id.0,
+        keep,
+        keep
+    )
+        .execute(&mut **conn)
+        .await?;
+    info!("User {} logged out everywhere.", user_id.0);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hash_client_address() {
+        let hash = hash_client_address("192.0.2.7", "key");
+        assert_eq!(hash, hash_client_address("192.0.2.7", "key"));
+        assert_ne!(hash, hash_client_address("192.0.2.8", "key"));
+        assert_ne!(hash, hash_client_address("192.0.2.7", "other key"));
+        // Without the key, the hash can't be recomputed.
+        assert_ne!(hash, blake3::hash(b"192.0.2.7").to_hex().to_string());
+    }
+
+    #[test]
+    fn test_public_id_hides_session_id() {
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let id = public_id(&session_id);
+        assert_eq!(id.len(), 16);
+        assert_eq!(id, public_id(&session_id));
+        assert!(!session_id.contains(&id));
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            user_agent: Some("test".to_string()),
+            ip_hash: None,
+        }
+    }
+
+    async fn setup() -> (Pool, UserId) {
+        let pool = crate::db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let user_id = crate::login::user::create_user("rolf", "identicon:1", &mut conn)
+            .await
+            .unwrap();
+        (pool, user_id)
+    }
+
+    async fn set_column(session_id: &SessionId, column: &str, modifier: &str, conn: &mut Connection) {
+        // The column name can't be a parameter, so this can't use `query!`.
+        sqlx::query(&format!(
+            "update session set {column} = datetime(CURRENT_TIMESTAMP, ?) where id = ?"
+        ))
+            .bind(modifier)
+            .bind(&session_id.0)
+            .execute(&mut **conn)
+            .await
+            .unwrap();
+    }
+
+    async fn last_seen(session_id: &SessionId, conn: &mut Connection) -> String {
+        sqlx::query!(
+            r#"select last_seen_at as "last_seen_at!: String" from session where id = ?"#,
+            session_id.0
+        )
+            .fetch_one(&mut **conn)
+            .await
+            .unwrap()
+            .last_seen_at
+    }
+
+    #[tokio::test]
+    async fn test_sessions_expire() {
+        let (pool, user_id) = setup().await;
+        let mut conn = pool.conn().await.unwrap();
+        let fresh = create_session(user_id, false, &client(), &mut conn).await.unwrap();
+        let old = create_session(user_id, false, &client(), &mut conn).await.unwrap();
+        let idle = create_session(user_id, false, &client(), &mut conn).await.unwrap();
+        assert_eq!(load_session(&fresh, &mut conn).await.unwrap().user_id, user_id);
+
+        // Used an hour ago, but created too long ago.
+        set_column(&old, "expires_at", "-1 minutes", &mut conn).await;
+        set_column(&old, "last_seen_at", "-1 hours", &mut conn).await;
+        assert!(load_session(&old, &mut conn).await.is_err());
+
+        // Still within its lifetime, but not used for too long.
+        set_column(&idle, "last_seen_at", &format!("-{} days", IDLE_TIMEOUT_DAYS + 1), &mut conn).await;
+        assert!(load_session(&idle, &mut conn).await.is_err());
+
+        assert_eq!(active_sessions(user_id, None, &mut conn).await.unwrap().len(), 1);
+        drop(conn);
+        assert_eq!(delete_expired_sessions(&pool).await.unwrap(), 2);
+        let mut conn = pool.conn().await.unwrap();
+        assert!(load_session(&fresh, &mut conn).await.is_ok());
+    }
+
+    /// Using a session moves the idle timeout, but only writes to the database
+    /// every few minutes.
+    #[tokio::test]
+    async fn test_session_use_renews_idle_timeout() {
+        let (pool, user_id) = setup().await;
+        let mut conn = pool.conn().await.unwrap();
+        let session_id = create_session(user_id, false, &client(), &mut conn).await.unwrap();
+
+        set_column(&session_id, "last_seen_at", "-1 minutes", &mut conn).await;
+        let recently = last_seen(&session_id, &mut conn).await;
+        touch_session(&session_id, &client(), &mut conn).await.unwrap();
+        assert_eq!(last_seen(&session_id, &mut conn).await, recently);
+
+        set_column(&session_id, "last_seen_at", &format!("-{} days", IDLE_TIMEOUT_DAYS - 1), &mut conn).await;
+        let long_ago = last_seen(&session_id, &mut conn).await;
+        touch_session(&session_id, &client(), &mut conn).await.unwrap();
+        assert!(last_seen(&session_id, &mut conn).await > long_ago);
+
+        // A day later, the session would have run into the idle timeout
+        // without the renewal.
+        set_column(&session_id, "last_seen_at", "-2 days", &mut conn).await;
+        assert!(load_session(&session_id, &mut conn).await.is_ok());
+    }
+
+    /// Banned users can't use their sessions until the ban ends.
+    #[tokio::test]
+    async fn test_ban_blocks_session() {
+        let (pool, user_id) = setup().await;
+        let mut conn = pool.conn().await.unwrap();
+        let session_id = create_session(user_id, false, &client(), &mut conn).await.unwrap();
+
+        let ban = sqlx::query!(
+            r"insert into user_sanction (user_id, kind, reason, created_by, expires_at)
+            values (?, 'ban', 'spam', ?, datetime(CURRENT_TIMESTAMP, '+1 hours'))",
+            user_id.0,
+            user_id.0
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+        assert!(load_session(&session_id, &mut conn).await.is_err());
+
+        sqlx::query!(
+            "update user_sanction set expires_at = datetime(CURRENT_TIMESTAMP, '-1 minutes') where id = ?",
+            ban
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        assert!(load_session(&session_id, &mut conn).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_delete_sessions() {
+        let (pool, user_id) = setup().await;
+        let mut conn = pool.conn().await.unwrap();
+        let current = create_session(user_id, false, &client(), &mut conn).await.unwrap();
+        let other = create_session(user_id, false, &client(), &mut conn).await.unwrap();
+
+        delete_sessions(user_id, Some(&current), &mut conn).await.unwrap();
+        assert!(load_session(&current, &mut conn).await.is_ok());
+        assert!(load_session(&other, &mut conn).await.is_err());
+
+        delete_sessions(user_id, None, &mut conn).await.unwrap();
+        assert!(load_session(&current, &mut conn).await.is_err());
+    }
 }
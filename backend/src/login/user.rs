@@ -7,6 +7,7 @@ use hyper::{header, StatusCode};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sqlx::Acquire;
 
 use pacosako::PlayerColor;
 
@@ -19,9 +20,16 @@ use super::{session::SessionData, UserId};
 #[derive(Serialize, Clone, Debug)]
 pub struct PublicUserData {
     pub name: String,
+    /// Chosen by the user and shown instead of the name, if set.
+    pub display_name: Option<String>,
     pub user_id: UserId,
     pub avatar: String,
+    pub bio: Option<String>,
+    /// Two letter country code, shown as a flag.
+    pub country: Option<String>,
     pub ai: Option<AiMetaData>,
+    /// Bot accounts play through the API, usually with an engine.
+    pub is_bot: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -75,18 +83,47 @@ pub async fn load_public_user_data(
     user_id: UserId,
     connection: &mut Connection,
 ) -> Result<PublicUserData, sqlx::Error> {
-    let res = sqlx::query!("select name, avatar from user where id = ?", user_id.0)
+    let res = sqlx::query!(
+        "select name, display_name, avatar, bio, country, is_bot from user where id = ?",
+        user_id.0
+    )
         .fetch_one(connection)
         .await?;
 
     Ok(PublicUserData {
         name: res.name.unwrap_or("Anonymous".to_string()),
+        display_name: res.display_name,
         user_id,
         avatar: res.avatar,
+        bio: res.bio,
+        country: res.country,
         ai: None,
+        is_bot: res.is_bot != 0,
     })
 }
 
+/// Turns the account into a bot account. This can't be undone.
+pub async fn set_bot(user_id: UserId, connection: &mut Connection) -> Result<(), sqlx::Error> {
+    sqlx::query!("update user set is_bot = 1 where id = ?", user_id.0)
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+/// Accounts that play as an AI. These are bot accounts and the accounts that
+/// stand in for the models of the frontend AI.
+pub async fn is_ai_account(user_id: UserId, connection: &mut Connection) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        r"select exists(select 1 from user where id = ? and is_bot = 1)
+            or exists(select 1 from user_modelName where user_id = ?) as is_ai",
+        user_id.0,
+        user_id.0
+    )
+        .fetch_one(connection)
+        .await?;
+    Ok(res.is_ai == Some(1))
+}
+
 pub async fn load_ai_config_for_game(
     game_key: &str,
     connection: &mut Connection,
@@ -247,62 +284,149 @@ pub async fn delete_user(session: SessionData, State(pool): State<Pool>) -> Resp
             .into_response();
     }
 
-    let mut connection = pool
-        .0
-        .acquire()
-        .await
-        .expect("Failed to acquire connection");
+    let result = async {
+        let mut connection = pool.conn().await?;
+        delete_user_data(session.user_id, &mut connection).await
+    }
+        .await;
+    if let Err(e) = result {
+        error!("Could not delete user {}: {:?}", session.user_id.0, e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "The account could not be deleted.")
+            .into_response();
+    }
+    info!("User {} deleted their account.", session.user_id.0);
+
+    (StatusCode::OK, "").into_response()
+}
 
-    // update game set white_player = NULL where white_player = 3;
-    // update game set black_player = NULL where black_player = 3;
-    // delete from session where user_id = 3;
-    // delete from login where user_id = 3;
-    // delete from user where id = 3;
+/// Removes the user and everything that belongs to them. Their games and the
+/// assignments they made stay, but lose the user. Rows referencing the user are removed first, otherwise
+/// the foreign keys block deleting the user. Everything happens in a single
+/// transaction, so an account is either deleted completely or not at all.
+async fn delete_user_data(user_id: UserId, connection: &mut Connection) -> Result<(), sqlx::Error> {
+    let id = user_id.0;
+    let mut tx = connection.begin().await?;
 
+    sqlx::query!("update game set white_player = NULL where white_player = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("update game set black_player = NULL where black_player = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from game_premove where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
     sqlx::query!(
-        "update game set white_player = NULL where white_player = ?",
-        session.user_id.0
+        r"update game_assignment_audit
+        set white_assignee = case when white_assignee = ? then NULL else white_assignee end,
+            black_assignee = case when black_assignee = ? then NULL else black_assignee end
+        where white_assignee = ? or black_assignee = ?",
+        id,
+        id,
+        id,
+        id
     )
-        .execute(&mut *connection)
-        .await
-        .expect("Error removing user from games");
-
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("update game_assignment_audit set assigned_by = NULL where assigned_by = ?", id)
+        .execute(&mut *tx)
+        .await?;
     sqlx::query!(
-        "update game set black_player = NULL where black_player = ?",
-        session.user_id.0
+        "update game_replay_metadata set created_by = NULL where created_by = ?",
+        id
     )
-        .execute(&mut *connection)
-        .await
-        .expect("Error removing user from games");
+        .execute(&mut *tx)
+        .await?;
 
-    sqlx::query!("delete from session where user_id = ?", session.user_id.0)
-        .execute(&mut *connection)
-        .await
-        .expect("Error removing sessions for user.");
+    sqlx::query!("delete from session where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "delete from oauth_token where login_id in (select id from login where user_id = ?)",
+        id
+    )
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from login where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from api_token where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from user_email where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from user_permission where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from user_role where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from user_modelName where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from account_export where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from fair_play_analysis where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from notification_log where user_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "delete from challenge where challenger_id = ? or challenged_id = ?",
+        id,
+        id
+    )
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "delete from follow where follower_id = ? or followed_id = ?",
+        id,
+        id
+    )
+        .execute(&mut *tx)
+        .await?;
 
-    sqlx::query!("delete from login where user_id = ?", session.user_id.0)
-        .execute(&mut *connection)
-        .await
-        .expect("Error removing login for user.");
+    // Tournaments of the user go away completely, the games stay.
+    sqlx::query!(
+        r"delete from tournament_player
+        where user_id = ? or tournament_id in (select id from tournament where created_by = ?)",
+        id,
+        id
+    )
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "delete from tournament_game where tournament_id in (select id from tournament where created_by = ?)",
+        id
+    )
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("delete from tournament where created_by = ?", id)
+        .execute(&mut *tx)
+        .await?;
 
-    sqlx::query!("delete from user where id = ?", session.user_id.0)
-        .execute(&mut *connection)
-        .await
-        .expect("Error removing user.");
+    sqlx::query!("delete from user where id = ?", id)
+        .execute(&mut *tx)
+        .await?;
 
-    (StatusCode::OK, "").into_response()
+    tx.commit().await
 }
 
-/// For a user that already exists, we create a new login entry.
-/// This makes the login work for this user in the future.
-pub async fn create_discord_login(
+/// For a user that already exists, we create a new login entry for an OAuth2
+/// provider. This makes the login work for this user in the future.
+pub async fn create_oauth_login(
     user_id: UserId,
-    identifier: String,
+    provider: &str,
+    identifier: &str,
     connection: &mut Connection,
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
-        "insert into login (user_id, type, identifier) values (?, 'discord',?)",
+        "insert into login (user_id, type, identifier) values (?, ?, ?)",
         user_id.0,
+        provider,
         identifier
     )
         .execute(connection)
@@ -310,6 +434,32 @@ pub async fn create_discord_login(
     Ok(())
 }
 
+/// Creates the username/password login for a new user.
+pub async fn create_password_login(
+    user_id: UserId,
+    username: &str,
+    hashed_password: &str,
+    connection: &mut Connection,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "insert into login (user_id, type, identifier, hashed_password) values (?, 'password', ?, ?)",
+        user_id.0,
+        username,
+        hashed_password
+    )
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+/// Removes a user that was just created, when creating their login failed.
+pub async fn delete_user_without_login(
+    user_id: UserId,
+    connection: &mut Connection,
+) -> Result<(), sqlx::Error> {
+    delete_user_data(user_id, connection).await
+}
+
 /// Allows anyone to get the public information of any user.
 /// Even if you are not logged in right now.
 pub async fn get_public_user_info(
@@ -320,4 +470,132 @@ pub async fn get_public_user_info(
 
     let user_data = load_public_user_data(UserId(user_id), &mut connection).await?;
     Ok(Json(user_data))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db;
+    use crate::sync_match::SynchronizedMatch;
+
+    #[tokio::test]
+    async fn test_delete_user_removes_everything() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let user = create_user("leaving", "identicon:1", &mut conn).await.unwrap();
+        let other = create_user("staying", "identicon:2", &mut conn).await.unwrap();
+        create_password_login(user, "leaving", "hash", &mut conn).await.unwrap();
+        let mut game = SynchronizedMatch {
+            key: String::new(),
+            actions: vec![],
+            timer: None,
+            setup_options: Default::default(),
+            white_player: Some(user),
+            black_player: Some(other),
+        };
+        db::game::insert(&mut game, &mut conn).await.unwrap();
+        let game_id: i64 = game.key.parse().unwrap();
+
+        let (u, o) = (user.0, other.0);
+        sqlx::query!(
+            "insert into session (id, user_id) values ('session', ?)",
+            u
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "insert into api_token (user_id, name, token_hash) values (?, 'bot', 'hash')",
+            u
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "insert into challenge (challenger_id, challenged_id, parameters) values (?, ?, '{}')",
+            o,
+            u
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query!(
+            r"insert into notification_log (game_id, user_id, kind, action_count)
+            values (?, ?, 'your_turn', 0)",
+            game_id,
+            u
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "insert into follow (follower_id, followed_id) values (?, ?)",
+            o,
+            u
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let own_tournament = sqlx::query!(
+            "insert into tournament (name, kind, parameters, created_by) values ('Own', 'Swiss', '{}', ?)",
+            u
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+        let other_tournament = sqlx::query!(
+            "insert into tournament (name, kind, parameters, created_by) values ('Other', 'Swiss', '{}', ?)",
+            o
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+        sqlx::query!(
+            "insert into tournament_player (tournament_id, user_id) values (?, ?), (?, ?), (?, ?)",
+            own_tournament,
+            o,
+            other_tournament,
+            u,
+            other_tournament,
+            o
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+
+        sqlx::query!(
+            "insert into game_assignment_audit (game_id, white_assignee, assigned_by) values (?, ?, ?)",
+            game_id,
+            u,
+            u
+        )
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+
+        delete_user_data(user, &mut conn).await.unwrap();
+
+        let users = sqlx::query!("select id as \"id!\" from user").fetch_all(&mut *conn).await.unwrap();
+        assert_eq!(users.iter().map(|r| r.id).collect::<Vec<_>>(), vec![o]);
+        let game = db::game::select(game_id, &mut conn).await.unwrap().unwrap();
+        assert_eq!(game.white_player, None);
+        assert_eq!(game.black_player, Some(other));
+        let tournaments = sqlx::query!("select id as \"id!\" from tournament")
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(tournaments.iter().map(|r| r.id).collect::<Vec<_>>(), vec![other_tournament]);
+        let players = sqlx::query!("select user_id from tournament_player")
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(players.iter().map(|r| r.user_id).collect::<Vec<_>>(), vec![o]);
+        // The audit log keeps the assignment without naming the user.
+        let audit = sqlx::query!("select game_id, white_assignee, assigned_by from game_assignment_audit")
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!((audit.game_id, audit.white_assignee, audit.assigned_by), (game_id, None, None));
+    }
+}
@@ -0,0 +1,120 @@
+//! Sends mails, like the link to verify an email address.
+//!
+//! The server does not talk to a mail server itself. Mails are either posted
+//! to a webhook which sends them, or written to a file in development and
+//! tests. Without any configured channel, no mails can be sent.
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::{config::EnvironmentConfig, ServerError};
+
+static MAILERS: OnceCell<Vec<Mailer>> = OnceCell::new();
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+/// All the ways a mail can be delivered.
+#[derive(Debug)]
+enum Mailer {
+    /// Appends the mail as a JSON line to a file.
+    File(String),
+    /// Posts the mail as JSON to a url.
+    Webhook(String),
+}
+
+/// Sets up the configured channels. Call this once on startup.
+pub fn init(config: &EnvironmentConfig) {
+    let mut mailers = vec![];
+    if let Some(ref path) = config.mail_file {
+        mailers.push(Mailer::File(path.clone()));
+    }
+    if let Some(ref url) = config.mail_webhook_url {
+        mailers.push(Mailer::Webhook(url.clone()));
+    }
+    info!("Starting mails with {} channel(s).", mailers.len());
+
+    MAILERS
+        .set(mailers)
+        .expect("Error setting up the MAILERS static variable.");
+}
+
+/// False if no channel is configured, e.g. in tests.
+pub fn is_available() -> bool {
+    MAILERS.get().is_some_and(|mailers| !mailers.is_empty())
+}
+
+/// Delivers the mail on all configured channels. Fails if any of them fails.
+pub async fn send(mail: &Mail) -> Result<(), ServerError> {
+    if !is_available() {
+        return Err(ServerError::NotAllowed("Mails can't be sent.".to_string()));
+    }
+    for mailer in MAILERS.get().into_iter().flatten() {
+        mailer.deliver(mail).await?;
+    }
+    Ok(())
+}
+
+impl Mailer {
+    async fn deliver(&self, mail: &Mail) -> Result<(), ServerError> {
+        match self {
+            Mailer::File(path) => {
+                let mut line = serde_json::to_string(mail)?;
+                line.push('\n');
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.write_all(line.as_bytes()).await?;
+            }
+            Mailer::Webhook(url) => {
+                reqwest::Client::new()
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(serde_json::to_string(mail)?)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Development and tests read the mails from the file, one JSON per line.
+    #[tokio::test]
+    async fn test_file_mailer_appends_lines() {
+        let path = std::env::temp_dir().join(format!("pacosako-mails-{}.jsonl", rand::random::<u64>()));
+        let mailer = Mailer::File(path.to_string_lossy().to_string());
+        for subject in ["first", "second"] {
+            let mail = Mail {
+                to: "rolf@example.com".to_string(),
+                subject: subject.to_string(),
+                text: "Hello\nRolf".to_string(),
+            };
+            mailer.deliver(&mail).await.unwrap();
+        }
+
+        let content = tokio::fs::read_to_string(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        let mails: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(mails.len(), 2);
+        assert_eq!(mails[0]["to"], "rolf@example.com");
+        assert_eq!(mails[0]["subject"], "first");
+        assert_eq!(mails[1]["subject"], "second");
+        assert_eq!(mails[1]["text"], "Hello\nRolf");
+    }
+}
@@ -13,15 +13,28 @@ use db::Pool;
 
 use crate::actors::websocket::SocketIdManagementError;
 
+mod account_export;
 mod actors;
+mod bot;
 mod caching;
+mod challenge;
 mod config;
 mod db;
+mod event_stream;
+mod export;
+mod fair_play;
+mod follow;
 mod game;
 mod grafana;
 mod language;
 mod login;
+mod mailer;
+mod moderation;
+mod notification;
+mod player_statistics;
 mod protection;
+mod rate_limit;
+mod replay_analysis;
 mod replay_data;
 mod secret_login;
 mod server;
@@ -31,6 +44,7 @@ mod templates;
 #[cfg(test)]
 mod test;
 mod timer;
+mod tournament;
 mod ws;
 
 /// This enum holds all errors that can be returned by the API.
@@ -186,8 +200,20 @@ async fn main() {
     let pool = init_database_pool(config.clone()).await;
 
     init_new_websocket_server(pool.clone());
+    notification::init(&config, pool.clone());
+    mailer::init(&config);
+    tokio::spawn(game::search::classify_old_games(pool.clone()));
+    tokio::spawn(replay_analysis::run(pool.clone()));
+    tokio::spawn(login::session::run_cleanup(pool.clone()));
+    tokio::spawn(account_export::run_cleanup(pool.clone()));
+    if let Some(model_url) = config.fair_play_model_url.clone() {
+        tokio::spawn(fair_play::run(pool.clone(), model_url));
+    }
 
     let state = AppState { config, pool };
 
     server::run(state).await;
+
+    info!("Server stopped.");
+    log::logger().flush();
 }
@@ -0,0 +1,683 @@
+//! Moderation: users report players and games, moderators act on it.
+//!
+//! Moderators can ban and mute users, annul games and change the players of a
+//! game. Everything a moderator does is written to `moderation_audit`.
+//!
+//! Bans are enforced where sessions and API tokens are loaded, so a banned user
+//! is logged out everywhere, including their websockets. Anonymous play is not
+//! tied to an account, so sides locked to a uuid are not affected. Mutes are
+//! recorded and shown to the user, there is no chat they would apply to yet.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use lazy_static::lazy_static;
+use pacosako::PlayerColor;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::{
+    db::{self, Connection, Pool},
+    login::{
+        permission::{self, ModerateGames, ModerateUsers, RequirePermission},
+        session::SessionData,
+        UserId,
+    },
+    player_statistics,
+    rate_limit::RateLimiter,
+    ws, AppState, ServerError,
+};
+
+/// Longer report descriptions are rejected.
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+
+/// Lists return at most this many entries.
+const LIST_LIMIT: i64 = 200;
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+lazy_static! {
+    /// Reports sent by the same user.
+    static ref REPORTS_PER_USER: RateLimiter = RateLimiter::new(10, HOUR);
+}
+
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router
+        .route("/report", post(create_report))
+        .route("/me/sanctions", get(my_sanctions))
+        .route("/moderation/reports", get(list_reports))
+        .route("/moderation/reports/:id", post(handle_report))
+        .route(
+            "/moderation/users/:user_id/sanctions",
+            get(list_sanctions).post(create_sanction),
+        )
+        .route("/moderation/sanctions/:id", delete(lift_sanction))
+        .route(
+            "/moderation/games/:game_id/annul",
+            post(annul_game).delete(restore_game),
+        )
+        .route("/moderation/games/:game_id/players", post(set_player))
+        .route("/moderation/audit", get(list_audit))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SanctionKind {
+    /// The user can't log in or use the API.
+    Ban,
+    /// The user can't write messages.
+    Mute,
+}
+
+impl SanctionKind {
+    fn name(self) -> &'static str {
+        match self {
+            SanctionKind::Ban => "ban",
+            SanctionKind::Mute => "mute",
+        }
+    }
+}
+
+/// Checks if the user has an active ban.
+pub async fn is_banned(user_id: UserId, conn: &mut Connection) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r"select exists (
+            select 1 from user_sanction
+            where user_id = ? and kind = 'ban' and lifted_at is null
+            and (expires_at is null or expires_at > CURRENT_TIMESTAMP)
+        ) as banned",
+        user_id.0
+    )
+        .fetch_one(conn)
+        .await?;
+    Ok(result.banned.is_positive())
+}
+
+/// What a moderator did, for the audit trail.
+pub struct AuditEntry<'a> {
+    pub action: &'a str,
+    pub user_id: Option<i64>,
+    pub game_id: Option<i64>,
+    pub report_id: Option<i64>,
+    pub details: Option<serde_json::Value>,
+    pub reason: Option<&'a str>,
+}
+
+pub async fn write_audit(
+    moderator: UserId,
+    entry: AuditEntry<'_>,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let details = entry.details.map(|d| d.to_string());
+    sqlx::query!(
+        r"insert into moderation_audit (moderator_id, action, user_id, game_id, report_id, details, reason)
+        values (?, ?, ?, ?, ?, ?, ?)",
+        moderator.0,
+        entry.action,
+        entry.user_id,
+        entry.game_id,
+        entry.report_id,
+        details,
+        entry.reason
+    )
+        .execute(conn)
+        .await?;
+    info!(
+        "Moderator {} did {} (user {:?}, game {:?}, report {:?}).",
+        moderator.0, entry.action, entry.user_id, entry.game_id, entry.report_id
+    );
+    Ok(())
+}
+
+pub async fn user_exists(user_id: UserId, conn: &mut Connection) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!("select id from user where id = ?", user_id.0)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row.is_some())
+}
+
+// Reports /////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ReportCategory {
+    Cheating,
+    Abuse,
+    Stalling,
+    Other,
+}
+
+impl ReportCategory {
+    fn name(self) -> &'static str {
+        match self {
+            ReportCategory::Cheating => "cheating",
+            ReportCategory::Abuse => "abuse",
+            ReportCategory::Stalling => "stalling",
+            ReportCategory::Other => "other",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ReportRequest {
+    user_id: Option<UserId>,
+    game_id: Option<i64>,
+    category: ReportCategory,
+    description: String,
+}
+
+/// Checks a report before it is stored. Returns the reason for rejecting it.
+fn validate_report(request: &ReportRequest, reported_by: UserId) -> Result<(), &'static str> {
+    if request.user_id.is_none() && request.game_id.is_none() {
+        return Err("Report a player, a game or both.");
+    }
+    if request.user_id == Some(reported_by) {
+        return Err("You can't report yourself.");
+    }
+    let description = request.description.trim();
+    if description.is_empty() {
+        return Err("Please describe what happened.");
+    }
+    if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err("The description is too long.");
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ReportCreated {
+    id: i64,
+}
+
+/// POST /api/report lets logged-in users report a player or a game.
+async fn create_report(
+    session: SessionData,
+    State(pool): State<Pool>,
+    Json(request): Json<ReportRequest>,
+) -> Result<Result<Json<ReportCreated>, (StatusCode, &'static str)>, ServerError> {
+    if let Err(problem) = validate_report(&request, session.user_id) {
+        return Ok(Err((StatusCode::BAD_REQUEST, problem)));
+    }
+    if !REPORTS_PER_USER.try_acquire(&session.user_id.0.to_string()) {
+        return Ok(Err((StatusCode::TOO_MANY_REQUESTS, "You sent too many reports.")));
+    }
+
+    let mut conn = pool.conn().await?;
+    if let Some(user_id) = request.user_id {
+        if !user_exists(user_id, &mut conn).await? {
+            return Err(ServerError::NotFound);
+        }
+    }
+    if let Some(game_id) = request.game_id {
+        if db::game::select(game_id, &mut conn).await?.is_none() {
+            return Err(ServerError::NotFound);
+        }
+    }
+
+    let user_id = request.user_id.map(|u| u.0);
+    let category = request.category.name();
+    let description = request.description.trim();
+    let result = sqlx::query!(
+        r"insert into report (reported_by, user_id, game_id, category, description)
+        values (?, ?, ?, ?, ?)",
+        session.user_id.0,
+        user_id,
+        request.game_id,
+        category,
+        description
+    )
+        .execute(&mut *conn)
+        .await?;
+    Ok(Ok(Json(ReportCreated {
+        id: result.last_insert_rowid(),
+    })))
+}
+
+#[derive(Serialize)]
+struct Report {
+    id: i64,
+    reported_by: i64,
+    user_id: Option<i64>,
+    game_id: Option<i64>,
+    category: String,
+    description: String,
+    created_at: Option<String>,
+    status: String,
+    handled_by: Option<i64>,
+    handled_at: Option<String>,
+    resolution: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReportListQuery {
+    status: Option<String>,
+}
+
+/// GET /api/moderation/reports lists open reports, oldest first. Use
+/// `?status=resolved` or `?status=dismissed` for handled ones.
+async fn list_reports(
+    _: RequirePermission<ModerateUsers>,
+    Query(query): Query<ReportListQuery>,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<Report>>, ServerError> {
+    let status = query.status.unwrap_or_else(|| "open".to_string());
+    let mut conn = pool.conn().await?;
+    let reports = sqlx::query_as!(
+        Report,
+        r#"select id as "id!", reported_by, user_id, game_id, category, description,
+        created_at as "created_at: String", status, handled_by,
+        handled_at as "handled_at: String", resolution
+        from report where status = ? order by id limit ?"#,
+        status,
+        LIST_LIMIT
+    )
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(Json(reports))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ReportOutcome {
+    Resolved,
+    Dismissed,
+}
+
+#[derive(Deserialize)]
+struct HandleReportRequest {
+    status: ReportOutcome,
+    resolution: Option<String>,
+}
+
+/// POST /api/moderation/reports/:id closes an open report.
+async fn handle_report(
+    RequirePermission { session, .. }: RequirePermission<ModerateUsers>,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+    Json(request): Json<HandleReportRequest>,
+) -> Result<(), ServerError> {
+    let status = match request.status {
+        ReportOutcome::Resolved => "resolved",
+        ReportOutcome::Dismissed => "dismissed",
+    };
+    let mut conn = pool.conn().await?;
+    let result = sqlx::query!(
+        r"update report set status = ?, handled_by = ?, handled_at = CURRENT_TIMESTAMP, resolution = ?
+        where id = ? and status = 'open'",
+        status,
+        session.user_id.0,
+        request.resolution,
+        id
+    )
+        .execute(&mut *conn)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotFound);
+    }
+
+    let entry = AuditEntry {
+        action: "handle_report",
+        user_id: None,
+        game_id: None,
+        report_id: Some(id),
+        details: Some(serde_json::json!({ "status": status })),
+        reason: request.resolution.as_deref(),
+    };
+    write_audit(session.user_id, entry, &mut conn).await
+}
+
+// Sanctions ///////////////////////////////////////////////////////////////////
+
+#[derive(Serialize)]
+struct Sanction {
+    id: i64,
+    user_id: i64,
+    kind: String,
+    reason: String,
+    created_by: i64,
+    created_at: Option<String>,
+    expires_at: Option<String>,
+    lifted_at: Option<String>,
+    lifted_by: Option<i64>,
+}
+
+/// GET /api/me/sanctions shows the logged-in user their active sanctions.
+async fn my_sanctions(
+    session: SessionData,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<Sanction>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let sanctions = sqlx::query_as!(
+        Sanction,
+        r#"select id as "id!", user_id, kind, reason, created_by,
+        created_at as "created_at: String", expires_at as "expires_at: String",
+        lifted_at as "lifted_at: String", lifted_by
+        from user_sanction
+        where user_id = ? and lifted_at is null
+        and (expires_at is null or expires_at > CURRENT_TIMESTAMP)
+        order by id"#,
+        session.user_id.0
+    )
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(Json(sanctions))
+}
+
+/// GET /api/moderation/users/:user_id/sanctions lists all sanctions of a
+/// user, including lifted and expired ones.
+async fn list_sanctions(
+    _: RequirePermission<ModerateUsers>,
+    Path(user_id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<Sanction>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let sanctions = sqlx::query_as!(
+        Sanction,
+        r#"select id as "id!", user_id, kind, reason, created_by,
+        created_at as "created_at: String", expires_at as "expires_at: String",
+        lifted_at as "lifted_at: String", lifted_by
+        from user_sanction where user_id = ? order by id desc"#,
+        user_id
+    )
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(Json(sanctions))
+}
+
+#[derive(Deserialize)]
+struct SanctionRequest {
+    kind: SanctionKind,
+    reason: String,
+    /// Leave out for a permanent sanction.
+    duration_hours: Option<u32>,
+}
+
+/// POST /api/moderation/users/:user_id/sanctions bans or mutes a user.
+/// Moderators can't sanction each other, an admin has to take away the role
+/// first.
+async fn create_sanction(
+    RequirePermission { session, .. }: RequirePermission<ModerateUsers>,
+    Path(user_id): Path<i64>,
+    State(pool): State<Pool>,
+    Json(request): Json<SanctionRequest>,
+) -> Result<Json<i64>, ServerError> {
+    let user_id = UserId(user_id);
+    let reason = request.reason.trim();
+    if reason.is_empty() || request.duration_hours == Some(0) {
+        return Err(ServerError::BadRequest);
+    }
+    let mut conn = pool.conn().await?;
+    if !user_exists(user_id, &mut conn).await? {
+        return Err(ServerError::NotFound);
+    }
+    if permission::is_allowed(user_id, permission::MODERATE_USERS, &mut conn).await? {
+        return Err(ServerError::NotAllowed(
+            "Moderators can't be sanctioned.".to_string(),
+        ));
+    }
+
+    let kind = request.kind.name();
+    let duration = request.duration_hours.map(|hours| format!("+{} hours", hours));
+    let result = sqlx::query!(
+        r"insert into user_sanction (user_id, kind, reason, created_by, expires_at)
+        values (?, ?, ?, ?, datetime(CURRENT_TIMESTAMP, ?))",
+        user_id.0,
+        kind,
+        reason,
+        session.user_id.0,
+        duration
+    )
+        .execute(&mut *conn)
+        .await?;
+    let id = result.last_insert_rowid();
+
+    let entry = AuditEntry {
+        action: kind,
+        user_id: Some(user_id.0),
+        game_id: None,
+        report_id: None,
+        details: Some(serde_json::json!({
+            "sanction_id": id,
+            "duration_hours": request.duration_hours,
+        })),
+        reason: Some(reason),
+    };
+    write_audit(session.user_id, entry, &mut conn).await?;
+    Ok(Json(id))
+}
+
+#[derive(Deserialize)]
+struct ReasonQuery {
+    reason: Option<String>,
+}
+
+/// DELETE /api/moderation/sanctions/:id lifts a sanction before it expires.
+async fn lift_sanction(
+    RequirePermission { session, .. }: RequirePermission<ModerateUsers>,
+    Path(id): Path<i64>,
+    Query(query): Query<ReasonQuery>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let Some(sanction) = sqlx::query!(
+        "select user_id, kind from user_sanction where id = ? and lifted_at is null",
+        id
+    )
+        .fetch_optional(&mut *conn)
+        .await?
+    else {
+        return Err(ServerError::NotFound);
+    };
+    sqlx::query!(
+        "update user_sanction set lifted_at = CURRENT_TIMESTAMP, lifted_by = ? where id = ?",
+        session.user_id.0,
+        id
+    )
+        .execute(&mut *conn)
+        .await?;
+
+    let action = format!("lift_{}", sanction.kind);
+    let entry = AuditEntry {
+        action: &action,
+        user_id: Some(sanction.user_id),
+        game_id: None,
+        report_id: None,
+        details: Some(serde_json::json!({ "sanction_id": id })),
+        reason: query.reason.as_deref(),
+    };
+    write_audit(session.user_id, entry, &mut conn).await
+}
+
+// Games ///////////////////////////////////////////////////////////////////////
+
+#[derive(Deserialize)]
+struct ReasonRequest {
+    reason: Option<String>,
+}
+
+/// POST /api/moderation/games/:game_id/annul takes a game out of the player
+/// statistics.
+async fn annul_game(
+    RequirePermission { session, .. }: RequirePermission<ModerateGames>,
+    Path(game_id): Path<i64>,
+    State(pool): State<Pool>,
+    Json(request): Json<ReasonRequest>,
+) -> Result<(), ServerError> {
+    set_annulled(session.user_id, game_id, true, request.reason.as_deref(), &pool).await
+}
+
+/// DELETE /api/moderation/games/:game_id/annul counts the game again.
+async fn restore_game(
+    RequirePermission { session, .. }: RequirePermission<ModerateGames>,
+    Path(game_id): Path<i64>,
+    Query(query): Query<ReasonQuery>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    set_annulled(session.user_id, game_id, false, query.reason.as_deref(), &pool).await
+}
+
+async fn set_annulled(
+    moderator: UserId,
+    game_id: i64,
+    annulled: bool,
+    reason: Option<&str>,
+    pool: &Pool,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let Some(game) = db::game::select(game_id, &mut conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    db::game::set_annulled(game_id, annulled, &mut conn).await?;
+    player_statistics::invalidate(&game);
+
+    let entry = AuditEntry {
+        action: if annulled { "annul" } else { "restore" },
+        user_id: None,
+        game_id: Some(game_id),
+        report_id: None,
+        details: None,
+        reason,
+    };
+    write_audit(moderator, entry, &mut conn).await
+}
+
+#[derive(Deserialize)]
+struct SetPlayerRequest {
+    color: PlayerColor,
+    /// Leave out to remove the player from this side.
+    user_id: Option<UserId>,
+    reason: Option<String>,
+}
+
+/// POST /api/moderation/games/:game_id/players changes or removes the player
+/// of one side. This also works for running games, the new player can move
+/// right away.
+async fn set_player(
+    RequirePermission { session, .. }: RequirePermission<ModerateGames>,
+    Path(game_id): Path<i64>,
+    State(pool): State<Pool>,
+    Json(request): Json<SetPlayerRequest>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let Some(game) = db::game::select(game_id, &mut conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    if let Some(user_id) = request.user_id {
+        if !user_exists(user_id, &mut conn).await? {
+            return Err(ServerError::NotFound);
+        }
+    }
+    let previous = game.player(request.color);
+
+    let (done, result) = oneshot::channel();
+    ws::to_logic(ws::LogicMsg::SetPlayer {
+        key: game.key.clone(),
+        color: request.color,
+        user_id: request.user_id,
+        done,
+    })
+        .await;
+    match result.await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            return Err(ServerError::Other(anyhow::anyhow!(
+                "Could not change the player of game {}: {}",
+                game_id,
+                e
+            )));
+        }
+        Err(_) => return Err(ServerError::NotFound),
+    }
+
+    let entry = AuditEntry {
+        action: "set_player",
+        user_id: request.user_id.or(previous).map(|u| u.0),
+        game_id: Some(game_id),
+        report_id: None,
+        details: Some(serde_json::json!({
+            "color": request.color,
+            "previous": previous,
+            "new": request.user_id,
+        })),
+        reason: request.reason.as_deref(),
+    };
+    write_audit(session.user_id, entry, &mut conn).await
+}
+
+// Audit ///////////////////////////////////////////////////////////////////////
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    user_id: Option<i64>,
+    game_id: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AuditRecord {
+    id: i64,
+    moderator_id: i64,
+    action: String,
+    user_id: Option<i64>,
+    game_id: Option<i64>,
+    report_id: Option<i64>,
+    details: Option<String>,
+    reason: Option<String>,
+    created_at: Option<String>,
+}
+
+/// GET /api/moderation/audit shows what moderators did, newest first.
+/// Filter with `?user_id=` or `?game_id=`.
+async fn list_audit(
+    _: RequirePermission<ModerateUsers>,
+    Query(query): Query<AuditQuery>,
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<AuditRecord>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let records = sqlx::query_as!(
+        AuditRecord,
+        r#"select id as "id!", moderator_id, action, user_id, game_id, report_id, details, reason,
+        created_at as "created_at: String"
+        from moderation_audit
+        where (? is null or user_id = ?) and (? is null or game_id = ?)
+        order by id desc limit ?"#,
+        query.user_id,
+        query.user_id,
+        query.game_id,
+        query.game_id,
+        LIST_LIMIT
+    )
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(Json(records))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn report(user_id: Option<i64>, game_id: Option<i64>, description: &str) -> ReportRequest {
+        ReportRequest {
+            user_id: user_id.map(UserId),
+            game_id,
+            category: ReportCategory::Other,
+            description: description.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_validate_report() {
+        let me = UserId(1);
+        assert!(validate_report(&report(Some(2), None, "Rude."), me).is_ok());
+        assert!(validate_report(&report(None, Some(7), "Engine moves."), me).is_ok());
+        assert!(validate_report(&report(None, None, "Something."), me).is_err());
+        assert!(validate_report(&report(Some(1), Some(7), "Me."), me).is_err());
+        assert!(validate_report(&report(Some(2), None, "   "), me).is_err());
+        let long = "a".repeat(MAX_DESCRIPTION_LENGTH + 1);
+        assert!(validate_report(&report(Some(2), None, &long), me).is_err());
+    }
+}
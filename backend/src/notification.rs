@@ -0,0 +1,369 @@
+//! Notifications for correspondence games.
+//!
+//! In correspondence games, players are not expected to watch the game all the
+//! time. So we tell them when it becomes their turn and when the time for their
+//! turn is about to run out.
+//!
+//! Anyone can queue a notification with `send`. A background task delivers
+//! them on all configured channels. Each notification is recorded in the
+//! `notification_log` table, which makes sure it is only delivered once.
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    config::EnvironmentConfig,
+    db::{Connection, Pool},
+    login::UserId,
+    ServerError,
+};
+
+static NOTIFICATIONS: OnceCell<UnboundedSender<Notification>> = OnceCell::new();
+
+/// What the notification is about.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    YourTurn,
+    DeadlineApproaching,
+}
+
+impl NotificationKind {
+    /// Name of the kind in the `notification_log` table.
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::YourTurn => "your_turn",
+            Self::DeadlineApproaching => "deadline_approaching",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    pub game_key: String,
+    pub user_id: UserId,
+    /// Number of actions in the game. Identifies the turn.
+    pub action_count: i64,
+    /// When the time for the turn runs out, if there is a deadline.
+    pub deadline: Option<DateTime<Utc>>,
+}
+
+impl Notification {
+    /// A human readable version of the notification.
+    fn text(&self, server_url: &str) -> String {
+        let link = format!("{}/game/{}", server_url, self.game_key);
+        match (self.kind, self.deadline) {
+            (NotificationKind::YourTurn, _) => format!("It is your turn: {link}"),
+            (NotificationKind::DeadlineApproaching, Some(deadline)) => format!(
+                "Your time to move runs out at {} UTC: {link}",
+                deadline.format("%Y-%m-%d %H:%M")
+            ),
+            (NotificationKind::DeadlineApproaching, None) => {
+                format!("Your time to move runs out soon: {link}")
+            }
+        }
+    }
+}
+
+/// All the ways a notification can be delivered.
+enum Notifier {
+    /// Appends the notification as a JSON line to a file.
+    File(String),
+    /// Posts the notification as JSON to a url.
+    Webhook(String),
+    /// Sends a direct message on Discord to players who logged in with Discord.
+    Discord { bot_token: String },
+}
+
+/// Starts the background task which delivers notifications.
+pub fn init(config: &EnvironmentConfig, pool: Pool) {
+    let mut notifiers = vec![];
+    if let Some(ref path) = config.notification_file {
+        notifiers.push(Notifier::File(path.clone()));
+    }
+    if let Some(ref url) = config.notification_webhook_url {
+        notifiers.push(Notifier::Webhook(url.clone()));
+    }
+    if let Some(ref bot_token) = config.discord_bot_token {
+        notifiers.push(Notifier::Discord {
+            bot_token: bot_token.clone(),
+        });
+    }
+    info!("Starting notifications with {} channel(s).", notifiers.len());
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    NOTIFICATIONS
+        .set(sender)
+        .expect("Error setting up the NOTIFICATIONS static variable.");
+
+    let client = reqwest::Client::new();
+    tokio::spawn(run(receiver, notifiers, client, config.server_url.clone(), pool));
+}
+
+/// Queues a notification. This never blocks, so it is safe to call from the
+/// game logic.
+pub fn send(notification: Notification) {
+    let Some(sender) = NOTIFICATIONS.get() else {
+        // Notifications are not set up, e.g. in tests.
+        return;
+    };
+    if let Err(e) = sender.send(notification) {
+        warn!("Could not queue notification: {:?}", e.0);
+    }
+}
+
+async fn run(
+    mut receiver: UnboundedReceiver<Notification>,
+    notifiers: Vec<Notifier>,
+    client: reqwest::Client,
+    server_url: String,
+    pool: Pool,
+) {
+    while let Some(notification) = receiver.recv().await {
+        if let Err(e) = handle(&notification, &notifiers, &client, &server_url, &pool).await {
+            warn!("Could not send notification {:?}: {:?}", notification, e);
+        }
+    }
+}
+
+async fn handle(
+    notification: &Notification,
+    notifiers: &[Notifier],
+    client: &reqwest::Client,
+    server_url: &str,
+    pool: &Pool,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    if !log_once(notification, &mut conn).await? {
+        // We already notified about this turn.
+        return Ok(());
+    }
+
+    let text = notification.text(server_url);
+    for notifier in notifiers {
+        if let Err(e) = deliver(notifier, notification, &text, client, &mut conn).await {
+            warn!("Could not deliver notification: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Records the notification. Returns false if it was already recorded before.
+async fn log_once(notification: &Notification, conn: &mut Connection) -> Result<bool, ServerError> {
+    let game_id: i64 = notification.game_key.parse()?;
+    let kind = notification.kind.as_str();
+    let rows_affected = sqlx::query!(
+        r"insert or ignore into notification_log (game_id, user_id, kind, action_count)
+        values (?, ?, ?, ?)",
+        game_id,
+        notification.user_id.0,
+        kind,
+        notification.action_count
+    )
+        .execute(conn)
+        .await?
+        .rows_affected();
+    Ok(rows_affected > 0)
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    #[serde(flatten)]
+    notification: &'a Notification,
+    text: &'a str,
+}
+
+async fn deliver(
+    notifier: &Notifier,
+    notification: &Notification,
+    text: &str,
+    client: &reqwest::Client,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let payload = WebhookPayload { notification, text };
+    match notifier {
+        Notifier::File(path) => {
+            let mut line = serde_json::to_string(&payload)?;
+            line.push('\n');
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(line.as_bytes()).await?;
+        }
+        Notifier::Webhook(url) => {
+            client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(&payload)?)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        Notifier::Discord { bot_token } => {
+            if let Some(discord_id) = discord_id(notification.user_id, conn).await? {
+                send_discord_dm(client, bot_token, &discord_id, text).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The Discord user id of the user, if they log in with Discord.
+async fn discord_id(user_id: UserId, conn: &mut Connection) -> Result<Option<String>, ServerError> {
+    let row = sqlx::query!(
+        "SELECT identifier FROM login WHERE type = 'discord' AND user_id = ?",
+        user_id.0
+    )
+        .fetch_optional(conn)
+        .await?;
+    Ok(row.map(|r| r.identifier))
+}
+
+#[derive(Serialize)]
+struct CreateDmBody<'a> {
+    recipient_id: &'a str,
+}
+
+#[derive(Deserialize)]
+struct DmChannel {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct MessageBody<'a> {
+    content: &'a str,
+}
+
+/// To send a direct message, the bot first opens a DM channel with the user.
+/// Discord returns the existing channel if there already is one.
+async fn send_discord_dm(
+    client: &reqwest::Client,
+    bot_token: &str,
+    discord_id: &str,
+    text: &str,
+) -> Result<(), ServerError> {
+    let auth = format!("Bot {bot_token}");
+
+    let response = client
+        .post("https://discord.com/api/v10/users/@me/channels")
+        .header(reqwest::header::AUTHORIZATION, &auth)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(&CreateDmBody {
+            recipient_id: discord_id,
+        })?)
+        .send()
+        .await?
+        .error_for_status()?;
+    let channel: DmChannel = serde_json::from_str(&response.text().await?)?;
+
+    client
+        .post(format!(
+            "https://discord.com/api/v10/channels/{}/messages",
+            channel.id
+        ))
+        .header(reqwest::header::AUTHORIZATION, &auth)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(&MessageBody { content: text })?)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{routing::post, Json, Router};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::db;
+    use crate::login::user::create_user;
+    use crate::sync_match::SynchronizedMatch;
+
+    /// A local webhook which remembers everything posted to it.
+    async fn mock_webhook() -> (String, Arc<Mutex<Vec<Value>>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let app = Router::new().route(
+            "/hook",
+            post({
+                let received = received.clone();
+                move |Json(body): Json<Value>| async move {
+                    received.lock().unwrap().push(body);
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn test_notification_is_delivered_once() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let user_id = create_user("rolf", "identicon:1", &mut conn).await.unwrap();
+        let mut game = SynchronizedMatch {
+            key: String::new(),
+            actions: vec![],
+            timer: None,
+            setup_options: Default::default(),
+            white_player: Some(user_id),
+            black_player: None,
+        };
+        db::game::insert(&mut game, &mut conn).await.unwrap();
+        drop(conn);
+
+        let (url, received) = mock_webhook().await;
+        let path = std::env::temp_dir().join(format!("notifications-{}.jsonl", game.key));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        let notifiers = vec![Notifier::Webhook(url), Notifier::File(path.clone())];
+        let client = reqwest::Client::new();
+        let notification = Notification {
+            kind: NotificationKind::YourTurn,
+            game_key: game.key.clone(),
+            user_id,
+            action_count: 2,
+            deadline: None,
+        };
+
+        for _ in 0..2 {
+            handle(&notification, &notifiers, &client, "https://example.com", &pool)
+                .await
+                .unwrap();
+        }
+
+        let text = format!("It is your turn: https://example.com/game/{}", game.key);
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0]["kind"], "your_turn");
+            assert_eq!(received[0]["text"], text.as_str());
+        }
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lines.lines().count(), 1);
+        let line: Value = serde_json::from_str(lines.trim()).unwrap();
+        assert_eq!(line["game_key"], game.key.as_str());
+
+        // The next turn is a new notification.
+        let next_turn = Notification {
+            action_count: 4,
+            ..notification
+        };
+        handle(&next_turn, &notifiers[..1], &client, "https://example.com", &pool)
+            .await
+            .unwrap();
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,411 @@
+//! Statistics of a player over all their finished games: results by color and
+//! time control, openings, game length, missed Pacos and results against AIs.
+//!
+//! Computing them replays every game of the player, so they are cached until
+//! one of the player's games finishes. Only the most recently requested players
+//! are kept.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use axum::{
+    extract::{Path, State},
+    routing::get,
+    Json, Router,
+};
+use cached::{Cached, SizedCache};
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use pacosako::analysis::incremental_replay::history_to_replay_notation_without_paco_in_2;
+use pacosako::{DenseBoard, PacoAction, PlayerColor, VictoryState};
+
+use crate::db::{self, game::StatisticsRecord, Pool};
+use crate::login::user::load_public_user_data;
+use crate::login::UserId;
+use crate::sync_match::SynchronizedMatch;
+use crate::timer::{ClockMode, TimerConfig};
+use crate::{AppState, ServerError};
+
+/// Only the most played openings are listed.
+const MAX_OPENINGS: usize = 10;
+
+/// Upper limit for the number of players whose statistics are cached.
+const CACHE_SIZE: usize = 1000;
+
+lazy_static! {
+    static ref CACHE: Mutex<SizedCache<UserId, PlayerStatistics>> =
+        Mutex::new(SizedCache::with_size(CACHE_SIZE));
+}
+
+/// Increased whenever the cache is invalidated. Statistics which were computed
+/// while a game finished may miss that game, so they are not cached.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Adds the statistics API to the given router.
+/// This is expected to be nested at "/api".
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router.route("/user/:user_id/statistics", get(get_statistics))
+}
+
+#[derive(Serialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct ResultCount {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PlayerOutcome {
+    Win,
+    Draw,
+    Loss,
+}
+
+impl PlayerOutcome {
+    /// None while the game is running.
+    fn new(victory_state: VictoryState, color: PlayerColor) -> Option<Self> {
+        match victory_state {
+            VictoryState::Running => None,
+            VictoryState::PacoVictory(winner) | VictoryState::TimeoutVictory(winner) => {
+                Some(if winner == color { Self::Win } else { Self::Loss })
+            }
+            VictoryState::NoProgressDraw | VictoryState::RepetitionDraw => Some(Self::Draw),
+        }
+    }
+}
+
+impl ResultCount {
+    fn add(&mut self, outcome: PlayerOutcome) {
+        match outcome {
+            PlayerOutcome::Win => self.wins += 1,
+            PlayerOutcome::Draw => self.draws += 1,
+            PlayerOutcome::Loss => self.losses += 1,
+        }
+    }
+
+    fn total(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+}
+
+/// Same categories as the "Create game" dialog. The limits apply to the
+/// `expected_time_limit` of the game: both budgets and 40 increments. A fixed
+/// time per move is given for each of 40 moves instead.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeControl {
+    Lightspeed,
+    Blitz,
+    Rapid,
+    Classical,
+    Correspondence,
+    Untimed,
+}
+
+impl TimeControl {
+    fn of(timer: Option<&TimerConfig>) -> Self {
+        let Some(timer) = timer else {
+            return Self::Untimed;
+        };
+        if timer.is_correspondence() {
+            return Self::Correspondence;
+        }
+        let expected = if timer.mode == ClockMode::FixedPerMove {
+            (timer.time_budget_white + timer.time_budget_black) * 40
+        } else {
+            let increment = timer.increment.unwrap_or_else(chrono::Duration::zero);
+            timer.time_budget_white + timer.time_budget_black + increment * 40
+        };
+        match expected.num_minutes() {
+            ..=9 => Self::Lightspeed,
+            10..=19 => Self::Blitz,
+            20..=119 => Self::Rapid,
+            _ => Self::Classical,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct OpeningStatistics {
+    pub opening: String,
+    pub results: ResultCount,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct AiStatistics {
+    pub model_name: String,
+    pub results: ResultCount,
+}
+
+#[derive(Serialize, Clone, Default, Debug, PartialEq)]
+pub struct PlayerStatistics {
+    /// Finished games. Games where the player played both sides don't count.
+    pub games: u32,
+    pub results: ResultCount,
+    pub as_white: ResultCount,
+    pub as_black: ResultCount,
+    pub by_time_control: BTreeMap<TimeControl, ResultCount>,
+    /// Most played first.
+    pub openings: Vec<OpeningStatistics>,
+    pub average_half_moves: f32,
+    /// Half-moves where the player could have won with Paco, but didn't.
+    pub missed_paco: u32,
+    pub against_ai: Vec<AiStatistics>,
+}
+
+/// What the statistics need to know about a single game.
+#[derive(Clone, Debug)]
+struct GameSummary {
+    color: PlayerColor,
+    outcome: PlayerOutcome,
+    time_control: TimeControl,
+    openings: Vec<String>,
+    half_moves: u32,
+    missed_paco: u32,
+    opponent_ai: Option<String>,
+}
+
+impl GameSummary {
+    /// None if the game does not count, because it is running or the player
+    /// played both sides.
+    fn new(user_id: UserId, record: StatisticsRecord) -> Result<Option<Self>, ServerError> {
+        let game: &SynchronizedMatch = &record.game;
+        let color = match (game.white_player == Some(user_id), game.black_player == Some(user_id)) {
+            (true, false) => PlayerColor::White,
+            (false, true) => PlayerColor::Black,
+            _ => return Ok(None),
+        };
+        let Some(outcome) = PlayerOutcome::new(game.current_state()?.victory_state, color) else {
+            return Ok(None);
+        };
+
+        let actions: Vec<PacoAction> = game.actions.iter().map(PacoAction::from).collect();
+        let initial_board = DenseBoard::with_options(&game.setup_options)?;
+        let replay = history_to_replay_notation_without_paco_in_2(&initial_board, &actions)?;
+        let missed_paco = replay
+            .notation()
+            .iter()
+            .filter(|half_move| half_move.current_player() == color && half_move.metadata().missed_paco())
+            .count() as u32;
+        let opening = record
+            .opening
+            .unwrap_or_else(|| replay.opening().to_string());
+
+        Ok(Some(Self {
+            color,
+            outcome,
+            time_control: TimeControl::of(game.timer.as_ref().map(|t| &t.config)),
+            openings: opening
+                .split(", ")
+                .filter(|o| !o.is_empty())
+                .map(str::to_string)
+                .collect(),
+            half_moves: replay.notation().len() as u32,
+            missed_paco,
+            opponent_ai: match color {
+                PlayerColor::White => record.black_ai,
+                PlayerColor::Black => record.white_ai,
+            },
+        }))
+    }
+}
+
+impl PlayerStatistics {
+    fn from_summaries(summaries: &[GameSummary]) -> Self {
+        let mut statistics = PlayerStatistics::default();
+        let mut openings: HashMap<&str, ResultCount> = HashMap::new();
+        let mut against_ai: HashMap<&str, ResultCount> = HashMap::new();
+        let mut half_moves = 0;
+
+        for game in summaries {
+            statistics.games += 1;
+            statistics.results.add(game.outcome);
+            match game.color {
+                PlayerColor::White => statistics.as_white.add(game.outcome),
+                PlayerColor::Black => statistics.as_black.add(game.outcome),
+            }
+            statistics
+                .by_time_control
+                .entry(game.time_control)
+                .or_default()
+                .add(game.outcome);
+            for opening in &game.openings {
+                openings.entry(opening).or_default().add(game.outcome);
+            }
+            if let Some(model_name) = &game.opponent_ai {
+                against_ai.entry(model_name).or_default().add(game.outcome);
+            }
+            half_moves += game.half_moves;
+            statistics.missed_paco += game.missed_paco;
+        }
+
+        if statistics.games > 0 {
+            statistics.average_half_moves = half_moves as f32 / statistics.games as f32;
+        }
+
+        let mut openings: Vec<OpeningStatistics> = openings
+            .into_iter()
+            .map(|(opening, results)| OpeningStatistics {
+                opening: opening.to_string(),
+                results,
+            })
+            .collect();
+        openings.sort_by(|a, b| {
+            b.results
+                .total()
+                .cmp(&a.results.total())
+                .then_with(|| a.opening.cmp(&b.opening))
+        });
+        openings.truncate(MAX_OPENINGS);
+        statistics.openings = openings;
+
+        let mut against_ai: Vec<AiStatistics> = against_ai
+            .into_iter()
+            .map(|(model_name, results)| AiStatistics {
+                model_name: model_name.to_string(),
+                results,
+            })
+            .collect();
+        against_ai.sort_by(|a, b| a.model_name.cmp(&b.model_name));
+        statistics.against_ai = against_ai;
+
+        statistics
+    }
+}
+
+/// Forgets the statistics of both players. Call this whenever a game is over.
+pub fn invalidate(game: &SynchronizedMatch) {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    let mut cache = CACHE.lock().unwrap();
+    for player in [game.white_player, game.black_player].into_iter().flatten() {
+        cache.cache_remove(&player);
+    }
+}
+
+/// Forgets the statistics of a user who got games without finishing them,
+/// e.g. by claiming them.
+pub fn invalidate_user(user_id: UserId) {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    CACHE.lock().unwrap().cache_remove(&user_id);
+}
+
+pub async fn load(user_id: UserId, pool: &Pool) -> Result<PlayerStatistics, ServerError> {
+    if let Some(statistics) = CACHE.lock().unwrap().cache_get(&user_id) {
+        return Ok(statistics.clone());
+    }
+
+    let generation = GENERATION.load(Ordering::SeqCst);
+    let records = {
+        let mut conn = pool.conn().await?;
+        db::game::for_player_statistics(user_id.0, &mut conn).await?
+    };
+    // Replaying all games takes a while, this must not block other requests.
+    let statistics = tokio::task::spawn_blocking(move || {
+        let mut summaries = Vec::with_capacity(records.len());
+        for record in records {
+            let key = record.game.key.clone();
+            match GameSummary::new(user_id, record) {
+                Ok(Some(summary)) => summaries.push(summary),
+                Ok(None) => {}
+                Err(e) => warn!("Game {} is missing from the statistics: {:?}", key, e),
+            }
+        }
+        PlayerStatistics::from_summaries(&summaries)
+    })
+        .await
+        .map_err(anyhow::Error::from)?;
+
+    if GENERATION.load(Ordering::SeqCst) == generation {
+        CACHE.lock().unwrap().cache_set(user_id, statistics.clone());
+    }
+    Ok(statistics)
+}
+
+async fn get_statistics(
+    Path(user_id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<Json<PlayerStatistics>, ServerError> {
+    let user_id = UserId(user_id);
+    {
+        let mut conn = pool.conn().await?;
+        load_public_user_data(user_id, &mut conn)
+            .await
+            .map_err(|_| ServerError::NotFound)?;
+    }
+    Ok(Json(load(user_id, &pool).await?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn summary(
+        color: PlayerColor,
+        outcome: PlayerOutcome,
+        openings: &[&str],
+        opponent_ai: Option<&str>,
+    ) -> GameSummary {
+        GameSummary {
+            color,
+            outcome,
+            time_control: TimeControl::Blitz,
+            openings: openings.iter().map(|o| o.to_string()).collect(),
+            half_moves: 30,
+            missed_paco: 1,
+            opponent_ai: opponent_ai.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_from_summaries() {
+        let statistics = PlayerStatistics::from_summaries(&[
+            summary(PlayerColor::White, PlayerOutcome::Win, &["Rai"], None),
+            summary(PlayerColor::Black, PlayerOutcome::Loss, &["Swedish Knights", "Rai"], Some("hedwig")),
+            summary(PlayerColor::Black, PlayerOutcome::Draw, &[], Some("hedwig")),
+        ]);
+
+        assert_eq!(statistics.games, 3);
+        assert_eq!(statistics.results, ResultCount { wins: 1, draws: 1, losses: 1 });
+        assert_eq!(statistics.as_white, ResultCount { wins: 1, draws: 0, losses: 0 });
+        assert_eq!(statistics.as_black, ResultCount { wins: 0, draws: 1, losses: 1 });
+        assert_eq!(statistics.by_time_control[&TimeControl::Blitz].total(), 3);
+        assert_eq!(statistics.openings[0].opening, "Rai");
+        assert_eq!(statistics.openings[0].results, ResultCount { wins: 1, draws: 0, losses: 1 });
+        assert_eq!(statistics.openings[1].opening, "Swedish Knights");
+        assert_eq!(statistics.average_half_moves, 30.0);
+        assert_eq!(statistics.missed_paco, 3);
+        assert_eq!(
+            statistics.against_ai,
+            vec![AiStatistics {
+                model_name: "hedwig".to_string(),
+                results: ResultCount { wins: 0, draws: 1, losses: 1 },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_time_control() {
+        let timer = |budget: i64, increment: i64| TimerConfig {
+            time_budget_white: chrono::Duration::seconds(budget),
+            time_budget_black: chrono::Duration::seconds(budget),
+            increment: Some(chrono::Duration::seconds(increment)),
+            mode: Default::default(),
+        };
+        assert_eq!(TimeControl::of(None), TimeControl::Untimed);
+        assert_eq!(TimeControl::of(Some(&timer(240, 0))), TimeControl::Lightspeed);
+        assert_eq!(TimeControl::of(Some(&timer(300, 0))), TimeControl::Blitz);
+        assert_eq!(TimeControl::of(Some(&timer(300, 15))), TimeControl::Rapid);
+        assert_eq!(TimeControl::of(Some(&timer(3600, 0))), TimeControl::Classical);
+        assert_eq!(
+            TimeControl::of(Some(&TimerConfig::correspondence(3))),
+            TimeControl::Correspondence
+        );
+        let per_move = TimerConfig {
+            mode: ClockMode::FixedPerMove,
+            ..timer(10, 0)
+        };
+        assert_eq!(TimeControl::of(Some(&per_move)), TimeControl::Blitz);
+    }
+}
@@ -11,10 +11,9 @@ use serde::Deserialize;
 
 use pacosako::PlayerColor;
 
-use crate::{db, ServerError};
+use crate::{db, player_statistics, ServerError};
 use crate::db::{Connection, Pool};
-use crate::login::permission::{BACKDATED_USER_ASSIGNMENT, is_allowed};
-use crate::login::session::SessionData;
+use crate::login::permission::{self, RequirePermission};
 use crate::login::UserId;
 
 /// This struct goes verbatim into `game_assignment_audit` table, so I have an idea
@@ -35,13 +34,11 @@ async fn perform_backdated_user_assignment(
 ) -> Result<(), ServerError> {
     info!("Backdating user assignment: {:#?}", data);
 
-    // First, validate whether the user doing the assignment has the permission to do so.
-    if !is_allowed(data.assigned_by, BACKDATED_USER_ASSIGNMENT, conn).await? {
-        return Err(ServerError::NotAllowed("You do not have permission to backdate user assignment".to_string()));
-    }
+    // The route already checked that the user doing the assignment has the
+    // permission to do so.
 
     // Load the current state of the game.
-    let Some(game) = db::game::select(data.game_id, conn).await? else {
+    let Some(mut game) = db::game::select(data.game_id, conn).await? else {
         return Err(ServerError::NotFound);
     };
 
@@ -85,6 +82,11 @@ async fn perform_backdated_user_assignment(
         db::game::set_player(data.game_id, PlayerColor::Black, black_assignee, &mut *conn).await?;
     }
 
+    // The game now counts for the statistics of the assigned players.
+    game.white_player = game.white_player.or(data.white_assignee);
+    game.black_player = game.black_player.or(data.black_assignee);
+    player_statistics::invalidate(&game);
+
     Ok(())
 }
 
@@ -96,7 +98,7 @@ pub struct BackdatedUserAssignmentPostParameters {
 }
 
 pub async fn backdate_user_assignment(
-    session: SessionData,
+    RequirePermission { session, .. }: RequirePermission<permission::BackdatedUserAssignment>,
     pool: State<Pool>,
     Json(params): Json<BackdatedUserAssignmentPostParameters>,
 ) -> Result<(), ServerError> {
@@ -107,4 +109,60 @@ pub async fn backdate_user_assignment(
         black_assignee: params.black_assignee.map(UserId),
         assigned_by: session.user_id,
     }, &mut conn).await
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use pacosako::const_tile::*;
+    use pacosako::setup_options::SetupOptions;
+    use pacosako::PacoAction::*;
+
+    use crate::login::user;
+    use crate::sync_match::SynchronizedMatch;
+
+    use super::*;
+
+    /// The statistics of a player include a game assigned to them afterwards.
+    #[tokio::test]
+    async fn test_assignment_updates_statistics() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let alice = user::create_user("alice", "identicon:1", &mut conn).await.unwrap();
+        let admin = user::create_user("admin", "identicon:2", &mut conn).await.unwrap();
+
+        // White wins with a paco on the first move.
+        let mut game = SynchronizedMatch {
+            key: String::new(),
+            actions: vec![],
+            timer: None,
+            setup_options: SetupOptions {
+                safe_mode: false,
+                draw_after_n_repetitions: 3,
+                starting_fen: Some("4k3/8/8/8/8/8/8/4R1K1 w 0 - - -".to_string()),
+            },
+            white_player: None,
+            black_player: None,
+        };
+        game.do_action(&[Lift(E1)]).unwrap();
+        game.do_action(&[Place(E8)]).unwrap();
+        db::game::insert(&mut game, &mut conn).await.unwrap();
+        let state = game.current_state().unwrap();
+        db::game::update(&game, &state, &mut conn).await.unwrap();
+        drop(conn);
+
+        assert_eq!(player_statistics::load(alice, &pool).await.unwrap().games, 0);
+
+        let mut conn = pool.conn().await.unwrap();
+        perform_backdated_user_assignment(BackdatedUserAssignment {
+            game_id: game.key.parse().unwrap(),
+            white_assignee: Some(alice),
+            black_assignee: None,
+            assigned_by: admin,
+        }, &mut conn).await.unwrap();
+
+        drop(conn);
+        let statistics = player_statistics::load(alice, &pool).await.unwrap();
+        assert_eq!(statistics.games, 1);
+        assert_eq!(statistics.results.wins, 1);
+    }
+}
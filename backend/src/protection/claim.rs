@@ -0,0 +1,309 @@
+//! Anonymous players can claim their games for their account.
+//!
+//! A side that was played without being logged in is locked to the uuid of the
+//! browser. While the browser still has this uuid, a logged-in user can claim
+//! the side. It is then locked to the user, like a side that was played while
+//! logged in. This works for running and finished games.
+//!
+//! Claiming a single game goes through the room of the game. Claiming all games
+//! of a uuid stores the players of all games at once and then refreshes the
+//! rooms that are loaded. Either way, connected clients see the new player
+//! right away. Storing a game never removes a player, so a room that is busy
+//! with a stale copy of the game can't undo a claim. Like backdated
+//! assignments, every claim is written to `game_assignment_audit`.
+
+use axum::extract::{Path, State};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use sqlx::Acquire;
+use tokio::sync::oneshot;
+
+use pacosako::PlayerColor;
+
+use crate::db::{Connection, Pool};
+use crate::login::session::SessionData;
+use crate::login::UserId;
+use crate::{player_statistics, ws, ServerError};
+
+#[derive(Deserialize)]
+pub struct ClaimRequest {
+    /// The uuid of the browser, from local storage.
+    uuid: String,
+}
+
+#[derive(Serialize)]
+pub struct ClaimedGame {
+    game_id: i64,
+    sides: Vec<PlayerColor>,
+}
+
+/// POST /api/game/:key/claim claims the sides of the game the uuid played.
+pub async fn claim_game(
+    session: SessionData,
+    Path(key): Path<String>,
+    State(pool): State<Pool>,
+    Json(request): Json<ClaimRequest>,
+) -> Result<Json<ClaimedGame>, ServerError> {
+    let game_id: i64 = key.parse()?;
+    let sides = claim(game_id, &request.uuid, session.user_id, &pool).await?;
+    if sides.is_empty() {
+        return Err(ServerError::NotAllowed(
+            "This browser did not play an unclaimed side of this game.".to_string(),
+        ));
+    }
+    Ok(Json(ClaimedGame { game_id, sides }))
+}
+
+/// POST /api/me/claim_games claims all games the uuid played. The frontend
+/// calls this after logging in.
+pub async fn claim_all_games(
+    session: SessionData,
+    State(pool): State<Pool>,
+    Json(request): Json<ClaimRequest>,
+) -> Result<Json<Vec<ClaimedGame>>, ServerError> {
+    let claimed = {
+        let mut conn = pool.conn().await?;
+        claim_all(&request.uuid, session.user_id, &mut conn).await?
+    };
+    if claimed.is_empty() {
+        return Ok(Json(claimed));
+    }
+
+    info!("User {} claimed {} games.", session.user_id.0, claimed.len());
+    player_statistics::invalidate_user(session.user_id);
+    // Rooms that are loaded pick up the new players, all others read them from
+    // the database when they are loaded.
+    for game in &claimed {
+        ws::to_logic(ws::LogicMsg::Refresh {
+            key: game.game_id.to_string(),
+        })
+            .await;
+    }
+    Ok(Json(claimed))
+}
+
+/// Claims all sides the uuid locked, except those that already have a player,
+/// and writes the audit log. The players of all games are set at once.
+async fn claim_all(
+    uuid: &str,
+    user_id: UserId,
+    conn: &mut Connection,
+) -> Result<Vec<ClaimedGame>, ServerError> {
+    if uuid.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut tx = conn.begin().await?;
+    let locks = sqlx::query!(
+        r"select game_uuid_lock.game_id, game_uuid_lock.player from game_uuid_lock
+        join game on game.id = game_uuid_lock.game_id
+        where game_uuid_lock.uuid = ?
+        and (case game_uuid_lock.player when 'White' then game.white_player else game.black_player end) is null
+        order by game_uuid_lock.game_id desc, game_uuid_lock.player desc",
+        uuid
+    )
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let mut claimed: Vec<ClaimedGame> = vec![];
+    for lock in locks {
+        let color = if lock.player == "White" { PlayerColor::White } else { PlayerColor::Black };
+        match claimed.last_mut() {
+            Some(game) if game.game_id == lock.game_id => game.sides.push(color),
+            _ => claimed.push(ClaimedGame {
+                game_id: lock.game_id,
+                sides: vec![color],
+            }),
+        }
+    }
+    if claimed.is_empty() {
+        return Ok(claimed);
+    }
+
+    let game_ids = serde_json::to_string(&claimed.iter().map(|g| g.game_id).collect::<Vec<_>>())?;
+    sqlx::query!(
+        r"update game set
+        white_player = coalesce(white_player, (select ? from game_uuid_lock
+            where game_id = game.id and uuid = ? and player = 'White')),
+        black_player = coalesce(black_player, (select ? from game_uuid_lock
+            where game_id = game.id and uuid = ? and player = 'Black'))
+        where id in (select value from json_each(?))",
+        user_id.0,
+        uuid,
+        user_id.0,
+        uuid,
+        game_ids
+    )
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "delete from game_uuid_lock where uuid = ? and game_id in (select value from json_each(?))",
+        uuid,
+        game_ids
+    )
+        .execute(&mut *tx)
+        .await?;
+    for game in &claimed {
+        let assignee = |color| game.sides.contains(&color).then_some(user_id.0);
+        let white = assignee(PlayerColor::White);
+        let black = assignee(PlayerColor::Black);
+        sqlx::query!(
+            "insert into game_assignment_audit (game_id, white_assignee, black_assignee, assigned_by) values (?, ?, ?, ?)",
+            game.game_id,
+            white,
+            black,
+            user_id.0,
+        )
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(claimed)
+}
+
+/// Claims the sides of the game locked by the uuid and writes the audit log.
+/// Returns the claimed sides, which may be none.
+async fn claim(
+    game_id: i64,
+    uuid: &str,
+    user_id: UserId,
+    pool: &Pool,
+) -> Result<Vec<PlayerColor>, ServerError> {
+    if uuid.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let (done, result) = oneshot::channel();
+    ws::to_logic(ws::LogicMsg::Claim {
+        key: game_id.to_string(),
+        uuid: uuid.to_string(),
+        user_id,
+        done,
+    })
+        .await;
+    let sides = match result.await {
+        Ok(Ok(sides)) => sides,
+        Ok(Err(e)) => {
+            return Err(ServerError::Other(anyhow::anyhow!(
+                "Could not claim game {}: {}",
+                game_id,
+                e
+            )));
+        }
+        Err(_) => return Err(ServerError::NotFound),
+    };
+    if sides.is_empty() {
+        return Ok(sides);
+    }
+
+    info!("User {} claimed {:?} of game {}.", user_id.0, sides, game_id);
+    let assignee = |color| sides.contains(&color).then_some(user_id.0);
+    let white = assignee(PlayerColor::White);
+    let black = assignee(PlayerColor::Black);
+    let mut conn = pool.conn().await?;
+    sqlx::query!(
+        "insert into game_assignment_audit (game_id, white_assignee, black_assignee, assigned_by) values (?, ?, ?, ?)",
+        game_id,
+        white,
+        black,
+        user_id.0,
+    )
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(sides)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db;
+    use crate::login::user::create_user;
+    use crate::sync_match::SynchronizedMatch;
+
+    async fn insert_game(white_player: Option<UserId>, conn: &mut Connection) -> i64 {
+        let mut game = SynchronizedMatch {
+            key: String::new(),
+            actions: vec![],
+            timer: None,
+            setup_options: Default::default(),
+            white_player,
+            black_player: None,
+        };
+        db::game::insert(&mut game, conn).await.unwrap();
+        game.key.parse().unwrap()
+    }
+
+    async fn players(id: i64, conn: &mut Connection) -> (Option<UserId>, Option<UserId>) {
+        let game = db::game::select(id, conn).await.unwrap().unwrap();
+        (game.white_player, game.black_player)
+    }
+
+    #[tokio::test]
+    async fn test_claim_all() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let user_id = create_user("alice", "identicon:1", &mut conn).await.unwrap();
+        let other = create_user("bob", "identicon:2", &mut conn).await.unwrap();
+
+        // Both sides were played from the same browser.
+        let both = insert_game(None, &mut conn).await;
+        db::game::insert_uuid_lock(both, PlayerColor::White, "uuid", &mut conn).await.unwrap();
+        db::game::insert_uuid_lock(both, PlayerColor::Black, "uuid", &mut conn).await.unwrap();
+        // White already has a player, only black can be claimed.
+        let black = insert_game(Some(other), &mut conn).await;
+        db::game::insert_uuid_lock(black, PlayerColor::White, "uuid", &mut conn).await.unwrap();
+        db::game::insert_uuid_lock(black, PlayerColor::Black, "uuid", &mut conn).await.unwrap();
+        // Another browser played this one.
+        let foreign = insert_game(None, &mut conn).await;
+        db::game::insert_uuid_lock(foreign, PlayerColor::White, "other", &mut conn).await.unwrap();
+
+        let claimed = claim_all("uuid", user_id, &mut conn).await.unwrap();
+        let claimed: Vec<_> = claimed.into_iter().map(|g| (g.game_id, g.sides)).collect();
+        assert_eq!(
+            claimed,
+            vec![
+                (black, vec![PlayerColor::Black]),
+                (both, vec![PlayerColor::White, PlayerColor::Black]),
+            ]
+        );
+
+        assert_eq!(players(both, &mut conn).await, (Some(user_id), Some(user_id)));
+        assert_eq!(players(black, &mut conn).await, (Some(other), Some(user_id)));
+        assert_eq!(players(foreign, &mut conn).await, (None, None));
+
+        assert_eq!(db::game::uuid_locks(both, &mut conn).await.unwrap(), (None, None));
+        assert_eq!(db::game::uuid_locks(black, &mut conn).await.unwrap(), (None, None));
+        assert_eq!(
+            db::game::uuid_locks(foreign, &mut conn).await.unwrap(),
+            (Some("other".to_string()), None)
+        );
+        let audits = sqlx::query!(
+            "select game_id, white_assignee, black_assignee from game_assignment_audit where assigned_by = ? order by game_id",
+            user_id.0
+        )
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        let audits: Vec<_> = audits
+            .into_iter()
+            .map(|a| (a.game_id, a.white_assignee, a.black_assignee))
+            .collect();
+        assert_eq!(
+            audits,
+            vec![(both, Some(user_id.0), Some(user_id.0)), (black, None, Some(user_id.0))]
+        );
+
+        // Nothing is left to claim.
+        assert!(claim_all("uuid", user_id, &mut conn).await.unwrap().is_empty());
+
+        // A room that loaded the game before the claim doesn't undo it.
+        let mut stale = db::game::select(both, &mut conn).await.unwrap().unwrap();
+        stale.white_player = None;
+        stale.black_player = None;
+        let state = stale.current_state().unwrap();
+        db::game::update(&stale, &state, &mut conn).await.unwrap();
+        assert_eq!(players(both, &mut conn).await, (Some(user_id), Some(user_id)));
+    }
+}
@@ -2,13 +2,14 @@
 //! can play it.
 //!
 //! Historically, I had introduced the "safe_mode" flag to games and a client
-//! side UUID in local storage. These UUID work as ephemeral keys that can own
-//! games as well. We never persist them, so a server restart will restart the
-//! safe_mode assignments.
+//! side UUID in local storage. These UUID work as keys that can own games as
+//! well. They are stored in `game_uuid_lock`, so the locks survive a server
+//! restart.
 //!
 //! We now support users as well. A side of a game can be owned by a user. This
 //! allows them to play the game on any device. We still support anonymous
-//! users, as before.
+//! users, as before. Once they log in, they can claim the sides their UUID
+//! locked for their account, see the `claim` module.
 //!
 //! We'll also assume safe_mode for all games. This is no longer optional.
 //!
@@ -21,10 +22,11 @@ use crate::login::UserId;
 use crate::ws::socket_auth::SocketIdentity;
 
 pub mod backdated_user_assignment;
+pub mod claim;
 
 #[derive(Debug)]
 pub enum SideProtection {
-    Unlocked,         // No one moved yet, or we didn't persist the UUID.
+    Unlocked,         // No one moved yet.
     UuidLock(String), // A UUID has locked this side.
     UserLock(UserId), // A user has locked this side.
 }
@@ -108,6 +110,23 @@ impl SideProtection {
         }
     }
 
+    pub fn get_uuid(&self) -> Option<&str> {
+        match self {
+            Self::UuidLock(uuid) => Some(uuid),
+            Self::Unlocked | Self::UserLock(_) => None,
+        }
+    }
+
+    /// The protection of a side with the stored player and uuid lock. The
+    /// player wins if both are set.
+    pub fn for_side(player: Option<UserId>, uuid: Option<String>) -> SideProtection {
+        match (player, uuid) {
+            (Some(player), _) => SideProtection::UserLock(player),
+            (None, Some(uuid)) => SideProtection::UuidLock(uuid),
+            (None, None) => SideProtection::Unlocked,
+        }
+    }
+
     pub fn for_user(player: Option<UserId>) -> SideProtection {
         if let Some(player) = player {
             SideProtection::UserLock(player)
@@ -126,3 +145,35 @@ impl From<&SocketIdentity> for SideProtection {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn identity(uuid: &str, user_id: Option<i64>) -> SocketIdentity {
+        SocketIdentity {
+            uuid: uuid.to_string(),
+            user_id: user_id.map(UserId),
+        }
+    }
+
+    #[test]
+    fn test_for_side() {
+        let user = identity("uuid", Some(1));
+        let anonymous = identity("uuid", None);
+
+        // A stored player wins over the uuid lock.
+        let side = SideProtection::for_side(Some(UserId(1)), Some("uuid".to_string()));
+        assert_eq!(side.get_user(), Some(UserId(1)));
+        assert_eq!(side.test(&user), ControlLevel::LockedByYou);
+        assert_eq!(side.test(&anonymous), ControlLevel::LockedByOther);
+
+        let side = SideProtection::for_side(None, Some("uuid".to_string()));
+        assert_eq!(side.get_uuid(), Some("uuid"));
+        assert_eq!(side.test(&anonymous), ControlLevel::LockedByYou);
+        assert_eq!(side.test(&identity("other", None)), ControlLevel::LockedByOther);
+
+        let side = SideProtection::for_side(None, None);
+        assert_eq!(side.test(&anonymous), ControlLevel::Unlocked);
+    }
+}
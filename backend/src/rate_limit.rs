@@ -0,0 +1,117 @@
+//! Limits how often something may happen within a time window, like creating
+//! accounts from the same address.
+//!
+//! Limits only live in memory, so they reset when the server restarts. This is
+//! good enough to stop spam scripts.
+
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use axum::http::HeaderMap;
+use dashmap::DashMap;
+
+/// When more keys than this are tracked, keys without recent events are
+/// forgotten, so the map doesn't grow forever.
+const CLEANUP_THRESHOLD: usize = 10_000;
+
+/// Allows at most `max` events per key within `window`.
+pub struct RateLimiter {
+    max: usize,
+    window: Duration,
+    events: DashMap<String, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(max: usize, window: Duration) -> Self {
+        RateLimiter {
+            max,
+            window,
+            events: DashMap::new(),
+        }
+    }
+
+    /// Records an event for the key and returns true, unless the key already
+    /// reached its limit. Then nothing is recorded and it returns false.
+    pub fn try_acquire(&self, key: &str) -> bool {
+        self.try_acquire_at(key, Instant::now())
+    }
+
+    fn try_acquire_at(&self, key: &str, now: Instant) -> bool {
+        if self.events.len() > CLEANUP_THRESHOLD {
+            self.cleanup(now);
+        }
+        let mut events = self.events.entry(key.to_string()).or_default();
+        while events
+            .front()
+            .is_some_and(|&event| now.duration_since(event) >= self.window)
+        {
+            events.pop_front();
+        }
+        if events.len() >= self.max {
+            return false;
+        }
+        events.push_back(now);
+        true
+    }
+
+    fn cleanup(&self, now: Instant) {
+        self.events.retain(|_, events| {
+            events
+                .back()
+                .is_some_and(|&event| now.duration_since(event) < self.window)
+        });
+    }
+}
+
+/// The address of the client. Behind a reverse proxy, this is the last
+/// address in the `X-Forwarded-For` header, which the proxy added.
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr, behind_proxy: bool) -> IpAddr {
+    if behind_proxy {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|address| address.trim().parse().ok())
+            .next_back();
+        if let Some(address) = forwarded {
+            return address;
+        }
+    }
+    peer.ip()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(limiter.try_acquire_at("a", start));
+        assert!(limiter.try_acquire_at("a", start + Duration::from_secs(10)));
+        assert!(!limiter.try_acquire_at("a", start + Duration::from_secs(20)));
+        // Other keys are independent.
+        assert!(limiter.try_acquire_at("b", start + Duration::from_secs(20)));
+        // The first event left the window, the rejected one was not recorded.
+        assert!(limiter.try_acquire_at("a", start + Duration::from_secs(60)));
+        assert!(!limiter.try_acquire_at("a", start + Duration::from_secs(69)));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1, 192.0.2.7".parse().unwrap());
+
+        assert_eq!(client_ip(&headers, peer, false), peer.ip());
+        assert_eq!(
+            client_ip(&headers, peer, true),
+            "192.0.2.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(client_ip(&HeaderMap::new(), peer, true), peer.ip());
+    }
+}
@@ -0,0 +1,144 @@
+//! Analyzes every finished game once and stores the result in
+//! `game_replay_metadata`, so the replay page doesn't have to.
+//!
+//! The analysis is the same the replay page computes in the browser,
+//! including the expensive Paco in 2 search. When the algorithm changes,
+//! increase `ANALYSIS_VERSION` and all games are analyzed again.
+
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+use pacosako::analysis::incremental_replay::history_to_replay_notation_incremental;
+use pacosako::analysis::HalfMoveMetadata;
+use pacosako::{DenseBoard, PacoAction};
+
+use crate::db::{self, replay_metadata::ReplayMetadataRecord, Pool};
+use crate::ServerError;
+
+/// Increase this when the analysis changes to recompute it for all games.
+pub const ANALYSIS_VERSION: i64 = 1;
+
+/// Category of the rows holding the `HalfMoveMetadata` of a half-move. Only
+/// half-moves with at least one flag set get a row.
+pub const HALF_MOVE_CATEGORY: &str = "half_move_metadata";
+/// Category of the row holding the opening, at action index 0. Every analyzed
+/// game has this row, even if no known opening was played.
+pub const OPENING_CATEGORY: &str = "opening";
+
+/// How many games are loaded from the database at once.
+const BATCH_SIZE: i64 = 20;
+
+/// How long the worker sleeps when nobody tells it about finished games.
+const IDLE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+static WAKE_UP: Notify = Notify::const_new();
+
+/// Tells the worker that a game is over and can be analyzed.
+pub fn game_finished() {
+    WAKE_UP.notify_one();
+}
+
+/// Runs the worker forever. Errors are logged and the worker tries again
+/// later.
+pub async fn run(pool: Pool) {
+    loop {
+        match analyze_batch(&pool).await {
+            // There may be more games waiting.
+            Ok(count) if count as i64 == BATCH_SIZE => continue,
+            Ok(_) => {}
+            Err(e) => warn!("Replay analysis failed: {:?}", e),
+        }
+        tokio::select! {
+            _ = WAKE_UP.notified() => {}
+            _ = tokio::time::sleep(IDLE_INTERVAL) => {}
+        }
+    }
+}
+
+/// Analyzes the next batch of games and returns how many games it analyzed.
+async fn analyze_batch(pool: &Pool) -> Result<usize, ServerError> {
+    let games = {
+        let mut conn = pool.conn().await?;
+        db::game::without_replay_analysis(ANALYSIS_VERSION, BATCH_SIZE, &mut conn).await?
+    };
+    let count = games.len();
+
+    for game in games {
+        let id: i64 = game.key.parse()?;
+        // The Paco in 2 search can take seconds, so we don't hold a database
+        // connection while it runs.
+        let rows = tokio::task::spawn_blocking(move || {
+            let actions: Vec<PacoAction> = game.actions.iter().map(PacoAction::from).collect();
+            let initial_board = DenseBoard::with_options(&game.setup_options)?;
+            analyze(&initial_board, &actions)
+        })
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        // Games that can't be replayed are stored without rows, so they are
+        // not tried again until the next version.
+        let rows = rows.unwrap_or_else(|e| {
+            warn!("Could not analyze the replay of game {}: {:?}", id, e);
+            vec![]
+        });
+
+        let mut conn = pool.conn().await?;
+        db::replay_metadata::store_analysis(id, ANALYSIS_VERSION, &rows, &mut conn).await?;
+    }
+
+    Ok(count)
+}
+
+fn analyze(initial_board: &DenseBoard, actions: &[PacoAction]) -> Result<Vec<ReplayMetadataRecord>, ServerError> {
+    let replay = history_to_replay_notation_incremental(initial_board, actions, || 0, |_| {})?;
+
+    let mut rows = vec![ReplayMetadataRecord {
+        action_index: 0,
+        category: OPENING_CATEGORY.to_string(),
+        metadata: serde_json::json!({ "opening": replay.opening() }).to_string(),
+        analysis_version: Some(ANALYSIS_VERSION),
+    }];
+    for half_move in replay.notation() {
+        if *half_move.metadata() == HalfMoveMetadata::default() {
+            continue;
+        }
+        rows.push(ReplayMetadataRecord {
+            action_index: half_move.action_index() as i64,
+            category: HALF_MOVE_CATEGORY.to_string(),
+            metadata: serde_json::to_string(half_move.metadata())?,
+            analysis_version: Some(ANALYSIS_VERSION),
+        });
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod test {
+    use pacosako::{BoardPosition, PacoAction::*};
+
+    use super::*;
+
+    #[test]
+    fn test_analyze() {
+        let pos = |s: &str| BoardPosition::try_from(s).unwrap();
+        // The white queen on h5 attacks the black king after f7>f6.
+        let actions = [
+            Lift(pos("e2")),
+            Place(pos("e4")),
+            Lift(pos("f7")),
+            Place(pos("f6")),
+            Lift(pos("d1")),
+            Place(pos("h5")),
+        ];
+        let rows = analyze(&DenseBoard::new(), &actions).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].action_index, 0);
+        assert_eq!(rows[0].category, OPENING_CATEGORY);
+        assert_eq!(rows[1].action_index, 6);
+        assert_eq!(rows[1].category, HALF_MOVE_CATEGORY);
+        assert!(rows[1].metadata.contains(r#""gives_sako":true"#));
+        assert!(rows.iter().all(|row| row.analysis_version == Some(ANALYSIS_VERSION)));
+    }
+}
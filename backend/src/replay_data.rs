@@ -6,7 +6,11 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{db::Pool, login::session::SessionData, ServerError};
+use crate::{
+    db::{self, Pool},
+    login::{session::SessionData, user},
+    ServerError,
+};
 
 #[derive(Serialize)]
 pub struct ReplayMetaData {
@@ -15,6 +19,9 @@ pub struct ReplayMetaData {
     category: String,
     /// data holds a json encoded payload to render the object.
     data: String,
+    /// The version of the server side replay analysis which created this
+    /// entry. None for entries posted by AIs.
+    analysis_version: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -25,26 +32,32 @@ pub struct ReplayMetaDataInput {
     data: String,
 }
 
+/// Only AI accounts can post metadata, everyone else would write into the
+/// replays of other players.
 pub async fn post_metadata(
-    _session: SessionData, // TODO: Verify that only AIs can call this.
+    session: SessionData,
     Path(key): Path<String>,
     pool: State<Pool>,
     Json(data): Json<Vec<ReplayMetaDataInput>>,
 ) -> Result<(), ServerError> {
+    let game_id: i64 = key.parse()?;
     let mut conn = pool.conn().await?;
+    if !user::is_ai_account(session.user_id, &mut conn).await? {
+        return Err(ServerError::NotAllowed(
+            "Only AI accounts can post replay metadata.".to_string(),
+        ));
+    }
 
     for ele in data {
-        sqlx::query!(
-            r"INSERT INTO game_replay_metadata
-        (game_id, action_index, category, metadata)
-        VALUES (?, ?, ?, ?)",
-            key,
+        db::replay_metadata::insert(
+            game_id,
             ele.action_index,
-            ele.category,
-            ele.data
+            &ele.category,
+            &ele.data,
+            session.user_id,
+            &mut conn,
         )
-        .execute(&mut conn)
-        .await?;
+            .await?;
     }
 
     Ok(())
@@ -55,15 +68,9 @@ pub async fn get_metadata(
     pool: State<Pool>,
     Path(key): Path<String>,
 ) -> Result<Json<Vec<ReplayMetaData>>, ServerError> {
-    let mut conn = pool.conn().await.unwrap();
-    let data = sqlx::query!(
-        r"SELECT action_index, category, metadata
-        FROM game_replay_metadata
-        WHERE game_id = ?",
-        key
-    )
-    .fetch_all(&mut conn)
-    .await?;
+    let game_id: i64 = key.parse()?;
+    let mut conn = pool.conn().await?;
+    let data = db::replay_metadata::for_game(game_id, &mut conn).await?;
 
     let mut result = Vec::new();
     for ele in data {
@@ -72,6 +79,7 @@ pub async fn get_metadata(
             action_index: ele.action_index,
             category: ele.category,
             data: ele.metadata,
+            analysis_version: ele.analysis_version,
         });
     }
     Ok(Json(result))
@@ -1,9 +1,8 @@
-//! Module for the "secret" login page where username/password login is allowed
-//! for a few beta users.
+//! Module for the "secret" login page where users can register and log in with
+//! a username and password.
 
 use axum::{extract::State, response::IntoResponse};
 use hyper::header;
-use tower_cookies::Cookies;
 
 use crate::{
     config::EnvironmentConfig,
@@ -14,7 +13,6 @@ use crate::{
 
 pub async fn secret_login(
     State(config): State<EnvironmentConfig>,
-    cookies: Cookies,
     pool: State<Pool>,
     session: Option<SessionData>,
 ) -> impl IntoResponse {
@@ -37,10 +35,11 @@ pub async fn secret_login(
         }
     }
 
-    // Discord parameters & cookie
-    let link = login::discord::generate_link(&config, false);
-    context.insert("discord_url", &link.url);
-    cookies.add(link.state_cookie());
+    let providers: Vec<_> = login::oauth::providers(&config)
+        .into_iter()
+        .map(|provider| (provider.name, provider.display_name))
+        .collect();
+    context.insert("oauth_providers", &providers);
 
     let body = tera
         .render("secret_login.html.tera", &context)
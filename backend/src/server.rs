@@ -1,13 +1,15 @@
 //! This module implements the server for the backend.
 //! We are using Axum as the web framework.
 
+use std::net::SocketAddr;
+
 use axum::{
     body::Body,
     extract::{Query, State},
     http::{header, HeaderMap},
     middleware,
     response::IntoResponse,
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
 use serde::Deserialize;
@@ -18,22 +20,44 @@ use tower_cookies::{CookieManagerLayer, Cookies};
 use tower_http::services::{ServeDir, ServeFile};
 
 use crate::{
-    caching,
+    account_export, bot, caching, challenge,
     db::Pool,
+    export, fair_play, follow,
     game, grafana, language,
     login::{
         self,
+        profile,
+        registration,
         session::SessionData,
         user::{self, load_public_user_data},
     },
-    replay_data, secret_login, templates, AppState, EnvironmentConfig,
+    moderation, player_statistics, replay_data, secret_login, templates, tournament, AppState,
+    EnvironmentConfig,
 };
 
 pub async fn run(state: AppState) {
-    let api: Router<AppState> = game::add_to_router(Router::new())
+    let api: Router<AppState> = bot::add_to_router(game::add_to_router(Router::new()));
+    let api = export::add_to_router(api);
+    let api = player_statistics::add_to_router(api);
+    let api = login::permission::add_to_router(api);
+    let api = login::profile::add_to_router(api);
+    let api = moderation::add_to_router(api);
+    let api = fair_play::add_to_router(api);
+    let api = account_export::add_to_router(api);
+    let api = follow::add_to_router(api);
+    let api = tournament::add_to_router(challenge::add_to_router(api))
         .route("/language", post(language::set_user_language))
         .route("/username_password", post(login::username_password_route))
+        .route("/register", post(registration::register))
+        .route("/register/username_available", get(registration::username_available))
+        .route("/register/verify_email", get(registration::verify_email))
+        .route("/me/email", post(registration::set_email))
         .route("/logout", get(login::logout_route))
+        .route(
+            "/me/sessions",
+            get(login::session::list_sessions).delete(login::session::revoke_all_sessions),
+        )
+        .route("/me/sessions/:id", delete(login::session::revoke_session))
         .route("/replay_meta_data/:game", get(replay_data::get_metadata))
         .route("/replay_meta_data/:game", post(replay_data::post_metadata))
         .route("/me/avatar", post(user::set_avatar))
@@ -45,9 +69,14 @@ pub async fn run(state: AppState) {
         )
         .route(
             "/oauth/pleaseCreateAccount",
-            get(login::discord::please_create_account),
+            get(login::oauth::please_create_account),
         )
         .route("/oauth/get_redirected", get(login::discord::get_redirected))
+        .route("/oauth/providers", get(login::oauth::list_providers))
+        .route("/oauth/:provider/login", get(login::oauth::login))
+        .route("/oauth/:provider/callback", get(login::oauth::callback))
+        .route("/me/logins", get(login::oauth::list_logins))
+        .route("/me/logins/:id", delete(login::oauth::remove_login))
         .route("/user/:user_id", get(user::get_public_user_info));
 
     // build our application with a single route
@@ -90,11 +119,50 @@ pub async fn run(state: AppState) {
     let listener = tokio::net::TcpListener::bind(&state.config.bind)
         .await
         .unwrap();
-    axum::serve(listener, app.into_make_service())
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
 }
 
+/// Resolves once the server is asked to stop (SIGTERM or Ctrl+C) and the
+/// websocket logic has been shut down. Axum then stops accepting connections
+/// and finishes the requests which are still in flight.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl+C, shutting down."),
+        _ = terminate => info!("Received SIGTERM, shutting down."),
+    }
+
+    crate::ws::shutdown().await;
+}
+
 async fn index(
     headers: HeaderMap,
     mut cookies: Cookies,
@@ -102,7 +170,18 @@ async fn index(
     pool: State<Pool>,
     session: Option<SessionData>,
 ) -> impl IntoResponse {
-    let lang = language::user_language(&headers, &mut cookies);
+    let mut connection = pool
+        .conn()
+        .await
+        .expect("Could not get connection from pool");
+
+    let stored_language = match &session {
+        Some(session) => profile::stored_language(session.user_id, &mut connection)
+            .await
+            .unwrap_or_default(),
+        None => None,
+    };
+    let lang = language::user_language(stored_language, &headers, &mut cookies);
 
     // Print what the hashes of elm.min.js and main.js are.
     // This is useful for debugging cache busting.
@@ -146,11 +225,6 @@ async fn index(
     );
 
     // Check data for currently logged in user.
-    let mut connection = pool
-        .conn()
-        .await
-        .expect("Could not get connection from pool");
-
     context.insert("name", "");
     context.insert("user_id", "-1");
     context.insert("avatar", "");
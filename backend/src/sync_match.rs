@@ -6,13 +6,14 @@ use chrono::{DateTime, Utc};
 use pacosako::variants::PieceSetupParameters;
 use serde::{Deserialize, Serialize};
 use serde_json::de::from_str;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 
 use pacosako::setup_options::SetupOptions;
 use pacosako::{fen, variants, PacoAction, PacoBoard, PacoError, PlayerColor};
 
 use crate::db::{self, Connection};
-use crate::login::user::{load_user_data_for_game, PublicUserData};
+use crate::login::user::{load_user_data_for_game, AiMetaData, PublicUserData};
 use crate::login::{user, UserId};
 use crate::protection::ControlLevel;
 use crate::timer::{Timer, TimerConfig, TimerState};
@@ -21,16 +22,19 @@ use crate::ServerError;
 
 
 /// Parameters required to initialize a new instance of the match.
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MatchParameters {
     timer: Option<TimerConfig>,
+    /// Shorthand for a correspondence timer. Takes precedence over `timer`.
+    #[serde(default)]
+    days_per_move: Option<u16>,
     safe_mode: Option<bool>,
     draw_after_n_repetitions: Option<u8>,
     pub ai_side_request: Option<AiSideRequest>,
     piece_setup: Option<PieceSetupParameters>,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AiSideRequest {
     /// Color the AI should play. Color None means the AI should play randomly.
     pub color: Option<PlayerColor>,
@@ -44,9 +48,14 @@ impl MatchParameters {
     /// Ensure that all values of the timer config are below 1_000_000.
     /// This ensures we don't trigger an overflow. See #85.
     pub fn sanitize(&self) -> Self {
-        let timer = self.timer.clone().map(|timer| timer.sanitize());
+        let timer = match self.days_per_move {
+            Some(days) => Some(TimerConfig::correspondence(days.into())),
+            None => self.timer.clone(),
+        };
+        let timer = timer.map(|timer| timer.sanitize());
         Self {
             timer,
+            days_per_move: None,
             safe_mode: self.safe_mode,
             draw_after_n_repetitions: self.draw_after_n_repetitions,
             ai_side_request: self.ai_side_request.clone(),
@@ -55,6 +64,9 @@ impl MatchParameters {
     }
 
     pub fn is_legal(&self) -> bool {
+        if self.days_per_move == Some(0) {
+            return false;
+        }
         let Some(timer) = &self.timer else {
             return true;
         };
@@ -71,12 +83,42 @@ pub struct StampedAction {
     timestamp: DateTime<Utc>,
 }
 
+impl StampedAction {
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+}
+
 impl From<&StampedAction> for PacoAction {
     fn from(stamped_action: &StampedAction) -> Self {
         stamped_action.action
     }
 }
 
+/// A chain of actions prepared for an upcoming turn. It is executed as soon as
+/// the opponent finishes their turn and dropped if it is not legal then.
+///
+/// With a condition, this is a conditional move: it only answers one specific
+/// turn of the opponent. The premoves in `then` are prepared for the turn after
+/// that, which allows whole trees of conditional moves in correspondence games.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Premove {
+    /// The exact actions of the opponent's turn this premove answers.
+    /// Without a condition, the premove answers any turn.
+    #[serde(default)]
+    pub condition: Option<Vec<PacoAction>>,
+    pub reply: Vec<PacoAction>,
+    #[serde(default)]
+    pub then: Vec<Premove>,
+}
+
+impl Premove {
+    /// Number of premoves in this tree, including this one.
+    pub fn size(&self) -> usize {
+        1 + self.then.iter().map(Premove::size).sum::<usize>()
+    }
+}
+
 /// A match is a recording of actions taken in it together with a unique
 /// identifier that can be used to connect to the game.
 /// It also takes care of tracking the timing and ensures actions are legal.
@@ -128,15 +170,16 @@ async fn _store_to_db(
     game: &SynchronizedMatch,
     mut conn: db::Connection,
 ) -> Result<(), anyhow::Error> {
-    db::game::update(game, &mut conn).await?;
+    let state = game.current_state()?;
+    db::game::update(game, &state, &mut conn).await?;
 
     Ok(())
 }
 
 /// A complete description of the match state.
-/// This is currently sent to all clients whenever the game state changes.
-/// As it is only a list of some actions, it should be lightweight enough
-/// that sending around the whole history is not a bottleneck.
+/// Clients using the legacy protocol get all of it whenever the game state
+/// changes. For long games with many spectators this gets heavy, so newer
+/// clients only receive a `MatchStateDelta`.
 #[derive(Clone, Debug)]
 pub struct CurrentMatchState {
     key: String,
@@ -170,6 +213,31 @@ pub struct CurrentMatchStateClient {
     pub black_control: ControlLevel,
 }
 
+/// An incremental update of a `CurrentMatchStateClient`.
+///
+/// Clients that subscribe with the delta protocol get this instead of the full
+/// state after every change. To apply it, the client keeps the first
+/// `keep_actions` actions it knows about and appends `new_actions`. A rollback
+/// is just a delta where `keep_actions` is shorter than the known history.
+///
+/// The `sequence` increases by one for every state broadcast in the room. If a
+/// client sees a gap, it has missed a delta and must ask for a resync.
+/// Player metadata is not part of the delta. When the players change, delta
+/// clients get a full `MatchStateSync` instead.
+#[derive(Serialize, Clone, Debug)]
+pub struct MatchStateDelta {
+    key: String,
+    sequence: u64,
+    keep_actions: usize,
+    new_actions: Vec<StampedAction>,
+    is_rollback: bool,
+    controlling_player: PlayerColor,
+    timer: Option<Timer>,
+    victory_state: pacosako::VictoryState,
+    white_control: ControlLevel,
+    black_control: ControlLevel,
+}
+
 /// A small version of the current match state that suffices to show a match in an overview.
 #[derive(Serialize, Clone, Debug)]
 pub struct CompressedMatchStateClient {
@@ -201,6 +269,11 @@ impl CurrentMatchState {
         })
     }
 
+    /// Number of actions in the history of this state.
+    pub fn action_count(&self) -> usize {
+        self.actions.len()
+    }
+
     fn victory_state(
         board: &pacosako::DenseBoard,
         timer: &Option<Timer>,
@@ -277,6 +350,29 @@ impl CurrentMatchStateClient {
     }
 }
 
+impl MatchStateDelta {
+    /// Turns the full state of a client into a delta against a history of
+    /// `known_actions` actions. We assume the client already has that prefix.
+    pub fn new(state: CurrentMatchStateClient, known_actions: usize, sequence: u64) -> Self {
+        let keep_actions = known_actions.min(state.actions.len());
+        let mut actions = state.actions;
+        let new_actions = actions.split_off(keep_actions);
+
+        Self {
+            key: state.key,
+            sequence,
+            keep_actions,
+            new_actions,
+            is_rollback: state.is_rollback,
+            controlling_player: state.controlling_player,
+            timer: state.timer,
+            victory_state: state.victory_state,
+            white_control: state.white_control,
+            black_control: state.black_control,
+        }
+    }
+}
+
 impl CompressedMatchStateClient {
     /// Tries to create a new COMPRESSED match state out of a synchronized match and an
     /// already projected board.
@@ -298,6 +394,111 @@ impl CompressedMatchStateClient {
             black_player,
         })
     }
+
+    /// Like `try_new` for a list of games, but loads the players of all games
+    /// with one query instead of a few queries per game.
+    pub async fn try_new_many(
+        games: &[SynchronizedMatch],
+        connection: &mut Connection,
+    ) -> Result<Vec<Self>, ServerError> {
+        let mut user_ids: Vec<UserId> = games
+            .iter()
+            .flat_map(|game| [game.white_player, game.black_player])
+            .flatten()
+            .collect();
+        user_ids.sort_by_key(|u| u.0);
+        user_ids.dedup();
+        let users = load_public_users(&user_ids, connection).await?;
+        let game_ids = games
+            .iter()
+            .map(|game| game.key.parse())
+            .collect::<Result<Vec<i64>, _>>()?;
+        let mut ai_configs = load_ai_configs_for_games(&game_ids, connection).await?;
+
+        let mut result = Vec::with_capacity(games.len());
+        for (game, game_id) in games.iter().zip(game_ids) {
+            let mut player = |user_id: Option<UserId>, color: &str| {
+                let mut user = users.get(&user_id?)?.clone();
+                user.ai = ai_configs.remove(&(game_id, color.to_string()));
+                Some(user)
+            };
+            let white_player = player(game.white_player, "w");
+            let black_player = player(game.black_player, "b");
+            let board = game.project()?;
+            result.push(Self {
+                key: game.key.clone(),
+                current_fen: fen::write_fen(&board),
+                victory_state: CurrentMatchState::victory_state(&board, &game.timer),
+                timer: game.timer.clone(),
+                white_player,
+                black_player,
+            });
+        }
+        Ok(result)
+    }
+}
+
+/// Loads the public data of many users at once. Unknown users are missing in
+/// the result.
+async fn load_public_users(
+    user_ids: &[UserId],
+    connection: &mut Connection,
+) -> Result<HashMap<UserId, PublicUserData>, ServerError> {
+    let ids = serde_json::to_string(&user_ids.iter().map(|u| u.0).collect::<Vec<_>>())?;
+    let rows = sqlx::query!(
+        r#"select id as "id!", name, display_name, avatar, bio, country, is_bot from user
+        where id in (select value from json_each(?))"#,
+        ids
+    )
+        .fetch_all(&mut **connection)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|res| {
+            let user_id = UserId(res.id);
+            let user = PublicUserData {
+                name: res.name.unwrap_or("Anonymous".to_string()),
+                display_name: res.display_name,
+                user_id,
+                avatar: res.avatar,
+                bio: res.bio,
+                country: res.country,
+                ai: None,
+                is_bot: res.is_bot != 0,
+            };
+            (user_id, user)
+        })
+        .collect())
+}
+
+/// Loads the AI configurations of many games at once, keyed by game id and
+/// color ("w" or "b"). Games without AI players are missing in the result.
+async fn load_ai_configs_for_games(
+    game_ids: &[i64],
+    connection: &mut Connection,
+) -> Result<HashMap<(i64, String), AiMetaData>, ServerError> {
+    let ids = serde_json::to_string(game_ids)?;
+    let rows = sqlx::query!(
+        r#"select game_id, player_color as "player_color!", model_name, model_strength, model_temperature, is_frontend_ai
+        from game_aiConfig where game_id in (select value from json_each(?))"#,
+        ids
+    )
+        .fetch_all(&mut **connection)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|res| {
+            let ai = AiMetaData {
+                model_name: res.model_name,
+                model_strength: res.model_strength as usize,
+                model_temperature: res.model_temperature,
+                is_frontend_ai: res.is_frontend_ai == Some(1),
+            };
+            ((res.game_id, res.player_color), ai)
+        })
+        .collect())
 }
 
 /// This implementation contains most of the "Business Logic" of the match.
@@ -392,6 +593,147 @@ impl SynchronizedMatch {
         Ok(state)
     }
 
+    /// Takes back the last completed turn of the given player, together with
+    /// everything that happened after it. The clock is settled first and the
+    /// increments of the removed turns are revoked.
+    ///
+    /// Returns `None` if the player has no completed turn or the game is over.
+    /// Otherwise returns the new state and the actions that were removed.
+    pub fn takeback(
+        &mut self,
+        requester: PlayerColor,
+    ) -> Result<Option<(CurrentMatchState, Vec<StampedAction>)>, PacoError> {
+        let board = self.project()?;
+        if board.victory_state().is_over() {
+            return Ok(None);
+        }
+        let turns = self.turns()?;
+        let Some(start) = turns
+            .iter()
+            .rev()
+            .find(|t| t.player == requester && t.completed)
+            .map(|t| t.start)
+        else {
+            return Ok(None);
+        };
+
+        self.update_timer(board.controlling_player());
+        if let Some(ref mut timer) = self.timer {
+            for turn in turns.iter().rev().take_while(|t| t.start >= start) {
+                timer.take_back_turn(turn.player, turn.completed);
+            }
+        }
+
+        let removed = self.actions.split_off(start);
+        let mut state = self.current_state()?;
+        state.is_rollback = true;
+        Ok(Some((state, removed)))
+    }
+
+    /// Start index and player of the last completed turn, if there is one.
+    pub fn last_completed_turn(&self) -> Result<Option<(usize, PlayerColor)>, PacoError> {
+        Ok(self
+            .turns()?
+            .iter()
+            .rev()
+            .find(|t| t.completed)
+            .map(|t| (t.start, t.player)))
+    }
+
+    /// Index of the first action of the turn that is in progress.
+    pub fn current_turn_start(&self) -> Result<usize, PacoError> {
+        Ok(self.turns()?.last().map_or(0, |t| t.start))
+    }
+
+    /// Executes the first premove that answers the last completed turn. A
+    /// matching condition takes precedence over premoves without condition.
+    /// If the conditional premove can't be executed, the premove without
+    /// condition is tried instead.
+    ///
+    /// The premove must complete the turn, otherwise it is not executed. If it
+    /// is not legal, the game stays unchanged. Nothing happens if the current
+    /// turn was already started.
+    ///
+    /// Returns the new state and the premoves prepared for the next turn.
+    pub fn execute_premove(
+        &mut self,
+        premoves: Vec<Premove>,
+    ) -> Result<Option<(CurrentMatchState, Vec<Premove>)>, PacoError> {
+        if self.current_turn_start()? != self.actions.len() {
+            return Ok(None);
+        }
+        let Some((opponent_turn_start, _)) = self.last_completed_turn()? else {
+            return Ok(None);
+        };
+        let opponent_turn: Vec<PacoAction> = self.actions[opponent_turn_start..]
+            .iter()
+            .map(PacoAction::from)
+            .collect();
+        let conditional = premoves
+            .iter()
+            .find(|p| p.condition.as_ref() == Some(&opponent_turn));
+        let unconditional = premoves.iter().find(|p| p.condition.is_none());
+
+        let mut result = Ok(None);
+        for premove in conditional.into_iter().chain(unconditional) {
+            result = self.try_premove(premove);
+            if let Ok(Some(_)) = result {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Executes a single premove, if it completes the turn. Otherwise the game
+    /// is restored.
+    fn try_premove(
+        &mut self,
+        premove: &Premove,
+    ) -> Result<Option<(CurrentMatchState, Vec<Premove>)>, PacoError> {
+        let action_count = self.actions.len();
+        let timer = self.timer.clone();
+        let player = self.project()?.controlling_player();
+        match self.do_action(&premove.reply) {
+            Ok(state)
+                if state.controlling_player != player || state.victory_state.is_over() =>
+            {
+                Ok(Some((state, premove.then.clone())))
+            }
+            result => {
+                // Illegal or incomplete, so we drop it and restore the game.
+                self.actions.truncate(action_count);
+                self.timer = timer;
+                result.map(|_| None)
+            }
+        }
+    }
+
+    /// Splits the action history into turns. The last turn may still be in
+    /// progress, all others are completed.
+    fn turns(&self) -> Result<Vec<Turn>, PacoError> {
+        let mut board = pacosako::DenseBoard::with_options(&self.setup_options)?;
+        let mut turns = vec![Turn {
+            start: 0,
+            player: board.controlling_player(),
+            completed: false,
+        }];
+        for (index, action) in self.actions.iter().enumerate() {
+            let player = board.controlling_player();
+            board.execute_trusted(action.action)?;
+            if board.controlling_player() != player {
+                if let Some(last) = turns.last_mut() {
+                    last.completed = true;
+                }
+                turns.push(Turn {
+                    start: index + 1,
+                    player: board.controlling_player(),
+                    completed: false,
+                });
+            }
+        }
+        Ok(turns)
+    }
+
     /// Takes a board state that is provided in terms of an action history and
     /// rolls back an in-progress move. This will never change the active player.
     /// Rolling back on a settled board state does nothing.
@@ -447,6 +789,14 @@ impl SynchronizedMatch {
     }
 }
 
+/// A turn of a player, see `SynchronizedMatch::turns`.
+struct Turn {
+    /// Index of the first action of the turn.
+    start: usize,
+    player: PlayerColor,
+    completed: bool,
+}
+
 #[cfg(test)]
 mod test {
     use pacosako::const_tile::*;
@@ -461,6 +811,7 @@ mod test {
             "Game1",
             MatchParameters {
                 timer: None,
+                days_per_move: None,
                 safe_mode: Some(false),
                 draw_after_n_repetitions: None,
                 ai_side_request: None,
@@ -482,4 +833,228 @@ mod test {
         // there are two moves in the state.
         assert_eq!(current_state.actions.len(), 2);
     }
+
+    fn client_state(state: CurrentMatchState) -> CurrentMatchStateClient {
+        CurrentMatchStateClient {
+            key: state.key,
+            actions: state.actions,
+            is_rollback: state.is_rollback,
+            controlling_player: state.controlling_player,
+            timer: state.timer,
+            victory_state: state.victory_state,
+            setup_options: state.setup_options,
+            white_player: None,
+            black_player: None,
+            white_control: ControlLevel::Unlocked,
+            black_control: ControlLevel::Unlocked,
+        }
+    }
+
+    /// A delta only contains the actions the client does not know yet.
+    #[test]
+    fn test_delta_contains_only_new_actions() {
+        let mut game = SynchronizedMatch::new_with_key(
+            "Game1",
+            MatchParameters {
+                timer: None,
+                days_per_move: None,
+                safe_mode: Some(false),
+                draw_after_n_repetitions: None,
+                ai_side_request: None,
+                piece_setup: None,
+            },
+        );
+
+        game.do_action(&[Lift(C2)]).unwrap();
+        let state = game.do_action(&[Place(C3)]).unwrap();
+
+        let delta = MatchStateDelta::new(client_state(state), 1, 7);
+        assert_eq!(delta.sequence, 7);
+        assert_eq!(delta.keep_actions, 1);
+        let new_actions: Vec<PacoAction> = delta.new_actions.iter().map(|a| a.action).collect();
+        assert_eq!(new_actions, vec![Place(C3)]);
+        assert!(!delta.is_rollback);
+    }
+
+    /// A rollback delta truncates the history and does not append anything.
+    #[test]
+    fn test_delta_for_rollback() {
+        let mut game = SynchronizedMatch::new_with_key(
+            "Game1",
+            MatchParameters {
+                timer: None,
+                days_per_move: None,
+                safe_mode: Some(false),
+                draw_after_n_repetitions: None,
+                ai_side_request: None,
+                piece_setup: None,
+            },
+        );
+
+        game.do_action(&[Lift(C2), Place(C3)]).unwrap();
+        game.do_action(&[Lift(D7)]).unwrap();
+        let state = game.rollback().unwrap();
+
+        let delta = MatchStateDelta::new(client_state(state), 3, 2);
+        assert_eq!(delta.keep_actions, 2);
+        assert!(delta.new_actions.is_empty());
+        assert!(delta.is_rollback);
+    }
+
+    fn untimed_game() -> SynchronizedMatch {
+        SynchronizedMatch::new_with_key(
+            "Game1",
+            MatchParameters {
+                timer: None,
+                days_per_move: None,
+                safe_mode: Some(false),
+                draw_after_n_repetitions: None,
+                ai_side_request: None,
+                piece_setup: None,
+            },
+        )
+    }
+
+    /// Taking back removes the last completed turn of the requester and
+    /// everything after it.
+    #[test]
+    fn test_takeback() {
+        let mut game = untimed_game();
+        game.do_action(&[Lift(C2), Place(C3)]).unwrap();
+        game.do_action(&[Lift(D7), Place(D6)]).unwrap();
+        game.do_action(&[Lift(E2)]).unwrap();
+
+        // Black takes back their move, white's started move is gone as well.
+        let (state, removed) = game.takeback(PlayerColor::Black).unwrap().unwrap();
+        assert_eq!(game.actions.len(), 2);
+        assert_eq!(removed.len(), 3);
+        assert_eq!(state.controlling_player, PlayerColor::Black);
+        assert!(state.is_rollback);
+
+        // White takes back their first move.
+        let (state, _) = game.takeback(PlayerColor::White).unwrap().unwrap();
+        assert!(game.actions.is_empty());
+        assert_eq!(state.controlling_player, PlayerColor::White);
+    }
+
+    /// Without a completed turn, there is nothing to take back.
+    #[test]
+    fn test_takeback_without_completed_turn() {
+        let mut game = untimed_game();
+        game.do_action(&[Lift(C2), Place(C3)]).unwrap();
+        game.do_action(&[Lift(D7)]).unwrap();
+
+        assert!(game.takeback(PlayerColor::Black).unwrap().is_none());
+        assert_eq!(game.actions.len(), 3);
+    }
+
+    fn premove(condition: Option<Vec<PacoAction>>, reply: Vec<PacoAction>) -> Premove {
+        Premove {
+            condition,
+            reply,
+            then: vec![],
+        }
+    }
+
+    /// A conditional premove is preferred, otherwise an unconditional one is
+    /// used.
+    #[test]
+    fn test_execute_premove() {
+        let mut game = untimed_game();
+        game.do_action(&[Lift(C2), Place(C3)]).unwrap();
+
+        let premoves = vec![
+            premove(None, vec![Lift(E7), Place(E6)]),
+            premove(Some(vec![Lift(C2), Place(C3)]), vec![Lift(D7), Place(D6)]),
+            premove(Some(vec![Lift(D2), Place(D3)]), vec![Lift(A7), Place(A6)]),
+        ];
+        let (state, then) = game.execute_premove(premoves).unwrap().unwrap();
+        assert_eq!(state.controlling_player, PlayerColor::White);
+        assert!(then.is_empty());
+        assert_eq!(PacoAction::from(&game.actions[2]), Lift(D7));
+
+        let premoves = vec![premove(Some(vec![Lift(C2), Place(C3)]), vec![Lift(A2)])];
+        assert!(game.execute_premove(premoves).unwrap().is_none());
+    }
+
+    /// Illegal and incomplete premoves are dropped without changing the game.
+    #[test]
+    fn test_execute_premove_illegal() {
+        let mut game = untimed_game();
+        game.do_action(&[Lift(C2), Place(C3)]).unwrap();
+
+        let illegal = vec![premove(None, vec![Lift(D7), Place(D4)])];
+        assert!(game.execute_premove(illegal).is_err());
+        assert_eq!(game.actions.len(), 2);
+
+        let incomplete = vec![premove(None, vec![Lift(D7)])];
+        assert!(game.execute_premove(incomplete).unwrap().is_none());
+        assert_eq!(game.actions.len(), 2);
+    }
+
+    /// If the matching conditional premove is not legal, the premove without
+    /// condition is played instead.
+    #[test]
+    fn test_execute_premove_falls_back() {
+        let mut game = untimed_game();
+        game.do_action(&[Lift(C2), Place(C3)]).unwrap();
+
+        let premoves = vec![
+            premove(Some(vec![Lift(C2), Place(C3)]), vec![Lift(D7), Place(D4)]),
+            premove(None, vec![Lift(E7), Place(E6)]),
+        ];
+        let (state, _) = game.execute_premove(premoves).unwrap().unwrap();
+        assert_eq!(state.controlling_player, PlayerColor::White);
+        assert_eq!(game.actions.len(), 4);
+        assert_eq!(PacoAction::from(&game.actions[2]), Lift(E7));
+
+        // An incomplete conditional premove falls back as well.
+        game.do_action(&[Lift(D2), Place(D3)]).unwrap();
+        let premoves = vec![
+            premove(Some(vec![Lift(D2), Place(D3)]), vec![Lift(A7)]),
+            premove(None, vec![Lift(H7), Place(H6)]),
+        ];
+        assert!(game.execute_premove(premoves).unwrap().is_some());
+        assert_eq!(PacoAction::from(&game.actions[6]), Lift(H7));
+    }
+
+    /// Loading many games at once gives the same result as loading each game.
+    #[tokio::test]
+    async fn test_compressed_states_of_many_games() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let alice = user::create_user("alice", "identicon:1", &mut conn).await.unwrap();
+        let bob = user::create_user("bob", "identicon:2", &mut conn).await.unwrap();
+
+        let mut games = vec![];
+        for (white_player, black_player) in [(Some(alice), Some(bob)), (Some(bob), None)] {
+            let mut game = untimed_game();
+            game.white_player = white_player;
+            game.black_player = black_player;
+            db::game::insert(&mut game, &mut conn).await.unwrap();
+            games.push(game);
+        }
+        let ai = user::AiMetaData {
+            model_name: "hedwig".to_string(),
+            model_strength: 100,
+            model_temperature: 0.1,
+            is_frontend_ai: false,
+        };
+        user::write_one_ai_config_for_game(&games[0].key, PlayerColor::Black, &ai, &mut conn)
+            .await
+            .unwrap();
+        games[1].do_action(&[Lift(C2), Place(C3)]).unwrap();
+
+        let many = CompressedMatchStateClient::try_new_many(&games, &mut conn).await.unwrap();
+        assert_eq!(many.len(), 2);
+        for (game, state) in games.iter().zip(many) {
+            let single = CompressedMatchStateClient::try_new(game, &game.project().unwrap(), &mut conn)
+                .await
+                .unwrap();
+            assert_eq!(
+                serde_json::to_value(state).unwrap(),
+                serde_json::to_value(single).unwrap()
+            );
+        }
+    }
 }
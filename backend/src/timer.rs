@@ -20,25 +20,81 @@ pub struct TimerConfig {
         deserialize_with = "deserialize_seconds"
     )]
     pub time_budget_black: Duration,
+    /// The increment for `ClockMode::Fischer` and the delay for
+    /// `ClockMode::Bronstein` and `ClockMode::SimpleDelay`. Other modes
+    /// ignore it.
     #[serde(default)]
     #[serde(
         serialize_with = "serialize_seconds_optional",
         deserialize_with = "deserialize_seconds_optional"
     )]
     pub increment: Option<Duration>,
+    /// Timers created before there were clock modes don't have this field.
+    /// They all used a Fischer increment.
+    #[serde(default)]
+    pub mode: ClockMode,
+}
+
+/// Describes how the clock reacts to the moves of the players.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum ClockMode {
+    /// The `increment` is added to the budget after each turn.
+    #[default]
+    Fischer,
+    /// After each turn, the time used in this turn is given back, but at
+    /// most the delay stored in `increment`.
+    Bronstein,
+    /// Also called US delay. The clock only starts running down after the
+    /// delay stored in `increment` has passed in a turn. Unused delay is lost.
+    SimpleDelay,
+    /// Time used by one player is added to the budget of the other player.
+    Hourglass,
+    /// The time budget is not a budget for the game, but for each turn. It is
+    /// restored fully at the end of each turn. Meant for correspondence games.
+    FixedPerMove,
 }
 
 impl TimerConfig {
     /// Ensure that all values of the timer config are below 1000000. This
     /// ensures we don't trigger an overflow. See #85.
+    /// Correspondence games are limited by `MAX_DAYS_PER_MOVE` instead.
     pub fn sanitize(&self) -> Self {
-        let time_budget_white = limit_for_safety(self.time_budget_white);
-        let time_budget_black = limit_for_safety(self.time_budget_black);
+        let time_budget_white = self.mode.limit_budget(self.time_budget_white);
+        let time_budget_black = self.mode.limit_budget(self.time_budget_black);
         let increment = self.increment.map(limit_for_safety);
         Self {
             time_budget_white,
             time_budget_black,
             increment,
+            mode: self.mode,
+        }
+    }
+
+    /// Correspondence games give each side a number of days per move.
+    pub fn correspondence(days_per_move: i64) -> Self {
+        let budget = ClockMode::FixedPerMove.limit_budget(Duration::days(days_per_move));
+        Self {
+            time_budget_white: budget,
+            time_budget_black: budget,
+            increment: None,
+            mode: ClockMode::FixedPerMove,
+        }
+    }
+
+    /// Correspondence games are games where players are not expected to be
+    /// online for the whole game. Players get notified when it is their turn.
+    /// A fixed time per move below `MIN_CORRESPONDENCE_TIME_PER_MOVE` is a
+    /// live game, e.g. 10 seconds per move.
+    pub fn is_correspondence(&self) -> bool {
+        self.mode == ClockMode::FixedPerMove
+            && self.time_budget_white.min(self.time_budget_black) >= MIN_CORRESPONDENCE_TIME_PER_MOVE
+    }
+
+    /// The time budget of the given player.
+    pub fn budget(&self, player: PlayerColor) -> Duration {
+        match player {
+            PlayerColor::White => self.time_budget_white,
+            PlayerColor::Black => self.time_budget_black,
         }
     }
 
@@ -53,6 +109,23 @@ impl TimerConfig {
     }
 }
 
+impl ClockMode {
+    /// Limits a time budget for this mode. Correspondence games have days per
+    /// move, so they get a higher limit than the other modes.
+    fn limit_budget(self, budget: Duration) -> Duration {
+        match self {
+            ClockMode::FixedPerMove => budget.min(MAX_DAYS_PER_MOVE),
+            _ => limit_for_safety(budget),
+        }
+    }
+}
+
+/// Upper limit for the time per move of correspondence games.
+const MAX_DAYS_PER_MOVE: Duration = Duration::days(30);
+
+/// Games with a fixed time per move count as correspondence games from here.
+const MIN_CORRESPONDENCE_TIME_PER_MOVE: Duration = Duration::hours(1);
+
 /// Ensure that all values of the timer config are below 1000000. This
 /// ensures we don't trigger an overflow. See #85.
 fn limit_for_safety(to_limit: Duration) -> Duration {
@@ -72,6 +145,14 @@ pub struct Timer {
         deserialize_with = "deserialize_seconds"
     )]
     time_left_black: Duration,
+    /// Time the controlling player spent in the current turn so far. Required
+    /// by the delay modes, a turn can consist of several actions.
+    #[serde(default = "Duration::zero")]
+    #[serde(
+        serialize_with = "serialize_seconds",
+        deserialize_with = "deserialize_seconds"
+    )]
+    turn_time_used: Duration,
     timer_state: TimerState,
     pub config: TimerConfig,
 }
@@ -135,19 +216,23 @@ impl Timer {
 
         let time_passed: Duration = now - self.last_timestamp;
 
-        let time_left = match player {
-            PlayerColor::White => {
-                self.time_left_white -= time_passed;
-                &mut self.time_left_white
-            }
-            PlayerColor::Black => {
-                self.time_left_black -= time_passed;
-                &mut self.time_left_black
-            }
+        // With a simple delay, the clock only runs once the delay is used up.
+        let time_charged = match self.config.mode {
+            ClockMode::SimpleDelay => (time_passed - self.delay_left()).max(Duration::zero()),
+            _ => time_passed,
         };
+        self.turn_time_used += time_passed;
+
+        if self.config.mode == ClockMode::Hourglass {
+            let other = self.time_left_mut(player.other());
+            *other = limit_for_safety(*other + time_charged);
+        }
 
         self.last_timestamp = now;
 
+        let time_left = self.time_left_mut(player);
+        *time_left -= time_charged;
+
         // Check if the time ran out
         if *time_left <= Duration::nanoseconds(0) {
             *time_left = Duration::nanoseconds(0);
@@ -162,21 +247,63 @@ impl Timer {
         self.timer_state = TimerState::Stopped
     }
 
-    /// Increases the given players budget by the increment configured in the
-    /// timer. This can not be directly included in the use time, because a
-    /// player may use time multiple timer in a single turn. (Each action calls
-    /// use time.)
+    /// Ends the turn of the given player and applies the increment, delay or
+    /// reset of the clock mode. This can not be directly included in the use
+    /// time, because a player may use time multiple timer in a single turn.
+    /// (Each action calls use time.)
     pub fn increment(&mut self, player: PlayerColor) {
-        if let Some(increment) = self.config.increment {
-            match player {
-                PlayerColor::White => {
-                    self.time_left_white = limit_for_safety(self.time_left_white + increment);
-                }
-                PlayerColor::Black => {
-                    self.time_left_black = limit_for_safety(self.time_left_black + increment);
-                }
+        let increment = self.config.increment.unwrap_or_else(Duration::zero);
+        let bonus = match self.config.mode {
+            ClockMode::Fischer => increment,
+            ClockMode::Bronstein => self.turn_time_used.min(increment),
+            ClockMode::SimpleDelay | ClockMode::Hourglass => Duration::zero(),
+            ClockMode::FixedPerMove => {
+                // The player gets the full budget back for their next turn.
+                *self.time_left_mut(player) = self.config.budget(player);
+                Duration::zero()
+            }
+        };
+        let mode = self.config.mode;
+        let time_left = self.time_left_mut(player);
+        *time_left = mode.limit_budget(*time_left + bonus);
+        self.turn_time_used = Duration::zero();
+    }
+
+    /// Undoes the effect a turn of the given player had on the clock, as far
+    /// as possible. This is used for takebacks, the time that was used in the
+    /// turn is not given back.
+    ///
+    /// A completed turn got an increment which is revoked. In correspondence
+    /// games, the player gets the full time for the repeated turn.
+    pub fn take_back_turn(&mut self, player: PlayerColor, completed: bool) {
+        let increment = self.config.increment.unwrap_or_else(Duration::zero);
+        let budget = self.config.budget(player);
+        let mode = self.config.mode;
+        let time_left = self.time_left_mut(player);
+        match mode {
+            ClockMode::Fischer if completed => {
+                *time_left = (*time_left - increment).max(Duration::zero());
             }
+            ClockMode::FixedPerMove => *time_left = budget,
+            _ => {}
+        }
+        self.turn_time_used = Duration::zero();
+    }
+
+    fn time_left_mut(&mut self, player: PlayerColor) -> &mut Duration {
+        match player {
+            PlayerColor::White => &mut self.time_left_white,
+            PlayerColor::Black => &mut self.time_left_black,
+        }
+    }
+
+    /// How much of the simple delay is left in the current turn.
+    fn delay_left(&self) -> Duration {
+        if self.config.mode != ClockMode::SimpleDelay {
+            return Duration::zero();
         }
+        let delay = self.config.increment.unwrap_or_else(Duration::zero);
+        (delay - self.turn_time_used).max(Duration::zero())
     }
 
     pub const fn get_state(&self) -> TimerState {
@@ -198,7 +325,7 @@ impl Timer {
             _ => self.last_timestamp,
         };
 
-        fake_now + time_left
+        fake_now + time_left + self.delay_left()
     }
 
     /// Ensure that all values of the timer config are below 1000000. This
@@ -206,8 +333,9 @@ impl Timer {
     pub fn sanitize(self) -> Self {
         Self {
             last_timestamp: self.last_timestamp,
-            time_left_white: limit_for_safety(self.time_left_white),
-            time_left_black: limit_for_safety(self.time_left_black),
+            time_left_white: self.config.mode.limit_budget(self.time_left_white),
+            time_left_black: self.config.mode.limit_budget(self.time_left_black),
+            turn_time_used: limit_for_safety(self.turn_time_used),
             timer_state: self.timer_state,
             config: self.config.sanitize(),
         }
@@ -245,6 +373,7 @@ impl From<TimerConfig> for Timer {
             last_timestamp: Utc::now(),
             time_left_white: config.time_budget_white,
             time_left_black: config.time_budget_black,
+            turn_time_used: Duration::zero(),
             timer_state: TimerState::NotStarted,
             config,
         }
@@ -257,6 +386,7 @@ impl From<&TimerConfig> for Timer {
             last_timestamp: Utc::now(),
             time_left_white: config.time_budget_white,
             time_left_black: config.time_budget_black,
+            turn_time_used: Duration::zero(),
             timer_state: TimerState::NotStarted,
             config: config.clone(),
         }
@@ -272,6 +402,16 @@ mod test {
             time_budget_white: Duration::seconds(5 * 60),
             time_budget_black: Duration::seconds(4 * 60),
             increment: None,
+            mode: ClockMode::Fischer,
+        }
+    }
+
+    fn config_with_mode(mode: ClockMode, increment: i64) -> TimerConfig {
+        TimerConfig {
+            time_budget_white: Duration::seconds(5 * 60),
+            time_budget_black: Duration::seconds(5 * 60),
+            increment: Some(Duration::seconds(increment)),
+            mode,
         }
     }
 
@@ -353,6 +493,7 @@ mod test {
             time_budget_white: Duration::seconds(5 * 60),
             time_budget_black: Duration::seconds(5 * 60),
             increment: Some(Duration::seconds(5)),
+            mode: ClockMode::Fischer,
         };
 
         let mut timer: Timer = config.into();
@@ -369,4 +510,162 @@ mod test {
         assert_eq!(timer.time_left_black, Duration::seconds(300));
         assert_eq!(timer.get_state(), TimerState::Running);
     }
+
+    #[test]
+    fn test_bronstein_delay() {
+        use PlayerColor::*;
+        let mut timer: Timer = config_with_mode(ClockMode::Bronstein, 5).into();
+        let now = Utc::now();
+        timer.start(now);
+
+        // A quick turn gets all the used time back.
+        let now = now + Duration::seconds(3);
+        timer.use_time(White, now);
+        timer.increment(White);
+        assert_eq!(timer.time_left_white, Duration::seconds(300));
+
+        // A slow turn over several actions gets at most the delay back.
+        let now = now + Duration::seconds(4);
+        timer.use_time(Black, now);
+        let now = now + Duration::seconds(6);
+        timer.use_time(Black, now);
+        timer.increment(Black);
+        assert_eq!(timer.time_left_black, Duration::seconds(295));
+        assert_eq!(timer.time_left_white, Duration::seconds(300));
+    }
+
+    #[test]
+    fn test_simple_delay() {
+        use PlayerColor::*;
+        let mut timer: Timer = config_with_mode(ClockMode::SimpleDelay, 5).into();
+        let now = Utc::now();
+        timer.start(now);
+
+        // The timeout includes the delay.
+        assert_eq!(timer.timeout(White), now + Duration::seconds(305));
+
+        // Within the delay, no time is used.
+        let now = now + Duration::seconds(3);
+        timer.use_time(White, now);
+        assert_eq!(timer.time_left_white, Duration::seconds(300));
+        assert_eq!(timer.timeout(White), now + Duration::seconds(302));
+
+        // After the delay, the clock runs down.
+        let now = now + Duration::seconds(4);
+        timer.use_time(White, now);
+        assert_eq!(timer.time_left_white, Duration::seconds(298));
+        timer.increment(White);
+        assert_eq!(timer.time_left_white, Duration::seconds(298));
+
+        // Unused delay is not carried over into the next turn.
+        let now = now + Duration::seconds(1);
+        timer.use_time(Black, now);
+        timer.increment(Black);
+        let now = now + Duration::seconds(6);
+        timer.use_time(White, now);
+        assert_eq!(timer.time_left_white, Duration::seconds(297));
+        assert_eq!(timer.time_left_black, Duration::seconds(300));
+    }
+
+    #[test]
+    fn test_hourglass() {
+        use PlayerColor::*;
+        let mut timer: Timer = config_with_mode(ClockMode::Hourglass, 5).into();
+        let now = Utc::now();
+        timer.start(now);
+
+        let now = now + Duration::seconds(20);
+        timer.use_time(White, now);
+        timer.increment(White);
+        assert_eq!(timer.time_left_white, Duration::seconds(280));
+        assert_eq!(timer.time_left_black, Duration::seconds(320));
+
+        let now = now + Duration::seconds(330);
+        timer.use_time(Black, now);
+        assert_eq!(timer.time_left_black, Duration::seconds(0));
+        assert_eq!(timer.get_state(), TimerState::Timeout(Black));
+    }
+
+    #[test]
+    fn test_fixed_per_move() {
+        use PlayerColor::*;
+        let mut timer: Timer = config_with_mode(ClockMode::FixedPerMove, 5).into();
+        let now = Utc::now();
+        timer.start(now);
+
+        // The budget is restored after each turn, the increment is ignored.
+        let now = now + Duration::seconds(200);
+        timer.use_time(White, now);
+        assert_eq!(timer.time_left_white, Duration::seconds(100));
+        timer.increment(White);
+        assert_eq!(timer.time_left_white, Duration::seconds(300));
+
+        // Running over the budget of a single turn is a timeout.
+        let now = now + Duration::seconds(301);
+        timer.use_time(Black, now);
+        assert_eq!(timer.get_state(), TimerState::Timeout(Black));
+    }
+
+    #[test]
+    fn test_config_without_mode_is_fischer() {
+        let config: TimerConfig = serde_json::from_str(
+            r#"{"time_budget_white":300.0,"time_budget_black":240.0,"increment":5.0}"#,
+        )
+        .unwrap();
+        assert_eq!(config.mode, ClockMode::Fischer);
+        assert_eq!(config.increment, Some(Duration::seconds(5)));
+
+        let timer: Timer = serde_json::from_str(
+            r#"{"last_timestamp":"2024-01-01T00:00:00Z","time_left_white":10.0,"time_left_black":10.0,"timer_state":"Running","config":{"time_budget_white":300.0,"time_budget_black":240.0,"increment":null}}"#,
+        )
+        .unwrap();
+        assert_eq!(timer.turn_time_used, Duration::zero());
+        assert_eq!(timer.config.mode, ClockMode::Fischer);
+    }
+
+    #[test]
+    fn test_correspondence_budget() {
+        let config = TimerConfig::correspondence(3);
+        assert!(config.is_correspondence());
+        assert_eq!(config.time_budget_white, Duration::days(3));
+
+        // A fixed time per move is not enough, 10 seconds per move is blitz.
+        let blitz = TimerConfig {
+            time_budget_white: Duration::seconds(10),
+            time_budget_black: Duration::seconds(10),
+            increment: None,
+            mode: ClockMode::FixedPerMove,
+        };
+        assert!(!blitz.is_correspondence());
+
+        // Correspondence games may exceed the usual limit, but not without bound.
+        let config = TimerConfig::correspondence(365).sanitize();
+        assert_eq!(config.time_budget_black, Duration::days(30));
+        let config = TimerConfig {
+            mode: ClockMode::Fischer,
+            ..TimerConfig::correspondence(20)
+        };
+        assert_eq!(config.sanitize().time_budget_white, Duration::seconds(1_000_000));
+    }
+
+    #[test]
+    fn test_take_back_turn() {
+        use PlayerColor::*;
+        let mut timer: Timer = config_with_mode(ClockMode::Fischer, 5).into();
+        let now = Utc::now();
+        timer.start(now);
+
+        let now = now + Duration::seconds(10);
+        timer.use_time(White, now);
+        timer.increment(White);
+        assert_eq!(timer.time_left_white, Duration::seconds(295));
+
+        // The increment is revoked, the time used stays used.
+        timer.take_back_turn(White, true);
+        assert_eq!(timer.time_left_white, Duration::seconds(290));
+
+        // A turn that was not completed never got an increment.
+        timer.take_back_turn(Black, false);
+        assert_eq!(timer.time_left_black, Duration::seconds(300));
+    }
 }
@@ -0,0 +1,594 @@
+//! Tournaments pair registered players into games with the same parameters.
+//!
+//! A Swiss tournament is played in rounds. The next round is paired once all
+//! games of the current round are over. An arena runs for a fixed time and
+//! pairs players again as soon as their game is over.
+//!
+//! Results are collected from the rooms: whenever a game is stored with a
+//! final `VictoryState`, `on_game_over` records the outcome and moves the
+//! tournament forward. Clients subscribe to a tournament over the websocket
+//! and get the full `TournamentInfo` after each change.
+
+use std::collections::HashSet;
+
+use axum::{
+    extract::{Path, State},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use pacosako::VictoryState;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::actors::websocket::SocketId;
+use crate::db::{self, tournament::TournamentRecord, Connection, Pool};
+use crate::event_stream::{self, StreamEvent};
+use crate::follow;
+use crate::login::permission::{CreateTournament, RequirePermission};
+use crate::login::session::SessionData;
+use crate::login::user::{load_public_user_data, PublicUserData};
+use crate::login::UserId;
+use crate::sync_match::{MatchParameters, SynchronizedMatch};
+use crate::ws::{self, ServerMessage};
+use crate::{AppState, ServerError};
+
+pub mod pairing;
+
+use pairing::{Outcome, Pairing, Standing, TournamentKind};
+
+/// Upper limit for the number of Swiss rounds.
+const MAX_ROUNDS: i64 = 20;
+
+/// Limits for the duration of an arena.
+const MIN_ARENA_MINUTES: i64 = 5;
+const MAX_ARENA_MINUTES: i64 = 24 * 60;
+
+const MAX_NAME_LENGTH: usize = 100;
+
+/// How many tournaments the overview shows.
+const LIST_LIMIT: i64 = 50;
+
+lazy_static! {
+    /// Websockets that want to know about changes of a tournament.
+    static ref SUBSCRIBERS: DashMap<i64, Vec<SocketId>> = DashMap::new();
+}
+
+/// Pairing a tournament reads and writes several tables. Only one task may
+/// do that at a time, so two games ending at once don't pair a round twice.
+static ADVANCE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Adds the tournament API to the given router.
+/// This is expected to be nested at "/api".
+pub fn add_to_router(api_router: Router<AppState>) -> Router<AppState> {
+    api_router
+        .route("/tournament", get(list_tournaments).post(create_tournament))
+        .route("/tournament/:id", get(get_tournament))
+        .route("/tournament/:id/join", post(join_tournament))
+        .route("/tournament/:id/leave", post(leave_tournament))
+        .route("/tournament/:id/start", post(start_tournament))
+}
+
+#[derive(Deserialize)]
+pub struct CreateTournamentRequest {
+    pub name: String,
+    pub kind: TournamentKind,
+    pub parameters: MatchParameters,
+    /// Swiss only.
+    #[serde(default)]
+    pub rounds: Option<i64>,
+    /// Arena only.
+    #[serde(default)]
+    pub duration_minutes: Option<i64>,
+}
+
+/// A tournament as shown in the overview.
+#[derive(Serialize, Clone, Debug)]
+pub struct TournamentSummary {
+    pub id: i64,
+    pub name: String,
+    pub kind: TournamentKind,
+    pub parameters: MatchParameters,
+    pub rounds: Option<i64>,
+    pub duration_minutes: Option<i64>,
+    pub status: String,
+    pub current_round: i64,
+    pub created_by: UserId,
+    pub created_at: Option<String>,
+    pub started_at: Option<String>,
+    pub ends_at: Option<i64>,
+    pub finished_at: Option<String>,
+}
+
+impl From<TournamentRecord> for TournamentSummary {
+    fn from(record: TournamentRecord) -> Self {
+        Self {
+            id: record.id,
+            name: record.name,
+            kind: record.kind,
+            parameters: record.parameters,
+            rounds: record.rounds,
+            duration_minutes: record.duration_minutes,
+            status: record.status,
+            current_round: record.current_round,
+            created_by: record.created_by,
+            created_at: record.created_at,
+            started_at: record.started_at,
+            ends_at: record.ends_at,
+            finished_at: record.finished_at,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TournamentPlayer {
+    pub user: PublicUserData,
+    pub withdrawn: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TournamentGameInfo {
+    pub round: i64,
+    /// None for a bye.
+    pub game_key: Option<String>,
+    pub white: UserId,
+    pub black: Option<UserId>,
+    pub outcome: Option<Outcome>,
+}
+
+/// Everything about a tournament, as shown on the tournament page.
+#[derive(Serialize, Clone, Debug)]
+pub struct TournamentInfo {
+    #[serde(flatten)]
+    pub summary: TournamentSummary,
+    pub players: Vec<TournamentPlayer>,
+    pub standings: Vec<Standing>,
+    pub games: Vec<TournamentGameInfo>,
+}
+
+/// Sent to the account stream of both players when a tournament game starts.
+#[derive(Serialize)]
+struct TournamentGameStart<'a> {
+    tournament_id: i64,
+    game_key: &'a str,
+    color: pacosako::PlayerColor,
+}
+
+/// Everything about a tournament in a shape the pairing logic understands.
+struct Loaded {
+    record: TournamentRecord,
+    players: Vec<(UserId, bool)>,
+    games: Vec<db::tournament::TournamentGame>,
+}
+
+impl Loaded {
+    async fn load(id: i64, conn: &mut Connection) -> Result<Self, ServerError> {
+        let Some(record) = db::tournament::select(id, conn).await? else {
+            return Err(ServerError::NotFound);
+        };
+        Ok(Self {
+            record,
+            players: db::tournament::players(id, conn).await?,
+            games: db::tournament::games(id, conn).await?,
+        })
+    }
+
+    fn all_players(&self) -> Vec<UserId> {
+        self.players.iter().map(|&(p, _)| p).collect()
+    }
+
+    fn active_players(&self) -> Vec<UserId> {
+        self.players
+            .iter()
+            .filter(|(_, withdrawn)| !withdrawn)
+            .map(|&(p, _)| p)
+            .collect()
+    }
+
+    fn pairings(&self) -> Vec<Pairing> {
+        self.games.iter().map(|g| g.pairing.clone()).collect()
+    }
+
+    fn has_pending_games(&self) -> bool {
+        self.games.iter().any(|g| g.pairing.outcome.is_none())
+    }
+
+    /// Active players of an arena who are not playing right now.
+    fn waiting_players(&self) -> Vec<UserId> {
+        let playing: HashSet<UserId> = self
+            .games
+            .iter()
+            .filter(|g| g.pairing.outcome.is_none())
+            .flat_map(|g| [Some(g.pairing.white), g.pairing.black])
+            .flatten()
+            .collect();
+        self.active_players()
+            .into_iter()
+            .filter(|p| !playing.contains(p))
+            .collect()
+    }
+
+    async fn into_info(self, conn: &mut Connection) -> Result<TournamentInfo, ServerError> {
+        let standings =
+            pairing::standings(self.record.kind, &self.all_players(), &self.pairings());
+        let mut players = Vec::with_capacity(self.players.len());
+        for (user_id, withdrawn) in self.players {
+            players.push(TournamentPlayer {
+                user: load_public_user_data(user_id, conn).await?,
+                withdrawn,
+            });
+        }
+        let games = self
+            .games
+            .into_iter()
+            .map(|g| TournamentGameInfo {
+                round: g.round,
+                game_key: g.game_key,
+                white: g.pairing.white,
+                black: g.pairing.black,
+                outcome: g.pairing.outcome,
+            })
+            .collect();
+        Ok(TournamentInfo {
+            summary: self.record.into(),
+            players,
+            standings,
+            games,
+        })
+    }
+}
+
+pub async fn load_info(id: i64, conn: &mut Connection) -> Result<TournamentInfo, ServerError> {
+    Loaded::load(id, conn).await?.into_info(conn).await
+}
+
+/// Creates a tournament. The route checks the `create_tournament` permission.
+pub async fn create(
+    user_id: UserId,
+    request: CreateTournamentRequest,
+    conn: &mut Connection,
+) -> Result<TournamentSummary, ServerError> {
+    let name = request.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ServerError::BadRequest);
+    }
+    // Games with AI are not paired with other players.
+    if !request.parameters.is_legal() || request.parameters.ai_side_request.is_some() {
+        return Err(ServerError::BadRequest);
+    }
+    let (rounds, duration_minutes) = match request.kind {
+        TournamentKind::Swiss => match request.rounds {
+            Some(rounds) if (1..=MAX_ROUNDS).contains(&rounds) => (Some(rounds), None),
+            _ => return Err(ServerError::BadRequest),
+        },
+        TournamentKind::Arena => match request.duration_minutes {
+            Some(minutes) if (MIN_ARENA_MINUTES..=MAX_ARENA_MINUTES).contains(&minutes) => {
+                (None, Some(minutes))
+            }
+            _ => return Err(ServerError::BadRequest),
+        },
+    };
+
+    let parameters = request.parameters.sanitize();
+    let id = db::tournament::insert(
+        name,
+        request.kind,
+        &parameters,
+        rounds,
+        duration_minutes,
+        user_id,
+        conn,
+    )
+        .await?;
+    info!("User {} created tournament {}.", user_id.0, id);
+
+    let Some(record) = db::tournament::select(id, conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    Ok(record.into())
+}
+
+/// Registers the user. Swiss tournaments only take players before they start,
+/// arenas also while they are running.
+pub async fn join(id: i64, user_id: UserId, conn: &mut Connection) -> Result<(), ServerError> {
+    let _guard = ADVANCE_LOCK.lock().await;
+    let Some(record) = db::tournament::select(id, conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    let open = match record.status.as_str() {
+        "registration" => true,
+        "running" => record.kind == TournamentKind::Arena,
+        _ => false,
+    };
+    if !open {
+        return Err(ServerError::NotAllowed(
+            "This tournament does not take new players.".to_string(),
+        ));
+    }
+    db::tournament::add_player(id, user_id, conn).await?;
+    if record.status == "running" {
+        advance(id, conn).await?;
+    }
+    publish(id, conn).await;
+    Ok(())
+}
+
+/// Withdraws the user. Games they are playing right now continue.
+pub async fn leave(id: i64, user_id: UserId, conn: &mut Connection) -> Result<(), ServerError> {
+    let _guard = ADVANCE_LOCK.lock().await;
+    let Some(record) = db::tournament::select(id, conn).await? else {
+        return Err(ServerError::NotFound);
+    };
+    if record.status == "finished" {
+        return Err(ServerError::NotAllowed(
+            "This tournament is over.".to_string(),
+        ));
+    }
+    db::tournament::withdraw_player(id, user_id, record.status == "registration", conn).await?;
+    publish(id, conn).await;
+    Ok(())
+}
+
+/// Starts the tournament and pairs the first games. Only the creator can do this.
+pub async fn start(id: i64, user_id: UserId, conn: &mut Connection) -> Result<(), ServerError> {
+    let _guard = ADVANCE_LOCK.lock().await;
+    let loaded = Loaded::load(id, conn).await?;
+    if loaded.record.created_by != user_id {
+        return Err(ServerError::NotAllowed(
+            "Only the creator can start the tournament.".to_string(),
+        ));
+    }
+    if loaded.active_players().len() < 2 {
+        return Err(ServerError::NotAllowed(
+            "A tournament needs at least two players.".to_string(),
+        ));
+    }
+    let ends_at = loaded
+        .record
+        .duration_minutes
+        .map(|minutes| Utc::now().timestamp_millis() + minutes * 60 * 1000);
+    if !db::tournament::start(id, ends_at, conn).await? {
+        return Err(ServerError::NotAllowed(
+            "This tournament already started.".to_string(),
+        ));
+    }
+    info!("Tournament {} started.", id);
+    advance(id, conn).await?;
+    publish(id, conn).await;
+    Ok(())
+}
+
+/// Records the result of a game if it belongs to a tournament. This is called
+/// whenever a game is stored, so it must be cheap for all other games.
+pub async fn on_game_over(
+    game_key: &str,
+    victory_state: VictoryState,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let Some(outcome) = Outcome::from_victory_state(victory_state) else {
+        return Ok(());
+    };
+    let _guard = ADVANCE_LOCK.lock().await;
+    let Some(id) = db::tournament::set_outcome(game_key, outcome, conn).await? else {
+        return Ok(());
+    };
+    info!("Game {} of tournament {} ended with {:?}.", game_key, id, outcome);
+    advance(id, conn).await?;
+    publish(id, conn).await;
+    Ok(())
+}
+
+/// Pairs waiting arena players and finishes arenas which are over. Called
+/// periodically, as arenas also need to end when no game finishes.
+pub async fn tick(conn: &mut Connection) -> Result<(), ServerError> {
+    let _guard = ADVANCE_LOCK.lock().await;
+    for id in db::tournament::running_arenas(conn).await? {
+        if advance(id, conn).await? {
+            publish(id, conn).await;
+        }
+    }
+    Ok(())
+}
+
+/// Pairs new games or finishes the tournament, whatever is due. Returns true
+/// if anything changed. Callers must hold the `ADVANCE_LOCK`.
+async fn advance(id: i64, conn: &mut Connection) -> Result<bool, ServerError> {
+    let mut changed = false;
+    loop {
+        let loaded = Loaded::load(id, conn).await?;
+        if loaded.record.status != "running" {
+            return Ok(changed);
+        }
+        match loaded.record.kind {
+            TournamentKind::Swiss => {
+                if loaded.has_pending_games() {
+                    return Ok(changed);
+                }
+                let rounds = loaded.record.rounds.unwrap_or(0);
+                let active = loaded.active_players();
+                if loaded.record.current_round >= rounds || active.len() < 2 {
+                    finish(id, conn).await?;
+                    return Ok(true);
+                }
+                let round = loaded.record.current_round;
+                if !db::tournament::next_round(id, round, conn).await? {
+                    return Ok(changed);
+                }
+                let pairings = pairing::swiss_pairings(&active, &loaded.pairings());
+                create_games(&loaded.record, round + 1, pairings, conn).await?;
+                info!("Tournament {} paired round {}.", id, round + 1);
+                // If the round only had byes, the next one is due right away.
+                changed = true;
+            }
+            TournamentKind::Arena => {
+                let over = loaded
+                    .record
+                    .ends_at
+                    .is_some_and(|ends_at| ends_at <= Utc::now().timestamp_millis());
+                if over {
+                    if !loaded.has_pending_games() {
+                        finish(id, conn).await?;
+                        return Ok(true);
+                    }
+                    return Ok(changed);
+                }
+                let pairings = pairing::arena_pairings(
+                    &loaded.waiting_players(),
+                    &loaded.all_players(),
+                    &loaded.pairings(),
+                );
+                if pairings.is_empty() {
+                    return Ok(changed);
+                }
+                create_games(&loaded.record, 0, pairings, conn).await?;
+                return Ok(true);
+            }
+        }
+    }
+}
+
+async fn finish(id: i64, conn: &mut Connection) -> Result<(), ServerError> {
+    if db::tournament::finish(id, conn).await? {
+        info!("Tournament {} is finished.", id);
+    }
+    Ok(())
+}
+
+/// Creates a game for each pairing and tells the players about it. Byes
+/// are only stored.
+async fn create_games(
+    record: &TournamentRecord,
+    round: i64,
+    pairings: Vec<Pairing>,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    for pairing in pairings {
+        let Some(black) = pairing.black else {
+            db::tournament::insert_game(record.id, round, None, &pairing, conn).await?;
+            continue;
+        };
+        let mut game = SynchronizedMatch::new_with_key("0", record.parameters.clone());
+        game.white_player = Some(pairing.white);
+        game.black_player = Some(black);
+        db::game::insert(&mut game, conn).await?;
+        db::tournament::insert_game(record.id, round, Some(&game.key), &pairing, conn).await?;
+
+        for (player, color) in [
+            (pairing.white, pacosako::PlayerColor::White),
+            (black, pacosako::PlayerColor::Black),
+        ] {
+            let event = TournamentGameStart {
+                tournament_id: record.id,
+                game_key: &game.key,
+                color,
+            };
+            event_stream::publish_to_user(player, StreamEvent::new("gameStart", &event)?);
+        }
+        follow::announce_game_start(&game.key, &[pairing.white, black], conn).await;
+    }
+    Ok(())
+}
+
+/// Sends the tournament to the websocket and remembers it for later updates.
+pub async fn subscribe(id: i64, socket: SocketId, conn: &mut Connection) {
+    let info = match load_info(id, conn).await {
+        Ok(info) => info,
+        Err(e) => {
+            warn!("Could not load tournament {}: {:?}", id, e);
+            ws::send_msg(
+                ServerMessage::Error(format!("Tournament {id} not found")),
+                &socket,
+            )
+                .await;
+            return;
+        }
+    };
+    {
+        let mut subscribers = SUBSCRIBERS.entry(id).or_default();
+        if !subscribers.contains(&socket) {
+            subscribers.push(socket);
+        }
+    }
+    ws::send_msg(ServerMessage::TournamentUpdate(Box::new(info)), &socket).await;
+}
+
+/// Sends the current state of the tournament to all subscribers. Errors are
+/// only logged, the change itself already happened.
+async fn publish(id: i64, conn: &mut Connection) {
+    let sockets: Vec<SocketId> = {
+        let Some(mut subscribers) = SUBSCRIBERS.get_mut(&id) else {
+            return;
+        };
+        // Forget websockets which are closed.
+        subscribers.retain(|socket| socket.sender().is_some());
+        subscribers.clone()
+    };
+    SUBSCRIBERS.remove_if(&id, |_, subscribers| subscribers.is_empty());
+    if sockets.is_empty() {
+        return;
+    }
+
+    let info = match load_info(id, conn).await {
+        Ok(info) => info,
+        Err(e) => {
+            warn!("Could not load tournament {} for subscribers: {:?}", id, e);
+            return;
+        }
+    };
+    let message = ServerMessage::TournamentUpdate(Box::new(info));
+    for socket in sockets {
+        ws::send_msg(message.clone(), &socket).await;
+    }
+}
+
+async fn list_tournaments(State(pool): State<Pool>) -> Result<Json<Vec<TournamentSummary>>, ServerError> {
+    let mut conn = pool.conn().await?;
+    let records = db::tournament::latest(LIST_LIMIT, &mut conn).await?;
+    Ok(Json(records.into_iter().map(TournamentSummary::from).collect()))
+}
+
+async fn create_tournament(
+    RequirePermission { session, .. }: RequirePermission<CreateTournament>,
+    State(pool): State<Pool>,
+    Json(request): Json<CreateTournamentRequest>,
+) -> Result<Json<TournamentSummary>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(create(session.user_id, request, &mut conn).await?))
+}
+
+async fn get_tournament(
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<Json<TournamentInfo>, ServerError> {
+    let mut conn = pool.conn().await?;
+    Ok(Json(load_info(id, &mut conn).await?))
+}
+
+async fn join_tournament(
+    session: SessionData,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    join(id, session.user_id, &mut conn).await
+}
+
+async fn leave_tournament(
+    session: SessionData,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    leave(id, session.user_id, &mut conn).await
+}
+
+async fn start_tournament(
+    session: SessionData,
+    Path(id): Path<i64>,
+    State(pool): State<Pool>,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    start(id, session.user_id, &mut conn).await
+}
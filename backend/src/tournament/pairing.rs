@@ -0,0 +1,458 @@
+//! Pairing and scoring of tournaments. This is pure logic, the tournament
+//! module takes care of loading and storing everything.
+//!
+//! A win is worth 2 points, a draw 1 point. In an arena, a player who won
+//! their last two games is on a streak and gets double points until they fail
+//! to win a game.
+
+use std::collections::{HashMap, HashSet};
+
+use pacosako::{PlayerColor, VictoryState};
+use serde::{Deserialize, Serialize};
+
+use crate::login::UserId;
+
+/// Upper limit for the search of Swiss pairings without rematches. Pairing
+/// happens while other tournament updates wait, so it must stay fast even when
+/// there is no such pairing.
+const MAX_PAIRING_STEPS: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TournamentKind {
+    /// A fixed number of rounds. Players with similar scores play each other
+    /// and nobody plays the same opponent twice, if possible.
+    Swiss,
+    /// Runs for a fixed time. Players are paired again as soon as their game
+    /// is over.
+    Arena,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    WhiteWins,
+    BlackWins,
+    Draw,
+}
+
+impl Outcome {
+    /// None while the game is running.
+    pub fn from_victory_state(victory_state: VictoryState) -> Option<Self> {
+        match victory_state {
+            VictoryState::Running => None,
+            VictoryState::PacoVictory(PlayerColor::White)
+            | VictoryState::TimeoutVictory(PlayerColor::White) => Some(Self::WhiteWins),
+            VictoryState::PacoVictory(PlayerColor::Black)
+            | VictoryState::TimeoutVictory(PlayerColor::Black) => Some(Self::BlackWins),
+            VictoryState::NoProgressDraw | VictoryState::RepetitionDraw => Some(Self::Draw),
+        }
+    }
+}
+
+/// A game of the tournament. Without a black player, this is a bye for white.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pairing {
+    pub white: UserId,
+    pub black: Option<UserId>,
+    /// None while the game is running.
+    pub outcome: Option<Outcome>,
+}
+
+impl Pairing {
+    fn is_bye(&self) -> bool {
+        self.black.is_none()
+    }
+
+    fn opponent(&self, player: UserId) -> Option<UserId> {
+        if self.white == player {
+            self.black
+        } else if self.black == Some(player) {
+            Some(self.white)
+        } else {
+            None
+        }
+    }
+
+    /// Points the player got from this game without any streak bonus.
+    /// A bye counts as a win.
+    fn base_points(&self, player: UserId) -> Option<u32> {
+        let outcome = self.outcome?;
+        let is_white = self.white == player;
+        Some(match outcome {
+            Outcome::Draw => 1,
+            Outcome::WhiteWins if is_white => 2,
+            Outcome::BlackWins if !is_white => 2,
+            _ => 0,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Standing {
+    pub user_id: UserId,
+    pub points: u32,
+    /// Sum of the points of all opponents.
+    pub buchholz: u32,
+    /// Points of beaten opponents plus half the points of drawn opponents.
+    pub sonneborn_berger: f32,
+    pub games: u32,
+    pub wins: u32,
+    /// Arena only, the player gets double points right now.
+    pub on_streak: bool,
+}
+
+/// Ranks the players. Ties are broken by Buchholz, then Sonneborn-Berger, then
+/// the order of `players`. Pairings must be in the order they were played.
+pub fn standings(kind: TournamentKind, players: &[UserId], pairings: &[Pairing]) -> Vec<Standing> {
+    let mut result: Vec<Standing> = players
+        .iter()
+        .map(|&user_id| Standing {
+            user_id,
+            points: 0,
+            buchholz: 0,
+            sonneborn_berger: 0.0,
+            games: 0,
+            wins: 0,
+            on_streak: false,
+        })
+        .collect();
+    let mut win_streak: HashMap<UserId, u32> = HashMap::new();
+
+    for pairing in pairings {
+        for player in [Some(pairing.white), pairing.black].into_iter().flatten() {
+            let Some(base) = pairing.base_points(player) else {
+                continue;
+            };
+            let Some(standing) = result.iter_mut().find(|s| s.user_id == player) else {
+                continue;
+            };
+            let streak = win_streak.entry(player).or_default();
+            let on_streak = kind == TournamentKind::Arena && *streak >= 2;
+            standing.points += if on_streak { base * 2 } else { base };
+            standing.games += 1;
+            if base == 2 {
+                standing.wins += 1;
+                *streak += 1;
+            } else {
+                *streak = 0;
+            }
+            standing.on_streak = kind == TournamentKind::Arena && *streak >= 2;
+        }
+    }
+
+    let points: HashMap<UserId, u32> = result.iter().map(|s| (s.user_id, s.points)).collect();
+    for standing in &mut result {
+        for pairing in pairings.iter().filter(|p| !p.is_bye()) {
+            let (Some(opponent), Some(base)) = (
+                pairing.opponent(standing.user_id),
+                pairing.base_points(standing.user_id),
+            ) else {
+                continue;
+            };
+            let opponent_points = points.get(&opponent).copied().unwrap_or(0);
+            standing.buchholz += opponent_points;
+            standing.sonneborn_berger += opponent_points as f32 * base as f32 / 2.0;
+        }
+    }
+
+    let seed: HashMap<UserId, usize> = players.iter().enumerate().map(|(i, &p)| (p, i)).collect();
+    result.sort_by(|a, b| {
+        b.points
+            .cmp(&a.points)
+            .then(b.buchholz.cmp(&a.buchholz))
+            .then(b.sonneborn_berger.total_cmp(&a.sonneborn_berger))
+            .then(seed[&a.user_id].cmp(&seed[&b.user_id]))
+    });
+    result
+}
+
+/// Pairs the next round of a Swiss tournament. Players are ranked by their
+/// standing and each player is paired with the highest ranked player they did
+/// not play yet. Only if that is impossible, rematches are allowed.
+///
+/// With an odd number of players, the lowest ranked player without a bye so
+/// far gets one.
+pub fn swiss_pairings(players: &[UserId], pairings: &[Pairing]) -> Vec<Pairing> {
+    let mut ranked: Vec<UserId> = standings(TournamentKind::Swiss, players, pairings)
+        .into_iter()
+        .map(|s| s.user_id)
+        .collect();
+
+    let mut result = Vec::with_capacity(ranked.len() / 2 + 1);
+    if ranked.len() % 2 == 1 {
+        let had_bye: HashSet<UserId> = pairings
+            .iter()
+            .filter(|p| p.is_bye())
+            .map(|p| p.white)
+            .collect();
+        let index = ranked
+            .iter()
+            .rposition(|p| !had_bye.contains(p))
+            .unwrap_or(ranked.len() - 1);
+        let player = ranked.remove(index);
+        result.push(Pairing {
+            white: player,
+            black: None,
+            outcome: Some(Outcome::WhiteWins),
+        });
+    }
+
+    let played: HashSet<(UserId, UserId)> = pairings
+        .iter()
+        .filter_map(|p| Some((p.white, p.black?)))
+        .flat_map(|(a, b)| [(a, b), (b, a)])
+        .collect();
+    let mut steps = MAX_PAIRING_STEPS;
+    let pairs = pair_without_rematch(&ranked, &played, &mut steps)
+        .unwrap_or_else(|| pair_greedily(&ranked, &played));
+
+    for (a, b) in pairs {
+        let (white, black) = assign_colors(a, b, pairings);
+        result.push(Pairing {
+            white,
+            black: Some(black),
+            outcome: None,
+        });
+    }
+    result
+}
+
+/// Pairs the first player with the best ranked possible partner and recurses.
+/// Backtracks if the rest can't be paired without rematches. This may take
+/// exponential time, so the search gives up once `steps` are used up.
+fn pair_without_rematch(
+    ranked: &[UserId],
+    played: &HashSet<(UserId, UserId)>,
+    steps: &mut usize,
+) -> Option<Vec<(UserId, UserId)>> {
+    let Some((&first, rest)) = ranked.split_first() else {
+        return Some(vec![]);
+    };
+    if *steps == 0 {
+        return None;
+    }
+    *steps -= 1;
+    for (i, &partner) in rest.iter().enumerate() {
+        if played.contains(&(first, partner)) {
+            continue;
+        }
+        let mut remaining = rest.to_vec();
+        remaining.remove(i);
+        if let Some(mut pairs) = pair_without_rematch(&remaining, played, steps) {
+            pairs.insert(0, (first, partner));
+            return Some(pairs);
+        }
+    }
+    None
+}
+
+/// Pairs each player with the best ranked partner they did not play yet, or
+/// the best ranked partner left if they played everyone. Never backtracks.
+fn pair_greedily(ranked: &[UserId], played: &HashSet<(UserId, UserId)>) -> Vec<(UserId, UserId)> {
+    let mut remaining = ranked.to_vec();
+    let mut pairs = Vec::with_capacity(remaining.len() / 2);
+    while remaining.len() >= 2 {
+        let first = remaining.remove(0);
+        let index = remaining
+            .iter()
+            .position(|&p| !played.contains(&(first, p)))
+            .unwrap_or(0);
+        pairs.push((first, remaining.remove(index)));
+    }
+    pairs
+}
+
+/// Pairs players waiting in an arena. Neighbours in the standings play each
+/// other, but not the opponent of their last game if that can be avoided.
+pub fn arena_pairings(waiting: &[UserId], players: &[UserId], pairings: &[Pairing]) -> Vec<Pairing> {
+    let mut ranked: Vec<UserId> = standings(TournamentKind::Arena, players, pairings)
+        .into_iter()
+        .map(|s| s.user_id)
+        .filter(|p| waiting.contains(p))
+        .collect();
+    let last_opponent = |player: UserId| {
+        pairings
+            .iter()
+            .rev()
+            .find(|p| p.white == player || p.black == Some(player))
+            .and_then(|p| p.opponent(player))
+    };
+
+    let mut result = vec![];
+    while ranked.len() >= 2 {
+        let first = ranked.remove(0);
+        let index = ranked
+            .iter()
+            .position(|&p| last_opponent(first) != Some(p))
+            .unwrap_or(0);
+        let partner = ranked.remove(index);
+        let (white, black) = assign_colors(first, partner, pairings);
+        result.push(Pairing {
+            white,
+            black: Some(black),
+            outcome: None,
+        });
+    }
+    result
+}
+
+/// The player who played white less often gets white. If both are balanced,
+/// the one who played black last gets white.
+fn assign_colors(a: UserId, b: UserId, pairings: &[Pairing]) -> (UserId, UserId) {
+    let balance = |player: UserId| -> i32 {
+        pairings
+            .iter()
+            .filter(|p| !p.is_bye())
+            .map(|p| {
+                if p.white == player {
+                    1
+                } else if p.black == Some(player) {
+                    -1
+                } else {
+                    0
+                }
+            })
+            .sum()
+    };
+    let last_was_white = |player: UserId| {
+        pairings
+            .iter()
+            .rev()
+            .filter(|p| !p.is_bye())
+            .find(|p| p.white == player || p.black == Some(player))
+            .is_some_and(|p| p.white == player)
+    };
+
+    match balance(a).cmp(&balance(b)) {
+        std::cmp::Ordering::Less => (a, b),
+        std::cmp::Ordering::Greater => (b, a),
+        std::cmp::Ordering::Equal if last_was_white(a) && !last_was_white(b) => (b, a),
+        std::cmp::Ordering::Equal => (a, b),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn users(n: i64) -> Vec<UserId> {
+        (1..=n).map(UserId).collect()
+    }
+
+    fn game(white: i64, black: i64, outcome: Outcome) -> Pairing {
+        Pairing {
+            white: UserId(white),
+            black: Some(UserId(black)),
+            outcome: Some(outcome),
+        }
+    }
+
+    #[test]
+    fn test_standings_with_tiebreaks() {
+        use Outcome::*;
+        // 1 beats 2 and 3, 2 beats 3, 4 draws with 3 and beats 2.
+        let pairings = vec![
+            game(1, 2, WhiteWins),
+            game(3, 4, Draw),
+            game(3, 1, BlackWins),
+            game(2, 4, BlackWins),
+        ];
+        let standings = standings(TournamentKind::Swiss, &users(4), &pairings);
+        let order: Vec<i64> = standings.iter().map(|s| s.user_id.0).collect();
+        assert_eq!(order, vec![1, 4, 3, 2]);
+
+        // Player 4 has 3 points from the games against 3 (1 point) and 2 (0 points).
+        assert_eq!(standings[1].points, 3);
+        assert_eq!(standings[1].buchholz, 1);
+        assert_eq!(standings[1].sonneborn_berger, 0.5);
+        assert_eq!(standings[0].points, 4);
+        assert_eq!(standings[0].buchholz, 1);
+    }
+
+    #[test]
+    fn test_arena_streak_doubles_points() {
+        use Outcome::*;
+        let pairings = vec![
+            game(1, 2, WhiteWins),
+            game(2, 1, BlackWins),
+            game(1, 2, WhiteWins),
+            game(2, 1, Draw),
+            game(1, 2, Draw),
+        ];
+        let standings = standings(TournamentKind::Arena, &users(2), &pairings);
+        // 2 + 2 + 4 (streak) + 2 (draw on streak) + 1
+        assert_eq!(standings[0].user_id, UserId(1));
+        assert_eq!(standings[0].points, 11);
+        assert!(!standings[0].on_streak);
+        assert_eq!(standings[1].points, 2);
+    }
+
+    #[test]
+    fn test_swiss_avoids_rematches() {
+        use Outcome::*;
+        let players = users(4);
+        let round_one = vec![game(1, 2, WhiteWins), game(4, 3, BlackWins)];
+        let round_two = swiss_pairings(&players, &round_one);
+        assert_eq!(round_two.len(), 2);
+        for pairing in &round_two {
+            let black = pairing.black.unwrap();
+            let pair = (pairing.white.0.min(black.0), pairing.white.0.max(black.0));
+            assert_ne!(pair, (1, 2));
+            assert_ne!(pair, (3, 4));
+        }
+        // The winners play each other.
+        assert!(round_two
+            .iter()
+            .any(|p| p.opponent(UserId(1)) == Some(UserId(3))));
+        // Player 1 had white and player 3 had black, so they switch.
+        assert!(round_two.iter().any(|p| p.black == Some(UserId(1))));
+    }
+
+    /// Three players who played everyone else but not each other can't all be
+    /// paired without a rematch. The search gives up instead of trying every
+    /// pairing of the others.
+    #[test]
+    fn test_swiss_without_rematch_free_pairing_is_fast() {
+        let players = users(30);
+        let pairings: Vec<Pairing> = (28..=30)
+            .flat_map(|a| (1..=27).map(move |b| game(a, b, Outcome::Draw)))
+            .collect();
+        let start = std::time::Instant::now();
+        let round = swiss_pairings(&players, &pairings);
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(round.len(), 15);
+        let mut paired: Vec<i64> = round
+            .iter()
+            .flat_map(|p| [Some(p.white), p.black])
+            .flatten()
+            .map(|u| u.0)
+            .collect();
+        paired.sort();
+        assert_eq!(paired, (1..=30).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_swiss_bye_goes_to_lowest_without_bye() {
+        use Outcome::*;
+        let players = users(3);
+        let round_one = swiss_pairings(&players, &[]);
+        let bye = round_one.iter().find(|p| p.is_bye()).unwrap();
+        assert_eq!(bye.white, UserId(3));
+
+        let mut history = vec![game(1, 2, BlackWins), bye.clone()];
+        history[1].outcome = Some(WhiteWins);
+        let round_two = swiss_pairings(&players, &history);
+        let bye = round_two.iter().find(|p| p.is_bye()).unwrap();
+        assert_ne!(bye.white, UserId(3));
+        assert_eq!(bye.white, UserId(1));
+    }
+
+    #[test]
+    fn test_arena_avoids_last_opponent() {
+        use Outcome::*;
+        let players = users(4);
+        let history = vec![game(1, 2, WhiteWins), game(3, 4, Draw)];
+        let pairs = arena_pairings(&[UserId(1), UserId(2), UserId(3)], &players, &history);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].opponent(UserId(1)), Some(UserId(3)));
+    }
+}
@@ -0,0 +1,412 @@
+//! Routes logic messages to the game they concern.
+//!
+//! Every game that is currently active gets its own task, which owns the
+//! `RoomState` of that game. Messages for the same game are handled in order
+//! by that task, while messages for different games are handled concurrently.
+//! This way a slow game or a heavy database write only delays its own room.
+//!
+//! Rooms that are idle for a while and don't hold any state that would be lost
+//! are shut down again. A room reports how many messages it received when it
+//! becomes idle. The dispatcher only retires it if it did not route another
+//! message to the room in the meantime, otherwise that message would end up in
+//! a room that is about to be dropped. When a new message arrives for a game
+//! whose task is still winding down, the new task waits for the old one to
+//! finish first. That keeps the ordering guarantee for the game.
+//!
+//! On shutdown, all rooms finish the messages they already received before the
+//! dispatcher confirms. This makes sure every game was stored.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use axum::extract::ws::Message;
+use serde_json::de::from_str;
+use tokio::sync::mpsc::{self, Receiver, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::actors::websocket::SocketId;
+use crate::db;
+use crate::tournament;
+use crate::ServerError;
+
+use super::{
+    handle_room_message, timeout_sweeper, rearm_running_timers, respond_to_time_drift_check, ClientMessage,
+    LogicMsg, RoomMsg, RoomState, RoutedClientMessage, StateProtocol,
+    SubscribeToMatchSocketData,
+};
+
+/// After this time without messages, a room checks if it can be shut down.
+const ROOM_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Owns the task handles of all active rooms and routes messages to them.
+pub(super) struct Dispatcher {
+    pool: db::Pool,
+    workers: HashMap<String, RoomWorker>,
+    next_worker_id: u64,
+    idle_sender: UnboundedSender<IdleReport>,
+    idle_receiver: UnboundedReceiver<IdleReport>,
+}
+
+/// Handle to the task owning a single room.
+struct RoomWorker {
+    /// Tells this task apart from earlier tasks of the same room.
+    id: u64,
+    sender: UnboundedSender<RoomMsg>,
+    handle: JoinHandle<()>,
+    /// Number of messages routed to this task.
+    routed: u64,
+}
+
+impl RoomWorker {
+    /// The room is only idle if it handled every message that was routed to
+    /// it. Reports of earlier tasks for the same room don't count.
+    fn is_idle(&self, report: &IdleReport) -> bool {
+        self.id == report.worker_id && self.routed == report.received
+    }
+}
+
+/// Sent by a room that is idle and can be dropped.
+struct IdleReport {
+    key: String,
+    worker_id: u64,
+    /// Number of messages the room received up to this point.
+    received: u64,
+}
+
+impl Dispatcher {
+    pub(super) fn new(pool: db::Pool) -> Self {
+        let (idle_sender, idle_receiver) = mpsc::unbounded_channel();
+        Self {
+            pool,
+            workers: HashMap::new(),
+            next_worker_id: 0,
+            idle_sender,
+            idle_receiver,
+        }
+    }
+
+    /// Routes messages until a shutdown is requested or the message queue is
+    /// closed. Then all rooms get to finish the messages they already received.
+    pub(super) async fn run(mut self, mut message_queue: Receiver<LogicMsg>) {
+        self.rearm_timers().await;
+        let sweeper = tokio::spawn(timeout_sweeper::run(self.pool.clone()));
+
+        let done = loop {
+            tokio::select! {
+                msg = message_queue.recv() => {
+                    match msg {
+                        Some(LogicMsg::Shutdown { done }) => break Some(done),
+                        Some(msg) => self.dispatch(msg).await,
+                        None => break None,
+                    }
+                }
+                Some(report) = self.idle_receiver.recv() => {
+                    self.retire(report);
+                }
+            }
+        };
+        sweeper.abort();
+        self.shutdown().await;
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+    }
+
+    async fn rearm_timers(&self) {
+        let result = match self.pool.conn().await {
+            Ok(mut conn) => rearm_running_timers(&mut conn).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            error!("Could not re-arm timers of running games: {:?}", e);
+        }
+    }
+
+    async fn dispatch(&mut self, msg: LogicMsg) {
+        match msg {
+            LogicMsg::Websocket { data, source } => match data {
+                Message::Text(ref text) => self.dispatch_text(text, source).await,
+                Message::Binary(payload) => {
+                    warn!("Binary message received: {:?}", payload);
+                }
+                Message::Ping(_) | Message::Pong(_) => {}
+                Message::Close(_) => {
+                    info!("Close message received. This is not implemented here.");
+                }
+            },
+            LogicMsg::Timeout { key, timestamp } => {
+                self.route(key, RoomMsg::Timeout { timestamp });
+            }
+            LogicMsg::Refresh { key } => {
+                self.route_if_loaded(&key, RoomMsg::Refresh);
+            }
+            LogicMsg::AiAction {
+                key,
+                action,
+                uuid,
+                session_id,
+            } => {
+                self.route(
+                    key,
+                    RoomMsg::AiAction {
+                        action,
+                        uuid,
+                        session_id,
+                    },
+                );
+            }
+            LogicMsg::BotAction {
+                key,
+                actions,
+                user_id,
+                done,
+            } => {
+                self.route(
+                    key,
+                    RoomMsg::BotAction {
+                        actions,
+                        user_id,
+                        done,
+                    },
+                );
+            }
+            LogicMsg::SetPlayer {
+                key,
+                color,
+                user_id,
+                done,
+            } => {
+                self.route(
+                    key,
+                    RoomMsg::SetPlayer {
+                        color,
+                        user_id,
+                        done,
+                    },
+                );
+            }
+            LogicMsg::Claim {
+                key,
+                uuid,
+                user_id,
+                done,
+            } => {
+                self.route(
+                    key,
+                    RoomMsg::Claim {
+                        uuid,
+                        user_id,
+                        done,
+                    },
+                );
+            }
+            LogicMsg::Shutdown { done } => {
+                // Handled by the main loop, this is never routed.
+                let _ = done.send(());
+            }
+        }
+    }
+
+    async fn dispatch_text(&mut self, text: &str, source: SocketId) {
+        info!("Data is: {:?}", text);
+
+        if let Ok(msg) = from_str::<ClientMessage>(text) {
+            if let ClientMessage::TimeDriftCheck { send } = msg {
+                // We do not have a game for this message.
+                respond_to_time_drift_check(send, &source).await;
+            } else if let ClientMessage::SubscribeToTournament { id } = msg {
+                // Tournaments don't belong to a room, loading one must not
+                // block the dispatcher.
+                let pool = self.pool.clone();
+                tokio::spawn(async move {
+                    match pool.conn().await {
+                        Ok(mut conn) => tournament::subscribe(id, source, &mut conn).await,
+                        Err(e) => warn!("Could not subscribe to tournament {}: {:?}", id, e),
+                    }
+                });
+            } else if let Some(key) = msg.key() {
+                let key = key.to_owned();
+                self.route(key, RoomMsg::Client { msg, source });
+            }
+        } else if let Ok(routed) = from_str::<RoutedClientMessage>(text) {
+            if routed.message_type == "subscribeToMatchSocket" {
+                if let Ok(data) = from_str::<SubscribeToMatchSocketData>(&routed.data) {
+                    let protocol = StateProtocol::from_version(data.protocol_version);
+                    self.route(data.key, RoomMsg::Subscribe { source, protocol });
+                }
+            }
+        }
+    }
+
+    /// Sends the message to the task of the room, starting it if required.
+    fn route(&mut self, key: String, msg: RoomMsg) {
+        let msg = match self.workers.get_mut(&key) {
+            Some(worker) => match worker.sender.send(msg) {
+                Ok(()) => {
+                    worker.routed += 1;
+                    return;
+                }
+                // The task already stopped, we need a new one.
+                Err(mpsc::error::SendError(msg)) => msg,
+            },
+            None => msg,
+        };
+
+        let previous = self.workers.remove(&key).map(|w| w.handle);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        sender
+            .send(msg)
+            .expect("The receiver of a new room worker can't be closed.");
+        let id = self.next_worker_id;
+        self.next_worker_id += 1;
+        let handle = tokio::spawn(run_room(
+            key.clone(),
+            id,
+            receiver,
+            previous,
+            self.pool.clone(),
+            self.idle_sender.clone(),
+        ));
+        self.workers.insert(
+            key,
+            RoomWorker {
+                id,
+                sender,
+                handle,
+                routed: 1,
+            },
+        );
+    }
+
+    /// Sends the message to the task of the room, but only if the room is
+    /// loaded already. Rooms with sockets are never retired, so this reaches
+    /// everyone who looks at the game.
+    fn route_if_loaded(&mut self, key: &str, msg: RoomMsg) {
+        if let Some(worker) = self.workers.get_mut(key) {
+            if worker.sender.send(msg).is_ok() {
+                worker.routed += 1;
+            }
+        }
+    }
+
+    /// Drops the sender of an idle room, which then stops on its own. Rooms
+    /// that got a message after they reported are kept, they report again
+    /// once they are idle.
+    fn retire(&mut self, report: IdleReport) {
+        if self.workers.get(&report.key).is_some_and(|w| w.is_idle(&report)) {
+            self.workers.remove(&report.key);
+            info!("Room {} is idle and shuts down.", report.key);
+        }
+    }
+
+    /// Stops routing messages and waits until all rooms are done.
+    async fn shutdown(&mut self) {
+        info!("Shutting down {} room(s).", self.workers.len());
+        let handles: Vec<_> = self.workers.drain().map(|(_, w)| w.handle).collect();
+        for handle in handles {
+            if let Err(e) = handle.await {
+                warn!("Room task did not shut down cleanly: {:?}", e);
+            }
+        }
+    }
+}
+
+/// The task owning a single room. It processes all messages for this game in
+/// the order they were routed.
+async fn run_room(
+    key: String,
+    worker_id: u64,
+    mut receiver: UnboundedReceiver<RoomMsg>,
+    previous: Option<JoinHandle<()>>,
+    pool: db::Pool,
+    idle_sender: UnboundedSender<IdleReport>,
+) {
+    // If there was a task for this room before, it must be done before we
+    // touch the game. Otherwise messages could overtake each other.
+    if let Some(previous) = previous {
+        let _ = previous.await;
+    }
+
+    let mut room_state = RoomState::default();
+    let mut reported_idle = false;
+    let mut received = 0;
+
+    loop {
+        let msg = match tokio::time::timeout(ROOM_IDLE_TIMEOUT, receiver.recv()).await {
+            Ok(Some(msg)) => msg,
+            // The dispatcher dropped the sender and everything was processed.
+            Ok(None) => break,
+            Err(_) => {
+                if !reported_idle && room_state.can_be_dropped() {
+                    reported_idle = true;
+                    let _ = idle_sender.send(IdleReport {
+                        key: key.clone(),
+                        worker_id,
+                        received,
+                    });
+                }
+                continue;
+            }
+        };
+        reported_idle = false;
+        received += 1;
+
+        if let Err(e) = handle_with_connection(&key, msg, &mut room_state, &pool).await {
+            match e {
+                ServerError::NotAllowed(_) => {
+                    warn!("Error in the websocket: Not allowed.");
+                }
+                _ => {
+                    warn!("Error in the websocket: {:?}", e);
+                }
+            }
+        }
+
+        // Give other rooms on this thread a chance to run, even if this room
+        // has a lot of messages queued up.
+        tokio::task::yield_now().await;
+    }
+}
+
+async fn handle_with_connection(
+    key: &str,
+    msg: RoomMsg,
+    room_state: &mut RoomState,
+    pool: &db::Pool,
+) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    handle_room_message(key, msg, room_state, &mut conn).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn worker(id: u64, routed: u64) -> RoomWorker {
+        let (sender, _) = mpsc::unbounded_channel();
+        RoomWorker {
+            id,
+            sender,
+            handle: tokio::spawn(async {}),
+            routed,
+        }
+    }
+
+    fn report(worker_id: u64, received: u64) -> IdleReport {
+        IdleReport {
+            key: "1".to_string(),
+            worker_id,
+            received,
+        }
+    }
+
+    #[tokio::test]
+    async fn room_with_new_messages_is_not_idle() {
+        let worker = worker(3, 2);
+        assert!(worker.is_idle(&report(3, 2)));
+        // A subscribe was routed after the room reported.
+        assert!(!worker.is_idle(&report(3, 1)));
+        // An earlier task of the same room reported.
+        assert!(!worker.is_idle(&report(2, 2)));
+    }
+}
@@ -1,4 +1,5 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use anyhow::bail;
 use axum::extract::ws::Message;
@@ -6,32 +7,53 @@ use chrono::{DateTime, Utc};
 use log::{info, warn};
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
-use serde_json::de::from_str;
 use serde_json::ser::to_string;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    oneshot,
+};
 
 use pacosako::{PacoAction, PlayerColor};
 
 use crate::db::Connection;
-use crate::login::user;
+use crate::login::{user, UserId};
 use crate::login::user::load_user_data_for_game;
+use crate::event_stream::{self, StreamEvent};
+use crate::follow::{self, FollowedPlayer};
+use crate::notification::{self, Notification, NotificationKind};
 use crate::ws::socket_auth::{SocketAuth, SocketIdentity};
 use crate::{
     actors::websocket::SocketId,
     db,
     login::SessionId,
-    protection::SideProtection,
-    sync_match::{CurrentMatchState, CurrentMatchStateClient, SynchronizedMatch},
+    player_statistics,
+    protection::{ControlLevel, SideProtection},
+    replay_analysis,
+    sync_match::{
+        CurrentMatchState, CurrentMatchStateClient, MatchStateDelta, Premove, SynchronizedMatch,
+    },
+    tournament::{self, TournamentInfo},
     ServerError,
 };
 
+mod dispatch;
+pub mod presence;
 pub mod socket_auth;
+mod timeout_sweeper;
 /// Handles all the websocket client logic.
 pub mod wake_up_queue;
 
 // Everything can send messages to the logic. The logic is a singleton.
 pub static TO_LOGIC: OnceCell<Sender<LogicMsg>> = OnceCell::new();
 
+/// Set once the server starts shutting down. From then on, no new websockets
+/// are accepted and the logic may stop processing messages.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
 pub async fn to_logic(msg: LogicMsg) {
     if let Err(e) = TO_LOGIC
         .get()
@@ -39,6 +61,11 @@ pub async fn to_logic(msg: LogicMsg) {
         .send(msg)
         .await
     {
+        if is_shutting_down() {
+            // The logic already stopped on purpose, nobody needs this anymore.
+            info!("Dropping message for logic during shutdown: {:?}", e.0);
+            return;
+        }
         error!(
             "Error sending message to logic: {}, this requires a server restart.",
             e
@@ -61,6 +88,61 @@ pub fn run_server(pool: db::Pool) {
     run_logic_server(message_queue, pool);
 }
 
+/// Shuts down the websocket logic gracefully:
+///
+/// 1. New websockets are rejected.
+/// 2. Wake ups which are already due are handed to the logic.
+/// 3. The logic processes everything that is queued up, so all games are stored.
+/// 4. Clients are told to reconnect. They will find the restarted server.
+///
+/// Timers that are not due yet are re-armed from the database on startup.
+pub async fn shutdown() {
+    info!("Shutting down the websocket logic.");
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+
+    wake_up_queue::flush().await;
+
+    let (done, done_receiver) = oneshot::channel();
+    to_logic(LogicMsg::Shutdown { done }).await;
+    if done_receiver.await.is_err() {
+        warn!("Logic stopped without confirming the shutdown.");
+    }
+
+    SocketId::close_all_for_restart().await;
+    event_stream::close_all();
+    info!("Websocket logic shut down.");
+}
+
+/// Puts all games with a running timer into the wake up queue. The queue only
+/// lives in memory, so without this a timeout would not be noticed after a
+/// restart until somebody opens the game again.
+async fn rearm_running_timers(conn: &mut Connection) -> Result<(), ServerError> {
+    let pending = db::game::pending_timeouts(conn).await?;
+    info!("Re-arming timers for {} running game(s).", pending.len());
+    for (key, timeout_at) in pending {
+        let Some(timeout_at) = DateTime::from_timestamp_millis(timeout_at) else {
+            warn!("Game {} has an invalid timeout {}.", key, timeout_at);
+            continue;
+        };
+        wake_up_queue::put_utc(key, timeout_at).await;
+    }
+
+    // Games from before the timeout was persisted need a full replay once.
+    // Storing them persists the timeout for the next restart.
+    for game in db::game::running_without_timeout(conn).await? {
+        let Ok(state) = game.current_state() else {
+            warn!("Could not compute state of game {} to re-arm its timer.", game.key);
+            continue;
+        };
+        if let Some(ref timer) = state.timer {
+            let next_reminder = timer.timeout(state.controlling_player);
+            wake_up_queue::put_utc(&game.key, next_reminder).await;
+        }
+        store_game(&game, &state, conn).await?;
+    }
+    Ok(())
+}
+
 /// A message that is sent to the logic where the logic has then to react.
 #[derive(Debug)]
 pub enum LogicMsg {
@@ -72,6 +154,12 @@ pub enum LogicMsg {
         key: String,
         timestamp: DateTime<Utc>,
     },
+    /// The game changed outside of its room. A loaded room locks sides that got
+    /// a player and sends the new state to its sockets, games without a room
+    /// are not loaded for this.
+    Refresh {
+        key: String,
+    },
     // This AI action comes over POST, not over the websocket.
     AiAction {
         key: String,
@@ -79,89 +167,234 @@ pub enum LogicMsg {
         uuid: String,
         session_id: Option<SessionId>,
     },
+    /// A chain of actions a bot submitted over the API. The bot learns on
+    /// `done` if the actions were accepted.
+    BotAction {
+        key: String,
+        actions: Vec<PacoAction>,
+        user_id: UserId,
+        done: oneshot::Sender<Result<(), String>>,
+    },
+    /// A moderator changes or clears the player of a side. This goes through
+    /// the room, so the locks of the room follow the change.
+    SetPlayer {
+        key: String,
+        color: PlayerColor,
+        user_id: Option<UserId>,
+        done: oneshot::Sender<Result<(), String>>,
+    },
+    /// A logged-in user claims the sides of a game that their browser's uuid
+    /// locked. The claimed sides are sent on `done`, no sides if there was
+    /// nothing to claim.
+    Claim {
+        key: String,
+        uuid: String,
+        user_id: UserId,
+        done: oneshot::Sender<Result<Vec<PlayerColor>, String>>,
+    },
+    /// Stop processing messages once all rooms are done with the messages
+    /// they already got. Confirms on `done` afterwards.
+    Shutdown {
+        done: oneshot::Sender<()>,
+    },
 }
 
+/// Number of threads the logic server may use. Messages for different games
+/// are processed concurrently, so a slow game does not block the others.
+const LOGIC_WORKER_THREADS: usize = 4;
+
+/// Upper limit for the number of premoves a player can send in one tree.
+const MAX_PREMOVE_TREE_SIZE: usize = 100;
+
+/// Upper limit for the number of premoves executed after a single action. Two
+/// players with premoves for each other could otherwise play a long sequence
+/// without anyone looking at the game.
+const MAX_PREMOVE_CHAIN: usize = 20;
+
 /// Spawn a thread that handles the server logic.
 fn run_logic_server(message_queue: Receiver<LogicMsg>, pool: db::Pool) {
     std::thread::spawn(move || {
         // Create a runtime that _must_ be driven from a call
         // to `Runtime::block_on`.
-        let rt = tokio::runtime::Builder::new_current_thread()
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(LOGIC_WORKER_THREADS)
             .enable_all()
             .build()
             .unwrap();
 
         // This will run the runtime and future on the current thread
-        rt.block_on(loop_logic_server(message_queue, pool))
-            .expect("Error running the logic server.");
+        rt.block_on(dispatch::Dispatcher::new(pool).run(message_queue));
     });
 }
 
-/// Simple loop that reacts to all the messages.
-async fn loop_logic_server(
-    mut message_queue: Receiver<LogicMsg>,
-    pool: db::Pool,
-) -> Result<(), ServerError> {
-    let mut server_state = ServerState::default();
-
-    while let Some(msg) = message_queue.recv().await {
-        let mut conn = pool.0.acquire().await?;
-        let res = handle_message(msg, &mut server_state, &mut conn).await;
-        if let Err(e) = res {
-            match e {
-                ServerError::NotAllowed(_) => {
-                    warn!("Error in the websocket: Not allowed.");
-                }
-                _ => {
-                    warn!("Error in the websocket: {:?}", e);
-                }
-            }
-        }
-    }
-    Ok(())
-}
-
+/// The state of a single game room. Each room is owned by its own task, see
+/// the `dispatch` module. The room is created lazily when the first message
+/// for the game arrives.
 #[derive(Debug, Default)]
-pub struct ServerState {
-    rooms: HashMap<String, GameRoom>,
+pub struct RoomState {
+    room: Option<GameRoom>,
 }
 
-impl ServerState {
+impl RoomState {
     /// Returns a room, creating it if required. The socket that asked is added
     /// to the room automatically.
-    fn room(&mut self, game: &SynchronizedMatch, asked_by: SocketId) -> &mut GameRoom {
-        let room = self.room_without_websocket(game);
-        room.connected.insert(asked_by);
-        room
-    }
-    fn room_without_websocket(&mut self, game: &SynchronizedMatch) -> &mut GameRoom {
-        let room = self.rooms.entry(game.key.clone()).or_insert(GameRoom {
-            connected: HashSet::new(),
-            white_player: SideProtection::for_user(game.white_player),
-            black_player: SideProtection::for_user(game.black_player),
+    async fn room(
+        &mut self,
+        game: &SynchronizedMatch,
+        asked_by: SocketId,
+        conn: &mut Connection,
+    ) -> Result<&mut GameRoom, ServerError> {
+        let room = self.room_without_websocket(game, conn).await?;
+        room.connected.entry(asked_by).or_insert(StateProtocol::FullState);
+        Ok(room)
+    }
+    async fn room_without_websocket(
+        &mut self,
+        game: &SynchronizedMatch,
+        conn: &mut Connection,
+    ) -> Result<&mut GameRoom, ServerError> {
+        // Sides without a player may be locked by the UUID of an anonymous
+        // player, which we only need to load for a new room.
+        let (white_uuid, black_uuid) = if self.room.is_none() {
+            db::game::uuid_locks(game.key.parse()?, conn).await?
+        } else {
+            (None, None)
+        };
+        let room = self.room.get_or_insert_with(|| GameRoom {
+            connected: HashMap::new(),
+            white_player: SideProtection::for_side(game.white_player, white_uuid),
+            black_player: SideProtection::for_side(game.black_player, black_uuid),
+            sequence: 0,
+            known_actions: game.actions.len(),
+            known_players: (game.white_player, game.black_player),
+            pending_takeback: None,
         });
-        room
+        Ok(room)
+    }
+    /// Locks the side to its new player after a moderator changed it. Without
+    /// this, the next action in the room would store the old player again.
+    fn reset_side(&mut self, game: &SynchronizedMatch, color: PlayerColor) {
+        let Some(room) = self.room.as_mut() else {
+            return;
+        };
+        let protection = SideProtection::for_user(game.player(color));
+        match color {
+            PlayerColor::White => room.white_player = protection,
+            PlayerColor::Black => room.black_player = protection,
+        }
+        room.pending_takeback = None;
+    }
+    /// Locks the sides that got a player outside of the room, e.g. when a user
+    /// claimed all games of their uuid.
+    fn lock_assigned_sides(&mut self, game: &SynchronizedMatch) {
+        let Some(room) = self.room.as_mut() else {
+            return;
+        };
+        for (color, side) in [
+            (PlayerColor::White, &mut room.white_player),
+            (PlayerColor::Black, &mut room.black_player),
+        ] {
+            if let Some(player) = game.player(color) {
+                *side = SideProtection::UserLock(player);
+            }
+        }
     }
     /// Call this method if we determine that a room is not backed by any game
     /// or if the last client disconnects.
-    fn destroy_room(&mut self, key: &str) {
-        self.rooms.remove(key);
+    fn destroy_room(&mut self) {
+        self.room = None;
+    }
+    /// A room can be dropped when nobody is connected anymore. Everything else
+    /// it holds, including the uuid locks, is restored from the database.
+    fn can_be_dropped(&self) -> bool {
+        let Some(room) = &self.room else {
+            return true;
+        };
+        !room.connected.keys().any(|s| s.get_owner().is_ok())
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct GameRoom {
-    connected: HashSet<SocketId>,
+    connected: HashMap<SocketId, StateProtocol>,
     pub white_player: SideProtection,
     pub black_player: SideProtection,
+    /// Sequence number of the last state that was broadcast to this room.
+    sequence: u64,
+    /// Number of actions in the last state that was broadcast to this room.
+    /// Deltas are computed against this.
+    known_actions: usize,
+    /// Players of the last state that was broadcast to this room. Deltas don't
+    /// carry player metadata, so delta clients need a full sync if this changes.
+    known_players: (Option<UserId>, Option<UserId>),
+    /// A takeback one player asked for, waiting for the opponent to answer.
+    pending_takeback: Option<PendingTakeback>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingTakeback {
+    requested_by: PlayerColor,
+    /// The request only applies to the position it was made in.
+    action_count: usize,
+}
+
+/// How a socket wants to be informed about changes to the game state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StateProtocol {
+    /// Send the whole `CurrentMatchStateClient` after every change.
+    /// This is what all clients did before there was a protocol version.
+    FullState,
+    /// Send a `MatchStateDelta` after every change.
+    Delta,
+}
+
+impl StateProtocol {
+    /// Version 1 is the full state protocol, version 2 introduced deltas.
+    /// Clients that don't send a version are treated as version 1.
+    fn from_version(protocol_version: u32) -> Self {
+        if protocol_version >= 2 {
+            Self::Delta
+        } else {
+            Self::FullState
+        }
+    }
 }
 
 /// All allowed messages that may be send by the client to the server.
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 enum ClientMessage {
     DoAction { key: String, action: Vec<PacoAction> },
     Rollback { key: String },
     TimeDriftCheck { send: DateTime<Utc> },
+    /// Sent by delta clients when they detect a gap in the sequence numbers.
+    /// The server answers with a full `MatchStateSync`.
+    Resync { key: String },
+    /// Asks the opponent to undo the last completed turn of the sender.
+    RequestTakeback { key: String },
+    /// Accepts or declines the takeback the opponent asked for.
+    AnswerTakeback { key: String, accept: bool },
+    /// Prepares a premove for the running turn of the opponent.
+    SetPremove { key: String, premove: Premove },
+    /// Drops all premoves of the sender.
+    ClearPremoves { key: String },
+    /// Asks for updates of a tournament. Not related to a single game.
+    SubscribeToTournament { id: i64 },
+}
+
+impl ClientMessage {
+    /// The key of the game this message is about, if there is one.
+    fn key(&self) -> Option<&str> {
+        match self {
+            Self::DoAction { key, .. }
+            | Self::Rollback { key }
+            | Self::Resync { key }
+            | Self::RequestTakeback { key }
+            | Self::AnswerTakeback { key, .. }
+            | Self::SetPremove { key, .. }
+            | Self::ClearPremoves { key } => Some(key),
+            Self::TimeDriftCheck { .. } | Self::SubscribeToTournament { .. } => None,
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -174,12 +407,38 @@ struct RoutedClientMessage {
 #[derive(Deserialize)]
 struct SubscribeToMatchSocketData {
     key: String,
+    #[serde(default)]
+    protocol_version: u32,
 }
 
 /// Messages that may be send by the server to the client.
 #[derive(Clone, Serialize, Debug)]
 pub enum ServerMessage {
     CurrentMatchState(Box<CurrentMatchStateClient>),
+    /// Full state for delta clients. Later deltas build on this sequence number.
+    MatchStateSync {
+        sequence: u64,
+        state: Box<CurrentMatchStateClient>,
+    },
+    MatchStateDelta(Box<MatchStateDelta>),
+    /// A player asked to take back their last turn.
+    TakebackRequested {
+        requested_by: PlayerColor,
+    },
+    /// The opponent declined the takeback.
+    TakebackDeclined,
+    /// Premoves the receiver prepared for the running turn of the opponent.
+    /// Only sent to the player who owns them.
+    Premoves {
+        premoves: Vec<Premove>,
+    },
+    /// Sent to tournament subscribers after each change of the tournament.
+    TournamentUpdate(Box<TournamentInfo>),
+    /// Sent to followers when players they follow start a game.
+    FollowedPlayerStartedGame {
+        game_key: String,
+        players: Vec<FollowedPlayer>,
+    },
     Error(String),
     TimeDriftResponse {
         send: DateTime<Utc>,
@@ -187,93 +446,257 @@ pub enum ServerMessage {
     },
 }
 
-/// This handle message is wired up, so that each message is handled separately.
-/// What we likely actually want is to only run messages sequentially that
-/// concern the same game key. So that is some possible performance improvement
-/// that is still open here.
-async fn handle_message(
-    msg: LogicMsg,
-    server_state: &mut ServerState,
+/// A message that concerns a single game. The dispatcher already figured out
+/// which game it belongs to and routed it to the task owning the room.
+#[derive(Debug)]
+enum RoomMsg {
+    Client {
+        msg: ClientMessage,
+        source: SocketId,
+    },
+    Subscribe {
+        source: SocketId,
+        protocol: StateProtocol,
+    },
+    Timeout {
+        timestamp: DateTime<Utc>,
+    },
+    Refresh,
+    AiAction {
+        action: PacoAction,
+        uuid: String,
+        session_id: Option<SessionId>,
+    },
+    BotAction {
+        actions: Vec<PacoAction>,
+        user_id: UserId,
+        done: oneshot::Sender<Result<(), String>>,
+    },
+    SetPlayer {
+        color: PlayerColor,
+        user_id: Option<UserId>,
+        done: oneshot::Sender<Result<(), String>>,
+    },
+    Claim {
+        uuid: String,
+        user_id: UserId,
+        done: oneshot::Sender<Result<Vec<PlayerColor>, String>>,
+    },
+}
+
+/// Handles a single message for the room of the game `key`. Messages for the
+/// same game are handled sequentially, messages for different games may be
+/// handled concurrently.
+async fn handle_room_message(
+    key: &str,
+    msg: RoomMsg,
+    room_state: &mut RoomState,
     conn: &mut Connection,
 ) -> Result<(), ServerError> {
     match msg {
-        LogicMsg::Websocket { data, source } => {
-            info!("Data is: {:?}", data);
-
-            match data {
-                Message::Text(ref text) => {
-                    if let Ok(client_msg) = from_str(text) {
-                        handle_client_message(client_msg, source, server_state, conn).await?;
-                    } else if let Ok(client_msg) = from_str(text) {
-                        let x: RoutedClientMessage = client_msg;
-                        println!(
-                            "Routed Client Message of type {} with data {}.",
-                            x.message_type, x.data
-                        );
-                        if x.message_type == "subscribeToMatchSocket" {
-                            if let Ok(data) = from_str::<SubscribeToMatchSocketData>(&x.data) {
-                                handle_subscribe_to_match(data.key, source, server_state, conn)
-                                    .await?;
-                            }
-                        }
-                    }
-                }
-                Message::Binary(payload) => {
-                    Err(anyhow::Error::msg(format!(
-                        "Binary message received: {:?}",
-                        payload
-                    )))?;
-                }
-                Message::Ping(_) | Message::Pong(_) => {}
-                Message::Close(_) => {
-                    info!("Close message received. This is not implemented here.");
-                }
-            };
+        RoomMsg::Client { msg, source } => {
+            handle_client_message(msg, source, room_state, conn).await
+        }
+        RoomMsg::Subscribe { source, protocol } => {
+            handle_subscribe_to_match(key.to_owned(), source, protocol, room_state, conn).await?;
             Ok(())
         }
-        LogicMsg::Timeout { key, timestamp } => {
+        RoomMsg::Timeout { timestamp } => {
             info!("Timeout was called for game {} at {}", key, timestamp);
 
-            let mut game = fetch_game(&key, conn).await?;
+            let mut game = fetch_game(key, conn).await?;
 
-            let state = progress_the_timer(&mut game, &key).await?;
+            let state = progress_the_timer(&mut game, key).await?;
 
-            store_game(&game, conn).await?;
+            store_game(&game, &state, conn).await?;
 
-            broadcast_state(server_state, &game, state, conn).await;
+            broadcast_state(room_state, &game, state, conn).await;
 
             Ok(())
         }
-        LogicMsg::AiAction {
-            key,
+        RoomMsg::Refresh => {
+            let game = fetch_game(key, conn).await?;
+            room_state.lock_assigned_sides(&game);
+            let state = game.current_state()?;
+            broadcast_state(room_state, &game, state, conn).await;
+            Ok(())
+        }
+        RoomMsg::AiAction {
             action,
             uuid,
             session_id,
         } => {
-            let mut game = fetch_game(&key, conn).await?;
+            let mut game = fetch_game(key, conn).await?;
 
-            let state = progress_the_timer(&mut game, &key).await?;
+            let state = progress_the_timer(&mut game, key).await?;
 
             if state.victory_state.is_over() {
-                store_game(&game, conn).await?;
+                store_game(&game, &state, conn).await?;
 
-                broadcast_state(server_state, &game, state, conn).await;
+                broadcast_state(room_state, &game, state, conn).await;
 
                 return Ok(()); // Do not do the AI action if the game is over.
             }
 
             // TODO: Check with the room if we are allowed to play on this game.
-            let room = server_state.room_without_websocket(&game);
+            let room = room_state.room_without_websocket(&game, conn).await?;
             ensure_uuid_is_allowed(room, &mut game, (uuid, session_id), conn).await?;
 
+            let previous_player = state.controlling_player;
             let state = game.do_action(&[action])?;
-            store_game(&game, conn).await?;
+            let (state, previous_player) =
+                run_premoves(&mut game, state, previous_player, room, conn).await?;
+            store_game(&game, &state, conn).await?;
+            notify_next_player(&game, previous_player, &state);
 
-            broadcast_state(server_state, &game, state, conn).await;
+            broadcast_state(room_state, &game, state, conn).await;
 
             Ok(())
         }
+        RoomMsg::BotAction {
+            actions,
+            user_id,
+            done,
+        } => {
+            let result = handle_bot_action(key, &actions, user_id, room_state, conn).await;
+            let answer = match &result {
+                Ok(()) => Ok(()),
+                Err(ServerError::NotAllowed(msg)) => Err(msg.clone()),
+                Err(ServerError::GameError(e)) => Err(format!("Illegal action: {e}")),
+                Err(_) => Err("Internal error.".to_string()),
+            };
+            let _ = done.send(answer);
+            result
+        }
+        RoomMsg::SetPlayer {
+            color,
+            user_id,
+            done,
+        } => {
+            let result = handle_set_player(key, color, user_id, room_state, conn).await;
+            let _ = done.send(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+            result
+        }
+        RoomMsg::Claim {
+            uuid,
+            user_id,
+            done,
+        } => {
+            let result = handle_claim(key, &uuid, user_id, room_state, conn).await;
+            let _ = done.send(result.as_ref().cloned().map_err(|e| e.to_string()));
+            result.map(|_| ())
+        }
+    }
+}
+
+async fn handle_set_player(
+    key: &str,
+    color: PlayerColor,
+    user_id: Option<UserId>,
+    room_state: &mut RoomState,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let Ok(mut game) = fetch_game(key, conn).await else {
+        return Err(ServerError::NotFound);
+    };
+    // The statistics of the previous player change as well.
+    player_statistics::invalidate(&game);
+    match color {
+        PlayerColor::White => game.white_player = user_id,
+        PlayerColor::Black => game.black_player = user_id,
+    }
+    db::game::set_players(game.key.parse()?, game.white_player, game.black_player, conn).await?;
+    db::game::delete_uuid_lock(game.key.parse()?, color, conn).await?;
+    player_statistics::invalidate(&game);
+
+    room_state.reset_side(&game, color);
+    let state = game.current_state()?;
+    broadcast_state(room_state, &game, state, conn).await;
+    Ok(())
+}
+
+/// Locks the sides of the game held by `uuid` to the user and stores the user
+/// as their player. Returns the claimed sides.
+async fn handle_claim(
+    key: &str,
+    uuid: &str,
+    user_id: UserId,
+    room_state: &mut RoomState,
+    conn: &mut Connection,
+) -> Result<Vec<PlayerColor>, ServerError> {
+    let Ok(mut game) = fetch_game(key, conn).await else {
+        return Err(ServerError::NotFound);
+    };
+    let room = room_state.room_without_websocket(&game, conn).await?;
+
+    let mut claimed = vec![];
+    for (color, side) in [
+        (PlayerColor::White, &mut room.white_player),
+        (PlayerColor::Black, &mut room.black_player),
+    ] {
+        if side.get_uuid() != Some(uuid) {
+            continue;
+        }
+        *side = SideProtection::UserLock(user_id);
+        match color {
+            PlayerColor::White => game.white_player = Some(user_id),
+            PlayerColor::Black => game.black_player = Some(user_id),
+        }
+        claimed.push(color);
     }
+    if claimed.is_empty() {
+        return Ok(claimed);
+    }
+
+    let id: i64 = game.key.parse()?;
+    db::game::set_players(id, game.white_player, game.black_player, conn).await?;
+    for &color in &claimed {
+        db::game::delete_uuid_lock(id, color, conn).await?;
+    }
+    player_statistics::invalidate(&game);
+
+    let state = game.current_state()?;
+    broadcast_state(room_state, &game, state, conn).await;
+    Ok(claimed)
+}
+
+/// Bots may only act for sides locked to their account, which is the case
+/// for games created from challenges.
+async fn handle_bot_action(
+    key: &str,
+    actions: &[PacoAction],
+    user_id: UserId,
+    room_state: &mut RoomState,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let Ok(mut game) = fetch_game(key, conn).await else {
+        return Err(ServerError::NotFound);
+    };
+    let state = progress_the_timer(&mut game, key).await?;
+    if state.victory_state.is_over() {
+        store_game(&game, &state, conn).await?;
+        broadcast_state(room_state, &game, state, conn).await;
+        return Err(ServerError::NotAllowed("The game is over.".to_string()));
+    }
+
+    let identity = SocketIdentity {
+        uuid: String::new(),
+        user_id: Some(user_id),
+    };
+    let room = room_state.room_without_websocket(&game, conn).await?;
+    if !controls_side(room, &identity, state.controlling_player) {
+        return Err(ServerError::NotAllowed("It is not your turn.".to_string()));
+    }
+
+    let previous_player = state.controlling_player;
+    let state = game.do_action(actions)?;
+    let (state, previous_player) =
+        run_premoves(&mut game, state, previous_player, room, conn).await?;
+    store_game(&game, &state, conn).await?;
+    notify_next_player(&game, previous_player, &state);
+    broadcast_state(room_state, &game, state, conn).await;
+
+    Ok(())
 }
 
 async fn progress_the_timer(
@@ -302,7 +725,7 @@ async fn progress_the_timer(
 async fn handle_client_message(
     msg: ClientMessage,
     sender: SocketId,
-    server_state: &mut ServerState,
+    room_state: &mut RoomState,
     conn: &mut Connection,
 ) -> Result<(), ServerError> {
     let key: &str = match msg {
@@ -312,28 +735,45 @@ async fn handle_client_message(
             respond_to_time_drift_check(send, &sender).await;
             return Ok(());
         }
+        ClientMessage::SubscribeToTournament { id } => {
+            tournament::subscribe(id, sender, conn).await;
+            return Ok(());
+        }
+        ClientMessage::Resync { key } => {
+            // Resyncing is read-only, so it works like a subscription.
+            handle_subscribe_to_match(key, sender, StateProtocol::Delta, room_state, conn)
+                .await?;
+            return Ok(());
+        }
+        ClientMessage::RequestTakeback { .. } | ClientMessage::AnswerTakeback { .. } => {
+            return handle_takeback(msg, sender, room_state, conn).await;
+        }
+        ClientMessage::SetPremove { .. } | ClientMessage::ClearPremoves { .. } => {
+            return handle_premove(msg, sender, room_state, conn).await;
+        }
     };
 
     let game = fetch_game(key, conn).await;
     let Ok(mut game) = game else {
-        server_state.destroy_room(key);
+        room_state.destroy_room();
         send_error(format!("Game {key} not found"), &sender).await;
         return Ok(());
     };
-    {
+    let previous_player = {
         // Whenever we access the game state, we should also advance the timer.
         // This is in an extra block, so we are sure that this state is not reused.
         // After the message has been processed, the state is likely different.
         let state = progress_the_timer(&mut game, key).await?;
         // If the timer has timed out already, we do not need to do anything else.
         if state.victory_state.is_over() {
-            store_game(&game, conn).await?;
-            broadcast_state(server_state, &game, state, conn).await;
+            store_game(&game, &state, conn).await?;
+            broadcast_state(room_state, &game, state, conn).await;
             return Ok(());
         }
-    }
+        state.controlling_player
+    };
 
-    let room = server_state.room(&game, sender);
+    let room = room_state.room(&game, sender, conn).await?;
     ensure_uuid_is_allowed(room, &mut game, sender.get_owner()?, conn).await?;
 
     let state = match msg {
@@ -346,17 +786,293 @@ async fn handle_client_message(
 
             game.rollback()?
         }
-        ClientMessage::TimeDriftCheck { .. } => {
-            unreachable!("We already handled the TimeDriftCheck message.");
+        ClientMessage::TimeDriftCheck { .. }
+        | ClientMessage::SubscribeToTournament { .. }
+        | ClientMessage::Resync { .. }
+        | ClientMessage::RequestTakeback { .. }
+        | ClientMessage::AnswerTakeback { .. }
+        | ClientMessage::SetPremove { .. }
+        | ClientMessage::ClearPremoves { .. } => {
+            unreachable!("We already handled messages without game actions.");
         }
     };
 
-    store_game(&game, conn).await?;
-    broadcast_state(server_state, &game, state, conn).await;
+    let (state, previous_player) =
+        run_premoves(&mut game, state, previous_player, room, conn).await?;
+    store_game(&game, &state, conn).await?;
+    notify_next_player(&game, previous_player, &state);
+    broadcast_state(room_state, &game, state, conn).await;
+
+    Ok(())
+}
+
+/// Takebacks have to be accepted by the opponent. If there is no opponent to
+/// ask, because both sides are controlled by the same player or the opponent
+/// is an AI running in the browser, the takeback is applied right away.
+async fn handle_takeback(
+    msg: ClientMessage,
+    sender: SocketId,
+    room_state: &mut RoomState,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let (ClientMessage::RequestTakeback { ref key } | ClientMessage::AnswerTakeback { ref key, .. }) =
+        msg
+    else {
+        unreachable!("Only takeback messages are handled here.");
+    };
+
+    let Ok(mut game) = fetch_game(key, conn).await else {
+        room_state.destroy_room();
+        send_error(format!("Game {key} not found"), &sender).await;
+        return Ok(());
+    };
+    let state = progress_the_timer(&mut game, key).await?;
+    if state.victory_state.is_over() {
+        store_game(&game, &state, conn).await?;
+        broadcast_state(room_state, &game, state, conn).await;
+        return Ok(());
+    }
+
+    let identity = SocketIdentity::resolve_user(&sender.get_owner()?, conn).await?;
+    let room = room_state.room(&game, sender, conn).await?;
+    let controls = |color: PlayerColor| controls_side(room, &identity, color);
+    let action_count = state.action_count();
+
+    let requested_by = match msg {
+        ClientMessage::RequestTakeback { .. } => {
+            let requested_by = match (controls(PlayerColor::White), controls(PlayerColor::Black)) {
+                // Both sides are played on the same device, so the last turn
+                // belongs to the player who is not moving now.
+                (true, true) => state.controlling_player.other(),
+                (true, false) => PlayerColor::White,
+                (false, true) => PlayerColor::Black,
+                (false, false) => {
+                    return Err(ServerError::NotAllowed(
+                        "You don't play in this game.".to_string(),
+                    ));
+                }
+            };
+            let opponent = requested_by.other();
+            let (white_player, black_player) =
+                load_user_data_for_game(&game.key, &mut *conn).await?;
+            let opponent_is_frontend_ai = match opponent {
+                PlayerColor::White => user::is_frontend_ai(&white_player),
+                PlayerColor::Black => user::is_frontend_ai(&black_player),
+            };
+            if !controls(opponent) && !opponent_is_frontend_ai {
+                room.pending_takeback = Some(PendingTakeback {
+                    requested_by,
+                    action_count,
+                });
+                broadcast_msg(room, ServerMessage::TakebackRequested { requested_by }).await;
+                return Ok(());
+            }
+            requested_by
+        }
+        ClientMessage::AnswerTakeback { accept, .. } => {
+            let Some(pending) = room.pending_takeback else {
+                return Ok(());
+            };
+            if pending.action_count != action_count {
+                // The game moved on, the request is outdated.
+                room.pending_takeback = None;
+                return Ok(());
+            }
+            if !controls(pending.requested_by.other()) {
+                return Err(ServerError::NotAllowed(
+                    "Only the opponent can answer a takeback request.".to_string(),
+                ));
+            }
+            room.pending_takeback = None;
+            if !accept {
+                broadcast_msg(room, ServerMessage::TakebackDeclined).await;
+                return Ok(());
+            }
+            pending.requested_by
+        }
+        _ => unreachable!("Only takeback messages are handled here."),
+    };
+
+    let Some((state, removed_actions)) = game.takeback(requested_by)? else {
+        send_error("There is nothing to take back.".to_string(), &sender).await;
+        return Ok(());
+    };
+    store_game(&game, &state, conn).await?;
+    db::game::insert_takeback(
+        &game.key,
+        requested_by,
+        game.actions.len(),
+        &removed_actions,
+        conn,
+    )
+        .await?;
+    db::game::clear_premoves(&game.key, None, conn).await?;
+    broadcast_state(room_state, &game, state, conn).await;
+
+    Ok(())
+}
+
+/// Checks if the side is locked to the identity.
+fn controls_side(room: &GameRoom, identity: &SocketIdentity, color: PlayerColor) -> bool {
+    let side = match color {
+        PlayerColor::White => &room.white_player,
+        PlayerColor::Black => &room.black_player,
+    };
+    side.test(identity) == ControlLevel::LockedByYou
+}
+
+/// Premoves can only be set by a player who controls exactly one side and only
+/// during the turn of the opponent. The sender gets their current premoves back.
+async fn handle_premove(
+    msg: ClientMessage,
+    sender: SocketId,
+    room_state: &mut RoomState,
+    conn: &mut Connection,
+) -> Result<(), ServerError> {
+    let (ClientMessage::SetPremove { ref key, .. } | ClientMessage::ClearPremoves { ref key }) =
+        msg
+    else {
+        unreachable!("Only premove messages are handled here.");
+    };
+
+    let Ok(mut game) = fetch_game(key, conn).await else {
+        room_state.destroy_room();
+        send_error(format!("Game {key} not found"), &sender).await;
+        return Ok(());
+    };
+    let state = progress_the_timer(&mut game, key).await?;
+    if state.victory_state.is_over() {
+        store_game(&game, &state, conn).await?;
+        broadcast_state(room_state, &game, state, conn).await;
+        return Ok(());
+    }
+
+    let identity = SocketIdentity::resolve_user(&sender.get_owner()?, conn).await?;
+    let room = room_state.room(&game, sender, conn).await?;
+    let player = match (
+        controls_side(room, &identity, PlayerColor::White),
+        controls_side(room, &identity, PlayerColor::Black),
+    ) {
+        (true, false) => PlayerColor::White,
+        (false, true) => PlayerColor::Black,
+        _ => {
+            return Err(ServerError::NotAllowed(
+                "Premoves require you to play exactly one side.".to_string(),
+            ));
+        }
+    };
+    let turn_start = game.current_turn_start()?;
+
+    match msg {
+        ClientMessage::SetPremove { premove, .. } => {
+            if state.controlling_player == player {
+                let error = "You can only premove during the turn of your opponent.";
+                send_error(error.to_string(), &sender).await;
+                return Ok(());
+            }
+            if premove.size() > MAX_PREMOVE_TREE_SIZE {
+                let error = format!("Premoves are limited to {MAX_PREMOVE_TREE_SIZE} moves.");
+                send_error(error, &sender).await;
+                return Ok(());
+            }
+            db::game::insert_premoves(&game.key, player, &identity, turn_start, &[premove], conn)
+                .await?;
+        }
+        ClientMessage::ClearPremoves { .. } => {
+            db::game::clear_premoves(&game.key, Some(player), conn).await?;
+        }
+        _ => unreachable!("Only premove messages are handled here."),
+    }
+
+    let premoves = db::game::premoves(&game.key, player, turn_start, conn).await?;
+    send_msg(ServerMessage::Premoves { premoves }, &sender).await;
 
     Ok(())
 }
 
+/// Executes premoves of the player who has to move now, as long as there are
+/// any for the turn that was just completed. Premoves that are not legal are
+/// dropped, as are premoves of someone who does not control the side anymore.
+/// Returns the new state together with the player who moved last.
+async fn run_premoves(
+    game: &mut SynchronizedMatch,
+    mut state: CurrentMatchState,
+    mut previous_player: PlayerColor,
+    room: &GameRoom,
+    conn: &mut Connection,
+) -> Result<(CurrentMatchState, PlayerColor), ServerError> {
+    for _ in 0..MAX_PREMOVE_CHAIN {
+        if state.victory_state.is_over() || game.current_turn_start()? != game.actions.len() {
+            break;
+        }
+        let Some((turn_start, _)) = game.last_completed_turn()? else {
+            break;
+        };
+        let player = state.controlling_player;
+        let mut owner = None;
+        let mut premoves = vec![];
+        for stored in db::game::take_premoves(&game.key, player, turn_start, conn).await? {
+            match stored.owner {
+                Some(identity) if controls_side(room, &identity, player) => {
+                    owner.get_or_insert(identity);
+                    premoves.push(stored.premove);
+                }
+                _ => info!("Dropped premove of someone who lost the side in game {}.", game.key),
+            }
+        }
+        let Some(owner) = owner else {
+            break;
+        };
+        match game.execute_premove(premoves) {
+            Ok(Some((new_state, then))) => {
+                let action_count = game.actions.len();
+                db::game::insert_premoves(&game.key, player, &owner, action_count, &then, conn)
+                    .await?;
+                state = new_state;
+                previous_player = player;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                info!("Dropped illegal premove in game {}: {:?}", game.key, e);
+                break;
+            }
+        }
+    }
+    Ok((state, previous_player))
+}
+
+/// Correspondence players are not expected to watch the game, so they get a
+/// notification when it becomes their turn.
+fn notify_next_player(
+    game: &SynchronizedMatch,
+    previous_player: PlayerColor,
+    state: &CurrentMatchState,
+) {
+    let is_correspondence = game
+        .timer
+        .as_ref()
+        .is_some_and(|t| t.config.is_correspondence());
+    if !is_correspondence
+        || state.victory_state.is_over()
+        || state.controlling_player == previous_player
+    {
+        return;
+    }
+    let Some(user_id) = game.player(state.controlling_player) else {
+        return;
+    };
+    notification::send(Notification {
+        kind: NotificationKind::YourTurn,
+        game_key: game.key.clone(),
+        user_id,
+        action_count: state.action_count() as i64,
+        deadline: state
+            .timer
+            .as_ref()
+            .map(|t| t.timeout(state.controlling_player)),
+    });
+}
+
 async fn respond_to_time_drift_check(sent_at: DateTime<Utc>, sender: &SocketId) {
     let message = ServerMessage::TimeDriftResponse {
         send: sent_at,
@@ -368,18 +1084,21 @@ async fn respond_to_time_drift_check(sent_at: DateTime<Utc>, sender: &SocketId)
 async fn handle_subscribe_to_match(
     key: String,
     sender: SocketId,
-    server_state: &mut ServerState,
+    protocol: StateProtocol,
+    room_state: &mut RoomState,
     conn: &mut sqlx::pool::PoolConnection<sqlx::Sqlite>,
 ) -> Result<(), anyhow::Error> {
     let game = fetch_game(&key, conn).await?;
     let state = game.current_state();
     let Ok(state) = state else {
-        server_state.destroy_room(&key);
+        room_state.destroy_room();
         send_error(format!("Could not connect to game {key}"), &sender).await;
         return Ok(());
     };
 
-    let room = server_state.room(&game, sender);
+    let room = room_state.room(&game, sender, conn).await?;
+    room.connected.insert(sender, protocol);
+    presence::watch(sender, &key);
 
     if let Some(ref timer) = state.timer {
         if !timer.get_state().is_finished() {
@@ -390,7 +1109,13 @@ async fn handle_subscribe_to_match(
     let client_state =
         CurrentMatchStateClient::try_new(state, room, sender.get_owner()?, conn).await?;
 
-    let response = ServerMessage::CurrentMatchState(Box::new(client_state));
+    let response = match protocol {
+        StateProtocol::FullState => ServerMessage::CurrentMatchState(Box::new(client_state)),
+        StateProtocol::Delta => ServerMessage::MatchStateSync {
+            sequence: room.sequence,
+            state: Box::new(client_state),
+        },
+    };
     send_msg(response, &sender).await;
     Ok(())
 }
@@ -405,8 +1130,8 @@ async fn handle_subscribe_to_match(
 /// If there are two different players connected, then the first player can only
 /// control white while the second player can only control black.
 ///
-/// Please note that currently game protection is not persisted across server
-/// restart. This means it is possible that the first move is done by black.
+/// Uuid locks are stored in `game_uuid_lock` and survive a restart, see the
+/// `protection` module.
 ///
 /// There is another exception with AI play. The side is assigned to the AI with
 /// game_aiConfig set to is_frontend_ai = 1. This the allows the player who
@@ -431,17 +1156,30 @@ async fn ensure_uuid_is_allowed(
         (&mut room.black_player, &mut room.white_player)
     };
 
+    let had_uuid_lock = side_protection.get_uuid().is_some();
     let is_allowed = side_protection.test_and_assign(&sender_identity);
 
     if is_allowed {
         // For persistence, update the game and set the user id for the side.
         // This permanently tracks the player on the game.
+        let color = if white_is_moving { PlayerColor::White } else { PlayerColor::Black };
         if let Some(user_id) = side_protection.get_user() {
-            if white_is_moving {
-                game.white_player = Some(user_id);
+            let player = if white_is_moving {
+                &mut game.white_player
             } else {
-                game.black_player = Some(user_id);
+                &mut game.black_player
+            };
+            let joins_game = *player != Some(user_id);
+            *player = Some(user_id);
+            if had_uuid_lock {
+                db::game::delete_uuid_lock(game.key.parse()?, color, conn).await?;
             }
+            if joins_game {
+                follow::announce_game_start(&game.key, &[user_id], conn).await;
+            }
+        } else if let Some(uuid) = side_protection.get_uuid().filter(|_| !had_uuid_lock) {
+            // Anonymous players keep their side across restarts.
+            db::game::insert_uuid_lock(game.key.parse()?, color, uuid, conn).await?;
         }
         Ok(())
     } else {
@@ -470,18 +1208,36 @@ async fn ensure_uuid_is_allowed(
 
 /// Broadcasts the `CurrentMatchState` to all clients connected to the room.
 /// Each client gets their own view, as they have different control levels.
+/// Depending on the protocol of the socket, this is either the full state or
+/// a delta against the previous broadcast.
 async fn broadcast_state(
-    server_state: &mut ServerState,
+    room_state: &mut RoomState,
     game: &SynchronizedMatch,
     state: CurrentMatchState,
     conn: &mut Connection,
 ) {
-    let Some(room) = server_state.rooms.get_mut(&game.key) else {
+    publish_to_game_stream(game, &state, conn).await;
+
+    let Some(room) = room_state.room.as_mut() else {
         return;
     };
 
+    room.sequence += 1;
+    let sequence = room.sequence;
+    let known_actions = room.known_actions;
+    let players = (game.white_player, game.black_player);
+    let players_changed = room.known_players != players;
+    room.known_actions = state.action_count();
+    room.known_players = players;
+    if room
+        .pending_takeback
+        .is_some_and(|p| p.action_count != room.known_actions)
+    {
+        room.pending_takeback = None;
+    }
+
     let mut disconnected_sockets = vec![];
-    'socket_loop: for target in &room.connected {
+    'socket_loop: for (target, protocol) in &room.connected {
         if let Ok(sender_metadata) = target.get_owner() {
             let Ok(client_state) =
                 CurrentMatchStateClient::try_new(state.clone(), room, sender_metadata, conn).await
@@ -490,11 +1246,19 @@ async fn broadcast_state(
                 warn!("Could not create client state for socket {:?}", target);
                 continue 'socket_loop;
             };
-            send_msg(
-                ServerMessage::CurrentMatchState(Box::new(client_state)),
-                target,
-            )
-                .await;
+            let message = match protocol {
+                StateProtocol::FullState => {
+                    ServerMessage::CurrentMatchState(Box::new(client_state))
+                }
+                StateProtocol::Delta if players_changed => ServerMessage::MatchStateSync {
+                    sequence,
+                    state: Box::new(client_state),
+                },
+                StateProtocol::Delta => ServerMessage::MatchStateDelta(Box::new(
+                    MatchStateDelta::new(client_state, known_actions, sequence),
+                )),
+            };
+            send_msg(message, target).await;
         } else {
             // If the socket is not alive, we remove it from the room.
             disconnected_sockets.push(*target);
@@ -506,7 +1270,36 @@ async fn broadcast_state(
     }
 }
 
-async fn send_msg(message: ServerMessage, target: &SocketId) {
+/// API clients follow games over an event stream instead of a websocket.
+async fn publish_to_game_stream(
+    game: &SynchronizedMatch,
+    state: &CurrentMatchState,
+    conn: &mut Connection,
+) {
+    if !event_stream::has_game_subscribers(&game.key) {
+        return;
+    }
+    let event = match CurrentMatchStateClient::try_new_without_sender(state.clone(), conn).await {
+        Ok(client_state) => StreamEvent::new("gameState", &client_state),
+        Err(e) => {
+            warn!("Could not create state for the stream of game {}: {:?}", game.key, e);
+            return;
+        }
+    };
+    match event {
+        Ok(event) => event_stream::publish_to_game(&game.key, event),
+        Err(e) => warn!("Could not serialize state of game {}: {:?}", game.key, e),
+    }
+}
+
+/// Sends the same message to all clients connected to the room.
+async fn broadcast_msg(room: &GameRoom, message: ServerMessage) {
+    for target in room.connected.keys() {
+        send_msg(message.clone(), target).await;
+    }
+}
+
+pub(crate) async fn send_msg(message: ServerMessage, target: &SocketId) {
     let Ok(msg) = to_string(&message) else {
         warn!("Could not serialize message: {:?}", message);
         return;
@@ -538,8 +1331,129 @@ async fn fetch_game(
 
 async fn store_game(
     game: &SynchronizedMatch,
+    state: &CurrentMatchState,
     conn: &mut Connection,
 ) -> Result<(), anyhow::Error> {
-    db::game::update(game, conn).await?;
+    db::game::update(game, state, conn).await?;
+    if state.victory_state.is_over() {
+        player_statistics::invalidate(game);
+        replay_analysis::game_finished();
+        if let Err(e) = tournament::on_game_over(&game.key, state.victory_state, conn).await {
+            warn!("Could not record the result of game {}: {:?}", game.key, e);
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use pacosako::const_tile::*;
+    use PacoAction::*;
+
+    use super::*;
+
+    fn room(black_player: SideProtection) -> GameRoom {
+        GameRoom {
+            connected: HashMap::new(),
+            white_player: SideProtection::UuidLock("white".to_string()),
+            black_player,
+            sequence: 0,
+            known_actions: 0,
+            known_players: (None, None),
+            pending_takeback: None,
+        }
+    }
+
+    /// Premoves only run while the player who prepared them holds the side.
+    #[tokio::test]
+    async fn test_premove_requires_the_side_lock() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let mut game = SynchronizedMatch {
+            key: String::new(),
+            actions: vec![],
+            timer: None,
+            setup_options: Default::default(),
+            white_player: None,
+            black_player: None,
+        };
+        db::game::insert(&mut game, &mut conn).await.unwrap();
+        let owner = SocketIdentity {
+            uuid: "black".to_string(),
+            user_id: None,
+        };
+        let premove = Premove {
+            condition: None,
+            reply: vec![Lift(E7), Place(E6)],
+            then: vec![],
+        };
+
+        // Someone else took over the side after the premove was prepared.
+        let premoves = [premove];
+        db::game::insert_premoves(&game.key, PlayerColor::Black, &owner, 0, &premoves, &mut conn)
+            .await
+            .unwrap();
+        let state = game.do_action(&[Lift(C2), Place(C3)]).unwrap();
+        let other = room(SideProtection::UuidLock("other".to_string()));
+        let (state, previous_player) =
+            run_premoves(&mut game, state, PlayerColor::White, &other, &mut conn)
+                .await
+                .unwrap();
+        assert_eq!(previous_player, PlayerColor::White);
+        assert_eq!(state.controlling_player, PlayerColor::Black);
+        assert_eq!(game.actions.len(), 2);
+        // The premove is gone, it does not come back for the right player.
+        let owned = room(SideProtection::UuidLock("black".to_string()));
+        let (state, _) = run_premoves(&mut game, state, PlayerColor::White, &owned, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(state.controlling_player, PlayerColor::Black);
+
+        db::game::insert_premoves(&game.key, PlayerColor::Black, &owner, 0, &premoves, &mut conn)
+            .await
+            .unwrap();
+        let (state, previous_player) =
+            run_premoves(&mut game, state, PlayerColor::White, &owned, &mut conn)
+                .await
+                .unwrap();
+        assert_eq!(previous_player, PlayerColor::Black);
+        assert_eq!(state.controlling_player, PlayerColor::White);
+        assert_eq!(game.actions.len(), 4);
+    }
+
+    /// Bots may only move for sides that belong to their account.
+    #[tokio::test]
+    async fn test_bot_moves_only_for_its_side() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+        let bot = user::create_user("bot", "identicon:1", &mut conn).await.unwrap();
+        let other = user::create_user("other", "identicon:2", &mut conn).await.unwrap();
+        let mut game = SynchronizedMatch {
+            key: String::new(),
+            actions: vec![],
+            timer: None,
+            setup_options: Default::default(),
+            white_player: Some(bot),
+            black_player: None,
+        };
+        db::game::insert(&mut game, &mut conn).await.unwrap();
+        let mut room_state = RoomState::default();
+
+        let result =
+            handle_bot_action(&game.key, &[Lift(C2), Place(C3)], other, &mut room_state, &mut conn)
+                .await;
+        assert!(matches!(result, Err(ServerError::NotAllowed(_))));
+
+        handle_bot_action(&game.key, &[Lift(C2), Place(C3)], bot, &mut room_state, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(fetch_game(&game.key, &mut conn).await.unwrap().actions.len(), 2);
+
+        // The black side is not locked to the bot, so it can't take it over.
+        let result =
+            handle_bot_action(&game.key, &[Lift(C7), Place(C6)], bot, &mut room_state, &mut conn)
+                .await;
+        assert!(matches!(result, Err(ServerError::NotAllowed(_))));
+        assert_eq!(fetch_game(&game.key, &mut conn).await.unwrap().actions.len(), 2);
+    }
+}
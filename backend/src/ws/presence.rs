@@ -0,0 +1,74 @@
+//! Which users are connected right now and which games they look at.
+//!
+//! The user of a socket is resolved from its session when it connects. This is
+//! only used to show followers who is online, permissions are still checked
+//! with a fresh `SocketIdentity` for every action.
+
+use std::collections::HashMap;
+
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+
+use crate::actors::websocket::SocketId;
+use crate::login::UserId;
+
+lazy_static! {
+    static ref PRESENCE: DashMap<SocketId, SocketPresence> = DashMap::new();
+}
+
+struct SocketPresence {
+    user_id: UserId,
+    /// The game the socket subscribed to last.
+    game_key: Option<String>,
+}
+
+/// Registers a socket of a logged-in user.
+pub fn connect(socket: SocketId, user_id: UserId) {
+    PRESENCE.insert(
+        socket,
+        SocketPresence {
+            user_id,
+            game_key: None,
+        },
+    );
+}
+
+pub fn disconnect(socket: SocketId) {
+    PRESENCE.remove(&socket);
+}
+
+/// Remembers the game a socket subscribed to. Sockets of anonymous players
+/// are ignored.
+pub fn watch(socket: SocketId, game_key: &str) {
+    if let Some(mut presence) = PRESENCE.get_mut(&socket) {
+        presence.game_key = Some(game_key.to_string());
+    }
+}
+
+/// All sockets of the user.
+pub fn sockets_of(user_id: UserId) -> Vec<SocketId> {
+    PRESENCE
+        .iter()
+        .filter(|entry| entry.user_id == user_id)
+        .map(|entry| *entry.key())
+        .collect()
+}
+
+/// The games each of the users is looking at. Users without a socket are
+/// missing in the result, connected users have an entry even if they don't
+/// look at a game.
+pub fn games_of(user_ids: &[UserId]) -> HashMap<UserId, Vec<String>> {
+    let mut result: HashMap<UserId, Vec<String>> = HashMap::new();
+    for entry in PRESENCE.iter() {
+        if !user_ids.contains(&entry.user_id) {
+            continue;
+        }
+        let games = result.entry(entry.user_id).or_default();
+        if let Some(key) = &entry.game_key {
+            if !games.contains(key) {
+                games.push(key.clone());
+            }
+        }
+    }
+    result
+}
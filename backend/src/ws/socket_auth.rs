@@ -13,6 +13,7 @@ use crate::login::{session, SessionId, UserId};
 pub type SocketAuth = (String, Option<SessionId>);
 
 /// You can resolve the session id of a socket to a user id.
+#[derive(Clone)]
 pub struct SocketIdentity {
     pub uuid: String,
     pub user_id: Option<UserId>,
@@ -0,0 +1,199 @@
+//! Periodically finalizes games whose clock ran out.
+//!
+//! Usually the wake up queue notices a timeout and the room of the game
+//! broadcasts it. The wake up queue only lives in memory though, so this
+//! sweeper works directly on the database as a safety net. It makes sure every
+//! game with an expired clock eventually gets its `TimeoutVictory`. Rooms that
+//! are loaded are told to broadcast the result.
+//!
+//! The sweeper also warns players of correspondence games when their time to
+//! move is about to run out, expires challenges nobody answered and pairs
+//! arena tournaments.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::challenge;
+use crate::db;
+use crate::notification::{self, Notification, NotificationKind};
+use crate::player_statistics;
+use crate::replay_analysis;
+use crate::tournament;
+use crate::ws::{self, LogicMsg};
+use crate::ServerError;
+
+/// How often the sweeper looks for expired games.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Correspondence players are warned when less than this time is left to move.
+const DEADLINE_WARNING: chrono::Duration = chrono::Duration::hours(12);
+
+/// How many games are finalized per database round trip.
+const SWEEP_BATCH_SIZE: i64 = 50;
+
+/// Runs the sweeper forever. Errors are logged and the sweeper tries again
+/// on the next interval.
+pub async fn run(pool: db::Pool) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let mut finished = vec![];
+        if let Err(e) = sweep(&pool, &mut finished).await {
+            warn!("Timeout sweeper failed: {:?}", e);
+        }
+        if !finished.is_empty() {
+            info!("Timeout sweeper finished {} game(s).", finished.len());
+        }
+        for key in finished {
+            ws::to_logic(LogicMsg::Refresh { key }).await;
+        }
+        if let Err(e) = warn_about_deadlines(&pool).await {
+            warn!("Could not check for approaching deadlines: {:?}", e);
+        }
+        if let Err(e) = expire_challenges(&pool).await {
+            warn!("Could not expire challenges: {:?}", e);
+        }
+        if let Err(e) = tick_tournaments(&pool).await {
+            warn!("Could not update arena tournaments: {:?}", e);
+        }
+    }
+}
+
+/// Queues a notification for every correspondence player who has to move
+/// soon. The notification log makes sure each player is warned once per turn.
+async fn warn_about_deadlines(pool: &db::Pool) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let now = Utc::now();
+    let games = db::game::approaching_deadline(
+        now.timestamp_millis(),
+        (now + DEADLINE_WARNING).timestamp_millis(),
+        &mut conn,
+    )
+        .await?;
+
+    for game in games {
+        notification::send(Notification {
+            kind: NotificationKind::DeadlineApproaching,
+            game_key: game.key,
+            user_id: game.user_id,
+            action_count: game.action_count,
+            deadline: DateTime::from_timestamp_millis(game.timeout_at),
+        });
+    }
+    Ok(())
+}
+
+async fn expire_challenges(pool: &db::Pool) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let expired = challenge::expire(&mut conn).await?;
+    if expired > 0 {
+        info!("{} challenge(s) expired.", expired);
+    }
+    Ok(())
+}
+
+async fn tick_tournaments(pool: &db::Pool) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    tournament::tick(&mut conn).await
+}
+
+/// Finalizes all games that are expired right now. The keys of the finished
+/// games are added to `finished`, also if a later game fails.
+async fn sweep(pool: &db::Pool, finished: &mut Vec<String>) -> Result<(), ServerError> {
+    let mut conn = pool.conn().await?;
+    let now = Utc::now().timestamp_millis();
+
+    loop {
+        let expired = db::game::expired(now, SWEEP_BATCH_SIZE, &mut conn).await?;
+        let batch_size = expired.len();
+
+        let mut progress = false;
+        for (mut game, loaded_action_history) in expired {
+            let state = game.timer_progress()?;
+            if !state.victory_state.is_over() {
+                // The persisted timeout was stale, store the correct one.
+                db::game::update(&game, &state, &mut conn).await?;
+                progress = true;
+                continue;
+            }
+            if db::game::finish_on_timeout(&game, &state, &loaded_action_history, &mut conn)
+                .await?
+            {
+                player_statistics::invalidate(&game);
+                replay_analysis::game_finished();
+                if let Err(e) =
+                    tournament::on_game_over(&game.key, state.victory_state, &mut conn).await
+                {
+                    warn!("Could not record the result of game {}: {:?}", game.key, e);
+                }
+                finished.push(game.key.clone());
+                progress = true;
+            }
+        }
+
+        // If nothing changed, the remaining games are being updated by their
+        // rooms right now. We will see them again in the next sweep.
+        if !progress || batch_size < SWEEP_BATCH_SIZE as usize {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sync_match::SynchronizedMatch;
+    use crate::timer::{ClockMode, Timer, TimerConfig};
+
+    #[tokio::test]
+    async fn test_expired_game_is_finished() {
+        let pool = db::test_pool().await;
+        let mut conn = pool.conn().await.unwrap();
+
+        let mut timer = Timer::from(TimerConfig {
+            time_budget_white: chrono::Duration::seconds(5),
+            time_budget_black: chrono::Duration::seconds(5),
+            increment: None,
+            mode: ClockMode::Fischer,
+        });
+        timer.start(Utc::now() - chrono::Duration::minutes(1));
+        let mut game = SynchronizedMatch {
+            key: String::new(),
+            actions: vec![],
+            timer: Some(timer),
+            setup_options: Default::default(),
+            white_player: None,
+            black_player: None,
+        };
+        db::game::insert(&mut game, &mut conn).await.unwrap();
+        // Nobody noticed the timeout, the room only stored the running clock.
+        let state = game.current_state().unwrap();
+        db::game::update(&game, &state, &mut conn).await.unwrap();
+        drop(conn);
+
+        let mut finished = vec![];
+        sweep(&pool, &mut finished).await.unwrap();
+        assert_eq!(finished, vec![game.key.clone()]);
+
+        let mut conn = pool.conn().await.unwrap();
+        let id: i64 = game.key.parse().unwrap();
+        let row = sqlx::query!(
+            r#"select victory_state, finished_at as "finished_at?: String", timeout_at from game where id = ?"#,
+            id
+        )
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(row.victory_state.as_deref(), Some(r#"{"TimeoutVictory":"Black"}"#));
+        assert!(row.finished_at.is_some());
+        assert_eq!(row.timeout_at, None);
+        drop(conn);
+
+        // The next sweep has nothing to do.
+        let mut finished = vec![];
+        sweep(&pool, &mut finished).await.unwrap();
+        assert!(finished.is_empty());
+    }
+}
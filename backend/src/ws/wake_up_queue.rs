@@ -1,6 +1,9 @@
 //! Implements a wake-up queue that is used to coordinate wake-up.
 //! The timers can be cancelled by sending a new shorter delay to the queue for
 //! the same key.
+//!
+//! The queue only lives in memory. Running games re-arm their timers when the
+//! server starts, see `rearm_running_timers` in the parent module.
 
 use chrono::Utc;
 use once_cell::sync::OnceCell;
@@ -8,13 +11,23 @@ use std::{
     collections::{BTreeMap, BTreeSet},
     time::{Duration, Instant},
 };
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    oneshot,
+};
 
 use crate::ws::{to_logic, LogicMsg};
 
 pub fn spawn_sleeper_thread() {
+    // The sender is set up before the thread starts, so wake ups can be put
+    // into the queue right away.
+    let (sender, receiver) = tokio::sync::mpsc::channel(100);
+    WAKE_UP_SENDER
+        .set(sender)
+        .expect("Failed to set wake up sender");
+
     std::thread::spawn(move || {
-        let sleeper = sleeper_task();
+        let sleeper = sleeper_task(receiver);
         tokio::runtime::Builder::new_current_thread()
             .enable_time()
             .build()
@@ -34,16 +47,33 @@ pub async fn put(key: impl Into<String>, wake_up: Instant) {
     if let Err(e) = WAKE_UP_SENDER
         .get()
         .expect("WAKE_UP_SENDER not initialized")
-        .send(WakeUpEntry {
+        .send(WakeUpMsg::Put(WakeUpEntry {
             key: key.into(),
             wake_up,
-        })
+        }))
         .await
     {
         warn!("Failed to send wake up entry: {}", e);
     }
 }
 
+/// Triggers all wake ups that are already due and returns once they have been
+/// handed to the logic. This is used when shutting down, so no timeout that
+/// was due gets lost.
+pub async fn flush() {
+    let (done, done_receiver) = oneshot::channel();
+    if let Err(e) = WAKE_UP_SENDER
+        .get()
+        .expect("WAKE_UP_SENDER not initialized")
+        .send(WakeUpMsg::Flush(done))
+        .await
+    {
+        warn!("Failed to flush the wake up queue: {}", e);
+        return;
+    }
+    let _ = done_receiver.await;
+}
+
 /// Returns a future that sleeps until the given wake up time.
 async fn sleep_until(entry: WakeUpEntry) {
     let now = Instant::now();
@@ -52,19 +82,20 @@ async fn sleep_until(entry: WakeUpEntry) {
     }
 }
 
-static WAKE_UP_SENDER: OnceCell<Sender<WakeUpEntry>> = OnceCell::new();
+static WAKE_UP_SENDER: OnceCell<Sender<WakeUpMsg>> = OnceCell::new();
 
-/// Runs the wake up queue as a tokio task.
-async fn sleeper_task() {
-    let (sender, mut receiver) = tokio::sync::mpsc::channel(100);
+/// Messages that can be sent to the wake up queue.
+#[derive(Debug)]
+enum WakeUpMsg {
+    Put(WakeUpEntry),
+    Flush(oneshot::Sender<()>),
+}
 
+/// Runs the wake up queue as a tokio task.
+async fn sleeper_task(mut receiver: Receiver<WakeUpMsg>) {
     let mut wake_ups: BTreeSet<WakeUpEntry> = BTreeSet::new();
     let mut wake_up_for_key: BTreeMap<String, WakeUpEntry> = BTreeMap::new();
 
-    WAKE_UP_SENDER
-        .set(sender)
-        .expect("Failed to set wake up sender");
-
     loop {
         // Check if there is a wake up
         let first = wake_ups.iter().next().cloned();
@@ -77,13 +108,27 @@ async fn sleeper_task() {
                     trigger_up_wake_ups(&mut wake_ups, &mut wake_up_for_key).await;
                 }
                 new_wake_up = new_wake_up => {
-                    let new_wake_up = new_wake_up.expect("Wake up receiver closed");
-                    update_wake_up(&mut wake_ups, &mut wake_up_for_key, new_wake_up);
+                    let msg = new_wake_up.expect("Wake up receiver closed");
+                    handle_msg(&mut wake_ups, &mut wake_up_for_key, msg).await;
                 }
             }
         } else {
-            let new_wake_up = receiver.recv().await.expect("Wake up receiver closed");
-            update_wake_up(&mut wake_ups, &mut wake_up_for_key, new_wake_up);
+            let msg = receiver.recv().await.expect("Wake up receiver closed");
+            handle_msg(&mut wake_ups, &mut wake_up_for_key, msg).await;
+        }
+    }
+}
+
+async fn handle_msg(
+    wake_ups: &mut BTreeSet<WakeUpEntry>,
+    wake_up_for_key: &mut BTreeMap<String, WakeUpEntry>,
+    msg: WakeUpMsg,
+) {
+    match msg {
+        WakeUpMsg::Put(new_wake_up) => update_wake_up(wake_ups, wake_up_for_key, new_wake_up),
+        WakeUpMsg::Flush(done) => {
+            trigger_up_wake_ups(wake_ups, wake_up_for_key).await;
+            let _ = done.send(());
         }
     }
 }
@@ -135,6 +135,26 @@ pub fn analyze_replay(data: String) -> Result<(), JsValue> {
     Ok(())
 }
 
+/// Like `analyzeReplay`, but without the expensive Paco in 2 search. This is
+/// used when the server already analyzed the replay.
+#[wasm_bindgen(js_name = "notateReplay")]
+pub fn notate_replay(data: String) -> Result<(), JsValue> {
+    utils::set_panic_hook();
+    let data: ActionHistoryBoardRepr = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+
+    let mut initial_board = DenseBoard::with_options(&data.setup).map_err(|e| e.to_string())?;
+    initial_board.draw_state.draw_after_n_repetitions = data.setup.draw_after_n_repetitions;
+    let notation = incremental_replay::history_to_replay_notation_without_paco_in_2(
+        &initial_board,
+        &data.action_history,
+    )
+        .map_err(|e| e.to_string())?;
+
+    analyze_replay_respond(&notation);
+
+    Ok(())
+}
+
 /// This function will report the replay data to Elm. This is extracted into a
 /// separate method in order to be able to call it from the incremental replay.
 pub fn analyze_replay_respond(analysis: &ReplayData) {
@@ -143,7 +143,12 @@ decodeCompressedMatchState =
 decodePublicUserData : Decoder PublicUserData
 decodePublicUserData =
     Decode.map3 PublicUserData
-        (Decode.field "name" Decode.string)
+        -- The display name replaces the name if the user has chosen one.
+        (Decode.oneOf
+            [ Decode.field "display_name" Decode.string
+            , Decode.field "name" Decode.string
+            ]
+        )
         (Decode.field "avatar" Decode.string)
         (Decode.field "ai" (Decode.nullable decodeAiMetaData))
 
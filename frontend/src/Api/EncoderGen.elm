@@ -41,3 +41,15 @@ analyzeReplay { action_history, setup } =
         [ ( "action_history", Encode.list Sako.encodeAction action_history )
         , ( "setup", Sako.encodeSetupOptions setup )
         ]
+
+
+notateReplay :
+    { action_history : List Sako.Action
+    , setup : Sako.SetupOptions
+    }
+    -> Value
+notateReplay { action_history, setup } =
+    Encode.object
+        [ ( "action_history", Encode.list Sako.encodeAction action_history )
+        , ( "setup", Sako.encodeSetupOptions setup )
+        ]
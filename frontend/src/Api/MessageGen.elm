@@ -53,6 +53,12 @@ port analyzePosition : Value -> Cmd msg
 port analyzeReplay : Value -> Cmd msg
 
 
+{-| Asks for the notation of a replay, without the expensive analysis. The
+result is sent to replayAnalysisCompleted.
+-}
+port notateReplay : Value -> Cmd msg
+
+
 {-| Signs up for the status of the given match.
 -}
 port subscribeToMatch : Value -> Cmd msg
@@ -1,9 +1,10 @@
-module Api.ReplayMetaData exposing (CueValueData, ReplayCue(..), ReplayMetaDataProcessed, empty, error, filter, getReplayMetaData)
+module Api.ReplayMetaData exposing (CueValueData, ReplayCue(..), ReplayMetaDataProcessed, ServerAnalysis, empty, error, filter, getReplayMetaData, serverAnalysis)
 
 import Api.Backend exposing (Api, getJson)
 import Arrow exposing (Arrow)
 import Dict exposing (Dict)
 import Json.Decode as Decode exposing (Decoder)
+import Notation exposing (HalfMoveMetadata)
 import Sako
 import Set exposing (Set)
 import Tile exposing (Tile(..))
@@ -45,6 +46,57 @@ accumulateCuesForAction actionIndex _ categoryDict accumulator =
             accumulator
 
 
+{-| The analysis the server stores for finished games, see
+doc/replay-analysis.md. Half-move metadata is keyed by the action index at the
+end of the half-move.
+-}
+type alias ServerAnalysis =
+    { opening : String
+    , halfMoves : Dict Int HalfMoveMetadata
+    }
+
+
+{-| Extracts the server analysis from the replay meta data. The server stores
+the opening for every game it analyzed, so this is Nothing if the game was not
+analyzed yet.
+-}
+serverAnalysis : ReplayMetaDataProcessed -> Maybe ServerAnalysis
+serverAnalysis replayMetaData =
+    let
+        decodeCues : Decoder a -> List ReplayCue -> Maybe a
+        decodeCues decoder cues =
+            cues
+                |> List.filterMap
+                    (\cue ->
+                        case cue of
+                            CueString data ->
+                                Decode.decodeString decoder data |> Result.toMaybe
+
+                            _ ->
+                                Nothing
+                    )
+                |> List.head
+
+        halfMoves =
+            Dict.get "half_move_metadata" replayMetaData
+                |> Maybe.withDefault Dict.empty
+                |> Dict.foldl
+                    (\actionIndex cues accumulator ->
+                        case decodeCues Notation.decodeHalfMoveMetadata cues of
+                            Just metadata ->
+                                Dict.insert actionIndex metadata accumulator
+
+                            Nothing ->
+                                accumulator
+                    )
+                    Dict.empty
+    in
+    Dict.get "opening" replayMetaData
+        |> Maybe.andThen (Dict.get 0)
+        |> Maybe.andThen (decodeCues (Decode.field "opening" Decode.string))
+        |> Maybe.map (\opening -> { opening = opening, halfMoves = halfMoves })
+
+
 {-| Transport and serialisation type
 -}
 type alias ReplayMetaData =
@@ -1,5 +1,6 @@
 module Api.Websocket exposing
     ( ClientMessage(..)
+    , FollowedPlayer
     , ServerMessage(..)
     , WebsocketConnectionState(..)
     , listen
@@ -84,6 +85,16 @@ type ServerMessage
     = TechnicalError String
     | NewMatchState CurrentMatchState
     | TimeDriftRespose { send : Posix, bounced : Posix }
+    | FollowedPlayerStartedGame { gameKey : String, players : List FollowedPlayer }
+
+
+{-| A followed player who started a game. The name is the display name if the
+player has one.
+-}
+type alias FollowedPlayer =
+    { userId : Int
+    , name : String
+    }
 
 
 decodeServerMessage : Decoder ServerMessage
@@ -102,9 +113,27 @@ decodeServerMessage =
             )
             (Decode.at [ "TimeDriftResponse", "send" ] Iso8601.decoder)
             (Decode.at [ "TimeDriftResponse", "bounced" ] Iso8601.decoder)
+        , Decode.field "FollowedPlayerStartedGame"
+            (Decode.map2
+                (\gameKey players ->
+                    FollowedPlayerStartedGame
+                        { gameKey = gameKey
+                        , players = players
+                        }
+                )
+                (Decode.field "game_key" Decode.string)
+                (Decode.field "players" (Decode.list decodeFollowedPlayer))
+            )
         ]
 
 
+decodeFollowedPlayer : Decoder FollowedPlayer
+decodeFollowedPlayer =
+    Decode.map2 FollowedPlayer
+        (Decode.field "user_id" Decode.int)
+        (Decode.field "name" Decode.string)
+
+
 send : ClientMessage -> Cmd msg
 send clientMessage =
     Ports.websocketSend (encodeClientMessage clientMessage)
@@ -5,6 +5,7 @@ module Notation exposing
     , SectionIndex
     , actionIndexForSectionIndex
     , decodeHalfMove
+    , decodeHalfMoveMetadata
     , initialSectionIndex
     , lastAction
     , lastSectionIndex
@@ -15,6 +16,7 @@ module Notation exposing
     , previousMove
     , sectionIndexDiff
     , sectionIndexDiffIsForward
+    , withMetadata
     )
 
 {-| Implements Paco Ŝako Style Notation.
@@ -23,6 +25,7 @@ All the implementation details are in rust, this is just a wrapper around it.
 
 -}
 
+import Dict exposing (Dict)
 import Json.Decode as Decode exposing (Decoder)
 import List.Extra as List
 import Sako
@@ -84,6 +87,34 @@ decodeHalfMoveMetadata =
         (Decode.field "paco_in_2_missed" Decode.bool)
 
 
+{-| Replaces the metadata of all half-moves. The metadata is keyed by the
+action index at the end of the half-move, half-moves without an entry have no
+flag set. This is how the server stores its replay analysis.
+-}
+withMetadata : Dict Int HalfMoveMetadata -> List HalfMove -> List HalfMove
+withMetadata metadata halfMoves =
+    List.map
+        (\halfMove ->
+            { halfMove
+                | metadata =
+                    List.last halfMove.actions
+                        |> Maybe.andThen (\section -> Dict.get section.actionIndex metadata)
+                        |> Maybe.withDefault noMetadata
+            }
+        )
+        halfMoves
+
+
+noMetadata : HalfMoveMetadata
+noMetadata =
+    { givesSako = False
+    , missedPaco = False
+    , givesOpponentPacoOpportunity = False
+    , pacoIn2Found = False
+    , pacoIn2Missed = False
+    }
+
+
 {-| Since a section is what you highlight in the replay view, we also want to
 store this information in a structured way.
 -}
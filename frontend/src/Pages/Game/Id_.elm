@@ -822,6 +822,10 @@ updateWebsocket shared serverMessage model =
                 |> Effect.fromCmd
             )
 
+        Api.Websocket.FollowedPlayerStartedGame _ ->
+            -- Followed players are not shown during a game.
+            ( model, Effect.none )
+
 
 subscriptions : Model -> Sub Msg
 subscriptions model =
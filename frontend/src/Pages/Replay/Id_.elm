@@ -16,7 +16,7 @@ import Api.Decoders exposing (ControlLevel(..), PublicUserData)
 import Api.EncoderGen
 import Api.MessageGen
 import Api.Ports
-import Api.ReplayMetaData exposing (ReplayCue(..), ReplayMetaDataProcessed)
+import Api.ReplayMetaData exposing (ReplayCue(..), ReplayMetaDataProcessed, ServerAnalysis)
 import Arrow
 import Browser.Navigation exposing (pushUrl)
 import CastingDeco
@@ -80,6 +80,8 @@ type alias Model =
 
     -- We store this inside & outside because of race conditions.
     , replayMetaData : ReplayMetaDataProcessed
+    , replayMetaDataLoaded : Bool
+    , serverAnalysis : Maybe ServerAnalysis
     , actionHistory : List Sako.Action
     , setupOptions : Sako.SetupOptions
     , key : String
@@ -121,6 +123,7 @@ type alias InnerModel =
 type DataLoadingWrapper
     = DownloadingReplayData
     | DownloadingReplayDataFailed Http.Error
+    | WaitingForReplayMetaData
     | ProcessingReplayData
     | Done InnerModel
 
@@ -130,6 +133,8 @@ init shared params url =
     ( { replay_url = url
       , replay = DownloadingReplayData
       , replayMetaData = Api.ReplayMetaData.empty
+      , replayMetaDataLoaded = False
+      , serverAnalysis = Nothing
       , actionHistory = []
       , setupOptions = Sako.dummySetupOptions
       , key = params.id
@@ -143,7 +148,7 @@ init shared params url =
       }
     , Cmd.batch
         [ Api.Backend.getReplay params.id HttpErrorReplay GotReplay
-        , Api.ReplayMetaData.getReplayMetaData params.id HttpErrorReplay GotReplayMetaData
+        , Api.ReplayMetaData.getReplayMetaData params.id HttpErrorMetaData GotReplayMetaData
         ]
         |> Effect.fromCmd
     )
@@ -229,8 +234,8 @@ update : Msg -> Model -> ( Model, Effect Msg )
 update msg model =
     case msg of
         GotReplay replay ->
-            ( { model
-                | replay = ProcessingReplayData
+            { model
+                | replay = WaitingForReplayMetaData
                 , actionHistory = removeTimestamps replay.actions
                 , victoryState = replay.victoryState
                 , whitePlayer = replay.whitePlayer
@@ -238,35 +243,35 @@ update msg model =
                 , whiteControl = replay.whiteControl
                 , blackControl = replay.blackControl
                 , setupOptions = replay.setupOptions
-              }
-            , replay
-                |> stripDownReplay
-                |> Api.EncoderGen.analyzeReplay
-                |> Api.MessageGen.analyzeReplay
-                |> Effect.fromCmd
-            )
+            }
+                |> startReplayAnalysis
 
         GotReplayAnalysis notation ->
             ( { model
-                | replay = Done (innerInit model notation)
+                | replay = Done (innerInit model (withServerAnalysis model.serverAnalysis notation))
               }
             , Effect.none
             )
 
         GotReplayMetaData replayMetaData ->
-            ( { model | replayMetaData = replayMetaData }
+            { model
+                | replayMetaData = replayMetaData
+                , replayMetaDataLoaded = True
+                , serverAnalysis = Api.ReplayMetaData.serverAnalysis replayMetaData
+            }
                 |> copyReplayMetaDataIntoInner
-            , Effect.none
-            )
+                |> startReplayAnalysis
 
         HttpErrorReplay error ->
             ( { model | replay = DownloadingReplayDataFailed error }, Effect.none )
 
-        HttpErrorMetaData error ->
-            ( { model | replayMetaData = Api.ReplayMetaData.error }
+        HttpErrorMetaData _ ->
+            { model
+                | replayMetaData = Api.ReplayMetaData.error
+                , replayMetaDataLoaded = True
+            }
                 |> copyReplayMetaDataIntoInner
-            , Effect.none
-            )
+                |> startReplayAnalysis
 
         PortError error ->
             ( model, Api.Ports.logToConsole error |> Effect.fromCmd )
@@ -374,18 +379,54 @@ copyReplayMetaDataIntoInner model =
             model
 
 
-{-| Remove all the timestamps from the replay and turn it into an RpcCall.
+{-| Once both the replay and its meta data are downloaded, this asks the
+web worker for the notation. Games the server already analyzed skip the
+expensive analysis in the browser and use the server analysis instead.
 -}
-stripDownReplay :
-    Replay
-    ->
-        { action_history : List Sako.Action
-        , setup : Sako.SetupOptions
-        }
-stripDownReplay replay =
-    { action_history = removeTimestamps replay.actions
-    , setup = replay.setupOptions
-    }
+startReplayAnalysis : Model -> ( Model, Effect Msg )
+startReplayAnalysis model =
+    case model.replay of
+        WaitingForReplayMetaData ->
+            if model.replayMetaDataLoaded then
+                let
+                    request =
+                        { action_history = model.actionHistory
+                        , setup = model.setupOptions
+                        }
+                in
+                ( { model | replay = ProcessingReplayData }
+                , case model.serverAnalysis of
+                    Just _ ->
+                        request
+                            |> Api.EncoderGen.notateReplay
+                            |> Api.MessageGen.notateReplay
+                            |> Effect.fromCmd
+
+                    Nothing ->
+                        request
+                            |> Api.EncoderGen.analyzeReplay
+                            |> Api.MessageGen.analyzeReplay
+                            |> Effect.fromCmd
+                )
+
+            else
+                ( model, Effect.none )
+
+        _ ->
+            ( model, Effect.none )
+
+
+withServerAnalysis : Maybe ServerAnalysis -> ReplayData -> ReplayData
+withServerAnalysis serverAnalysis replayData =
+    case serverAnalysis of
+        Just analysis ->
+            { replayData
+                | notation = Notation.withMetadata analysis.halfMoves replayData.notation
+                , opening = analysis.opening
+            }
+
+        Nothing ->
+            replayData
 
 
 removeTimestamps : List ( Sako.Action, Posix ) -> List Sako.Action
@@ -512,6 +553,9 @@ body shared model =
         DownloadingReplayDataFailed _ ->
             Pages.NotFound.body
 
+        WaitingForReplayMetaData ->
+            Element.text T.loadingReplayData
+
         ProcessingReplayData ->
             Element.text T.loadingReplayData
 
@@ -16,6 +16,7 @@ const {
     generateRandomPosition,
     analyzePosition,
     analyzeReplay,
+    notateReplay,
     subscribeToMatch,
     determineLegalActions,
     determineAiMove,
@@ -58,6 +59,9 @@ function forwardToWasm(messageType: any, data: any) {
     if (messageType === "analyzeReplay") {
         analyzeReplay(data);
     }
+    if (messageType === "notateReplay") {
+        notateReplay(data);
+    }
     if (messageType === "subscribeToMatch") {
         subscribeToMatch(data);
     }
@@ -366,7 +366,9 @@ class WebsocketWrapper {
      * an exponential back-off strategy.
      */
     private connection_closed(ev: CloseEvent) {
-        if (ev.wasClean) {
+        // 1012 (Service Restart) is sent by the server when it shuts down for
+        // a deploy. The connection is closed cleanly, but we still reconnect.
+        if (ev.wasClean && ev.code !== 1012) {
             console.log("Websocket connection was closed cleanly.")
             return;
         }
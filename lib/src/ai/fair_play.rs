@@ -0,0 +1,237 @@
+//! Fair-play analysis: how closely does a player follow the model?
+//!
+//! A game is replayed and every decision of one player is evaluated with a
+//! `ModelBackend`. We record the rank of the played action in the policy and
+//! how much value the player gave away with it. Someone who consults the AI
+//! during a game agrees with its favorite much more often than others and
+//! rarely loses value. Comparing the statistics between players is left to
+//! the caller.
+
+use crate::ai::model_backend::ModelBackend;
+use crate::ai::model_evaluation::ModelEvaluation;
+use crate::{DenseBoard, PacoAction, PacoBoard, PacoError, PlayerColor, VictoryState};
+
+/// An action of the analyzed player in a position where they had a choice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    /// Position of the played action in the sorted policy, 1 is the favorite.
+    pub policy_rank: usize,
+    /// Normalized policy of the played action.
+    pub policy: f32,
+    /// How much the value dropped for the player with this action, in
+    /// hundredths of a value. This is never negative.
+    pub value_loss: f32,
+    /// Seconds the player took for this action, if known.
+    pub think_time: Option<f32>,
+}
+
+/// Replays `actions` starting from `board` and evaluates every decision of
+/// `player`. Forced actions, where only one action is legal, are skipped.
+///
+/// `think_times` is indexed like `actions`, missing entries are unknown.
+pub async fn analyze_game(
+    backend: &mut impl ModelBackend,
+    mut board: DenseBoard,
+    actions: &[PacoAction],
+    think_times: &[Option<f32>],
+    player: PlayerColor,
+) -> Result<Vec<Decision>, PacoError> {
+    let mut decisions = vec![];
+    // Evaluation of the current position, if we already know it.
+    let mut evaluation: Option<ModelEvaluation> = None;
+
+    for (index, &action) in actions.iter().enumerate() {
+        if board.victory_state().is_over() {
+            break;
+        }
+        if board.controlling_player != player || board.actions()?.len() <= 1 {
+            board.execute_trusted(action)?;
+            evaluation = None;
+            continue;
+        }
+
+        let before = match evaluation.take() {
+            Some(evaluation) => evaluation,
+            None => backend.evaluate_model(&board).await?,
+        };
+        let sorted = before.sorted();
+        let Some(rank) = sorted.iter().position(|(a, _)| *a == action) else {
+            return Err(PacoError::MlModelError(format!(
+                "Played action {:?} is missing in the policy.",
+                action
+            )));
+        };
+
+        board.execute_trusted(action)?;
+        let value_after = match board.victory_state() {
+            VictoryState::Running => {
+                let after = backend.evaluate_model(&board).await?;
+                // The value is always given for the player to move.
+                let value = if board.controlling_player == player {
+                    after.value
+                } else {
+                    -after.value
+                };
+                evaluation = Some(after);
+                value
+            }
+            state => final_value(state, player),
+        };
+
+        decisions.push(Decision {
+            policy_rank: rank + 1,
+            policy: sorted[rank].1,
+            value_loss: ((before.value - value_after) * 100.).max(0.),
+            think_time: think_times.get(index).copied().flatten(),
+        });
+    }
+
+    Ok(decisions)
+}
+
+/// Value of a finished game for the player.
+fn final_value(state: VictoryState, player: PlayerColor) -> f32 {
+    match state {
+        VictoryState::PacoVictory(winner) | VictoryState::TimeoutVictory(winner) => {
+            if winner == player {
+                1.
+            } else {
+                -1.
+            }
+        }
+        VictoryState::Running | VictoryState::NoProgressDraw | VictoryState::RepetitionDraw => 0.,
+    }
+}
+
+/// Summary of many decisions, usually from several games.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FairPlayStatistics {
+    pub decisions: usize,
+    /// Share of decisions where the model's favorite was played.
+    pub top1_agreement: f32,
+    pub average_rank: f32,
+    /// Average value loss per decision, in hundredths of a value.
+    pub average_value_loss: f32,
+    /// Average think time of decisions that played the favorite.
+    pub think_time_top1: Option<f32>,
+    /// Average think time of all other decisions.
+    pub think_time_other: Option<f32>,
+}
+
+impl FairPlayStatistics {
+    pub fn from_decisions(decisions: &[Decision]) -> Self {
+        if decisions.is_empty() {
+            return Self::default();
+        }
+        let count = decisions.len() as f32;
+        let top1 = decisions.iter().filter(|d| d.policy_rank == 1).count();
+
+        FairPlayStatistics {
+            decisions: decisions.len(),
+            top1_agreement: top1 as f32 / count,
+            average_rank: decisions.iter().map(|d| d.policy_rank as f32).sum::<f32>() / count,
+            average_value_loss: decisions.iter().map(|d| d.value_loss).sum::<f32>() / count,
+            think_time_top1: mean(
+                decisions
+                    .iter()
+                    .filter(|d| d.policy_rank == 1)
+                    .filter_map(|d| d.think_time),
+            ),
+            think_time_other: mean(
+                decisions
+                    .iter()
+                    .filter(|d| d.policy_rank != 1)
+                    .filter_map(|d| d.think_time),
+            ),
+        }
+    }
+}
+
+fn mean(values: impl Iterator<Item = f32>) -> Option<f32> {
+    let (sum, count) = values.fold((0., 0), |(sum, count), v| (sum + v, count + 1));
+    if count == 0 {
+        None
+    } else {
+        Some(sum / count as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Prefers the actions with the highest action index and thinks every
+    /// position is equal.
+    struct IndexBackend;
+
+    impl ModelBackend for IndexBackend {
+        async fn evaluate_model(
+            &mut self,
+            board: &DenseBoard,
+        ) -> Result<ModelEvaluation, PacoError> {
+            let mut raw = [0f32; 133];
+            for (i, r) in raw.iter_mut().enumerate().skip(1) {
+                *r = i as f32;
+            }
+            ModelEvaluation::new(board.actions()?, board.controlling_player, &raw)
+        }
+    }
+
+    #[tokio::test]
+    async fn following_the_model_agrees_everywhere() -> Result<(), PacoError> {
+        let mut board = DenseBoard::new();
+        let mut actions = vec![];
+        for _ in 0..12 {
+            let action = if board.controlling_player == PlayerColor::White {
+                IndexBackend.evaluate_model(&board).await?.sorted()[0].0
+            } else {
+                board.actions()?.into_iter().next().unwrap()
+            };
+            board.execute(action)?;
+            actions.push(action);
+        }
+
+        let think_times = vec![Some(2.); actions.len()];
+        let decisions = analyze_game(
+            &mut IndexBackend,
+            DenseBoard::new(),
+            &actions,
+            &think_times,
+            PlayerColor::White,
+        )
+        .await?;
+
+        assert!(!decisions.is_empty());
+        let stats = FairPlayStatistics::from_decisions(&decisions);
+        assert_eq!(stats.top1_agreement, 1.);
+        assert_eq!(stats.average_rank, 1.);
+        assert_eq!(stats.average_value_loss, 0.);
+        assert_eq!(stats.think_time_top1, Some(2.));
+        assert_eq!(stats.think_time_other, None);
+        Ok(())
+    }
+
+    #[test]
+    fn statistics_of_decisions() {
+        let decision = |policy_rank, value_loss, think_time| Decision {
+            policy_rank,
+            policy: 0.,
+            value_loss,
+            think_time,
+        };
+        let stats = FairPlayStatistics::from_decisions(&[
+            decision(1, 0., Some(1.)),
+            decision(1, 10., Some(3.)),
+            decision(4, 20., None),
+            decision(2, 30., Some(10.)),
+        ]);
+        assert_eq!(stats.decisions, 4);
+        assert_eq!(stats.top1_agreement, 0.5);
+        assert_eq!(stats.average_rank, 2.);
+        assert_eq!(stats.average_value_loss, 15.);
+        assert_eq!(stats.think_time_top1, Some(2.));
+        assert_eq!(stats.think_time_other, Some(10.));
+
+        assert_eq!(FairPlayStatistics::from_decisions(&[]).decisions, 0);
+    }
+}
@@ -6,6 +6,7 @@
 //! It mirrors Jtac, which we are using to train our models.
 //! https://github.com/roSievers/Jtac.jl/
 
+pub mod fair_play;
 pub mod flexible_representation;
 pub mod glue;
 pub mod repr;
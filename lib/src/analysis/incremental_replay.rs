@@ -94,6 +94,25 @@ pub fn history_to_replay_notation_incremental(
     })
 }
 
+/// Like `history_to_replay_notation_incremental`, but without the expensive
+/// Paco in 2 step. The `paco_in_2_*` metadata is never set. This is fast
+/// enough to analyze many games at once on the server.
+pub fn history_to_replay_notation_without_paco_in_2(
+    initial_board: &DenseBoard,
+    actions: &[PacoAction],
+) -> Result<ReplayData, PacoError> {
+    let raw_half_moves = sort_actions_into_half_moves(initial_board, actions)?;
+    let mut half_moves = derive_notation(initial_board, raw_half_moves)?;
+    let opening = opening::classify_opening(initial_board, actions)?;
+    annotate_sako(initial_board, &mut half_moves)?;
+
+    Ok(ReplayData {
+        notation: half_moves,
+        opening,
+        progress: 1.0,
+    })
+}
+
 /// This first step takes care of sorting the actions into half moves.
 /// This is a prerequisite for all other steps, but doesn't annotate the half
 /// moves with any metadata.
@@ -16,6 +16,8 @@ pub mod puzzle;
 pub mod reverse_amazon_search;
 pub(crate) mod tree;
 
+pub use opening::classify_opening;
+
 #[derive(Serialize, PartialEq, Debug)]
 pub struct ReplayData {
     notation: Vec<HalfMove>,
@@ -23,6 +25,17 @@ pub struct ReplayData {
     progress: f32,
 }
 
+impl ReplayData {
+    pub fn notation(&self) -> &[HalfMove] {
+        &self.notation
+    }
+
+    /// Name of the opening, empty if no known opening was played.
+    pub fn opening(&self) -> &str {
+        &self.opening
+    }
+}
+
 /// Represents a single line in the sidebar, like "g2>Pf3>Pe4>Pd5>d6".
 /// This would be represented as [g2>Pf3][>Pe4][>Pd5][>d6].
 /// Where each section also points to the action index to jump there easily.
@@ -35,6 +48,30 @@ pub struct HalfMove {
     metadata: HalfMoveMetadata,
 }
 
+impl HalfMove {
+    pub fn move_number(&self) -> u32 {
+        self.move_number
+    }
+
+    pub fn current_player(&self) -> PlayerColor {
+        self.current_player
+    }
+
+    pub fn metadata(&self) -> &HalfMoveMetadata {
+        &self.metadata
+    }
+
+    /// The number of actions played at the end of this half-move.
+    pub fn action_index(&self) -> usize {
+        self.actions.last().map_or(0, |section| section.action_index)
+    }
+
+    /// The whole half-move as one label, like "g2>Pf3>Pe4>Pd5>d6".
+    pub fn label(&self) -> String {
+        self.actions.iter().map(|section| section.label.as_str()).collect()
+    }
+}
+
 /// Represents a single section in a half-move. Like `g2xPf3`.
 #[derive(Serialize, PartialEq, Eq, Debug, Clone)]
 pub struct HalfMoveSection {
@@ -54,6 +91,13 @@ pub struct HalfMoveMetadata {
     paco_in_2_missed: bool,
 }
 
+impl HalfMoveMetadata {
+    /// The player could have won with Paco in this half-move, but didn't.
+    pub fn missed_paco(&self) -> bool {
+        self.missed_paco
+    }
+}
+
 /// Metadata with all flags set to false.
 impl Default for HalfMoveMetadata {
     fn default() -> Self {
@@ -4,7 +4,7 @@ use crate::{DenseBoard, PacoAction, PacoBoard, PacoError, PieceType, substrate::
 use crate::PlayerColor::White;
 
 /// Returns all the openings that can be detected on the given replay.
-pub(crate) fn classify_opening(
+pub fn classify_opening(
     initial_board: &DenseBoard,
     actions: &[PacoAction],
 ) -> Result<String, PacoError> {
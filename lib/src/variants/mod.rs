@@ -1,12 +1,12 @@
 use crate::fen;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 pub mod fischer_random;
 
 pub const DEFAULT_STARTING_FEN: &str =
     "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w 0 AHah - -";
 
-#[derive(Deserialize, Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub enum PieceSetupParameters {
     DefaultPieceSetup,
     FischerRandom,